@@ -0,0 +1,122 @@
+//! Decryption of a burst of packets off the read loop, delivered in order.
+//!
+//! `spawn_blocking` spawns a task on the blocking pool of tokio per packet, as the stream
+//! processors did before, the rest go through [`ordered`] as they do now.
+//!
+//! Run with `cargo bench --features testing --bench offload`.
+
+use std::{hint::black_box, sync::Arc};
+
+use airplay::bench::{Offload, Priority, PriorityPool, ordered};
+use chacha20poly1305::{AeadInPlace, ChaCha20Poly1305, KeyInit};
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use tokio::{runtime::Runtime, sync::mpsc, task};
+
+/// Packets of a burst, e.g. a frame of mirrored video
+const PACKETS: usize = 256;
+/// Payload of a realtime audio packet, 352 frames of 16 bit stereo
+const PAYLOAD: usize = 1408;
+const WORKERS: usize = 2;
+
+struct Burst {
+    cipher: Arc<ChaCha20Poly1305>,
+    packets: Vec<Arc<Vec<u8>>>,
+}
+
+impl Burst {
+    fn new() -> Self {
+        let cipher = ChaCha20Poly1305::new(&[7; 32].into());
+        let packets = (0..PACKETS)
+            .map(|_| {
+                let mut payload = vec![0xa5; PAYLOAD];
+                cipher
+                    .encrypt_in_place(&[0; 12].into(), &[], &mut payload)
+                    .unwrap();
+                Arc::new(payload)
+            })
+            .collect();
+        Self {
+            cipher: Arc::new(cipher),
+            packets,
+        }
+    }
+
+    fn decrypt(&self, i: usize) -> impl FnOnce() -> usize + Send + 'static {
+        let cipher = Arc::clone(&self.cipher);
+        let packet = Arc::clone(&self.packets[i]);
+        move || {
+            let mut payload = packet.to_vec();
+            cipher
+                .decrypt_in_place(&[0; 12].into(), &[], &mut payload)
+                .unwrap();
+            payload.len()
+        }
+    }
+
+    async fn offloaded(&self, offload: Offload) -> usize {
+        let mut delivered = 0;
+        ordered(
+            offload,
+            |queue| async move {
+                for i in 0..PACKETS {
+                    queue.push(self.decrypt(i)).await;
+                }
+                Ok(())
+            },
+            |len| {
+                delivered += black_box(len);
+                Ok(())
+            },
+        )
+        .await
+        .unwrap();
+        delivered
+    }
+
+    async fn spawn_blocking(&self) -> usize {
+        let (tx, mut rx) = mpsc::channel(WORKERS);
+        let read = async move {
+            for i in 0..PACKETS {
+                tx.send(task::spawn_blocking(self.decrypt(i)))
+                    .await
+                    .unwrap();
+            }
+        };
+        let deliver = async {
+            let mut delivered = 0;
+            while let Some(handle) = rx.recv().await {
+                delivered += black_box(handle.await.unwrap());
+            }
+            delivered
+        };
+        tokio::join!(read, deliver).1
+    }
+}
+
+fn offload(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let burst = Burst::new();
+    let pool = Arc::new(PriorityPool::new(WORKERS).unwrap());
+
+    let mut group = c.benchmark_group("offload");
+    group.throughput(Throughput::Elements(PACKETS as u64));
+    group.bench_function("spawn_blocking", |b| {
+        b.to_async(&rt).iter(|| burst.spawn_blocking());
+    });
+    group.bench_function("inline", |b| {
+        b.to_async(&rt)
+            .iter(|| burst.offloaded(Offload::dedicated(0)));
+    });
+    group.bench_function(BenchmarkId::new("dedicated", WORKERS), |b| {
+        b.to_async(&rt)
+            .iter(|| burst.offloaded(Offload::dedicated(WORKERS)));
+    });
+    group.bench_function(BenchmarkId::new("pooled", WORKERS), |b| {
+        b.to_async(&rt)
+            .iter(|| burst.offloaded(Offload::pooled(WORKERS, &pool, Priority::Audio)));
+    });
+    group.finish();
+}
+
+criterion_group!(benches, offload);
+criterion_main!(benches);
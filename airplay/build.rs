@@ -1,33 +1,32 @@
-use std::{path::Path, process::Command};
-
 fn main() {
-    let mut build = cc::Build::new();
-    let out = std::env::var("OUT_DIR").unwrap();
-    let shairplay = Path::new(&out).join("shairplay");
+    #[cfg(feature = "fairplay")]
+    build_playfair();
+}
 
-    if !shairplay.exists() {
-        let command = Command::new("git")
-            .arg("clone")
-            .arg("--depth")
-            .arg("1")
-            .arg("https://github.com/juhovh/shairplay")
-            .arg(&shairplay)
-            .status()
-            .unwrap();
-        assert!(command.success());
-    }
+/// Compiles the playfair sources vendored from shairplay, see `vendor/playfair/README.md`.
+#[cfg(feature = "fairplay")]
+fn build_playfair() {
+    use std::path::Path;
 
-    let playfair_dir = shairplay
-        .join("src")
-        .join("lib")
-        .join("playfair")
-        .join("*.c");
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    let playfair = Path::new(&manifest_dir).join("vendor").join("playfair");
+    println!("cargo:rerun-if-changed={}", playfair.to_str().unwrap());
 
-    let playfair_dir_str = playfair_dir.to_str().unwrap();
+    let sources = playfair.join("*.c");
+    let sources: Vec<_> = glob::glob(sources.to_str().unwrap())
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert!(
+        !sources.is_empty(),
+        "no playfair sources in {}, copy them as described in its README.md \
+         or build without the `fairplay` feature",
+        playfair.display()
+    );
 
-    for entry in glob::glob(playfair_dir_str).unwrap() {
-        build.file(entry.unwrap());
-    }
-    build.cargo_warnings(false).compile("fairplay3");
-    println!("cargo:rerun-if-changed={}", shairplay.to_str().unwrap());
+    cc::Build::new()
+        .files(sources)
+        .include(&playfair)
+        .cargo_warnings(false)
+        .compile("fairplay3");
 }
@@ -0,0 +1,284 @@
+//! Bonjour advertisement of the receiver, so senders are able to discover it.
+//!
+//! Both `_airplay._tcp` and legacy `_raop._tcp` services are published, TXT records of them
+//! mirror the `/info` response.
+
+use std::fmt::Write as _;
+
+use bitflags::bitflags;
+use macaddr::MacAddr6;
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use thiserror::Error;
+
+use crate::{config::Config, crypto::pairing::legacy::State as LegacyPairing, rtsp};
+
+bitflags! {
+    /// Value of `flags`/`sf` TXT keys.
+    #[repr(transparent)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct StatusFlags: u32 {
+        const ProblemDetected = 1 << 0;
+        const NotConfigured = 1 << 1;
+        const AudioCableAttached = 1 << 2;
+        const PinRequired = 1 << 3;
+        const PasswordRequired = 1 << 7;
+        const OneTimePairingRequired = 1 << 9;
+        const ReceiverSessionActive = 1 << 11;
+    }
+}
+
+impl Default for StatusFlags {
+    fn default() -> Self {
+        Self::AudioCableAttached
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum AdvertiseError {
+    #[error("mdns: {0}")]
+    Mdns(#[from] mdns_sd::Error),
+}
+
+/// Registered services, unregistered on drop.
+pub struct Advertisement {
+    daemon: ServiceDaemon,
+    airplay: Service,
+    raop: Service,
+    status: StatusFlags,
+}
+
+/// Services put together before the RTSP port is known, see [`Advertisement::new`].
+pub(crate) struct PendingAdvertisement {
+    airplay: Service,
+    raop: Service,
+    status: StatusFlags,
+}
+
+struct Service {
+    ty: &'static str,
+    instance: String,
+    hostname: String,
+    port: u16,
+    properties: Vec<(&'static str, String)>,
+    /// Key of status flags in `properties`
+    status_key: &'static str,
+}
+
+impl Advertisement {
+    const AIRPLAY_TYPE: &'static str = "_airplay._tcp.local.";
+    const RAOP_TYPE: &'static str = "_raop._tcp.local.";
+
+    /// Publishes the receiver listening for RTSP connections on `port`.
+    ///
+    /// # Errors
+    ///
+    /// If mDNS daemon couldn't be started or rejected the services.
+    pub fn new<A, V>(
+        cfg: &Config<A, V>,
+        device_id: MacAddr6,
+        port: u16,
+    ) -> Result<Self, AdvertiseError> {
+        Self::pending(cfg, device_id).register(port)
+    }
+
+    pub(crate) fn pending<A, V>(cfg: &Config<A, V>, device_id: MacAddr6) -> PendingAdvertisement {
+        let status = StatusFlags::default();
+        let (airplay, raop) = Self::services(cfg, device_id, 0, status);
+        PendingAdvertisement {
+            airplay,
+            raop,
+            status,
+        }
+    }
+
+    fn services<A, V>(
+        cfg: &Config<A, V>,
+        device_id: MacAddr6,
+        port: u16,
+        status: StatusFlags,
+    ) -> (Service, Service) {
+        let features = cfg.features.bits();
+        // Lower and upper 32 bits
+        #[allow(clippy::cast_possible_truncation)]
+        let features = format!("0x{:X},0x{:X}", features as u32, features >> 32);
+        let pk = LegacyPairing::from_signing_privkey(cfg.pairing.legacy_pairing_key)
+            .verifying_key()
+            .iter()
+            .fold(String::new(), |mut hex, byte| {
+                let _ = write!(hex, "{byte:02x}");
+                hex
+            });
+        let hostname = format!("{}.local.", cfg.name.replace(' ', "-"));
+        let status_value = format!("{:#x}", status.bits());
+
+        let airplay = Service {
+            ty: Self::AIRPLAY_TYPE,
+            instance: cfg.name.clone(),
+            hostname: hostname.clone(),
+            port,
+            properties: vec![
+                ("deviceid", device_id.to_string()),
+                ("features", features.clone()),
+                ("flags", status_value.clone()),
+                ("model", cfg.model.clone()),
+                ("manufacturer", cfg.manufacturer.clone()),
+                ("fv", cfg.fw_version.clone()),
+                ("protovers", rtsp::PROTOCOL_VERSION.to_string()),
+                ("srcvers", rtsp::SOURCE_VERSION.to_string()),
+                ("pk", pk.clone()),
+            ],
+            status_key: "flags",
+        };
+
+        let raop = Service {
+            ty: Self::RAOP_TYPE,
+            instance: format!("{}@{}", device_id.to_string().replace(':', ""), cfg.name),
+            hostname,
+            port,
+            properties: vec![
+                ("am", cfg.model.clone()),
+                ("ch", "2".to_string()),
+                ("cn", "0,1,2,3".to_string()),
+                ("da", "true".to_string()),
+                ("et", "0,3,5".to_string()),
+                ("ft", features),
+                ("md", "0,1,2".to_string()),
+                ("pk", pk),
+                ("sf", status_value),
+                ("tp", "UDP".to_string()),
+                ("vn", "65537".to_string()),
+                ("vs", rtsp::SOURCE_VERSION.to_string()),
+            ],
+            status_key: "sf",
+        };
+
+        (airplay, raop)
+    }
+
+    #[must_use]
+    pub fn status(&self) -> StatusFlags {
+        self.status
+    }
+
+    /// Re-announces services with updated status flags, e.g. when a session starts or ends.
+    ///
+    /// Services stay registered, only their TXT records are replaced.
+    ///
+    /// # Errors
+    ///
+    /// If mDNS daemon has stopped.
+    pub fn set_status(&mut self, status: StatusFlags) -> Result<(), AdvertiseError> {
+        if status == self.status {
+            return Ok(());
+        }
+
+        let value = format!("{:#x}", status.bits());
+        for service in [&mut self.airplay, &mut self.raop] {
+            service.set_property(service.status_key, value.clone());
+            self.daemon.register(service.info()?)?;
+        }
+        self.status = status;
+
+        Ok(())
+    }
+}
+
+impl PendingAdvertisement {
+    /// Publishes the receiver listening for RTSP connections on `port`.
+    pub(crate) fn register(mut self, port: u16) -> Result<Advertisement, AdvertiseError> {
+        self.airplay.port = port;
+        self.raop.port = port;
+
+        let daemon = ServiceDaemon::new()?;
+        daemon.register(self.airplay.info()?)?;
+        daemon.register(self.raop.info()?)?;
+        tracing::info!(name = %self.airplay.instance, %port, "services advertised");
+
+        Ok(Advertisement {
+            daemon,
+            airplay: self.airplay,
+            raop: self.raop,
+            status: self.status,
+        })
+    }
+}
+
+impl Service {
+    fn set_property(&mut self, key: &str, value: String) {
+        if let Some((_, old)) = self.properties.iter_mut().find(|(k, _)| *k == key) {
+            *old = value;
+        }
+    }
+
+    fn info(&self) -> Result<ServiceInfo, mdns_sd::Error> {
+        ServiceInfo::new(
+            self.ty,
+            &self.instance,
+            &self.hostname,
+            "",
+            self.port,
+            &self.properties[..],
+        )
+        .map(ServiceInfo::enable_addr_auto)
+    }
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        for service in [&self.airplay, &self.raop] {
+            let fullname = format!("{}.{}", service.instance, service.ty);
+            if let Err(err) = self.daemon.unregister(&fullname) {
+                tracing::warn!(%err, %fullname, "service not unregistered");
+            }
+        }
+        let _ = self.daemon.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use macaddr::MacAddr6;
+
+    use super::{Advertisement, StatusFlags};
+    use crate::config::{Config, Features};
+
+    #[test]
+    fn txt_records() {
+        let cfg = Config::<(), ()> {
+            name: "Living Room".to_string(),
+            features: Features::from_bits_retain(0x0001_0000_0000_0200),
+            ..Default::default()
+        };
+        let device_id = MacAddr6::new(0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff);
+
+        let (mut airplay, raop) =
+            Advertisement::services(&cfg, device_id, 7000, StatusFlags::default());
+        let airplay_info = airplay.info().unwrap();
+        let raop_info = raop.info().unwrap();
+
+        assert_eq!(
+            "Living Room._airplay._tcp.local.",
+            airplay_info.get_fullname()
+        );
+        assert_eq!(
+            "AABBCCDDEEFF@Living Room._raop._tcp.local.",
+            raop_info.get_fullname()
+        );
+        assert_eq!(
+            Some("0x200,0x10000"),
+            airplay_info.get_property_val_str("features")
+        );
+        assert_eq!(Some("0x4"), airplay_info.get_property_val_str("flags"));
+        assert_eq!(Some("0x4"), raop_info.get_property_val_str("sf"));
+        assert_eq!(
+            Some(64),
+            airplay_info.get_property_val_str("pk").map(str::len)
+        );
+
+        airplay.set_property(airplay.status_key, "0x804".to_string());
+        assert_eq!(
+            Some("0x804"),
+            airplay.info().unwrap().get_property_val_str("flags")
+        );
+    }
+}
@@ -1,14 +1,36 @@
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    ops::RangeInclusive,
+    sync::Arc,
+    time::Duration,
+};
+
 use bitflags::bitflags;
 use derivative::Derivative;
+use thiserror::Error;
+
+use crate::{
+    crypto,
+    playback::{
+        audio::{AudioFormat, CodecKind, FormatError},
+        channel::Backpressure,
+        event::{EventSink, NullEventSink},
+        session::{NullSession, SessionHandler},
+    },
+};
 
+pub use crate::crypto::rsa::{RsaError, RsaPrivateKey};
 pub use macaddr::MacAddr6;
 
-#[derive(Debug, Derivative)]
-#[derivative(Default)]
+/// Authors of the manifest, which may be empty
+const MANUFACTURER: &str = env!("CARGO_PKG_AUTHORS");
+
+#[derive(Derivative)]
+#[derivative(Debug, Default)]
 pub struct Config<ADev, VDev> {
     pub mac_addr: MacAddr6,
     pub features: Features,
-    #[derivative(Default(value = "env!(\"CARGO_PKG_AUTHORS\").to_string()"))]
+    #[derivative(Default(value = "MANUFACTURER.to_string()"))]
     pub manufacturer: String,
     #[derivative(Default(value = "env!(\"CARGO_PKG_NAME\").to_string()"))]
     pub model: String,
@@ -17,8 +39,55 @@ pub struct Config<ADev, VDev> {
     #[derivative(Default(value = "env!(\"CARGO_PKG_VERSION\").to_string()"))]
     pub fw_version: String,
     pub pairing: Pairing,
+    /// Count of packets per stream decrypted concurrently off the network read loop, also the
+    /// threads each stream spawns with [`DecryptPriority::PerStream`]. Zero decrypts them on the
+    /// read loop itself
+    #[derivative(Default(value = "2"))]
+    pub decrypt_workers: usize,
+    pub decrypt_priority: DecryptPriority,
+    pub sockets: SocketConfig,
+    /// Port [`Receiver::serve`](crate::rtsp::Receiver::serve) accepts RTSP connections on,
+    /// bound to [`SocketConfig::bind_ip`]. Zero picks any free one
+    #[derivative(Default(value = "7000"))]
+    pub rtsp_port: u16,
+    /// Publish the receiver over Bonjour while
+    /// [`Receiver::serve`](crate::rtsp::Receiver::serve) runs
+    #[derivative(Default(value = "true"))]
+    pub advertise: bool,
+    /// Streams without packets or `/feedback` for this long are closed, as if the sender tore
+    /// them down. Realtime audio counts control packets only, since its data stops while paused
+    #[derivative(Default(value = "Duration::from_secs(10)"))]
+    pub idle_timeout: Duration,
+    /// Sessions without `/feedback` for this long are stale, see
+    /// [`RouterService::is_stale`](crate::rtsp::RouterService::is_stale), and ended.
+    /// Senders post it every couple of seconds
+    #[derivative(Default(value = "Duration::from_secs(8)"))]
+    pub feedback_timeout: Duration,
+    /// Whether a sender announcing or setting up a session while another one plays is
+    /// answered with 453 as if the receiver was busy, or takes the receiver over
+    pub session_policy: SessionPolicy,
+    /// Request bodies longer than this are refused with 413 before they're read whole
+    #[derivative(Default(value = "2 * 1024 * 1024"))]
+    pub max_body_len: usize,
+    /// SETUP requests with more streams are refused, senders set up a couple at once
+    #[derivative(Default(value = "8"))]
+    pub max_setup_streams: usize,
+    /// Drops and delays packets of every stream as they're read, `None` leaves them be
+    #[cfg(feature = "testing")]
+    pub ingest_shaping: Option<IngestShaping>,
+    /// Raw packets kept of each stream, logged once it fails, zero keeps none. See
+    /// [`RouterService::recent_packets`](crate::rtsp::RouterService::recent_packets)
+    #[cfg(feature = "diagnostics")]
+    #[derivative(Default(value = "32"))]
+    pub packet_history: usize,
+    pub buffers: BufferConfig,
     pub audio: Audio<ADev>,
     pub video: Video<VDev>,
+    #[derivative(Debug = "ignore", Default(value = "Arc::new(NullSession)"))]
+    pub session: Arc<dyn SessionHandler>,
+    /// Also delivered to [`RouterService::subscribe_events`](crate::rtsp::RouterService::subscribe_events)
+    #[derivative(Debug = "ignore", Default(value = "Arc::new(NullEventSink)"))]
+    pub events: Arc<dyn EventSink>,
 }
 
 #[derive(Derivative)]
@@ -26,16 +95,211 @@ pub struct Config<ADev, VDev> {
 pub struct Pairing {
     #[derivative(Debug = "ignore", Default(value = "[5; 32]"))]
     pub legacy_pairing_key: [u8; 32],
+    /// Answers `Apple-Challenge` of legacy RAOP senders, the ones checking the answer expect
+    /// [`RsaPrivateKey::airport_express`]. Challenges are left unanswered without it
+    #[derivative(Debug = "ignore")]
+    pub challenge_key: Option<RsaPrivateKey>,
+    /// Streams of senders passing neither key nor IV in setup are played in the clear.
+    /// Their setup is refused otherwise, so a key stripped on the way can't downgrade them
+    pub allow_unencrypted: bool,
+}
+
+/// Binding of sockets allocated during setup, i.e. of streams, events and timing
+#[derive(Derivative, Clone)]
+#[derivative(Debug, Default)]
+pub struct SocketConfig {
+    /// Unspecified address binds to every interface of the family the sender connected with
+    #[derivative(Default(value = "IpAddr::V4(Ipv4Addr::UNSPECIFIED)"))]
+    pub bind_ip: IpAddr,
+    /// Event port, NTP timing port and ports of streams are allocated with it, PTP keeps
+    /// its well-known ones
+    pub ports: PortAllocation,
+}
+
+/// How ports of sockets allocated during setup are picked.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum PortAllocation {
+    /// Any free port the OS picks
+    #[default]
+    Ephemeral,
+    /// Ports tried in order, e.g. the ones a firewall lets through
+    Range(RangeInclusive<u16>),
+}
+
+/// What becomes of senders asking for a session while another one plays.
+///
+/// Keys, timing and remote control of a session are kept by the receiver rather than by the
+/// session, so there's never more than one played apart.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SessionPolicy {
+    /// Refused until the session playing is torn down or abandoned
+    #[default]
+    Exclusive,
+    /// Each new sender takes the receiver over from the one before, like other receivers let
+    /// them
+    Takeover,
+}
+
+/// How decryption of streams played at once shares the CPU.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DecryptPriority {
+    /// Every stream decrypts on threads of its own
+    #[default]
+    PerStream,
+    /// Audio is decrypted ahead of video on `threads` threads of the receiver, at least one,
+    /// so mirroring doesn't starve the audio played along
+    AudioFirst { threads: usize },
+}
+
+/// Sizes of buffers stream packets are read into, bounded by [`BufferConfig::validate`]
+#[derive(Derivative, Clone, Copy, PartialEq, Eq)]
+#[derivative(Debug, Default)]
+pub struct BufferConfig {
+    /// Hunk audio packets are carved from, also reported to senders of buffered audio
+    #[derivative(Default(value = "4 * 1024 * 1024"))]
+    pub audio: u32,
+    /// Hunk video packets are carved from, larger frames get a hunk of their own
+    #[derivative(Default(value = "4 * 1024 * 1024"))]
+    pub video: u32,
+    /// Largest realtime audio datagram, e.g. MTU of the network less IP and UDP headers.
+    /// Longer ones are dropped and counted as truncated
+    #[derivative(Default(value = "16 * 1024"))]
+    pub audio_packet: usize,
+    /// Control datagrams are truncated to it
+    #[derivative(Default(value = "16 * 1024"))]
+    pub control_packet: usize,
+    /// Longer event messages close the event connection
+    #[derivative(Default(value = "1024 * 1024"))]
+    pub event_message: usize,
+}
+
+#[derive(Debug, Error)]
+#[error("{buffer} buffer of {size} bytes, expected {min}..={max}")]
+pub struct BufferConfigError {
+    pub buffer: &'static str,
+    pub size: usize,
+    pub min: usize,
+    pub max: usize,
+}
+
+impl BufferConfig {
+    /// Bounds of the audio and video hunks. Every audio packet fits the smallest one, as
+    /// buffered ones are prefixed with 16-bit length and datagrams can't be longer either
+    pub const HUNK: RangeInclusive<usize> = 64 * 1024..=256 * 1024 * 1024;
+    /// Bounds of datagram buffers, the smallest one fits a packet of an Ethernet frame
+    pub const PACKET: RangeInclusive<usize> = 1500..=64 * 1024;
+    pub const EVENT_MESSAGE: RangeInclusive<usize> = 4 * 1024..=16 * 1024 * 1024;
+
+    /// Checks sizes are within their bounds, so setup neither allocates gigabytes nor reads
+    /// packets into buffers too small for them.
+    ///
+    /// # Errors
+    ///
+    /// Of the first buffer out of its bounds.
+    pub fn validate(&self) -> Result<(), BufferConfigError> {
+        let check = |buffer, size: usize, bounds: &RangeInclusive<usize>| {
+            if bounds.contains(&size) {
+                Ok(())
+            } else {
+                Err(BufferConfigError {
+                    buffer,
+                    size,
+                    min: *bounds.start(),
+                    max: *bounds.end(),
+                })
+            }
+        };
+
+        check("audio", self.audio as usize, &Self::HUNK)?;
+        check("video", self.video as usize, &Self::HUNK)?;
+        check("audio packet", self.audio_packet, &Self::PACKET)?;
+        check("control packet", self.control_packet, &Self::PACKET)?;
+        check("event message", self.event_message, &Self::EVENT_MESSAGE)
+    }
 }
 
 #[derive(Derivative)]
 #[derivative(Debug, Default)]
 pub struct Audio<Device> {
-    #[derivative(Default(value = "4 * 1024 * 1024"))]
-    pub buf_size: u32,
+    /// Max count of packets requested to be resent at once for realtime stream
+    #[derivative(Default(value = "64"))]
+    pub retransmit_window: u16,
+    /// Count of realtime packets held to restore their order, zero passes them as they arrive.
+    /// Capped, as the deadline below, by the latency the sender requested
+    #[derivative(Default(value = "16"))]
+    pub reorder_depth: u16,
+    /// Held realtime packets are released after this long, missing ones before them are skipped
+    #[derivative(Default(value = "Duration::from_millis(50)"))]
+    pub reorder_deadline: Duration,
+    /// Hold realtime packets until their playout time, mapped from RTP timestamps through
+    /// the sender's clock, instead of passing them as soon as they're decrypted.
+    /// The ones arriving after it are dropped
+    pub playout_scheduling: bool,
+    /// Packets queued for the stream while it's busy, so a stalled stream doesn't stall
+    /// reading them. Packets past it are handled as backpressure of the stream kind says
+    #[derivative(Default(value = "256"))]
+    pub queue_len: usize,
+    /// Realtime audio is played as it arrives, so old packets are worth the least
+    #[derivative(Default(value = "Backpressure::DropOldest"))]
+    pub realtime_backpressure: Backpressure,
+    /// Buffered audio is sent ahead of time, so the sender is slowed down instead
+    #[derivative(Default(value = "Backpressure::Block"))]
+    pub buffered_backpressure: Backpressure,
+    /// Buffered packets failing to decrypt one after another that end the stream, as the key
+    /// negotiated is taken for wrong then. Zero drops them without ever ending it
+    #[derivative(Default(value = "64"))]
+    pub max_decrypt_failures: u32,
+    /// Streams of other formats are refused during setup with 415, rather than the device
+    /// failing on them later. Senders pick another format then
+    pub supported_formats: SupportedFormats,
     pub device: Device,
 }
 
+/// Impairment of a poor network, applied to each stream on its own.
+#[cfg(feature = "testing")]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct IngestShaping {
+    /// Fraction of packets lost, `0.0..=1.0`
+    pub loss: f64,
+    /// Reading pauses for up to this long before each packet, so the ones arriving meanwhile
+    /// come in a burst
+    pub jitter: Duration,
+    /// Bytes per second packets are read at, zero doesn't limit them
+    pub max_rate: u64,
+    /// Seed of losses and pauses, so impaired runs repeat
+    pub seed: u64,
+}
+
+/// Formats the audio device plays, an empty list doesn't limit its part of the format.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SupportedFormats {
+    pub codecs: Vec<CodecKind>,
+    pub sample_rates: Vec<u32>,
+    pub bits_per_sample: Vec<u32>,
+    pub channels: Vec<u8>,
+}
+
+impl SupportedFormats {
+    /// # Errors
+    ///
+    /// If any part of the negotiated `format` isn't supported.
+    pub fn check(&self, format: &AudioFormat) -> Result<(), FormatError> {
+        fn allows<T: PartialEq>(supported: &[T], value: &T) -> bool {
+            supported.is_empty() || supported.contains(value)
+        }
+
+        if allows(&self.codecs, &format.codec)
+            && allows(&self.sample_rates, &format.sample_rate)
+            && allows(&self.bits_per_sample, &format.bits_per_sample)
+            && allows(&self.channels, &format.channels)
+        {
+            Ok(())
+        } else {
+            Err(FormatError::NotSupported(*format))
+        }
+    }
+}
+
 #[derive(Derivative)]
 #[derivative(Debug, Default)]
 pub struct Video<Device> {
@@ -45,8 +309,16 @@ pub struct Video<Device> {
     pub height: u32,
     #[derivative(Default(value = "30"))]
     pub fps: u32,
-    #[derivative(Default(value = "4 * 1024 * 1024"))]
-    pub buf_size: u32,
+    /// Codes of [`PacketKind::Other`](crate::playback::video::PacketKind::Other) packets
+    /// carrying encrypted payload, e.g. 4097 used by some senders.
+    /// Packets already looking like plaintext are passed as is.
+    pub decrypted_other_kinds: Vec<u16>,
+    /// Deliver payload packets joined into frames with
+    /// [`VideoStream::on_frame`](crate::playback::video::VideoStream::on_frame)
+    pub reassemble_frames: bool,
+    /// Offer H.265 to senders, which may then send
+    /// [`PacketKind::HvcC`](crate::playback::video::PacketKind::HvcC) records and H.265 frames
+    pub hevc: bool,
     pub device: Device,
 }
 
@@ -123,7 +395,7 @@ bitflags! {
 /// Modify it if you make any changes into the code.
 impl Default for Features {
     fn default() -> Self {
-        Self::Video
+        let features = Self::Video
             | Self::Photo
             | Self::VideoHTTPLiveStreaming
             | Self::Unknown6
@@ -142,8 +414,6 @@ impl Default for Features {
             | Self::ReceiveAudioALAC
             | Self::ReceiveAudioAAC_LC
 
-            // A glitch whether /fp-setup is called, but the audio/video data is clear
-            | Self::MFiSoft_FairPlay
             | Self::AudioUnencrypted
             // TODO : require stream type130 | Self::AirPlayVideoV2
 
@@ -153,6 +423,59 @@ impl Default for Features {
             // Enable AirPlay2, using buffered audio (e.g. Apple Music)
             | Self::BufferedAudio
             | Self::NTPClock
-            | Self::PTPClock
+            | Self::PTPClock;
+
+        // A glitch whether /fp-setup is called, but the audio/video data is clear
+        if crypto::fairplay::SUPPORTED {
+            features | Self::MFiSoft_FairPlay
+        } else {
+            features
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BufferConfig, SupportedFormats};
+    use crate::playback::audio::{AudioFormat, CodecKind, FormatError};
+
+    #[test]
+    fn buffers_bounded() {
+        assert!(BufferConfig::default().validate().is_ok());
+
+        let small_audio = BufferConfig {
+            audio: u32::from(u16::MAX) - 1,
+            ..Default::default()
+        };
+        let err = small_audio.validate().unwrap_err();
+        assert_eq!("audio", err.buffer);
+        assert_eq!(64 * 1024, err.min);
+
+        let huge_event = BufferConfig {
+            event_message: usize::MAX,
+            ..Default::default()
+        };
+        assert_eq!("event message", huge_event.validate().unwrap_err().buffer);
+    }
+
+    #[test]
+    fn formats_limited() {
+        // ALAC/44100/16/2 and AAC-LC/48000/16/2
+        let (lossless, lossy) = (
+            AudioFormat::from_bits(0x40000).unwrap(),
+            AudioFormat::from_bits(0x0080_0000).unwrap(),
+        );
+        assert!(SupportedFormats::default().check(&lossy).is_ok());
+
+        let supported = SupportedFormats {
+            codecs: vec![CodecKind::Alac, CodecKind::AacLc],
+            sample_rates: vec![44100],
+            ..Default::default()
+        };
+        assert!(supported.check(&lossless).is_ok());
+        assert_eq!(
+            Err(FormatError::NotSupported(lossy)),
+            supported.check(&lossy)
+        );
     }
 }
@@ -48,6 +48,17 @@ const MESSAGES: [&[u8]; 4] = [
     ],
 ];
 const FP_HEADER: &[u8] = &[70, 80, 76, 89, 3, 1, 4, 0, 0, 0, 0, 20];
+const SUFFIX_LEN: usize = 20;
+/// Magic, version 3, msg type 1 and seq 3
+const KEY_MSG_PREFIX: &[u8] = &[70, 80, 76, 89, 3, 1, 3];
+
+/// Whether keys can be decrypted, i.e. the crate is built with `fairplay` feature
+pub const SUPPORTED: bool = cfg!(feature = "fairplay");
+
+/// Length of the stage-2 message, which key is derived from
+pub const KEY_MSG_LEN: usize = 164;
+/// Length of `ekey` sent in setup
+pub const ENCRYPTED_KEY_LEN: usize = 72;
 
 #[derive(Debug, Error)]
 pub enum DecodingError {
@@ -61,6 +72,12 @@ pub enum DecodingError {
     InvalidMode(u8),
     #[error("invalid seq: {0}")]
     InvalidSeq(u8),
+    #[error("invalid length: {0}")]
+    InvalidLength(usize),
+    #[error("not a key message")]
+    NotKeyMessage,
+    #[error("fairplay isn't supported, the crate is built without `fairplay` feature")]
+    Unsupported,
 }
 
 pub fn decode_buf(buf: impl AsRef<[u8]>) -> Result<Vec<u8>, DecodingError> {
@@ -75,20 +92,19 @@ pub fn decode_buf(buf: impl AsRef<[u8]>) -> Result<Vec<u8>, DecodingError> {
     match buf.get(5) {
         Some(1) => match buf.get(6) {
             Some(1) => match buf.get(14) {
-                Some(mode @ 0..=4) => Ok(MESSAGES[*mode as usize].to_vec()),
+                Some(mode @ 0..=3) => Ok(MESSAGES[*mode as usize].to_vec()),
                 Some(mode) => Err(DecodingError::InvalidMode(*mode)),
                 None => Err(DecodingError::InsufficientData),
             },
             Some(3) => {
-                let mut output = vec![0; FP_HEADER.len() + 20];
-                output[..FP_HEADER.len()].copy_from_slice(FP_HEADER);
-                match buf.get(buf.len() - 20..) {
-                    Some(suffix) => {
-                        output[FP_HEADER.len()..].copy_from_slice(suffix);
-                        Ok(output)
-                    }
-                    None => Err(DecodingError::InsufficientData),
-                }
+                let Some(suffix) = buf.last_chunk::<SUFFIX_LEN>() else {
+                    return Err(DecodingError::InsufficientData);
+                };
+
+                let mut output = Vec::with_capacity(FP_HEADER.len() + SUFFIX_LEN);
+                output.extend_from_slice(FP_HEADER);
+                output.extend_from_slice(suffix);
+                Ok(output)
             }
             Some(seq) => Err(DecodingError::InvalidSeq(*seq)),
             None => Err(DecodingError::InsufficientData),
@@ -98,15 +114,43 @@ pub fn decode_buf(buf: impl AsRef<[u8]>) -> Result<Vec<u8>, DecodingError> {
     }
 }
 
-pub fn decrypt_key(message: impl AsRef<[u8]>, encrypted_aes_key: impl AsRef<[u8]>) -> AesKey128 {
+/// # Errors
+///
+/// If `message` isn't the stage-2 message or `encrypted_aes_key` has unexpected length,
+/// playfair reads them blindly. Without `fairplay` feature valid ones are rejected as well.
+pub fn decrypt_key(
+    message: impl AsRef<[u8]>,
+    encrypted_aes_key: impl AsRef<[u8]>,
+) -> Result<AesKey128, DecodingError> {
+    let message = message.as_ref();
+    let encrypted_aes_key = encrypted_aes_key.as_ref();
+
+    let message: &[u8; KEY_MSG_LEN] = message
+        .try_into()
+        .map_err(|_| DecodingError::InvalidLength(message.len()))?;
+    let encrypted_aes_key: &[u8; ENCRYPTED_KEY_LEN] = encrypted_aes_key
+        .try_into()
+        .map_err(|_| DecodingError::InvalidLength(encrypted_aes_key.len()))?;
+    if !message.starts_with(KEY_MSG_PREFIX) {
+        return Err(DecodingError::NotKeyMessage);
+    }
+
+    playfair_decrypt(message, encrypted_aes_key)
+}
+
+#[cfg(feature = "fairplay")]
+#[allow(clippy::unnecessary_wraps)]
+fn playfair_decrypt(
+    message: &[u8; KEY_MSG_LEN],
+    encrypted_aes_key: &[u8; ENCRYPTED_KEY_LEN],
+) -> Result<AesKey128, DecodingError> {
     unsafe extern "C" {
         fn playfair_decrypt(msg: *const u8, cipher_text: *const u8, out: *mut u8);
     }
 
-    let message = message.as_ref();
-    let encrypted_aes_key = encrypted_aes_key.as_ref();
     let mut aes = AesKey128::default();
 
+    // SAFETY: lengths of all buffers are fixed by their types, playfair doesn't retain them
     unsafe {
         playfair_decrypt(
             message.as_ptr(),
@@ -115,7 +159,15 @@ pub fn decrypt_key(message: impl AsRef<[u8]>, encrypted_aes_key: impl AsRef<[u8]
         );
     }
 
-    aes
+    Ok(aes)
+}
+
+#[cfg(not(feature = "fairplay"))]
+fn playfair_decrypt(
+    _message: &[u8; KEY_MSG_LEN],
+    _encrypted_aes_key: &[u8; ENCRYPTED_KEY_LEN],
+) -> Result<AesKey128, DecodingError> {
+    Err(DecodingError::Unsupported)
 }
 
 #[cfg(test)]
@@ -124,7 +176,7 @@ mod tests {
 
     use base64::Engine;
 
-    use super::decrypt_key;
+    use super::{DecodingError, KEY_MSG_LEN, SUPPORTED, decode_buf, decrypt_key};
 
     const AES_KEY_BASE64: &[&str] = &[
         "RlBMWQECAQAAAAA8AAAAAG1EuhK5H0jgYesjD8U6v6IAAAAQihBgRl1RuAjfES0ItgRQH54+opzgkC88Q7gdUxnQV194UX4B",
@@ -208,7 +260,45 @@ mod tests {
                 .expect("invalid base64 for aes key");
             assert_eq!(164, message.len());
             assert_eq!(72, aeskey.len());
-            assert_eq!(expected, &hex::encode(decrypt_key(message, aeskey)));
+            let key = decrypt_key(message, aeskey);
+            if SUPPORTED {
+                assert_eq!(expected, &hex::encode(key.unwrap()));
+            } else {
+                assert!(matches!(key, Err(DecodingError::Unsupported)));
+            }
         }
     }
+
+    #[test]
+    fn reject_malformed_messages() {
+        // Stage 1 with unknown mode
+        const STAGE1: &[u8] = &[70, 80, 76, 89, 3, 1, 1, 0, 0, 0, 0, 4, 2, 0, 4, 187];
+        assert!(matches!(
+            decode_buf(STAGE1),
+            Err(DecodingError::InvalidMode(4))
+        ));
+        assert!(matches!(
+            decode_buf([70, 80, 76, 89, 3, 1, 3]),
+            Err(DecodingError::InsufficientData)
+        ));
+
+        let message = hex::decode(MESSAGE3_HEX[0]).unwrap();
+        assert!(matches!(
+            decrypt_key(&message[..KEY_MSG_LEN - 1], [0; 72]),
+            Err(DecodingError::InvalidLength(163))
+        ));
+        assert!(matches!(
+            decrypt_key(&message, [0; 16]),
+            Err(DecodingError::InvalidLength(16))
+        ));
+        assert!(matches!(
+            decrypt_key(
+                super::MESSAGES[0]
+                    .get(..KEY_MSG_LEN)
+                    .unwrap_or(&[0; KEY_MSG_LEN]),
+                [0; 72]
+            ),
+            Err(DecodingError::NotKeyMessage)
+        ));
+    }
 }
@@ -1,5 +1,6 @@
 pub mod fairplay;
 pub mod pairing;
+pub mod rsa;
 pub mod streaming;
 
 type AesCtr128BE = ctr::Ctr128BE<aes::Aes128>;
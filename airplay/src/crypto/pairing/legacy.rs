@@ -22,7 +22,8 @@ enum Inner {
     #[default]
     Empty,
     Established {
-        verify_their: VerifyingKey,
+        /// Boxed as it holds the decompressed point too, which would bloat every state
+        verify_their: Box<VerifyingKey>,
         pubkey_their: PublicKey,
         pubkey_our: PublicKey,
         shared_secret: SharedSecret,
@@ -78,7 +79,7 @@ impl State {
         response[X25519_KEY_LEN..].copy_from_slice(&signature);
 
         self.state = Inner::Established {
-            verify_their,
+            verify_their: Box::new(verify_their),
             pubkey_our,
             pubkey_their,
             shared_secret,
@@ -138,7 +139,8 @@ pub enum Error {
     Verification,
 }
 
-fn cipher(shared_secret: &[u8]) -> AesCtr128BE {
+/// Keystream of both signatures, the receiver's one comes first.
+pub(crate) fn cipher(shared_secret: &[u8]) -> AesCtr128BE {
     cipher_with_hashed_aes_iv(b"Pair-Verify-AES-Key", b"Pair-Verify-AES-IV", shared_secret)
 }
 
@@ -0,0 +1,342 @@
+use std::net::IpAddr;
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use rand_core::OsRng;
+use rsa::{
+    BigUint, Oaep, Pkcs1v15Sign, pkcs1::DecodeRsaPrivateKey, pkcs8::DecodePrivateKey,
+    traits::PublicKeyParts,
+};
+use sha1::Sha1;
+use thiserror::Error;
+
+/// Private key of `AirPort` Express, extracted from its firmware and long since public
+const AIRPORT_EXPRESS_KEY: &str = include_str!("airport_express.pem");
+
+/// Longest message of [`sign_challenge`]: challenge, IPv6 address and MAC
+const CHALLENGE_MSG_LEN: usize = 16 + 16 + 6;
+
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+pub enum RsaError {
+    #[error("malformed PKCS#1 private key")]
+    MalformedKey,
+    #[error("malformed PEM, expected RSA PRIVATE KEY or PRIVATE KEY block")]
+    MalformedPem,
+    #[error("key of {0} bytes is too short to sign challenges")]
+    KeyTooShort(usize),
+    #[error("message of {0} bytes doesn't fit the key")]
+    MessageTooLong(usize),
+    #[error("ciphertext isn't OAEP encrypted to the key")]
+    Decryption,
+    #[error("signing failed, key is inconsistent")]
+    Signing,
+}
+
+/// RSA private key, only able to sign with PKCS#1 v1.5 padding as `RSA_private_encrypt` does
+/// and to decrypt OAEP with SHA-1, as RAOP senders encrypt AES keys with.
+///
+/// Private operations are blinded, so secret keys are as safe with it as the bundled one.
+pub struct RsaPrivateKey(rsa::RsaPrivateKey);
+
+impl RsaPrivateKey {
+    /// Minimal padding of PKCS#1 v1.5: `00 01`, eight `FF` and `00` separator
+    const PADDING_LEN: usize = 11;
+
+    /// Key of `AirPort` Express, the one senders checking challenges expect.
+    ///
+    /// # Panics
+    ///
+    /// Never, the bundled key is known to be valid.
+    #[must_use]
+    pub fn airport_express() -> Self {
+        Self::from_pem(AIRPORT_EXPRESS_KEY).expect("bundled key is valid")
+    }
+
+    /// PEM encoded key, either PKCS#1 `RSA PRIVATE KEY` or PKCS#8 `PRIVATE KEY`.
+    ///
+    /// # Errors
+    ///
+    /// If `pem` has no such block or the key in it is malformed.
+    pub fn from_pem(pem: &str) -> Result<Self, RsaError> {
+        for (label, parse) in [
+            ("RSA PRIVATE KEY", Self::from_pkcs1_der as fn(&[u8]) -> _),
+            ("PRIVATE KEY", Self::from_pkcs8_der),
+        ] {
+            let begin = format!("-----BEGIN {label}-----");
+            let end = format!("-----END {label}-----");
+            let Some((_, rest)) = pem.split_once(&begin) else {
+                continue;
+            };
+            let (body, _) = rest.split_once(&end).ok_or(RsaError::MalformedPem)?;
+            let body = body
+                .chars()
+                .filter(|c| !c.is_ascii_whitespace())
+                .collect::<String>();
+            let der = STANDARD.decode(body).map_err(|_| RsaError::MalformedPem)?;
+            return parse(&der);
+        }
+        Err(RsaError::MalformedPem)
+    }
+
+    /// DER encoded key, either PKCS#1 or PKCS#8.
+    ///
+    /// # Errors
+    ///
+    /// If `der` is neither of them.
+    pub fn from_der(der: &[u8]) -> Result<Self, RsaError> {
+        Self::from_pkcs1_der(der).or_else(|_| Self::from_pkcs8_der(der))
+    }
+
+    /// # Errors
+    ///
+    /// If `der` isn't DER encoded `RSAPrivateKey` structure.
+    pub fn from_pkcs1_der(der: &[u8]) -> Result<Self, RsaError> {
+        rsa::RsaPrivateKey::from_pkcs1_der(der)
+            .map(Self)
+            .map_err(|_| RsaError::MalformedKey)
+    }
+
+    /// # Errors
+    ///
+    /// If `der` isn't DER encoded `PrivateKeyInfo` structure with RSA key in it.
+    pub fn from_pkcs8_der(der: &[u8]) -> Result<Self, RsaError> {
+        rsa::RsaPrivateKey::from_pkcs8_der(der)
+            .map(Self)
+            .map_err(|_| RsaError::MalformedKey)
+    }
+
+    /// Big endian modulus, public and private exponents, primes are recovered from them.
+    ///
+    /// # Errors
+    ///
+    /// If they don't make a valid key.
+    pub fn from_components(n: &[u8], e: &[u8], d: &[u8]) -> Result<Self, RsaError> {
+        let [n, e, d] = [n, e, d].map(BigUint::from_bytes_be);
+        rsa::RsaPrivateKey::from_components(n, e, d, Vec::new())
+            .map(Self)
+            .map_err(|_| RsaError::MalformedKey)
+    }
+
+    /// Length of modulus and of signatures, in bytes
+    #[must_use]
+    #[allow(clippy::len_without_is_empty)] // Valid keys are never empty
+    pub fn len(&self) -> usize {
+        self.0.size()
+    }
+
+    /// # Errors
+    ///
+    /// If `msg` with padding doesn't fit in [`Self::len`] bytes.
+    pub fn sign_pkcs1(&self, msg: &[u8]) -> Result<Vec<u8>, RsaError> {
+        self.0
+            .sign_with_rng(&mut OsRng, Pkcs1v15Sign::new_unprefixed(), msg)
+            .map_err(|err| match err {
+                rsa::Error::MessageTooLong => RsaError::MessageTooLong(msg.len()),
+                _ => RsaError::Signing,
+            })
+    }
+
+    /// Decrypts OAEP with SHA-1 and empty label, i.e. `RSA_PKCS1_OAEP_PADDING`.
+    ///
+    /// # Errors
+    ///
+    /// If `ciphertext` isn't as long as the key or its padding is malformed, both reported
+    /// alike.
+    pub fn decrypt_oaep(&self, ciphertext: &[u8]) -> Result<Vec<u8>, RsaError> {
+        self.0
+            .decrypt_blinded(&mut OsRng, Oaep::new::<Sha1>(), ciphertext)
+            .map_err(|_| RsaError::Decryption)
+    }
+
+    /// Encrypts OAEP with SHA-1 and empty label to the public part of the key, as RAOP senders
+    /// wrap AES keys.
+    ///
+    /// # Errors
+    ///
+    /// If `msg` with padding doesn't fit in [`Self::len`] bytes.
+    #[cfg(feature = "testing")]
+    pub fn encrypt_oaep(&self, msg: &[u8]) -> Result<Vec<u8>, RsaError> {
+        rsa::RsaPublicKey::from(&self.0)
+            .encrypt(&mut OsRng, Oaep::new::<Sha1>(), msg)
+            .map_err(|_| RsaError::MessageTooLong(msg.len()))
+    }
+}
+
+/// Signs `Apple-Challenge` of RAOP senders along with the address they connected to and
+/// the MAC of receiver, proving it's an `AirPort` Express.
+///
+/// # Errors
+///
+/// If challenge is too long for the key.
+pub fn sign_challenge(
+    key: &RsaPrivateKey,
+    challenge: &[u8],
+    local_ip: IpAddr,
+    mac_addr: [u8; 6],
+) -> Result<Vec<u8>, RsaError> {
+    const MIN_LEN: usize = 32;
+
+    let mut msg = challenge.to_vec();
+    match local_ip.to_canonical() {
+        IpAddr::V4(ip) => msg.extend_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => msg.extend_from_slice(&ip.octets()),
+    }
+    msg.extend_from_slice(&mac_addr);
+    if msg.len() < MIN_LEN {
+        msg.resize(MIN_LEN, 0);
+    }
+
+    key.sign_pkcs1(&msg)
+}
+
+/// Checks `key` is long enough to sign any challenge, so it's refused before the first one.
+///
+/// # Errors
+///
+/// If signatures of the key can't fit [`sign_challenge`] messages.
+pub fn check_challenge_key(key: &RsaPrivateKey) -> Result<(), RsaError> {
+    if key.len() < CHALLENGE_MSG_LEN + RsaPrivateKey::PADDING_LEN {
+        return Err(RsaError::KeyTooShort(key.len()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use base64::{Engine, engine::general_purpose::STANDARD};
+
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use rand_core::OsRng;
+
+    use super::{RsaError, RsaPrivateKey, check_challenge_key, sign_challenge};
+
+    /// 1024 bits key generated for tests only
+    pub(crate) const TEST_KEY: &str = "MIICXQIBAAKBgQDRJkTw9A3r1cY1p2Agyc6zFOvcSNYPSEocmtTOoZQIYQqlgpkXTZcZ7LiOh0P3QCG9VoQ1oK8f+JiF6NFxast4JCmbmgSSg/reyegSFh1hmloZ6EYesH2dF0afl23yMixdCqRtXL99ZEZciMjhVWEIZgUfruIbt/YEdzayt//WtQIDAQABAoGBAKKSerhJHKEatE10YvZZp7/wHT5Hp4yNhn4hpBVR531z4RCyKUnB2Bggt3yijCQex5sjKvV8h2P0mqpMRLFCcBD0dVhongmyTyMVSaeipJRM+JcVSwynaB6O202N2aEGTTNeADLrwPuQc6vbWaeDk8U310MYVGJ9y1sPgUS6VWPpAkEA124KIm1eOksRin4kWrDKM8h8ZXCBLIFPQn8Wtp3cSIHbx46SccXYtKx2EF6zZSbI4kCHMa6jhxINa7fQTD2tNwJBAPiJc4vrvRhxxa//Vj9OxDLakAYQpy6XZU34h45FX0r5qlz3zJDXagsTcPFqvOml4GCUBrdv5mAUO+XGH0y4sXMCQQDVYBiJoOoDsvbcxbQcX57nS1xq3NQU0L9AjcBhgFn/NyAIuB40slHCIk+ORz5IofG2p1NA0kkGL8s7NgUKfsxhAkB7L42QxYhVLFcCk7lI6Z5/YitOhkSG6E0y50Mw9ht0Sujw6XWAOPT7hmhNkE0QNcJPvC7UDueNqZnCmn97BHQXAkB6ilnB6QtkxaFzDGNrrDA58U9xB6L2a4cgBGf22iItzXjhkaTiyskTusKAfdj6Ch3GPiWDZRMs/yk4vR4TxwBr";
+
+    /// Freshly generated key of `bits`, for ones too short to be worth keeping
+    pub(crate) fn generated_key(bits: usize) -> RsaPrivateKey {
+        RsaPrivateKey(rsa::RsaPrivateKey::new(&mut OsRng, bits).unwrap())
+    }
+
+    #[test]
+    fn sign_pkcs1() {
+        // Signature made by OpenSSL's `RSA_private_encrypt`
+        const SIGNATURE: &str = "zs9DTsy5CCR3I5kqBC6psmQXNOR3Z2suDtygU9wwDeP2cTYhW2j8DGG/nW31Po12lAT8zQIll4fyXJgrcHtx+igvYmMgJeY86kobCABUK00K3Wlifr7HBpjJEPqs2ja6EmdadaBAxe5YcKdhuLA+3pye79lIawXFEJNsTdU08r8=";
+
+        let key = RsaPrivateKey::from_pkcs1_der(&STANDARD.decode(TEST_KEY).unwrap()).unwrap();
+        assert_eq!(128, key.len());
+
+        let challenge = (0..16).collect::<Vec<u8>>();
+        let mac_addr = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+        let ipv4 = Ipv4Addr::new(192, 168, 1, 10);
+        for ip in [IpAddr::V4(ipv4), IpAddr::V6(ipv4.to_ipv6_mapped())] {
+            let signature = sign_challenge(&key, &challenge, ip, mac_addr).unwrap();
+            assert_eq!(SIGNATURE, STANDARD.encode(signature));
+        }
+
+        assert_eq!(
+            Err(RsaError::MessageTooLong(118)),
+            key.sign_pkcs1(&[0; 118])
+        );
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn oaep_round_trip() {
+        let key = RsaPrivateKey::airport_express();
+        let aes_key = [7; 16];
+        let ekey = key.encrypt_oaep(&aes_key).unwrap();
+        assert_eq!(key.len(), ekey.len());
+        assert_eq!(Ok(aes_key.to_vec()), key.decrypt_oaep(&ekey));
+
+        let long = vec![0; key.len() - 41];
+        assert_eq!(
+            Err(RsaError::MessageTooLong(long.len())),
+            key.encrypt_oaep(&long)
+        );
+    }
+
+    #[test]
+    fn malformed_keys() {
+        let der = STANDARD.decode(TEST_KEY).unwrap();
+        for len in [0, 2, 10, 200] {
+            assert!(RsaPrivateKey::from_pkcs1_der(&der[..len]).is_err());
+        }
+        // Even modulus
+        assert!(RsaPrivateKey::from_components(&[0, 4], &[3], &[3]).is_err());
+    }
+
+    fn pem(label: &str, der: &[u8]) -> String {
+        let body = STANDARD.encode(der);
+        let lines = body
+            .as_bytes()
+            .chunks(64)
+            .map(|line| str::from_utf8(line).unwrap());
+        format!(
+            "-----BEGIN {label}-----\n{}\n-----END {label}-----\n",
+            lines.collect::<Vec<_>>().join("\n")
+        )
+    }
+
+    #[test]
+    fn keys_of_every_encoding() {
+        /// `rsaEncryption` algorithm of PKCS#8 keys
+        const RSA_ENCRYPTION_OID: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+
+        let pkcs1 = STANDARD.decode(TEST_KEY).unwrap();
+        // PrivateKeyInfo around the PKCS#1 key, lengths fit two bytes
+        let mut pkcs8 = vec![0x02, 0x01, 0x00, 0x30, 0x0d, 0x06, 0x09];
+        pkcs8.extend_from_slice(RSA_ENCRYPTION_OID);
+        pkcs8.extend_from_slice(&[0x05, 0x00, 0x04, 0x82]);
+        pkcs8.extend_from_slice(&u16::try_from(pkcs1.len()).unwrap().to_be_bytes());
+        pkcs8.extend_from_slice(&pkcs1);
+        let len = u16::try_from(pkcs8.len()).unwrap().to_be_bytes();
+        pkcs8.splice(0..0, [0x30, 0x82, len[0], len[1]]);
+
+        let msg = [7; 32];
+        let signature = RsaPrivateKey::from_pkcs1_der(&pkcs1)
+            .unwrap()
+            .sign_pkcs1(&msg)
+            .unwrap();
+        for key in [
+            RsaPrivateKey::from_der(&pkcs1),
+            RsaPrivateKey::from_der(&pkcs8),
+            RsaPrivateKey::from_pem(&pem("RSA PRIVATE KEY", &pkcs1)),
+            RsaPrivateKey::from_pem(&pem("PRIVATE KEY", &pkcs8)),
+        ] {
+            assert_eq!(signature, key.unwrap().sign_pkcs1(&msg).unwrap());
+        }
+    }
+
+    #[test]
+    fn malformed_pem() {
+        let pkcs1 = STANDARD.decode(TEST_KEY).unwrap();
+        for pem in [
+            String::new(),
+            pem("PUBLIC KEY", &pkcs1),
+            pem("RSA PRIVATE KEY", &pkcs1).replace("-----END", "-----FIN"),
+            pem("RSA PRIVATE KEY", &pkcs1).replace('M', "!"),
+        ] {
+            assert!(matches!(
+                RsaPrivateKey::from_pem(&pem),
+                Err(RsaError::MalformedPem)
+            ));
+        }
+        // PKCS#1 key labeled as PKCS#8 one
+        assert!(matches!(
+            RsaPrivateKey::from_pem(&pem("PRIVATE KEY", &pkcs1)),
+            Err(RsaError::MalformedKey)
+        ));
+    }
+
+    #[test]
+    fn bundled_key_signs_challenges() {
+        let key = RsaPrivateKey::airport_express();
+        assert_eq!(256, key.len());
+        assert_eq!(Ok(()), check_challenge_key(&key));
+
+        // 384 bits are too few for IPv6 address
+        let short = generated_key(384);
+        assert_eq!(Err(RsaError::KeyTooShort(48)), check_challenge_key(&short));
+    }
+}
@@ -1,113 +1,625 @@
-use aes::cipher::{BlockDecryptMut, KeyIvInit as _, StreamCipher as _, block_padding::NoPadding};
+use aes::cipher::{
+    BlockDecryptMut, KeyIvInit as _, StreamCipher as _, StreamCipherSeek as _,
+    block_padding::NoPadding,
+};
+use aes_gcm::Aes128Gcm;
 use chacha20poly1305::{AeadInPlace, ChaCha20Poly1305, Key, KeyInit as _, Nonce, Tag};
+use thiserror::Error;
 
-use super::{AesCbc128, AesCtr128BE, AesIv128, AesKey128, cipher_with_hashed_aes_iv};
+use super::{
+    AesCbc128, AesCtr128BE, AesIv128, AesKey128, cipher_with_hashed_aes_iv,
+    fairplay::{self, DecodingError},
+    hash_aes_key,
+    rsa::{RsaError, RsaPrivateKey},
+};
+
+/// Authenticated decryption of packets carrying their nonce and tag, i.e. of buffered audio.
+pub trait AeadCipher: Send + Sync + 'static {
+    /// Layout of packets, the AAD, nonce and tag passed below follow it
+    fn config(&self) -> BufferedConfig;
+
+    /// # Errors
+    ///
+    /// If `inout` isn't authentic, it may be garbled then.
+    fn open_in_place(
+        &self,
+        nonce: &[u8],
+        aad: &[u8],
+        tag: &[u8],
+        inout: &mut [u8],
+    ) -> Result<(), ()>;
+}
+
+/// Unauthenticated decryption, i.e. of realtime audio and video.
+pub trait StreamCipher: Send + Sync + 'static {
+    /// `keystream_pos` is the count of bytes of the stream decrypted before `inout`, ciphers
+    /// restarting on each packet, as the one of realtime audio, get zero.
+    fn decrypt(&self, keystream_pos: u64, inout: &mut [u8]);
+}
+
+/// Passes packets as is, for tests of processing that don't care about encryption.
+#[cfg(test)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NullCipher {
+    /// Layout of buffered audio, the trailer is ignored otherwise
+    pub config: BufferedConfig,
+}
+
+/// AEAD of buffered audio, told apart by length of the shared key sent in setup.
+///
+/// Both have 12 bytes nonce and 16 bytes tag. Packet trailer is the tag followed by the
+/// last 8 bytes of nonce, its first bytes are zeros.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BufferedSuite {
+    #[default]
+    ChaCha20Poly1305,
+    Aes128Gcm,
+    /// No key was sent, packets are in the clear and have no trailer
+    Unencrypted,
+}
+
+/// Where AEAD inputs are in buffered audio packets, resolved in setup along with the suite.
+///
+/// Packet is the RTP header, encrypted payload and trailer of the tag followed by the sent part
+/// of nonce. AAD is taken from the fixed part of the header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferedConfig {
+    pub suite: BufferedSuite,
+    /// Fixed part of RTP header, CSRC list and extension may follow it
+    pub header_len: usize,
+    pub aad_offset: usize,
+    pub aad_len: usize,
+    pub nonce_len: usize,
+    pub tag_len: usize,
+    /// Bytes of nonce sent in trailer, the last ones of it, the first are zeros
+    pub sent_nonce_len: usize,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum BufferedConfigError {
+    #[error("aad of {len} bytes at {offset} is out of {header_len} bytes header")]
+    AadOutOfHeader {
+        offset: usize,
+        len: usize,
+        header_len: usize,
+    },
+    #[error("{suite:?} takes {expected} bytes {part}, not {len}")]
+    SuiteMismatch {
+        suite: BufferedSuite,
+        part: &'static str,
+        expected: usize,
+        len: usize,
+    },
+    #[error("{sent} bytes of nonce sent out of {len}")]
+    SentNonce { sent: usize, len: usize },
+}
+
+/// How the sender encrypted `ekey` of realtime audio, per the negotiated mode.
+#[derive(Clone, Copy)]
+pub enum KeyWrap<'a> {
+    /// RSA-OAEP to the challenge key, by RAOP senders
+    Rsa(&'a RsaPrivateKey),
+    /// `FairPlay`, keyed by the last message of its setup
+    FairPlay(&'a [u8]),
+}
+
+#[derive(Debug, Error)]
+pub enum EkeyError {
+    #[error("iv of {0} bytes, expected 16")]
+    IvLength(usize),
+    #[error("decrypted key of {0} bytes, expected 16")]
+    KeyLength(usize),
+    #[error("rsa: {0}")]
+    Rsa(#[from] RsaError),
+    #[error("fairplay: {0}")]
+    FairPlay(#[from] DecodingError),
+}
+
+enum BufferedAead {
+    ChaCha20Poly1305(ChaCha20Poly1305),
+    Aes128Gcm(Box<Aes128Gcm>),
+    Unencrypted,
+}
 
 pub struct AudioBufferedCipher {
-    inner: ChaCha20Poly1305,
+    inner: BufferedAead,
+    config: BufferedConfig,
 }
 
-impl AudioBufferedCipher {
-    pub const KEY_LEN: usize = 32;
-    pub const AAD_LEN: usize = 8;
-    pub const TAG_LEN: usize = 16;
-    pub const NONCE_LEN: usize = 12;
+impl BufferedSuite {
+    /// Bytes of nonce sent in trailer
+    pub const SENT_NONCE_LEN: usize = 8;
+
+    pub fn from_key_len(len: usize) -> Option<Self> {
+        [Self::ChaCha20Poly1305, Self::Aes128Gcm, Self::Unencrypted]
+            .into_iter()
+            .find(|suite| suite.key_len() == len)
+    }
+
+    pub const fn key_len(self) -> usize {
+        match self {
+            Self::ChaCha20Poly1305 => 32,
+            Self::Aes128Gcm => 16,
+            Self::Unencrypted => 0,
+        }
+    }
+
+    pub const fn nonce_len(self) -> usize {
+        match self {
+            Self::ChaCha20Poly1305 | Self::Aes128Gcm => 12,
+            Self::Unencrypted => 0,
+        }
+    }
+
+    pub const fn tag_len(self) -> usize {
+        match self {
+            Self::ChaCha20Poly1305 | Self::Aes128Gcm => 16,
+            Self::Unencrypted => 0,
+        }
+    }
+}
 
-    pub fn new(key: [u8; Self::KEY_LEN]) -> Self {
+impl BufferedConfig {
+    /// Layout of the buffered streams senders set up, AAD is timestamp and SSRC of the header.
+    pub const fn new(suite: BufferedSuite) -> Self {
         Self {
-            inner: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            suite,
+            header_len: 12,
+            aad_offset: 4,
+            aad_len: 8,
+            nonce_len: suite.nonce_len(),
+            tag_len: suite.tag_len(),
+            sent_nonce_len: if suite.nonce_len() < BufferedSuite::SENT_NONCE_LEN {
+                suite.nonce_len()
+            } else {
+                BufferedSuite::SENT_NONCE_LEN
+            },
         }
     }
 
-    pub fn open_in_place(
+    pub const fn trailer_len(&self) -> usize {
+        self.tag_len + self.sent_nonce_len
+    }
+
+    /// Checks AAD lies within the header, so within every packet long enough to be read,
+    /// and nonce and tag fit the suite.
+    ///
+    /// # Errors
+    ///
+    /// On the first part out of place.
+    pub fn validate(&self) -> Result<(), BufferedConfigError> {
+        if self
+            .aad_offset
+            .checked_add(self.aad_len)
+            .is_none_or(|end| end > self.header_len)
+        {
+            return Err(BufferedConfigError::AadOutOfHeader {
+                offset: self.aad_offset,
+                len: self.aad_len,
+                header_len: self.header_len,
+            });
+        }
+        for (part, expected, len) in [
+            ("nonce", self.suite.nonce_len(), self.nonce_len),
+            ("tag", self.suite.tag_len(), self.tag_len),
+        ] {
+            if len != expected {
+                return Err(BufferedConfigError::SuiteMismatch {
+                    suite: self.suite,
+                    part,
+                    expected,
+                    len,
+                });
+            }
+        }
+        if self.sent_nonce_len > self.nonce_len {
+            return Err(BufferedConfigError::SentNonce {
+                sent: self.sent_nonce_len,
+                len: self.nonce_len,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for BufferedConfig {
+    fn default() -> Self {
+        Self::new(BufferedSuite::default())
+    }
+}
+
+impl AudioBufferedCipher {
+    /// Longest trailer of any valid config, the whole nonce sent
+    pub const MAX_TRAILER_LEN: usize = 28;
+    pub const MAX_NONCE_LEN: usize = 12;
+
+    /// Cipher of `shk` sent in buffered stream setup with the layout of its suite, `None` if
+    /// its length doesn't match any suite. Empty `shk` passes packets as is.
+    ///
+    /// Unlike keys of pairing, `shk` isn't derived with HKDF, there's neither salt nor info
+    /// string. It's the AEAD key as is, the setup request carrying it is protected already.
+    pub fn from_shared_key(shk: &[u8]) -> Option<Self> {
+        let suite = BufferedSuite::from_key_len(shk.len())?;
+        Self::new(shk, BufferedConfig::new(suite)).ok()
+    }
+
+    /// # Errors
+    ///
+    /// If `config` isn't valid or `shk` isn't a key of its suite.
+    pub fn new(shk: &[u8], config: BufferedConfig) -> Result<Self, BufferedConfigError> {
+        config.validate()?;
+        let key_mismatch = || BufferedConfigError::SuiteMismatch {
+            suite: config.suite,
+            part: "key",
+            expected: config.suite.key_len(),
+            len: shk.len(),
+        };
+
+        let inner = match config.suite {
+            BufferedSuite::ChaCha20Poly1305 if shk.len() == config.suite.key_len() => {
+                BufferedAead::ChaCha20Poly1305(ChaCha20Poly1305::new(Key::from_slice(shk)))
+            }
+            BufferedSuite::Aes128Gcm => BufferedAead::Aes128Gcm(Box::new(
+                Aes128Gcm::new_from_slice(shk).map_err(|_| key_mismatch())?,
+            )),
+            BufferedSuite::Unencrypted if shk.is_empty() => BufferedAead::Unencrypted,
+            BufferedSuite::ChaCha20Poly1305 | BufferedSuite::Unencrypted => {
+                return Err(key_mismatch());
+            }
+        };
+
+        Ok(Self { inner, config })
+    }
+}
+
+impl AeadCipher for AudioBufferedCipher {
+    fn config(&self) -> BufferedConfig {
+        self.config
+    }
+
+    /// Fails as well if `nonce` or `tag` length doesn't match the suite.
+    fn open_in_place(
         &self,
-        nonce: [u8; Self::NONCE_LEN],
-        aad: [u8; Self::AAD_LEN],
-        tag: [u8; Self::TAG_LEN],
+        nonce: &[u8],
+        aad: &[u8],
+        tag: &[u8],
         inout: &mut [u8],
     ) -> Result<(), ()> {
-        self.inner
-            .decrypt_in_place_detached(
-                Nonce::from_slice(&nonce),
-                &aad,
-                inout,
-                Tag::from_slice(&tag),
-            )
-            .map_err(|_| ())
+        let suite = self.config.suite;
+        if nonce.len() != suite.nonce_len() || tag.len() != suite.tag_len() {
+            return Err(());
+        }
+
+        match &self.inner {
+            BufferedAead::ChaCha20Poly1305(chacha) => chacha
+                .decrypt_in_place_detached(
+                    Nonce::from_slice(nonce),
+                    aad,
+                    inout,
+                    Tag::from_slice(tag),
+                )
+                .map_err(|_| ()),
+            BufferedAead::Aes128Gcm(gcm) => gcm
+                .decrypt_in_place_detached(nonce.into(), aad, inout, tag.into())
+                .map_err(|_| ()),
+            BufferedAead::Unencrypted => Ok(()),
+        }
     }
 }
 
+#[derive(Clone)]
 pub struct AudioRealtimeCipher {
-    aescbc: AesCbc128,
+    /// `None` if the sender negotiated no encryption
+    aescbc: Option<AesCbc128>,
 }
 
 impl AudioRealtimeCipher {
     pub fn new(key: AesKey128, eiv: AesIv128) -> Self {
         Self {
-            aescbc: AesCbc128::new(&key.into(), eiv.as_ref().into()),
+            aescbc: Some(AesCbc128::new(&key.into(), eiv.as_ref().into())),
+        }
+    }
+
+    /// Passes payloads as is, for senders that didn't pass a key.
+    pub const fn unencrypted() -> Self {
+        Self { aescbc: None }
+    }
+
+    /// Decrypts the key passed by the sender, the one of paired senders is hashed with
+    /// `shared_secret` afterwards.
+    ///
+    /// # Errors
+    ///
+    /// If `eiv` isn't an AES IV or `ekey` can't be decrypted to an AES key.
+    pub fn from_ekey_eiv(
+        ekey: &[u8],
+        eiv: &[u8],
+        wrap: KeyWrap<'_>,
+        shared_secret: Option<&[u8]>,
+    ) -> Result<Self, EkeyError> {
+        let eiv = AesIv128::try_from(eiv).map_err(|_| EkeyError::IvLength(eiv.len()))?;
+        let key = wrap.decrypt(ekey)?;
+        let key = shared_secret.map_or(key, |secret| hash_aes_key(key, secret));
+        Ok(Self::new(key, eiv))
+    }
+}
+
+impl KeyWrap<'_> {
+    /// # Errors
+    ///
+    /// If `ekey` isn't wrapped this way or doesn't hold an AES key.
+    pub fn decrypt(self, ekey: &[u8]) -> Result<AesKey128, EkeyError> {
+        match self {
+            Self::Rsa(key) => {
+                let aes_key = key.decrypt_oaep(ekey)?;
+                AesKey128::try_from(&aes_key[..]).map_err(|_| EkeyError::KeyLength(aes_key.len()))
+            }
+            Self::FairPlay(message) => Ok(fairplay::decrypt_key(message, ekey)?),
         }
     }
+}
 
-    pub fn decrypt(&self, buf: &mut [u8]) {
+impl StreamCipher for AudioRealtimeCipher {
+    /// Cipher restarts on each packet, so `keystream_pos` is ignored.
+    fn decrypt(&self, _keystream_pos: u64, buf: &mut [u8]) {
+        let Some(aescbc) = &self.aescbc else {
+            return;
+        };
         let encrypted_len = buf.len() - (buf.len() % 16);
-        let _ = self
-            .aescbc
+        let _ = aescbc
             .clone()
             .decrypt_padded_mut::<NoPadding>(&mut buf[..encrypted_len]);
     }
 }
 
 pub struct VideoCipher {
-    aesctr: AesCtr128BE,
-    og: [u8; 16],
-    next_decrypt_count: usize,
+    /// `None` if the sender negotiated no encryption
+    aesctr: Option<AesCtr128BE>,
 }
 
 impl VideoCipher {
     pub fn new(key: AesKey128, stream_connection_id: u64) -> Self {
         Self {
-            aesctr: cipher_with_hashed_aes_iv(
+            aesctr: Some(cipher_with_hashed_aes_iv(
                 format!("AirPlayStreamKey{stream_connection_id}"),
                 format!("AirPlayStreamIV{stream_connection_id}"),
                 key,
-            ),
-            og: [0; 16],
-            next_decrypt_count: 0,
+            )),
         }
     }
 
-    pub fn decrypt(&mut self, inout: &mut [u8]) {
-        let n = self.next_decrypt_count;
-
-        // Step 1: Process leftover bytes from the previous call
-        if n > 0 {
-            for (i, x) in inout.iter_mut().enumerate().take(n) {
-                *x ^= self.og[(16 - n) + i];
-            }
-        }
+    /// Passes payloads as is, for senders that didn't pass a key.
+    pub const fn unencrypted() -> Self {
+        Self { aesctr: None }
+    }
+}
 
-        // Step 2: Decrypt full blocks
-        let encryptlen = ((inout.len() - n) / 16) * 16;
-        self.aesctr.apply_keystream(&mut inout[n..n + encryptlen]);
+impl StreamCipher for VideoCipher {
+    /// Payloads of the stream share a single keystream, so `keystream_pos` is the count of
+    /// bytes decrypted before `inout`. Allows to decrypt packets independently of each other.
+    ///
+    /// Holds no state between calls, a frame that was skipped or failed to decrypt doesn't
+    /// affect the following ones as long as their positions are known.
+    fn decrypt(&self, keystream_pos: u64, inout: &mut [u8]) {
+        let Some(aesctr) = &self.aesctr else {
+            return;
+        };
+        let mut aesctr = aesctr.clone();
+        aesctr.seek(keystream_pos);
+        aesctr.apply_keystream(inout);
+    }
+}
 
-        // Step 3: Handle remaining partial block
-        let restlen = (inout.len() - n) % 16;
-        let reststart = inout.len() - restlen;
-        self.next_decrypt_count = 0;
+#[cfg(test)]
+impl AeadCipher for NullCipher {
+    fn config(&self) -> BufferedConfig {
+        self.config
+    }
 
-        if restlen > 0 {
-            self.og.fill(0);
-            self.og[..restlen].copy_from_slice(&inout[reststart..]);
-            self.aesctr.apply_keystream(&mut self.og);
-            inout[reststart..].copy_from_slice(&self.og[..restlen]);
-            self.next_decrypt_count = 16 - restlen;
-        }
+    fn open_in_place(
+        &self,
+        _nonce: &[u8],
+        _aad: &[u8],
+        _tag: &[u8],
+        _inout: &mut [u8],
+    ) -> Result<(), ()> {
+        Ok(())
     }
 }
 
+#[cfg(test)]
+impl StreamCipher for NullCipher {
+    fn decrypt(&self, _keystream_pos: u64, _inout: &mut [u8]) {}
+}
+
 #[cfg(test)]
 mod tests {
-    use super::VideoCipher;
+    use super::{
+        AeadCipher, AudioBufferedCipher, AudioRealtimeCipher, BufferedConfig, BufferedConfigError,
+        BufferedSuite, EkeyError, KeyWrap, StreamCipher, VideoCipher,
+    };
+    use crate::crypto::rsa::{RsaError, RsaPrivateKey, tests::TEST_KEY};
+
+    #[test]
+    fn buffered_suite_by_key_len() {
+        for (key_len, suite) in [
+            (32, BufferedSuite::ChaCha20Poly1305),
+            (16, BufferedSuite::Aes128Gcm),
+        ] {
+            let cipher = AudioBufferedCipher::from_shared_key(&vec![0; key_len]).unwrap();
+            assert_eq!(BufferedConfig::new(suite), cipher.config());
+            assert_eq!(24, cipher.config().trailer_len());
+            assert!(cipher.config().trailer_len() <= AudioBufferedCipher::MAX_TRAILER_LEN);
+            assert!(suite.nonce_len() <= AudioBufferedCipher::MAX_NONCE_LEN);
+
+            // Mismatched lengths are rejected rather than panicking
+            assert!(
+                cipher
+                    .open_in_place(&[0; 8], &[0; 8], &[0; 16], &mut [])
+                    .is_err()
+            );
+        }
+        assert!(AudioBufferedCipher::from_shared_key(&[0; 24]).is_none());
+    }
+
+    #[test]
+    fn buffered_config_validated() {
+        for suite in [BufferedSuite::ChaCha20Poly1305, BufferedSuite::Aes128Gcm] {
+            let config = BufferedConfig::new(suite);
+            assert_eq!(Ok(()), config.validate());
+
+            // Whole nonce sent still fits the trailer
+            let whole_nonce = BufferedConfig {
+                sent_nonce_len: config.nonce_len,
+                ..config
+            };
+            assert_eq!(Ok(()), whole_nonce.validate());
+            assert!(whole_nonce.trailer_len() <= AudioBufferedCipher::MAX_TRAILER_LEN);
+
+            for (offset, len) in [(8, 8), (12, 1), (usize::MAX, 2)] {
+                let aad = BufferedConfig {
+                    aad_offset: offset,
+                    aad_len: len,
+                    ..config
+                };
+                assert!(matches!(
+                    aad.validate(),
+                    Err(BufferedConfigError::AadOutOfHeader { .. })
+                ));
+            }
+            // Extended header leaves room for longer AAD
+            let extended = BufferedConfig {
+                header_len: 16,
+                aad_len: 12,
+                ..config
+            };
+            assert_eq!(Ok(()), extended.validate());
+
+            let short_tag = BufferedConfig {
+                tag_len: 12,
+                ..config
+            };
+            assert!(matches!(
+                short_tag.validate(),
+                Err(BufferedConfigError::SuiteMismatch { part: "tag", .. })
+            ));
+            let oversent = BufferedConfig {
+                sent_nonce_len: config.nonce_len + 1,
+                ..config
+            };
+            assert!(matches!(
+                oversent.validate(),
+                Err(BufferedConfigError::SentNonce { .. })
+            ));
+        }
+
+        let gcm = BufferedConfig::new(BufferedSuite::Aes128Gcm);
+        assert!(matches!(
+            AudioBufferedCipher::new(&[0; 32], gcm),
+            Err(BufferedConfigError::SuiteMismatch { part: "key", .. })
+        ));
+    }
+
+    // Test case 2 of the GCM specification, NIST SP 800-38D
+    #[test]
+    fn buffered_gcm_vector() {
+        let cipher = AudioBufferedCipher::from_shared_key(&[0; 16]).unwrap();
+        let tag = hex::decode("ab6e47d42cec13bdf53a67b21257bddf").unwrap();
+        let mut payload = hex::decode("0388dace60b6a392f328c2b971b2fe78").unwrap();
+
+        let mut forged = tag.clone();
+        forged[0] ^= 1;
+        assert!(
+            cipher
+                .open_in_place(&[0; 12], &[], &forged, &mut payload)
+                .is_err()
+        );
+        cipher
+            .open_in_place(&[0; 12], &[], &tag, &mut payload)
+            .unwrap();
+        assert_eq!([0; 16], payload[..]);
+    }
+
+    #[test]
+    fn buffered_first_packet_of_shared_key() {
+        let shk = (0..32).collect::<Vec<u8>>();
+        // Timestamp and SSRC of the packet, nonce is its sequence number
+        let aad = [0x00, 0x00, 0x01, 0x60, 0x12, 0x34, 0x56, 0x78];
+        let nonce = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        let tag = [
+            83, 141, 113, 15, 166, 109, 136, 134, 76, 226, 81, 11, 105, 247, 81, 42,
+        ];
+        let mut payload = [15, 53, 14, 170, 69, 42, 200, 27, 78, 26, 27, 91];
+
+        let cipher = AudioBufferedCipher::from_shared_key(&shk).unwrap();
+        assert_eq!(BufferedSuite::ChaCha20Poly1305, cipher.config().suite);
+        cipher
+            .open_in_place(&nonce, &aad, &tag, &mut payload)
+            .unwrap();
+        assert_eq!(b"first packet", &payload);
+    }
+
+    #[test]
+    fn unencrypted_passes_payloads() {
+        let cipher = AudioBufferedCipher::from_shared_key(&[]).unwrap();
+        let config = cipher.config();
+        assert_eq!(BufferedSuite::Unencrypted, config.suite);
+        assert_eq!(Ok(()), config.validate());
+        assert_eq!(0, config.trailer_len());
+
+        let mut payload = *b"in the clear";
+        cipher
+            .open_in_place(&[], &[0; 8], &[], &mut payload)
+            .unwrap();
+        assert_eq!(b"in the clear", &payload);
+        AudioRealtimeCipher::unencrypted().decrypt(0, &mut payload);
+        VideoCipher::unencrypted().decrypt(100, &mut payload);
+        assert_eq!(b"in the clear", &payload);
+
+        let unencrypted = BufferedConfig::new(BufferedSuite::Unencrypted);
+        assert!(matches!(
+            AudioBufferedCipher::new(&[0; 16], unencrypted),
+            Err(BufferedConfigError::SuiteMismatch { part: "key", .. })
+        ));
+    }
+
+    #[test]
+    fn realtime_key_of_rsa_ekey() {
+        use base64::{Engine, engine::general_purpose::STANDARD};
+
+        // Key 00..0f encrypted by `openssl pkeyutl -pkeyopt rsa_padding_mode:oaep`
+        const EKEY: &str = "IoiH1hY8sm3qQzVPh/odte4b295PKEJFYyZ33TofNwVeM4AFPSIioiTvB1vLmn+3xe0eoC7DrySQloymhk3uUdPDXFbjEEt7l4qlMbEL+9XOsQSAuMWKQfA/KBT9/pzh5nZXnItPLknDKkQAP78NFs2PFWdv/H6hhHw+Okt7uEE=";
+        const EIV: [u8; 16] = [15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0];
+        // "sixteen byte msg" under the key and IV above
+        const ENCRYPTED: [u8; 16] = [
+            0xc9, 0xab, 0xb1, 0xad, 0xad, 0x5e, 0xdb, 0xf9, 0x0c, 0xef, 0x36, 0x99, 0x5d, 0x35,
+            0x74, 0xc8,
+        ];
+
+        let key = RsaPrivateKey::from_pkcs1_der(&STANDARD.decode(TEST_KEY).unwrap()).unwrap();
+        let ekey = STANDARD.decode(EKEY).unwrap();
+        let wrap = KeyWrap::Rsa(&key);
+        let aes_key: [u8; 16] = std::array::from_fn(|i| u8::try_from(i).unwrap());
+        assert_eq!(aes_key, wrap.decrypt(&ekey).unwrap());
+
+        let cipher = AudioRealtimeCipher::from_ekey_eiv(&ekey, &EIV, wrap, None).unwrap();
+        // Trailing partial block is passed in the clear
+        let mut payload = [&ENCRYPTED[..], b"tail"].concat();
+        cipher.decrypt(0, &mut payload);
+        assert_eq!(b"sixteen byte msgtail", &payload[..]);
+
+        let mut tampered = ekey.clone();
+        tampered[10] ^= 1;
+        assert!(matches!(
+            AudioRealtimeCipher::from_ekey_eiv(&tampered, &EIV, wrap, None),
+            Err(EkeyError::Rsa(RsaError::Decryption))
+        ));
+        assert!(matches!(
+            AudioRealtimeCipher::from_ekey_eiv(&ekey, &EIV[..8], wrap, None),
+            Err(EkeyError::IvLength(8))
+        ));
+    }
 
     #[test]
     fn test_video_decipher() {
@@ -169,11 +681,42 @@ mod tests {
             244, 147, 183, 57, 16, 194, 217,
         ];
 
-        let mut input = (0..1025usize).map(|x| (x % 255) as u8).collect::<Vec<_>>();
-        let mut cipher = VideoCipher::new([1; 16], 1000);
+        let input = (0..255u8).cycle().take(1025).collect::<Vec<_>>();
+        let cipher = VideoCipher::new([1; 16], 1000);
+
+        let mut whole = input.clone();
+        cipher.decrypt(0, &mut whole);
+        assert_eq!(whole, OUTPUT);
+
+        // Out of order and not aligned to blocks
+        let mut chunked = input;
+        let (head, tail) = chunked.split_at_mut(100);
+        cipher.decrypt(100, tail);
+        cipher.decrypt(0, head);
+        assert_eq!(chunked, OUTPUT);
+    }
+
+    #[test]
+    fn video_decipher_skipped_frame() {
+        let frames: [&[u8]; 3] = [&[1; 40], &[2; 17], &[3; 33]];
+        let cipher = VideoCipher::new([7; 16], 42);
 
-        cipher.decrypt(&mut input);
+        let mut positions = Vec::new();
+        let mut sealed = Vec::new();
+        let mut pos = 0;
+        for frame in frames {
+            let mut buf = frame.to_vec();
+            cipher.decrypt(pos, &mut buf);
+            positions.push(pos);
+            sealed.push(buf);
+            pos += frame.len() as u64;
+        }
 
-        assert_eq!(input, OUTPUT);
+        // Frame N + 1 is never decrypted, N + 2 only depends on its own position
+        for i in [0, 2] {
+            cipher.decrypt(positions[i], &mut sealed[i]);
+            assert_eq!(frames[i], &sealed[i][..]);
+        }
+        assert_ne!(frames[1], &sealed[1][..]);
     }
 }
@@ -1,10 +1,19 @@
 #![warn(clippy::pedantic)]
 
+pub mod advertise;
 pub mod config;
 pub mod playback;
+pub mod remote;
 pub mod rtsp;
 
 pub(crate) mod crypto;
 // TODO : pub(crate) mod rtsp;
 pub(crate) mod streaming;
 pub(crate) mod util;
+
+/// Internals measured by `benches/`, not part of the API
+#[cfg(feature = "testing")]
+#[doc(hidden)]
+pub mod bench {
+    pub use crate::streaming::offload::{Offload, Priority, PriorityPool, ordered};
+}
@@ -0,0 +1,92 @@
+//! Cover art of the playing track, passed on as sent rather than decoded.
+//!
+//! Senders label it with `Content-Type`, though not always correctly, so the image is also
+//! recognized by its leading bytes.
+
+use bytes::Bytes;
+
+/// Content type of the parameter clearing the artwork, sent with an empty body
+pub const NONE_MIME: &str = "image/none";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Artwork {
+    /// E.g. `image/jpeg`, of the image itself if it's recognized, as labeled otherwise
+    pub mime: String,
+    pub bytes: Bytes,
+}
+
+/// Brands of ISO media files holding HEIF images, as in their `ftyp` box
+const HEIC_BRANDS: &[&[u8; 4]] = &[b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx"];
+const HEIF_BRANDS: &[&[u8; 4]] = &[b"mif1", b"msf1"];
+
+impl Artwork {
+    /// `None` if there's none, i.e. the artwork is cleared.
+    #[must_use]
+    pub fn new(content_type: &str, bytes: Bytes) -> Option<Self> {
+        if bytes.is_empty() || content_type == NONE_MIME {
+            return None;
+        }
+        let mime = sniff(&bytes).unwrap_or(content_type).to_string();
+        Some(Self { mime, bytes })
+    }
+}
+
+/// Type of the image by its magic bytes, `None` if it's none of the ones senders send.
+fn sniff(image: &[u8]) -> Option<&'static str> {
+    const JPEG: &[u8] = &[0xff, 0xd8, 0xff];
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+    if image.starts_with(JPEG) {
+        return Some("image/jpeg");
+    }
+    if image.starts_with(PNG) {
+        return Some("image/png");
+    }
+    // Box size, `ftyp` and the major brand
+    let brand = image.get(4..12).filter(|ftyp| ftyp.starts_with(b"ftyp"))?;
+    let brand = &brand[4..];
+    if HEIC_BRANDS.iter().any(|heic| heic[..] == *brand) {
+        Some("image/heic")
+    } else if HEIF_BRANDS.iter().any(|heif| heif[..] == *brand) {
+        Some("image/heif")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::{Artwork, NONE_MIME};
+
+    fn mime(content_type: &str, image: &'static [u8]) -> Option<String> {
+        Artwork::new(content_type, Bytes::from_static(image)).map(|artwork| artwork.mime)
+    }
+
+    #[test]
+    fn mime_of_magic_bytes() {
+        let jpeg = b"\xff\xd8\xff\xe0\0\x10JFIF";
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        let heic = b"\0\0\0\x18ftypheic\0\0\0\0mif1heic";
+        let mif1 = b"\0\0\0\x18ftypmif1\0\0\0\0mif1heic";
+        assert_eq!(Some("image/jpeg".into()), mime("image/jpeg", jpeg));
+        // Mislabeled ones are taken for what they are
+        assert_eq!(Some("image/png".into()), mime("image/jpeg", png));
+        assert_eq!(Some("image/heic".into()), mime("image/jpeg", heic));
+        assert_eq!(Some("image/heif".into()), mime("image/heic", mif1));
+        // Unknown ones are left as labeled, e.g. a video brand
+        assert_eq!(Some("image/gif".into()), mime("image/gif", b"GIF89a"));
+        assert_eq!(
+            Some("image/heic".into()),
+            mime("image/heic", b"\0\0\0\x18ftypmp42")
+        );
+    }
+
+    #[test]
+    fn none_clears_artwork() {
+        assert_eq!(None, mime("image/jpeg", b""));
+        assert_eq!(None, mime(NONE_MIME, b""));
+        assert_eq!(None, mime(NONE_MIME, b"\xff\xd8\xff"));
+    }
+}
@@ -0,0 +1,312 @@
+use std::{
+    ffi::{c_int, c_uint},
+    ptr::NonNull,
+};
+
+use super::{AudioPacket, AudioParams, CodecKind, DecodeError, PcmBuffer};
+
+/// Decoder of raw AAC-LC and AAC-ELD access units into [`PcmBuffer`].
+///
+/// Every sample is aligned to the most significant bit of `i32`.
+pub struct AacDecoder {
+    handle: NonNull<ffi::Decoder>,
+    pcm: Vec<i16>,
+    out: PcmBuffer,
+    /// Previous frame failed, decoder has to drop its state carried over from it
+    resync: bool,
+}
+
+// SAFETY: handle is exclusively owned and only used through `&mut self`
+unsafe impl Send for AacDecoder {}
+
+impl AacDecoder {
+    /// Upper bound of samples per channel in a frame
+    const MAX_FRAME_LEN: usize = 2048;
+
+    const SAMPLE_RATES: [u32; 13] = [
+        96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+    ];
+
+    /// # Errors
+    ///
+    /// If params don't describe AAC stream or decoder rejects the derived config.
+    pub fn new(params: &AudioParams) -> Result<Self, DecodeError> {
+        let mut config = Self::audio_specific_config(params)?;
+
+        // SAFETY: no preconditions
+        let handle = unsafe { ffi::aacDecoder_Open(ffi::TT_MP4_RAW, 1) };
+        let handle = NonNull::new(handle).ok_or(DecodeError::Aac(ffi::AAC_DEC_OUT_OF_MEMORY))?;
+        let mut decoder = Self {
+            handle,
+            pcm: vec![0; Self::MAX_FRAME_LEN * usize::from(params.format.channels)],
+            out: PcmBuffer::of_format(&params.format, 16),
+            resync: false,
+        };
+
+        let mut conf = config.as_mut_ptr();
+        let len =
+            c_uint::try_from(config.len()).map_err(|_| DecodeError::InvalidParams("config"))?;
+        // SAFETY: handle is open, config is copied by the decoder
+        let err = unsafe {
+            ffi::aacDecoder_ConfigRaw(decoder.handle.as_ptr(), &raw mut conf, &raw const len)
+        };
+        decoder.check(err)?;
+
+        Ok(decoder)
+    }
+
+    /// Decodes payload of decrypted packet holding a single access unit.
+    ///
+    /// # Errors
+    ///
+    /// If payload is truncated or corrupted, the next packet is decoded as if after a gap.
+    pub fn decode(&mut self, packet: &AudioPacket) -> Result<&PcmBuffer, DecodeError> {
+        if packet.rtp.len() < AudioPacket::HEADER_LEN {
+            return Err(DecodeError::Malformed);
+        }
+
+        let flags = if std::mem::take(&mut self.resync) {
+            ffi::AACDEC_INTR
+        } else {
+            0
+        };
+
+        let payload = packet.payload();
+        let len = c_uint::try_from(payload.len()).map_err(|_| DecodeError::Malformed)?;
+        let mut buf = payload.as_ptr().cast_mut();
+        let mut left = len;
+        // SAFETY: handle is open, input is only read and copied into internal buffer
+        let err = unsafe {
+            ffi::aacDecoder_Fill(
+                self.handle.as_ptr(),
+                &raw mut buf,
+                &raw const len,
+                &raw mut left,
+            )
+        };
+        self.check(err)?;
+        if left != 0 {
+            self.resync = true;
+            return Err(DecodeError::Malformed);
+        }
+
+        let pcm_len = c_int::try_from(self.pcm.len()).unwrap_or(c_int::MAX);
+        // SAFETY: handle is open, output length is passed along
+        let err = unsafe {
+            ffi::aacDecoder_DecodeFrame(self.handle.as_ptr(), self.pcm.as_mut_ptr(), pcm_len, flags)
+        };
+        self.check(err)?;
+
+        // SAFETY: handle is open, info lives as long as it
+        let info = unsafe { ffi::aacDecoder_GetStreamInfo(self.handle.as_ptr()).as_ref() };
+        let decoded = info
+            .and_then(|info| usize::try_from(info.frame_size * info.num_channels).ok())
+            .ok_or(DecodeError::Malformed)?
+            .min(self.pcm.len());
+
+        self.out.samples.clear();
+        self.out.samples.extend(
+            self.pcm[..decoded]
+                .iter()
+                .map(|&sample| i32::from(sample) << 16),
+        );
+        Ok(&self.out)
+    }
+
+    fn check(&mut self, err: c_int) -> Result<(), DecodeError> {
+        if err == ffi::AAC_DEC_OK {
+            Ok(())
+        } else {
+            self.resync = true;
+            Err(DecodeError::Aac(err))
+        }
+    }
+
+    /// `AudioSpecificConfig` of the stream, see ISO/IEC 14496-3
+    pub(crate) fn audio_specific_config(params: &AudioParams) -> Result<Vec<u8>, DecodeError> {
+        const AOT_ESCAPE: u32 = 31;
+        const AOT_AAC_LC: u32 = 2;
+        const AOT_ER_AAC_ELD: u32 = 39;
+
+        let format = params.format;
+        let freq_index = Self::SAMPLE_RATES
+            .iter()
+            .position(|&rate| rate == format.sample_rate)
+            .ok_or(DecodeError::InvalidParams("sample rate"))?;
+        if !(1..=7).contains(&format.channels) {
+            return Err(DecodeError::InvalidParams("channels"));
+        }
+
+        let mut writer = BitWriter::default();
+        match format.codec {
+            CodecKind::AacLc => {
+                let short_frame = match params.samples_per_frame {
+                    1024 => false,
+                    960 => true,
+                    _ => return Err(DecodeError::InvalidParams("samples per frame")),
+                };
+                writer.write(AOT_AAC_LC, 5);
+                writer.write(u32::try_from(freq_index).unwrap(), 4);
+                writer.write(format.channels.into(), 4);
+                // GASpecificConfig: frame length, no core coder, no extension
+                writer.write(short_frame.into(), 1);
+                writer.write(0, 2);
+            }
+            CodecKind::AacEld => {
+                let short_frame = match params.samples_per_frame {
+                    512 => false,
+                    480 => true,
+                    _ => return Err(DecodeError::InvalidParams("samples per frame")),
+                };
+                writer.write(AOT_ESCAPE, 5);
+                writer.write(AOT_ER_AAC_ELD - AOT_ESCAPE - 1, 6);
+                writer.write(u32::try_from(freq_index).unwrap(), 4);
+                writer.write(format.channels.into(), 4);
+                // ELDSpecificConfig: frame length, no resilience tools, no SBR, ELDEXT_TERM
+                writer.write(short_frame.into(), 1);
+                writer.write(0, 3 + 1 + 4);
+                // epConfig
+                writer.write(0, 2);
+            }
+            codec => return Err(DecodeError::UnsupportedCodec(codec)),
+        }
+
+        Ok(writer.finish())
+    }
+}
+
+impl Drop for AacDecoder {
+    fn drop(&mut self) {
+        // SAFETY: handle is open and not used afterwards
+        unsafe { ffi::aacDecoder_Close(self.handle.as_ptr()) };
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: usize,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, len: u8) {
+        for i in (0..len).rev() {
+            if self.bits.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            let bit = (value >> i) & 1;
+            *self.bytes.last_mut().unwrap() |= u8::from(bit == 1) << (7 - self.bits % 8);
+            self.bits += 1;
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Subset of `aacdecoder_lib.h` of the system `libfdk-aac`
+#[allow(non_snake_case)]
+mod ffi {
+    use std::ffi::{c_int, c_uint};
+
+    pub const TT_MP4_RAW: c_int = 0;
+
+    pub const AAC_DEC_OK: c_int = 0;
+    pub const AAC_DEC_OUT_OF_MEMORY: c_int = 0x2;
+
+    /// Input is discontinuous, decoder resyncs its internals
+    pub const AACDEC_INTR: c_uint = 4;
+
+    #[repr(C)]
+    pub struct Decoder {
+        _opaque: [u8; 0],
+    }
+
+    /// Leading fields of `CStreamInfo`, the rest is never read
+    #[repr(C)]
+    pub struct StreamInfo {
+        pub sample_rate: c_int,
+        pub frame_size: c_int,
+        pub num_channels: c_int,
+    }
+
+    #[link(name = "fdk-aac")]
+    unsafe extern "C" {
+        pub fn aacDecoder_Open(transport: c_int, layers: c_uint) -> *mut Decoder;
+        pub fn aacDecoder_ConfigRaw(
+            decoder: *mut Decoder,
+            conf: *mut *mut u8,
+            length: *const c_uint,
+        ) -> c_int;
+        pub fn aacDecoder_Fill(
+            decoder: *mut Decoder,
+            buffer: *mut *mut u8,
+            size: *const c_uint,
+            valid: *mut c_uint,
+        ) -> c_int;
+        pub fn aacDecoder_DecodeFrame(
+            decoder: *mut Decoder,
+            pcm: *mut i16,
+            pcm_len: c_int,
+            flags: c_uint,
+        ) -> c_int;
+        pub fn aacDecoder_GetStreamInfo(decoder: *mut Decoder) -> *mut StreamInfo;
+        pub fn aacDecoder_Close(decoder: *mut Decoder);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use super::{super::AudioFormat, AacDecoder, AudioPacket, AudioParams, DecodeError};
+
+    fn params(bits: u32, samples_per_frame: u32) -> AudioParams {
+        AudioParams {
+            samples_per_frame,
+            format: AudioFormat::from_bits(bits).unwrap(),
+            latency: None,
+        }
+    }
+
+    #[test]
+    fn audio_specific_config() {
+        assert_eq!(
+            vec![0x12, 0x10],
+            AacDecoder::audio_specific_config(&params(0x0040_0000, 1024)).unwrap()
+        );
+        assert_eq!(
+            vec![0xf8, 0xe8, 0x50, 0x00],
+            AacDecoder::audio_specific_config(&params(0x0100_0000, 480)).unwrap()
+        );
+        assert!(matches!(
+            AacDecoder::audio_specific_config(&params(0x0100_0000, 1024)),
+            Err(DecodeError::InvalidParams(_))
+        ));
+        assert!(matches!(
+            AacDecoder::audio_specific_config(&params(0x40000, 352)),
+            Err(DecodeError::UnsupportedCodec(_))
+        ));
+    }
+
+    #[test]
+    fn decode_corrupted() {
+        let mut decoder = AacDecoder::new(&params(0x0100_0000, 480)).unwrap();
+
+        assert!(matches!(
+            decoder.decode(&AudioPacket {
+                rtp: BytesMut::zeroed(AudioPacket::HEADER_LEN),
+                playout_time: None
+            }),
+            Err(DecodeError::Aac(_))
+        ));
+        assert!(matches!(
+            decoder.decode(&AudioPacket {
+                rtp: BytesMut::zeroed(4),
+                playout_time: None
+            }),
+            Err(DecodeError::Malformed)
+        ));
+    }
+}
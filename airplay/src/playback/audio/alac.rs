@@ -0,0 +1,267 @@
+use alac::{Decoder, StreamInfo};
+
+use super::{AudioFormat, AudioPacket, AudioParams, CodecKind, DecodeError, PcmBuffer};
+
+/// Decoder of ALAC frames into [`PcmBuffer`].
+///
+/// Every sample is aligned to the most significant bit of `i32` regardless of format's bit depth,
+/// so 24-bit lossless samples keep their full precision. Channels are interleaved in the order
+/// of ALAC elements, e.g. C L R Ls Rs LFE of 5.1.
+pub struct AlacDecoder {
+    inner: Decoder,
+    /// Samples of the longest packet
+    max_samples: usize,
+    out: PcmBuffer,
+}
+
+impl AlacDecoder {
+    pub(crate) const COOKIE_LEN: usize = 24;
+
+    // Defaults of reference encoder, senders don't pass them
+    const RICE_HISTORY_MULT: u8 = 40;
+    const RICE_INITIAL_HISTORY: u8 = 10;
+    const RICE_LIMIT: u8 = 14;
+    const MAX_RUN: u16 = 255;
+
+    /// # Errors
+    ///
+    /// If params don't describe ALAC stream or can't form a valid config.
+    pub fn new(params: &AudioParams) -> Result<Self, DecodeError> {
+        let format = params.format;
+        let info = StreamInfo::from_cookie(&Self::magic_cookie(params)?)?;
+        let max_samples = info.max_samples_per_packet() as usize;
+
+        Ok(Self {
+            inner: Decoder::new(info),
+            max_samples,
+            out: PcmBuffer::of_format(&format, format.bits_per_sample),
+        })
+    }
+
+    /// Decodes payload of decrypted packet, partial frames produce less samples.
+    ///
+    /// # Errors
+    ///
+    /// If payload is truncated or corrupted.
+    pub fn decode(&mut self, packet: &AudioPacket) -> Result<&PcmBuffer, DecodeError> {
+        if packet.rtp.len() < AudioPacket::HEADER_LEN {
+            return Err(DecodeError::Malformed);
+        }
+
+        let samples = &mut self.out.samples;
+        samples.resize(self.max_samples, 0);
+        let len = self.inner.decode_packet(packet.payload(), samples)?.len();
+        samples.truncate(len);
+        Ok(&self.out)
+    }
+
+    /// `ALACSpecificConfig` of the stream, which MP4 carries as is too.
+    pub(crate) fn magic_cookie(
+        params: &AudioParams,
+    ) -> Result<[u8; Self::COOKIE_LEN], DecodeError> {
+        let format = params.format;
+        if format.codec != CodecKind::Alac {
+            return Err(DecodeError::UnsupportedCodec(format.codec));
+        }
+
+        let bit_depth = u8::try_from(format.bits_per_sample)
+            .ok()
+            .filter(|bits| (1..=32).contains(bits))
+            .ok_or(DecodeError::InvalidParams("bit depth"))?;
+        if !(1..=AudioFormat::MAX_CHANNELS).contains(&format.channels) {
+            return Err(DecodeError::InvalidParams("channels"));
+        }
+        if params.samples_per_frame == 0 {
+            return Err(DecodeError::InvalidParams("samples per frame"));
+        }
+
+        let mut cookie = [0u8; Self::COOKIE_LEN];
+        cookie[..4].copy_from_slice(&params.samples_per_frame.to_be_bytes());
+        // compatible version is zero
+        cookie[5] = bit_depth;
+        cookie[6] = Self::RICE_HISTORY_MULT;
+        cookie[7] = Self::RICE_INITIAL_HISTORY;
+        cookie[8] = Self::RICE_LIMIT;
+        cookie[9] = params.format.channels;
+        cookie[10..12].copy_from_slice(&Self::MAX_RUN.to_be_bytes());
+        // max frame bytes and average bitrate are unknown
+        cookie[20..].copy_from_slice(&params.format.sample_rate.to_be_bytes());
+        Ok(cookie)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use bytes::BytesMut;
+
+    use super::{super::AudioFormat, AlacDecoder, AudioPacket, AudioParams, DecodeError};
+
+    /// Writes uncompressed (escaped) ALAC frame of interleaved `samples`, channels are paired
+    /// into CPEs and the odd one left is put into SCE
+    pub(crate) fn uncompressed_frame(bits: u8, channels: u8, samples: &[i32]) -> Vec<u8> {
+        let channels = usize::from(channels);
+        let frames = samples.len() / channels;
+        let mut writer = BitWriter::default();
+        for first in (0..channels).step_by(2) {
+            let element_channels = (channels - first).min(2);
+            // SCE or CPE, instance tag, unused
+            writer.write(u32::from(element_channels == 2), 3);
+            writer.write(0, 4 + 12);
+            // partial frame (1), sample shift (00), uncompressed (1)
+            writer.write(0b1001, 4);
+            writer.write(u32::try_from(frames).unwrap(), 32);
+            for frame in samples.chunks(channels) {
+                for sample in &frame[first..first + element_channels] {
+                    writer.write(sample.cast_unsigned(), bits);
+                }
+            }
+        }
+        // END
+        writer.write(7, 3);
+        writer.finish()
+    }
+
+    fn packet(payload: &[u8]) -> AudioPacket {
+        let mut rtp = BytesMut::zeroed(AudioPacket::HEADER_LEN);
+        rtp.extend_from_slice(payload);
+        AudioPacket {
+            rtp,
+            playout_time: None,
+        }
+    }
+
+    #[derive(Default)]
+    struct BitWriter {
+        bytes: Vec<u8>,
+        bits: usize,
+    }
+
+    impl BitWriter {
+        fn write(&mut self, value: u32, len: u8) {
+            for i in (0..len).rev() {
+                if self.bits.is_multiple_of(8) {
+                    self.bytes.push(0);
+                }
+                let bit = (value >> i) & 1;
+                *self.bytes.last_mut().unwrap() |= u8::from(bit == 1) << (7 - self.bits % 8);
+                self.bits += 1;
+            }
+        }
+
+        fn finish(self) -> Vec<u8> {
+            self.bytes
+        }
+    }
+
+    #[test]
+    fn decode_16bit_stereo() {
+        let params = AudioParams {
+            samples_per_frame: 352,
+            format: AudioFormat::from_bits(0x40000).unwrap(),
+            latency: None,
+        };
+        let samples = [1, -1, 0x7fff, -0x8000];
+        let payload = uncompressed_frame(16, 2, &samples);
+
+        let mut decoder = AlacDecoder::new(&params).unwrap();
+        let pcm = decoder.decode(&packet(&payload)).unwrap();
+
+        assert_eq!(
+            samples
+                .iter()
+                .map(|sample| sample << 16)
+                .collect::<Vec<_>>(),
+            pcm.samples
+        );
+    }
+
+    #[test]
+    fn decode_24bit_96khz() {
+        let format = AudioFormat::from_bits(0x0020_0000)
+            .unwrap()
+            .with_layout(Some(96000), None, None)
+            .unwrap();
+        let params = AudioParams {
+            samples_per_frame: 352,
+            format,
+            latency: None,
+        };
+        let samples = [1, -1, 0x7f_ffff, -0x80_0000, 0x12_3456, -0x12_3456];
+        let payload = uncompressed_frame(24, 2, &samples);
+
+        let mut decoder = AlacDecoder::new(&params).unwrap();
+        let pcm = decoder.decode(&packet(&payload)).unwrap();
+
+        assert_eq!(
+            vec![
+                0x100,
+                -0x100,
+                0x7fff_ff00,
+                i32::MIN,
+                0x1234_5600,
+                -0x1234_5600
+            ],
+            pcm.samples
+        );
+    }
+
+    #[test]
+    fn decode_surround() {
+        let format = AudioFormat::from_bits(0x0020_0000)
+            .unwrap()
+            .with_layout(None, None, Some(6))
+            .unwrap();
+        let params = AudioParams {
+            samples_per_frame: 352,
+            format,
+            latency: None,
+        };
+        // Two frames of C L R Ls Rs LFE
+        let samples = (1..=12).collect::<Vec<i32>>();
+        let payload = uncompressed_frame(24, 6, &samples);
+
+        let mut decoder = AlacDecoder::new(&params).unwrap();
+        let pcm = decoder.decode(&packet(&payload)).unwrap();
+
+        assert_eq!(
+            samples.iter().map(|sample| sample << 8).collect::<Vec<_>>(),
+            pcm.samples
+        );
+    }
+
+    #[test]
+    fn decode_corrupted() {
+        let params = AudioParams {
+            samples_per_frame: 352,
+            format: AudioFormat::from_bits(0x40000).unwrap(),
+            latency: None,
+        };
+        let payload = uncompressed_frame(16, 2, &[1, 2, 3, 4]);
+
+        let mut decoder = AlacDecoder::new(&params).unwrap();
+        assert!(matches!(
+            decoder.decode(&packet(&payload[..payload.len() - 2])),
+            Err(DecodeError::Alac(_))
+        ));
+        assert!(matches!(
+            decoder.decode(&AudioPacket {
+                rtp: BytesMut::zeroed(4),
+                playout_time: None
+            }),
+            Err(DecodeError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn reject_not_alac() {
+        let params = AudioParams {
+            samples_per_frame: 1024,
+            format: AudioFormat::from_bits(0x0040_0000).unwrap(),
+            latency: None,
+        };
+        assert!(matches!(
+            AlacDecoder::new(&params),
+            Err(DecodeError::UnsupportedCodec(_))
+        ));
+    }
+}
@@ -0,0 +1,311 @@
+//! Stream writing received audio into a file, for debugging interop with senders.
+
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+};
+
+use thiserror::Error;
+
+use super::{AudioDecoder, AudioPacket, AudioParams, AudioStream, DecodeError};
+use crate::playback::Stream;
+
+/// What of each packet is written.
+///
+/// Streams are passed packets already decrypted, so that's the rawest capture there is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capture {
+    /// Whole RTP packets, each prefixed with its 32 bits big endian length
+    Rtp,
+    /// ALAC or AAC frames without RTP header, prefixed as packets are
+    Encoded,
+    /// Decoded samples as WAV, packets failed to be decoded are skipped
+    Pcm,
+}
+
+#[derive(Debug, Error)]
+pub enum FileStreamError {
+    #[error("capture file: {0}")]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+}
+
+/// Writes packets into a file as [`Capture`] says, the file is complete once the stream ends.
+///
+/// Failed writes are logged and end the capture rather than the stream.
+pub struct FileAudioStream {
+    sink: Mutex<Sink>,
+}
+
+struct Sink {
+    path: PathBuf,
+    /// `None` once a write failed
+    out: Option<BufWriter<File>>,
+    capture: Capture,
+    decoder: Option<AudioDecoder>,
+    wav: Option<WavFormat>,
+    /// Bytes written after the header
+    data_len: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct WavFormat {
+    channels: u16,
+    sample_rate: u32,
+    /// Container width, samples of other depths are widened
+    bits: u16,
+}
+
+impl FileAudioStream {
+    /// Creates the file at `path`, replacing one that's there.
+    ///
+    /// # Errors
+    ///
+    /// If the file couldn't be created or samples of `params` can't be decoded for
+    /// [`Capture::Pcm`].
+    pub fn create(
+        path: impl AsRef<Path>,
+        capture: Capture,
+        params: &AudioParams,
+    ) -> Result<Self, FileStreamError> {
+        let path = path.as_ref().to_path_buf();
+        let (decoder, wav) = match capture {
+            Capture::Pcm => {
+                let format = params.format;
+                let wav = WavFormat {
+                    channels: format.channels.into(),
+                    sample_rate: format.sample_rate,
+                    bits: match format.bits_per_sample {
+                        ..=16 => 16,
+                        17..=24 => 24,
+                        _ => 32,
+                    },
+                };
+                (Some(AudioDecoder::new(params)?), Some(wav))
+            }
+            Capture::Rtp | Capture::Encoded => (None, None),
+        };
+
+        let mut out = BufWriter::new(File::create(&path)?);
+        if let Some(wav) = wav {
+            wav.write_header(&mut out, 0)?;
+        }
+        tracing::info!(path = %path.display(), ?capture, "audio capture started");
+
+        Ok(Self {
+            sink: Mutex::new(Sink {
+                path,
+                out: Some(out),
+                capture,
+                decoder,
+                wav,
+                data_len: 0,
+            }),
+        })
+    }
+}
+
+impl Sink {
+    fn write(&mut self, packet: &AudioPacket) {
+        let Some(out) = &mut self.out else {
+            return;
+        };
+
+        let res = match self.capture {
+            Capture::Rtp => write_prefixed(out, &packet.rtp),
+            Capture::Encoded => write_prefixed(out, packet.payload()),
+            Capture::Pcm => {
+                let (Some(decoder), Some(wav)) = (&mut self.decoder, self.wav) else {
+                    return;
+                };
+                match decoder.decode(packet) {
+                    Ok(pcm) => wav.write_samples(out, &pcm.samples),
+                    Err(err) => {
+                        tracing::warn!(%err, "captured packet not decoded");
+                        return;
+                    }
+                }
+            }
+        };
+
+        match res {
+            Ok(len) => self.data_len += len,
+            Err(err) => {
+                tracing::error!(%err, path = %self.path.display(), "audio capture stopped");
+                self.out = None;
+            }
+        }
+    }
+
+    /// Flushes the file, with sizes of WAV header filled in.
+    fn finish(&mut self) -> io::Result<()> {
+        let Some(mut out) = self.out.take() else {
+            return Ok(());
+        };
+        if let Some(wav) = self.wav {
+            out.seek(SeekFrom::Start(0))?;
+            wav.write_header(&mut out, self.data_len)?;
+        }
+        out.into_inner()
+            .map_err(io::IntoInnerError::into_error)?
+            .sync_all()
+    }
+}
+
+impl Drop for Sink {
+    fn drop(&mut self) {
+        match self.finish() {
+            Ok(()) => tracing::info!(
+                path = %self.path.display(),
+                len = %self.data_len,
+                "audio capture finished"
+            ),
+            Err(err) => tracing::error!(%err, path = %self.path.display(), "audio capture lost"),
+        }
+    }
+}
+
+/// Returns count of bytes written.
+fn write_prefixed(out: &mut impl Write, data: &[u8]) -> io::Result<u64> {
+    let len = u32::try_from(data.len()).map_err(|_| io::ErrorKind::InvalidInput)?;
+    out.write_all(&len.to_be_bytes())?;
+    out.write_all(data)?;
+    Ok(u64::from(len) + 4)
+}
+
+impl WavFormat {
+    const HEADER_LEN: u32 = 44;
+
+    /// Sizes of the header are capped, as players read past them anyway.
+    fn write_header(self, out: &mut impl Write, data_len: u64) -> io::Result<()> {
+        let data_len = u32::try_from(data_len)
+            .unwrap_or(u32::MAX)
+            .min(u32::MAX - Self::HEADER_LEN);
+        let block_align = self.channels * self.bits / 8;
+
+        out.write_all(b"RIFF")?;
+        out.write_all(&(Self::HEADER_LEN - 8 + data_len).to_le_bytes())?;
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        // Integer PCM
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&self.channels.to_le_bytes())?;
+        out.write_all(&self.sample_rate.to_le_bytes())?;
+        out.write_all(&(self.sample_rate * u32::from(block_align)).to_le_bytes())?;
+        out.write_all(&block_align.to_le_bytes())?;
+        out.write_all(&self.bits.to_le_bytes())?;
+        out.write_all(b"data")?;
+        out.write_all(&data_len.to_le_bytes())
+    }
+
+    /// Samples are aligned to the most significant bit, so their top bytes are written.
+    fn write_samples(self, out: &mut impl Write, samples: &[i32]) -> io::Result<u64> {
+        let width = usize::from(self.bits / 8);
+        for sample in samples {
+            out.write_all(&sample.to_le_bytes()[4 - width..])?;
+        }
+        Ok((samples.len() * width) as u64)
+    }
+}
+
+impl Stream for FileAudioStream {
+    type Content = AudioPacket;
+
+    fn on_data(&self, packet: Self::Content) {
+        self.sink
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .write(&packet);
+    }
+
+    fn on_ok(self) {
+        tracing::debug!("captured stream finished");
+    }
+
+    fn on_err(self, err: Box<dyn Error>) {
+        tracing::debug!(%err, "captured stream failed");
+    }
+}
+
+impl AudioStream for FileAudioStream {}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use bytes::BytesMut;
+
+    use super::{Capture, FileAudioStream};
+    use crate::playback::{
+        Stream,
+        audio::{AudioFormat, AudioPacket, AudioParams, alac::tests::uncompressed_frame},
+    };
+
+    fn params() -> AudioParams {
+        AudioParams {
+            samples_per_frame: 352,
+            format: AudioFormat::from_bits(0x40000).unwrap(),
+            latency: None,
+        }
+    }
+
+    fn packet(payload: &[u8]) -> AudioPacket {
+        let mut rtp = BytesMut::from(&[0x80, 0x60, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0][..]);
+        rtp.extend_from_slice(payload);
+        AudioPacket {
+            rtp,
+            playout_time: None,
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rairplay-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn packets_captured_with_length() {
+        let path = temp_path("encoded.bin");
+        let stream = FileAudioStream::create(&path, Capture::Encoded, &params()).unwrap();
+        stream.on_data(packet(&[1, 2, 3]));
+        stream.on_data(packet(&[]));
+        stream.on_ok();
+
+        assert_eq!(
+            vec![0, 0, 0, 3, 1, 2, 3, 0, 0, 0, 0],
+            fs::read(&path).unwrap()
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn pcm_captured_as_wav() {
+        let path = temp_path("pcm.wav");
+        let stream = FileAudioStream::create(&path, Capture::Pcm, &params()).unwrap();
+        stream.on_data(packet(&uncompressed_frame(16, 2, &[1, -1])));
+        // Undecodable packets are skipped
+        stream.on_data(packet(&[0xff]));
+        stream.on_data(packet(&uncompressed_frame(16, 2, &[0x1234, -0x8000])));
+        stream.on_err(std::io::Error::other("gone").into());
+
+        let wav = fs::read(&path).unwrap();
+        assert_eq!(b"RIFF", &wav[..4]);
+        assert_eq!(36 + 8, u32::from_le_bytes(wav[4..8].try_into().unwrap()));
+        // 2 channels, 44100 Hz, 16 bits
+        assert_eq!([2, 0, 0x44, 0xac, 0, 0], wav[22..28]);
+        assert_eq!([16, 0], wav[34..36]);
+        assert_eq!(b"data", &wav[36..40]);
+        assert_eq!(8, u32::from_le_bytes(wav[40..44].try_into().unwrap()));
+        assert_eq!([1, 0, 0xff, 0xff, 0x34, 0x12, 0, 0x80], wav[44..]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn unwritable_capture_refused() {
+        let path = temp_path("missing").join("rtp.bin");
+        assert!(FileAudioStream::create(path, Capture::Rtp, &params()).is_err());
+    }
+}
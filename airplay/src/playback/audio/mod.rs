@@ -0,0 +1,1015 @@
+use std::{
+    error::Error,
+    ops::RangeInclusive,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use bytes::BytesMut;
+use thiserror::Error;
+
+use super::{Device, Stream};
+
+#[cfg(feature = "aac")]
+mod aac;
+mod alac;
+mod file;
+mod pcm;
+
+pub use super::channel::Backpressure;
+#[cfg(feature = "aac")]
+pub use aac::AacDecoder;
+pub use alac::AlacDecoder;
+pub use file::{Capture, FileAudioStream, FileStreamError};
+pub use pcm::PcmDecoder;
+
+pub type ChannelAudioStream<T = AudioPacket> = super::channel::ChannelStream<T>;
+pub type ChannelAudioReceiver<T = AudioPacket> = super::channel::ChannelReceiver<T>;
+
+pub trait AudioDevice: Device<Params = AudioParams, Stream: AudioStream> {
+    fn get_volume(&self) -> f32;
+    fn set_volume(&self, value: f32);
+}
+
+pub trait AudioStream: Stream<Content = AudioPacket> {
+    /// Called once packets from `from_seq` (or every packet if `None`) up to `until_seq`
+    /// exclusive have been flushed by the sender, e.g. on seek. None of them is passed to
+    /// [`Stream::on_data`] afterwards, the ones already passed are to be dropped.
+    ///
+    /// Sequence numbers are 16 bits wide for realtime stream and 24 bits for buffered one.
+    fn on_flush(&self, from_seq: Option<u32>, until_seq: u32) {
+        let _ = (from_seq, until_seq);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AudioParams {
+    pub samples_per_frame: u32,
+    pub format: AudioFormat,
+    /// Sent for realtime stream only
+    pub latency: Option<Latency>,
+}
+
+/// Bounds of the delay between receiving and playing realtime audio, requested by the sender.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Latency {
+    pub min_samples: u32,
+    pub max_samples: u32,
+    pub sample_rate: u32,
+}
+
+/// Decoded single bit of `audioFormat` field sent during setup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioFormat {
+    pub codec: CodecKind,
+    pub bits_per_sample: u32,
+    pub sample_rate: u32,
+    pub channels: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodecKind {
+    Pcm,
+    AacLc,
+    AacEld,
+    Opus,
+    Alac,
+}
+
+#[derive(Debug)]
+pub struct AudioPacket {
+    pub rtp: BytesMut,
+    /// Local time the packet is to be played at, mapped from its RTP timestamp through the
+    /// sender's clock. Set only if held until then, see
+    /// [`Audio::playout_scheduling`](crate::config::Audio::playout_scheduling)
+    pub playout_time: Option<SystemTime>,
+}
+
+/// Fixed part of RTP header, see RFC 3550
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtpHeader {
+    pub version: u8,
+    pub padding: bool,
+    pub extension: bool,
+    pub csrc_count: u8,
+    pub marker: bool,
+    pub payload_type: u8,
+    pub seq: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+}
+
+impl AudioPacket {
+    /// Just RTP header
+    pub const HEADER_LEN: usize = 12;
+
+    /// Bytes missing from a truncated packet are read as zeros.
+    #[must_use]
+    pub fn header(&self) -> RtpHeader {
+        let mut raw = [0u8; Self::HEADER_LEN];
+        let len = self.rtp.len().min(Self::HEADER_LEN);
+        raw[..len].copy_from_slice(&self.rtp[..len]);
+
+        RtpHeader {
+            version: raw[0] >> 6,
+            padding: raw[0] & 0x20 != 0,
+            extension: raw[0] & 0x10 != 0,
+            csrc_count: raw[0] & 0x0f,
+            marker: raw[1] & 0x80 != 0,
+            payload_type: raw[1] & 0x7f,
+            seq: u16::from_be_bytes([raw[2], raw[3]]),
+            timestamp: u32::from_be_bytes([raw[4], raw[5], raw[6], raw[7]]),
+            ssrc: u32::from_be_bytes([raw[8], raw[9], raw[10], raw[11]]),
+        }
+    }
+
+    /// Sequence number of buffered stream packet, which spans payload type too.
+    #[must_use]
+    pub fn buffered_seq(&self) -> u32 {
+        let header = self.header();
+        u32::from(header.marker) << 23
+            | u32::from(header.payload_type) << 16
+            | u32::from(header.seq)
+    }
+
+    /// Length of the header with CSRC list and extension, where the payload starts.
+    ///
+    /// `None` if the packet is too short to contain them.
+    #[must_use]
+    pub fn header_len(rtp: &[u8]) -> Option<usize> {
+        let &[flags, ..] = rtp.first_chunk::<{ Self::HEADER_LEN }>()?;
+        let mut len = Self::HEADER_LEN + 4 * usize::from(flags & 0x0f);
+
+        if flags & 0x10 != 0 {
+            // 16 bits of profile data, then length of extension in 32 bits words
+            let ext = rtp.get(len..len + 4)?;
+            len += 4 + 4 * usize::from(u16::from_be_bytes([ext[2], ext[3]]));
+        }
+
+        (len <= rtp.len()).then_some(len)
+    }
+
+    /// Data after the header, CSRC list and extension.
+    ///
+    /// Empty if the packet is too short to contain them.
+    #[must_use]
+    pub fn payload(&self) -> &[u8] {
+        Self::header_len(&self.rtp).map_or(&[], |len| &self.rtp[len..])
+    }
+}
+
+impl Latency {
+    /// # Errors
+    ///
+    /// If bounds are inverted or sample rate is zero.
+    pub fn new(min_samples: u32, max_samples: u32, sample_rate: u32) -> Result<Self, LatencyError> {
+        if min_samples > max_samples {
+            return Err(LatencyError::Inverted {
+                min: min_samples,
+                max: max_samples,
+            });
+        }
+        if sample_rate == 0 {
+            return Err(LatencyError::ZeroSampleRate);
+        }
+
+        Ok(Self {
+            min_samples,
+            max_samples,
+            sample_rate,
+        })
+    }
+
+    /// Samples buffered before the playback starts, the lowest delay the sender accepts.
+    #[must_use]
+    pub fn target_samples(&self) -> u32 {
+        self.min_samples
+    }
+
+    /// Delay of the playback after packets are received, i.e. of [`Self::target_samples`].
+    #[must_use]
+    pub fn playout_delay(&self) -> Duration {
+        let nanos = u64::from(self.target_samples()) * 1_000_000_000 / u64::from(self.sample_rate);
+        Duration::from_nanos(nanos)
+    }
+
+    /// Count of packets the target spans, so the most worth holding for reordering.
+    #[must_use]
+    pub fn target_depth(&self, samples_per_frame: u32) -> u16 {
+        if samples_per_frame == 0 {
+            return 0;
+        }
+        let depth = self.target_samples().div_ceil(samples_per_frame);
+        u16::try_from(depth).unwrap_or(u16::MAX)
+    }
+}
+
+/// Decoded samples of a single [`AudioPacket`]
+#[derive(Debug)]
+pub struct PcmPacket {
+    /// RTP timestamp of the first sample
+    pub timestamp: u32,
+    pub pcm: PcmBuffer,
+}
+
+/// Samples as every decoder produces them, whatever the codec.
+///
+/// Samples are interleaved, one of each channel per frame in the order of the codec, e.g.
+/// L R of stereo or C L R Ls Rs LFE of 5.1 ALAC. Each is a native `i32` aligned to its most
+/// significant bit, so samples of any depth are in full scale of `i32` and the bits below
+/// `bits` are zero. [`PcmBuffer::to_le_bytes`] and [`PcmBuffer::to_f32`] convert them for
+/// audio APIs taking packed little endian integers or floats.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PcmBuffer {
+    pub channels: u8,
+    pub sample_rate: u32,
+    /// Significant bits of each sample, e.g. 16 of AAC or 24 of lossless ALAC
+    pub bits: u32,
+    pub samples: Vec<i32>,
+}
+
+impl PcmBuffer {
+    pub(crate) fn of_format(format: &AudioFormat, bits: u32) -> Self {
+        Self {
+            channels: format.channels,
+            sample_rate: format.sample_rate,
+            bits,
+            samples: Vec::new(),
+        }
+    }
+
+    /// Count of samples of each channel.
+    #[must_use]
+    pub fn frames(&self) -> usize {
+        self.samples
+            .len()
+            .checked_div(self.channels.into())
+            .unwrap_or_default()
+    }
+
+    /// Samples scaled into `-1.0..=1.0`, in the same order.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn to_f32(&self) -> Vec<f32> {
+        // f32 holds 24 significant bits, deeper samples are rounded
+        const SCALE: f32 = 1.0 / 2_147_483_648.0;
+        self.samples
+            .iter()
+            .map(|&sample| sample as f32 * SCALE)
+            .collect()
+    }
+
+    /// Samples packed as little endian integers of `bits` rounded up to whole bytes, e.g.
+    /// `S16_LE` or `S24_3LE` of ALSA.
+    #[must_use]
+    pub fn to_le_bytes(&self) -> Vec<u8> {
+        let width = self.bits.div_ceil(8).clamp(1, 4) as usize;
+        self.samples
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes()[4 - width..].to_vec())
+            .collect()
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum FormatError {
+    #[error("unknown audio format: {0:#x}")]
+    Unknown(u32),
+    #[error("audio format index {0} out of range")]
+    IndexOutOfRange(u8),
+    #[error("audio format index {index} isn't among offered {offered:#x}")]
+    NotOffered { index: u8, offered: u32 },
+    #[error("unknown content type: {0}")]
+    UnknownContentType(u8),
+    #[error("content type {content_type} doesn't match {codec:?} audio format")]
+    ContentTypeMismatch { content_type: u8, codec: CodecKind },
+    #[error(
+        "{codec:?} audio can't be {sample_rate} Hz, {bits_per_sample} bits, {channels} channels"
+    )]
+    UnsupportedLayout {
+        codec: CodecKind,
+        sample_rate: u32,
+        bits_per_sample: u32,
+        channels: u8,
+    },
+    #[error(
+        "{:?} audio of {} Hz, {} bits, {} channels isn't supported by the device",
+        .0.codec, .0.sample_rate, .0.bits_per_sample, .0.channels
+    )]
+    NotSupported(AudioFormat),
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum LatencyError {
+    #[error("min latency of {min} samples is above max of {max}")]
+    Inverted { min: u32, max: u32 },
+    #[error("sample rate is zero")]
+    ZeroSampleRate,
+}
+
+#[derive(Debug, Error)]
+pub enum DecodeError {
+    #[error("unsupported codec: {0:?}")]
+    UnsupportedCodec(CodecKind),
+    #[error("invalid stream parameter: {0}")]
+    InvalidParams(&'static str),
+    #[error("malformed packet")]
+    Malformed,
+    #[error("alac: {0}")]
+    Alac(#[from] ::alac::InvalidData),
+    #[cfg(feature = "aac")]
+    #[error("aac decoder error: {0:#x}")]
+    Aac(std::ffi::c_int),
+}
+
+/// Decoder picked by codec of the stream format.
+pub enum AudioDecoder {
+    Pcm(PcmDecoder),
+    Alac(AlacDecoder),
+    /// Only with `aac` feature, which links the system `libfdk-aac`
+    #[cfg(feature = "aac")]
+    Aac(AacDecoder),
+}
+
+impl AudioDecoder {
+    /// # Errors
+    ///
+    /// If codec isn't supported or params are invalid for it.
+    pub fn new(params: &AudioParams) -> Result<Self, DecodeError> {
+        match params.format.codec {
+            CodecKind::Pcm => PcmDecoder::new(params).map(Self::Pcm),
+            CodecKind::Alac => AlacDecoder::new(params).map(Self::Alac),
+            #[cfg(feature = "aac")]
+            CodecKind::AacLc | CodecKind::AacEld => AacDecoder::new(params).map(Self::Aac),
+            codec => Err(DecodeError::UnsupportedCodec(codec)),
+        }
+    }
+
+    /// # Errors
+    ///
+    /// If payload is truncated or corrupted.
+    pub fn decode(&mut self, packet: &AudioPacket) -> Result<&PcmBuffer, DecodeError> {
+        match self {
+            Self::Pcm(decoder) => decoder.decode(packet),
+            Self::Alac(decoder) => decoder.decode(packet),
+            #[cfg(feature = "aac")]
+            Self::Aac(decoder) => decoder.decode(packet),
+        }
+    }
+}
+
+/// Wrapper decoding packets before feeding them into inner stream.
+///
+/// Packets failed to be decoded are skipped.
+pub struct PcmStream<S> {
+    decoder: Mutex<AudioDecoder>,
+    inner: S,
+}
+
+impl<S> PcmStream<S>
+where
+    S: Stream<Content = PcmPacket>,
+{
+    /// # Errors
+    ///
+    /// If decoder can't be created for passed params.
+    pub fn new(params: &AudioParams, inner: S) -> Result<Self, DecodeError> {
+        Ok(Self {
+            decoder: Mutex::new(AudioDecoder::new(params)?),
+            inner,
+        })
+    }
+}
+
+impl<S> Stream for PcmStream<S>
+where
+    S: Stream<Content = PcmPacket>,
+{
+    type Content = AudioPacket;
+
+    fn on_data(&self, packet: Self::Content) {
+        let mut decoder = self.decoder.lock().unwrap();
+        match decoder.decode(&packet) {
+            Ok(pcm) => self.inner.on_data(PcmPacket {
+                timestamp: packet.header().timestamp,
+                pcm: pcm.clone(),
+            }),
+            Err(err) => tracing::warn!(%err, "packet decoding failed"),
+        }
+    }
+
+    fn on_raw(&self, packet: &[u8]) {
+        self.inner.on_raw(packet);
+    }
+
+    fn on_ok(self) {
+        self.inner.on_ok();
+    }
+
+    fn on_err(self, err: Box<dyn Error>) {
+        self.inner.on_err(err);
+    }
+}
+
+impl<S> AudioStream for PcmStream<S> where S: Stream<Content = PcmPacket> {}
+
+impl CodecKind {
+    /// Codec of `ct` field sent along with `audioFormat` during setup, known values are
+    /// 1 (PCM), 2 (ALAC), 4 (AAC-LC), 8 (AAC-ELD) and 32 (Opus).
+    #[must_use]
+    pub fn from_content_type(content_type: u8) -> Option<Self> {
+        match content_type {
+            1 => Some(Self::Pcm),
+            2 => Some(Self::Alac),
+            4 => Some(Self::AacLc),
+            8 => Some(Self::AacEld),
+            32 => Some(Self::Opus),
+            _ => None,
+        }
+    }
+
+    /// `ct` field of the codec, the inverse of [`Self::from_content_type`].
+    #[must_use]
+    pub fn content_type(self) -> u8 {
+        match self {
+            Self::Pcm => 1,
+            Self::Alac => 2,
+            Self::AacLc => 4,
+            Self::AacEld => 8,
+            Self::Opus => 32,
+        }
+    }
+}
+
+impl AudioFormat {
+    /// First two bits are reserved.
+    const RESERVED_BITS: u32 = 2;
+
+    /// Exactly one bit of known format must be set, otherwise `None` is returned.
+    #[must_use]
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        if !bits.is_power_of_two() || bits.trailing_zeros() < Self::RESERVED_BITS {
+            return None;
+        }
+
+        AUDIO_FORMATS.get(bits.trailing_zeros() as usize).copied()
+    }
+
+    /// Picks the active format of `offered` ones, by its `index` into [`AUDIO_FORMATS`] if
+    /// present, otherwise exactly one format must be offered.
+    ///
+    /// # Errors
+    ///
+    /// If no single known format is chosen, an index out of the table or the one which isn't
+    /// offered is never defaulted.
+    pub fn select(offered: u32, index: Option<u8>) -> Result<Self, FormatError> {
+        let Some(index) = index else {
+            return Self::from_bits(offered).ok_or(FormatError::Unknown(offered));
+        };
+
+        let format = 1u32
+            .checked_shl(index.into())
+            .and_then(Self::from_bits)
+            .ok_or(FormatError::IndexOutOfRange(index))?;
+        // No mask at all means the index is the only thing telling the format
+        if offered != 0 && offered & format.to_bits() == 0 {
+            return Err(FormatError::NotOffered { index, offered });
+        }
+
+        Ok(format)
+    }
+
+    /// Checks `ct` field names the same codec as the format, so framing of packets is known.
+    ///
+    /// # Errors
+    ///
+    /// If content type is unknown or of other codec.
+    pub fn check_content_type(&self, content_type: u8) -> Result<(), FormatError> {
+        match CodecKind::from_content_type(content_type) {
+            Some(codec) if codec == self.codec => Ok(()),
+            Some(_) => Err(FormatError::ContentTypeMismatch {
+                content_type,
+                codec: self.codec,
+            }),
+            None => Err(FormatError::UnknownContentType(content_type)),
+        }
+    }
+
+    /// Sample rates lossless streams can be sent at.
+    pub const SAMPLE_RATES: RangeInclusive<u32> = 8000..=192_000;
+    /// Bit depths of lossless samples, ALAC ones are never deeper than 32 bits.
+    pub const LOSSLESS_BITS: [u32; 4] = [16, 20, 24, 32];
+    /// Channels of 7.1 at most, as ALAC has no layouts beyond it.
+    pub const MAX_CHANNELS: u8 = 8;
+
+    /// Overrides the layout of a table format with the one sent along, as lossless streams of
+    /// e.g. 24 bits at 96 kHz are set up with the closest format of [`AUDIO_FORMATS`].
+    ///
+    /// # Errors
+    ///
+    /// If the layout is out of [`Self::SAMPLE_RATES`], [`Self::MAX_CHANNELS`] or bit depth
+    /// is set for a lossy codec or isn't of [`Self::LOSSLESS_BITS`].
+    pub fn with_layout(
+        self,
+        sample_rate: Option<u32>,
+        bits_per_sample: Option<u32>,
+        channels: Option<u8>,
+    ) -> Result<Self, FormatError> {
+        let format = Self {
+            sample_rate: sample_rate.unwrap_or(self.sample_rate),
+            bits_per_sample: bits_per_sample.unwrap_or(self.bits_per_sample),
+            channels: channels.unwrap_or(self.channels),
+            ..self
+        };
+
+        let lossless = matches!(format.codec, CodecKind::Alac | CodecKind::Pcm);
+        let bits_valid = if lossless {
+            Self::LOSSLESS_BITS.contains(&format.bits_per_sample)
+        } else {
+            format.bits_per_sample == 0
+        };
+        if !Self::SAMPLE_RATES.contains(&format.sample_rate)
+            || !(1..=Self::MAX_CHANNELS).contains(&format.channels)
+            || !bits_valid
+        {
+            return Err(FormatError::UnsupportedLayout {
+                codec: format.codec,
+                sample_rate: format.sample_rate,
+                bits_per_sample: format.bits_per_sample,
+                channels: format.channels,
+            });
+        }
+
+        Ok(format)
+    }
+
+    /// Inverse of [`Self::from_bits`].
+    ///
+    /// # Panics
+    ///
+    /// If format isn't in [`AUDIO_FORMATS`], e.g. the one of [`Self::with_layout`].
+    #[must_use]
+    pub fn to_bits(&self) -> u32 {
+        AUDIO_FORMATS
+            .iter()
+            .skip(Self::RESERVED_BITS as usize)
+            .position(|format| format == self)
+            .map(|pos| 1 << (pos + Self::RESERVED_BITS as usize))
+            .expect("format must be from the table")
+    }
+}
+
+pub static AUDIO_FORMATS: [AudioFormat; 32] = [
+    // 0    Dummy
+    AudioFormat {
+        codec: CodecKind::Pcm,
+        bits_per_sample: 0,
+        sample_rate: 0,
+        channels: 0,
+    },
+    // 1    Dummy
+    AudioFormat {
+        codec: CodecKind::Pcm,
+        bits_per_sample: 0,
+        sample_rate: 0,
+        channels: 0,
+    },
+    // 2	0x4	PCM/8000/16/1
+    AudioFormat {
+        codec: CodecKind::Pcm,
+        bits_per_sample: 16,
+        sample_rate: 8000,
+        channels: 1,
+    },
+    // 3	0x8	PCM/8000/16/2
+    AudioFormat {
+        codec: CodecKind::Pcm,
+        bits_per_sample: 16,
+        sample_rate: 8000,
+        channels: 2,
+    },
+    // 4	0x10	PCM/16000/16/1
+    AudioFormat {
+        codec: CodecKind::Pcm,
+        bits_per_sample: 16,
+        sample_rate: 16000,
+        channels: 1,
+    },
+    // 5	0x20	PCM/16000/16/2
+    AudioFormat {
+        codec: CodecKind::Pcm,
+        bits_per_sample: 16,
+        sample_rate: 16000,
+        channels: 2,
+    },
+    // 6	0x40	PCM/24000/16/1
+    AudioFormat {
+        codec: CodecKind::Pcm,
+        bits_per_sample: 16,
+        sample_rate: 24000,
+        channels: 1,
+    },
+    // 7	0x80	PCM/24000/16/2
+    AudioFormat {
+        codec: CodecKind::Pcm,
+        bits_per_sample: 16,
+        sample_rate: 24000,
+        channels: 2,
+    },
+    // 8	0x100	PCM/32000/16/1
+    AudioFormat {
+        codec: CodecKind::Pcm,
+        bits_per_sample: 16,
+        sample_rate: 32000,
+        channels: 1,
+    },
+    // 9	0x200	PCM/32000/16/2
+    AudioFormat {
+        codec: CodecKind::Pcm,
+        bits_per_sample: 16,
+        sample_rate: 32000,
+        channels: 2,
+    },
+    // 10	0x400	PCM/44100/16/1
+    AudioFormat {
+        codec: CodecKind::Pcm,
+        bits_per_sample: 16,
+        sample_rate: 44100,
+        channels: 1,
+    },
+    // 11	0x800	PCM/44100/16/2
+    AudioFormat {
+        codec: CodecKind::Pcm,
+        bits_per_sample: 16,
+        sample_rate: 44100,
+        channels: 2,
+    },
+    // 12	0x1000	PCM/44100/24/1
+    AudioFormat {
+        codec: CodecKind::Pcm,
+        bits_per_sample: 24,
+        sample_rate: 44100,
+        channels: 1,
+    },
+    // 13	0x2000	PCM/44100/24/2
+    AudioFormat {
+        codec: CodecKind::Pcm,
+        bits_per_sample: 24,
+        sample_rate: 44100,
+        channels: 2,
+    },
+    // 14	0x4000	PCM/48000/16/1
+    AudioFormat {
+        codec: CodecKind::Pcm,
+        bits_per_sample: 16,
+        sample_rate: 48000,
+        channels: 1,
+    },
+    // 15	0x8000	PCM/48000/16/2
+    AudioFormat {
+        codec: CodecKind::Pcm,
+        bits_per_sample: 16,
+        sample_rate: 48000,
+        channels: 2,
+    },
+    // 16	0x10000	PCM/48000/24/1
+    AudioFormat {
+        codec: CodecKind::Pcm,
+        bits_per_sample: 24,
+        sample_rate: 48000,
+        channels: 1,
+    },
+    // 17	0x20000	PCM/48000/24/2
+    AudioFormat {
+        codec: CodecKind::Pcm,
+        bits_per_sample: 24,
+        sample_rate: 48000,
+        channels: 2,
+    },
+    // 18	0x40000	ALAC/44100/16/2
+    AudioFormat {
+        codec: CodecKind::Alac,
+        bits_per_sample: 16,
+        sample_rate: 44100,
+        channels: 2,
+    },
+    // 19	0x80000	ALAC/44100/24/2
+    AudioFormat {
+        codec: CodecKind::Alac,
+        bits_per_sample: 24,
+        sample_rate: 44100,
+        channels: 2,
+    },
+    // 20	0x100000	ALAC/48000/16/2
+    AudioFormat {
+        codec: CodecKind::Alac,
+        bits_per_sample: 16,
+        sample_rate: 48000,
+        channels: 2,
+    },
+    // 21	0x200000	ALAC/48000/24/2
+    AudioFormat {
+        codec: CodecKind::Alac,
+        bits_per_sample: 24,
+        sample_rate: 48000,
+        channels: 2,
+    },
+    // 22	0x400000	AAC-LC/44100/2
+    AudioFormat {
+        codec: CodecKind::AacLc,
+        bits_per_sample: 0,
+        sample_rate: 44100,
+        channels: 2,
+    },
+    // 23	0x800000	AAC-LC/48000/2
+    AudioFormat {
+        codec: CodecKind::AacLc,
+        bits_per_sample: 0,
+        sample_rate: 48000,
+        channels: 2,
+    },
+    // 24	0x1000000	AAC-ELD/44100/2
+    AudioFormat {
+        codec: CodecKind::AacEld,
+        bits_per_sample: 0,
+        sample_rate: 44100,
+        channels: 2,
+    },
+    // 25	0x2000000	AAC-ELD/48000/2
+    AudioFormat {
+        codec: CodecKind::AacEld,
+        bits_per_sample: 0,
+        sample_rate: 48000,
+        channels: 2,
+    },
+    // 26	0x4000000	AAC-ELD/16000/1
+    AudioFormat {
+        codec: CodecKind::AacEld,
+        bits_per_sample: 0,
+        sample_rate: 16000,
+        channels: 1,
+    },
+    // 27	0x8000000	AAC-ELD/24000/1
+    AudioFormat {
+        codec: CodecKind::AacEld,
+        bits_per_sample: 0,
+        sample_rate: 24000,
+        channels: 1,
+    },
+    // 28	0x10000000	OPUS/16000/1
+    AudioFormat {
+        codec: CodecKind::Opus,
+        bits_per_sample: 0,
+        sample_rate: 16000,
+        channels: 1,
+    },
+    // 29	0x20000000	OPUS/24000/1
+    AudioFormat {
+        codec: CodecKind::Opus,
+        bits_per_sample: 0,
+        sample_rate: 24000,
+        channels: 1,
+    },
+    // 30	0x40000000	OPUS/48000/1
+    AudioFormat {
+        codec: CodecKind::Opus,
+        bits_per_sample: 0,
+        sample_rate: 48000,
+        channels: 1,
+    },
+    // 31	0x80000000	AAC-ELD/44100/1
+    AudioFormat {
+        codec: CodecKind::AacEld,
+        bits_per_sample: 0,
+        sample_rate: 44100,
+        channels: 1,
+    },
+    // 32	0x100000000	AAC-ELD/48000/1 doesn't fit into 32 bits of `audioFormat`
+];
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::BytesMut;
+
+    use super::{
+        AudioFormat, AudioPacket, CodecKind, FormatError, Latency, LatencyError, PcmBuffer,
+        RtpHeader,
+    };
+
+    #[test]
+    fn pcm_converted() {
+        let pcm = PcmBuffer {
+            channels: 2,
+            sample_rate: 44100,
+            bits: 24,
+            samples: vec![i32::MIN, 0x4000_0000, 0x1234_5600, -0x100],
+        };
+        assert_eq!(2, pcm.frames());
+        assert_eq!(vec![-1.0, 0.5], pcm.to_f32()[..2]);
+        assert_eq!(
+            vec![0, 0, 0x80, 0, 0, 0x40, 0x56, 0x34, 0x12, 0xff, 0xff, 0xff],
+            pcm.to_le_bytes()
+        );
+    }
+
+    #[test]
+    fn latency_target() {
+        let latency = Latency::new(11025, 88200, 44100).unwrap();
+        assert_eq!(Duration::from_millis(250), latency.playout_delay());
+        // 31.3 packets are rounded up
+        assert_eq!(32, latency.target_depth(352));
+        assert_eq!(0, latency.target_depth(0));
+
+        assert_eq!(0, Latency::new(0, 0, 44100).unwrap().target_depth(352));
+    }
+
+    #[test]
+    fn latency_rejected() {
+        assert_eq!(
+            Err(LatencyError::Inverted {
+                min: 88200,
+                max: 11025
+            }),
+            Latency::new(88200, 11025, 44100)
+        );
+        assert_eq!(Err(LatencyError::ZeroSampleRate), Latency::new(0, 11025, 0));
+    }
+
+    #[test]
+    fn audio_format_bits_roundtrip() {
+        for bit in AudioFormat::RESERVED_BITS..u32::BITS {
+            let format = AudioFormat::from_bits(1 << bit).expect("known format");
+            assert_eq!(1 << bit, format.to_bits());
+        }
+    }
+
+    #[test]
+    fn audio_format_decoding() {
+        assert_eq!(
+            Some(AudioFormat {
+                codec: CodecKind::Alac,
+                bits_per_sample: 16,
+                sample_rate: 44100,
+                channels: 2,
+            }),
+            AudioFormat::from_bits(0x40000)
+        );
+        assert_eq!(
+            Some(CodecKind::AacEld),
+            AudioFormat::from_bits(0x0100_0000).map(|format| format.codec)
+        );
+        assert_eq!(None, AudioFormat::from_bits(0));
+        assert_eq!(None, AudioFormat::from_bits(0x1));
+        assert_eq!(None, AudioFormat::from_bits(0x2));
+        assert_eq!(None, AudioFormat::from_bits(0x40000 | 0x80000));
+    }
+
+    #[test]
+    fn audio_format_selection() {
+        let alac = AudioFormat::from_bits(0x40000).unwrap();
+        let aac_lc = AudioFormat::from_bits(0x0040_0000).unwrap();
+        let offered = alac.to_bits() | aac_lc.to_bits();
+
+        assert_eq!(Ok(alac), AudioFormat::select(alac.to_bits(), None));
+        assert_eq!(Ok(aac_lc), AudioFormat::select(offered, Some(22)));
+        assert_eq!(Ok(aac_lc), AudioFormat::select(0, Some(22)));
+        assert_eq!(
+            Err(FormatError::Unknown(offered)),
+            AudioFormat::select(offered, None)
+        );
+        assert_eq!(
+            Err(FormatError::NotOffered { index: 19, offered }),
+            AudioFormat::select(offered, Some(19))
+        );
+        assert_eq!(
+            Err(FormatError::IndexOutOfRange(32)),
+            AudioFormat::select(offered, Some(32))
+        );
+        assert_eq!(
+            Err(FormatError::IndexOutOfRange(1)),
+            AudioFormat::select(offered, Some(1))
+        );
+    }
+
+    #[test]
+    fn lossless_layout_overridden() {
+        let alac = AudioFormat::from_bits(0x0020_0000).unwrap();
+        let hires = alac.with_layout(Some(192_000), Some(24), Some(2)).unwrap();
+        assert_eq!(192_000, hires.sample_rate);
+        assert_eq!(24, hires.bits_per_sample);
+        assert_eq!(Ok(alac), alac.with_layout(None, None, None));
+
+        let err = |sample_rate, bits_per_sample, channels| {
+            Err(FormatError::UnsupportedLayout {
+                codec: CodecKind::Alac,
+                sample_rate,
+                bits_per_sample,
+                channels,
+            })
+        };
+        assert_eq!(
+            err(384_000, 24, 2),
+            alac.with_layout(Some(384_000), None, None)
+        );
+        assert_eq!(err(48000, 12, 2), alac.with_layout(None, Some(12), None));
+        assert_eq!(err(48000, 24, 9), alac.with_layout(None, None, Some(9)));
+
+        // Lossy codecs have no bit depth
+        let aac_lc = AudioFormat::from_bits(0x0040_0000).unwrap();
+        assert!(aac_lc.with_layout(Some(96000), None, Some(6)).is_ok());
+        assert!(aac_lc.with_layout(None, Some(16), None).is_err());
+    }
+
+    #[test]
+    fn content_type_checked() {
+        let alac = AudioFormat::from_bits(0x40000).unwrap();
+        let aac_eld = AudioFormat::from_bits(0x0100_0000).unwrap();
+
+        assert_eq!(Ok(()), alac.check_content_type(2));
+        assert_eq!(Ok(()), aac_eld.check_content_type(8));
+        assert_eq!(
+            Err(FormatError::ContentTypeMismatch {
+                content_type: 4,
+                codec: CodecKind::Alac
+            }),
+            alac.check_content_type(4)
+        );
+        assert_eq!(
+            Err(FormatError::UnknownContentType(3)),
+            alac.check_content_type(3)
+        );
+
+        for codec in [
+            CodecKind::Pcm,
+            CodecKind::Alac,
+            CodecKind::AacLc,
+            CodecKind::AacEld,
+            CodecKind::Opus,
+        ] {
+            assert_eq!(
+                Some(codec),
+                CodecKind::from_content_type(codec.content_type())
+            );
+        }
+    }
+
+    #[test]
+    fn rtp_header() {
+        let packet = AudioPacket {
+            rtp: BytesMut::from(
+                &[
+                    0x80, 0xe0, 0x12, 0x34, 0x00, 0x01, 0x00, 0x00, 0xde, 0xad, 0xbe, 0xef,
+                ][..],
+            ),
+            playout_time: None,
+        };
+
+        assert_eq!(
+            RtpHeader {
+                version: 2,
+                padding: false,
+                extension: false,
+                csrc_count: 0,
+                marker: true,
+                payload_type: 0x60,
+                seq: 0x1234,
+                timestamp: 0x0001_0000,
+                ssrc: 0xdead_beef,
+            },
+            packet.header()
+        );
+        assert!(packet.payload().is_empty());
+    }
+
+    #[test]
+    fn rtp_payload_after_csrc_and_extension() {
+        let packet = AudioPacket {
+            rtp: BytesMut::from(
+                &[
+                    // one CSRC and extension
+                    0x91, 0x60, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                    // CSRC
+                    0x00, 0x00, 0x00, 0x01, // extension of one word
+                    0xbe, 0xde, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, // payload
+                    0xaa, 0xbb,
+                ][..],
+            ),
+            playout_time: None,
+        };
+
+        let header = packet.header();
+        assert!(header.extension);
+        assert_eq!(1, header.csrc_count);
+        assert_eq!(Some(24), AudioPacket::header_len(&packet.rtp));
+        assert_eq!(&[0xaa, 0xbb], packet.payload());
+
+        let truncated = AudioPacket {
+            rtp: BytesMut::from(&packet.rtp[..18]),
+            playout_time: None,
+        };
+        assert_eq!(None, AudioPacket::header_len(&truncated.rtp));
+        assert!(truncated.payload().is_empty());
+    }
+}
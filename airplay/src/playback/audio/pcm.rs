@@ -0,0 +1,96 @@
+use super::{AudioFormat, AudioPacket, AudioParams, CodecKind, DecodeError, PcmBuffer};
+
+/// Decoder of uncompressed payload, whose samples are big endian as RTP sends them.
+pub struct PcmDecoder {
+    /// Bytes of each sample
+    width: usize,
+    out: PcmBuffer,
+}
+
+impl PcmDecoder {
+    /// # Errors
+    ///
+    /// If params don't describe PCM stream of whole bytes per sample.
+    pub fn new(params: &AudioParams) -> Result<Self, DecodeError> {
+        let format = params.format;
+        if format.codec != CodecKind::Pcm {
+            return Err(DecodeError::UnsupportedCodec(format.codec));
+        }
+        if !matches!(format.bits_per_sample, 16 | 24 | 32) {
+            return Err(DecodeError::InvalidParams("bit depth"));
+        }
+        if !(1..=AudioFormat::MAX_CHANNELS).contains(&format.channels) {
+            return Err(DecodeError::InvalidParams("channels"));
+        }
+
+        Ok(Self {
+            width: format.bits_per_sample as usize / 8,
+            out: PcmBuffer::of_format(&format, format.bits_per_sample),
+        })
+    }
+
+    /// # Errors
+    ///
+    /// If payload doesn't hold whole frames.
+    pub fn decode(&mut self, packet: &AudioPacket) -> Result<&PcmBuffer, DecodeError> {
+        if packet.rtp.len() < AudioPacket::HEADER_LEN {
+            return Err(DecodeError::Malformed);
+        }
+        let payload = packet.payload();
+        if !payload
+            .len()
+            .is_multiple_of(self.width * usize::from(self.out.channels))
+        {
+            return Err(DecodeError::Malformed);
+        }
+
+        self.out.samples.clear();
+        self.out
+            .samples
+            .extend(payload.chunks_exact(self.width).map(|sample| {
+                let mut bytes = [0; 4];
+                bytes[..sample.len()].copy_from_slice(sample);
+                i32::from_be_bytes(bytes)
+            }));
+        Ok(&self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use super::{super::AudioFormat, AudioPacket, AudioParams, DecodeError, PcmDecoder};
+
+    fn packet(payload: &[u8]) -> AudioPacket {
+        let mut rtp = BytesMut::zeroed(AudioPacket::HEADER_LEN);
+        rtp.extend_from_slice(payload);
+        AudioPacket {
+            rtp,
+            playout_time: None,
+        }
+    }
+
+    #[test]
+    fn decode_big_endian() {
+        // PCM/44100/16/2
+        let params = AudioParams {
+            samples_per_frame: 352,
+            format: AudioFormat::from_bits(0x800).unwrap(),
+            latency: None,
+        };
+        let mut decoder = PcmDecoder::new(&params).unwrap();
+
+        let pcm = decoder
+            .decode(&packet(&[0x00, 0x01, 0xff, 0xff, 0x7f, 0xff, 0x80, 0x00]))
+            .unwrap();
+        assert_eq!((2, 44100, 16), (pcm.channels, pcm.sample_rate, pcm.bits));
+        assert_eq!(vec![1 << 16, -1 << 16, 0x7fff_0000, i32::MIN], pcm.samples);
+        assert_eq!(2, pcm.frames());
+
+        assert!(matches!(
+            decoder.decode(&packet(&[0, 1, 2])),
+            Err(DecodeError::Malformed)
+        ));
+    }
+}
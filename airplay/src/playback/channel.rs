@@ -0,0 +1,300 @@
+//! Ready to use stream forwarding packets into a channel, for when implementing [`Stream`]
+//! by hand isn't worth it.
+//!
+//! ```ignore
+//! let (stream, mut packets) = channel::<AudioPacket>(64, Backpressure::DropOldest);
+//! // Return `stream` from `Device::create` and drain `packets` elsewhere:
+//! while let Some(packet) = packets.recv().await { /* ... */ }
+//! ```
+//! Wrapping the stream into [`PcmStream`](super::audio::PcmStream) yields decoded
+//! [`PcmPacket`](super::audio::PcmPacket)s in the same way. Packets can be taken as
+//! [`futures::Stream`] as well, see [`audio_stream`] and [`video_stream`].
+
+use std::{
+    error::Error,
+    sync::{Arc, Condvar, Mutex, PoisonError},
+    time::Duration,
+};
+
+use futures::stream;
+use tokio::sync::{
+    Mutex as AsyncMutex,
+    mpsc::{self, error::TrySendError},
+};
+
+use super::{
+    Stream,
+    audio::{AudioPacket, AudioStream},
+    video::{AccessUnit, VideoPacket, VideoStream},
+};
+
+/// Recheck interval of a blocked sender, in case wake up from receiver was missed
+const BLOCK_RECHECK: Duration = Duration::from_millis(10);
+
+/// What to do with new content when the channel is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backpressure {
+    /// Make room by dropping the oldest queued content, the stream never waits
+    #[default]
+    DropOldest,
+    /// Drop the new content instead, the stream never waits either
+    DropNewest,
+    /// Wait until receiver takes something.
+    ///
+    /// Blocks the thread the stream is driven by, so the receiver must be drained on another
+    /// thread, otherwise it deadlocks on single threaded runtime.
+    Block,
+}
+
+struct Shared<T> {
+    rx: AsyncMutex<mpsc::Receiver<T>>,
+    room: Condvar,
+    room_lock: Mutex<()>,
+    error: Mutex<Option<String>>,
+}
+
+/// Sending half, to be returned from [`Device::create`](super::Device::create).
+pub struct ChannelStream<T> {
+    tx: mpsc::Sender<T>,
+    shared: Arc<Shared<T>>,
+    backpressure: Backpressure,
+}
+
+/// Receiving half owned by the user, ends when the stream does.
+pub struct ChannelReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// # Panics
+///
+/// If `capacity` is zero.
+#[must_use]
+pub fn channel<T: Send + 'static>(
+    capacity: usize,
+    backpressure: Backpressure,
+) -> (ChannelStream<T>, ChannelReceiver<T>) {
+    let (tx, rx) = mpsc::channel(capacity);
+    let shared = Arc::new(Shared {
+        rx: AsyncMutex::new(rx),
+        room: Condvar::new(),
+        room_lock: Mutex::new(()),
+        error: Mutex::new(None),
+    });
+
+    (
+        ChannelStream {
+            tx,
+            shared: Arc::clone(&shared),
+            backpressure,
+        },
+        ChannelReceiver { shared },
+    )
+}
+
+/// Audio packets as [`futures::Stream`], ending when the stream does.
+///
+/// # Panics
+///
+/// If `capacity` is zero.
+pub fn audio_stream(
+    capacity: usize,
+    backpressure: Backpressure,
+) -> (
+    ChannelStream<AudioPacket>,
+    impl futures::Stream<Item = AudioPacket>,
+) {
+    let (stream, rx) = channel(capacity, backpressure);
+    (stream, rx.into_stream())
+}
+
+/// Video packets as [`futures::Stream`], ending when the stream does.
+///
+/// Codec records come as packets of their own, reassembled frames as a single payload packet
+/// each.
+///
+/// # Panics
+///
+/// If `capacity` is zero.
+pub fn video_stream(
+    capacity: usize,
+    backpressure: Backpressure,
+) -> (
+    ChannelStream<VideoPacket>,
+    impl futures::Stream<Item = VideoPacket>,
+) {
+    let (stream, rx) = channel(capacity, backpressure);
+    (stream, rx.into_stream())
+}
+
+impl<T: Send + 'static> Stream for ChannelStream<T> {
+    type Content = T;
+
+    fn on_data(&self, mut content: Self::Content) {
+        loop {
+            match self.tx.try_send(content) {
+                Ok(()) => return,
+                Err(TrySendError::Closed(_)) => {
+                    tracing::trace!("receiver gone, content dropped");
+                    return;
+                }
+                Err(TrySendError::Full(back)) => content = back,
+            }
+
+            match self.backpressure {
+                Backpressure::DropOldest => {
+                    // Receiver holds the lock only while taking content, so there's room soon
+                    // anyway and the new content is dropped instead
+                    let Ok(mut rx) = self.shared.rx.try_lock() else {
+                        tracing::trace!("channel full, new content dropped");
+                        return;
+                    };
+                    if rx.try_recv().is_ok() {
+                        tracing::trace!("channel full, oldest content dropped");
+                    }
+                }
+                Backpressure::DropNewest => {
+                    tracing::trace!("channel full, new content dropped");
+                    return;
+                }
+                Backpressure::Block => {
+                    let guard = self.shared.room_lock.lock().unwrap();
+                    let _ = self.shared.room.wait_timeout(guard, BLOCK_RECHECK).unwrap();
+                }
+            }
+        }
+    }
+
+    fn on_ok(self) {
+        tracing::debug!("channel stream finished");
+    }
+
+    fn on_err(self, err: Box<dyn Error>) {
+        tracing::debug!(%err, "channel stream failed");
+        *self.shared.error.lock().unwrap() = Some(err.to_string());
+    }
+}
+
+impl AudioStream for ChannelStream<AudioPacket> {}
+
+impl VideoStream for ChannelStream<VideoPacket> {
+    fn on_frame(&self, frame: AccessUnit) {
+        self.on_data(frame.into());
+    }
+}
+
+impl<T> ChannelReceiver<T> {
+    /// Returns `None` once the stream is finished and everything sent is taken.
+    pub async fn recv(&mut self) -> Option<T> {
+        let content = self.shared.rx.lock().await.recv().await;
+        self.shared.room.notify_all();
+        content
+    }
+
+    pub fn try_recv(&mut self) -> Option<T> {
+        let content = self.shared.rx.try_lock().ok()?.try_recv().ok();
+        self.shared.room.notify_all();
+        content
+    }
+
+    /// Lagging consumer gets packets dropped or slows the stream down, as chosen by
+    /// [`Backpressure`].
+    pub fn into_stream(self) -> impl futures::Stream<Item = T> {
+        stream::unfold(self, |mut rx| async move {
+            let content = rx.recv().await?;
+            Some((content, rx))
+        })
+    }
+
+    /// Error the stream finished with, known once [`Self::recv`] returned `None`.
+    #[must_use]
+    pub fn error(&self) -> Option<String> {
+        self.shared
+            .error
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, thread};
+
+    use crate::playback::{
+        Stream,
+        video::{AccessUnit, PacketKind, VideoPacket, VideoStream},
+    };
+
+    use bytes::BytesMut;
+    use futures::StreamExt as _;
+
+    use super::{Backpressure, channel};
+
+    #[test]
+    fn drop_oldest_keeps_newest() {
+        let (stream, mut rx) = channel::<u32>(2, Backpressure::DropOldest);
+        (0..5).for_each(|i| stream.on_data(i));
+        stream.on_err(io::Error::other("gone").into());
+
+        assert_eq!(Some(3), rx.try_recv());
+        assert_eq!(Some(4), rx.try_recv());
+        assert_eq!(None, rx.try_recv());
+        assert_eq!(Some("gone"), rx.error().as_deref());
+    }
+
+    #[test]
+    fn drop_newest_keeps_oldest() {
+        let (stream, mut rx) = channel::<u32>(2, Backpressure::DropNewest);
+        (0..5).for_each(|i| stream.on_data(i));
+
+        assert_eq!(Some(0), rx.try_recv());
+        assert_eq!(Some(1), rx.try_recv());
+        assert_eq!(None, rx.try_recv());
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_receiver() {
+        let (stream, mut rx) = channel::<u32>(1, Backpressure::Block);
+        let sender = thread::spawn(move || {
+            (0..10).for_each(|i| stream.on_data(i));
+            stream.on_ok();
+        });
+
+        let mut received = Vec::new();
+        while let Some(i) = rx.recv().await {
+            received.push(i);
+        }
+        sender.join().unwrap();
+
+        assert_eq!((0..10).collect::<Vec<_>>(), received);
+        assert_eq!(None, rx.error());
+    }
+
+    #[test]
+    fn frames_passed_as_packets() {
+        let (stream, mut rx) = channel::<VideoPacket>(2, Backpressure::DropNewest);
+        stream.on_frame(AccessUnit {
+            timestamp: 42,
+            pts: None,
+            dts: None,
+            is_keyframe: true,
+            data: BytesMut::from(&b"frame"[..]),
+        });
+
+        let packet = rx.try_recv().unwrap();
+        assert!(matches!(packet.kind, PacketKind::Payload));
+        assert_eq!(42, packet.timestamp);
+        assert!(packet.is_keyframe);
+        assert_eq!(&b"frame"[..], packet.payload);
+    }
+
+    #[tokio::test]
+    async fn receive_as_stream() {
+        let (stream, rx) = channel::<u32>(4, Backpressure::DropOldest);
+        (0..3).for_each(|i| stream.on_data(i));
+        stream.on_ok();
+
+        let doubled = rx.into_stream().map(|i| i * 2).collect::<Vec<_>>().await;
+        assert_eq!(vec![0, 2, 4], doubled);
+    }
+}
@@ -0,0 +1,168 @@
+//! DMAP (DAAP) tagged metadata of the playing track.
+//!
+//! Every item is a 4 bytes code, 4 bytes big endian length and the value, containers hold
+//! nested items as their value.
+
+use thiserror::Error;
+
+pub const MIME: &str = "application/x-dmap-tagged";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrackMetadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub composer: Option<String>,
+    pub genre: Option<String>,
+    pub track_number: Option<u16>,
+    pub duration_ms: Option<u32>,
+}
+
+#[derive(Debug, Error)]
+pub enum DmapError {
+    #[error("truncated item of {0:?}")]
+    Truncated([u8; 4]),
+    #[error("containers nested deeper than {MAX_DEPTH}")]
+    TooDeep,
+}
+
+const ITEM_HEADER_LEN: usize = 8;
+
+/// Containers which items are looked into
+const CONTAINERS: &[&[u8; 4]] = &[b"mlit", b"mlcl", b"mcon"];
+
+/// Senders nest a couple of containers at most, deeper ones would only exhaust the stack
+const MAX_DEPTH: usize = 8;
+
+impl TrackMetadata {
+    /// Unknown codes are skipped along with their values, so do the known ones with unexpected
+    /// value length.
+    ///
+    /// # Errors
+    ///
+    /// If any item is longer than the rest of the buffer or containers are nested deeper than
+    /// senders ever do.
+    pub fn parse(buf: &[u8]) -> Result<Self, DmapError> {
+        let mut metadata = Self::default();
+        metadata.parse_items(buf, 0)?;
+        Ok(metadata)
+    }
+
+    fn parse_items(&mut self, mut buf: &[u8], depth: usize) -> Result<(), DmapError> {
+        while !buf.is_empty() {
+            let Some((header, rest)) = buf.split_first_chunk::<ITEM_HEADER_LEN>() else {
+                return Err(DmapError::Truncated([0; 4]));
+            };
+            let code: [u8; 4] = header[..4].try_into().unwrap();
+            let len = u32::from_be_bytes(header[4..].try_into().unwrap()) as usize;
+            let Some(value) = rest.get(..len) else {
+                return Err(DmapError::Truncated(code));
+            };
+            buf = &rest[len..];
+
+            if CONTAINERS.contains(&&code) {
+                if depth == MAX_DEPTH {
+                    return Err(DmapError::TooDeep);
+                }
+                self.parse_items(value, depth + 1)?;
+                continue;
+            }
+
+            let text = || Some(String::from_utf8_lossy(value).into_owned());
+            match &code {
+                b"minm" => self.title = text(),
+                b"asar" => self.artist = text(),
+                b"asal" => self.album = text(),
+                b"asaa" => self.album_artist = text(),
+                b"ascp" => self.composer = text(),
+                b"asgn" => self.genre = text(),
+                b"astn" => {
+                    if let Ok(number) = value.try_into() {
+                        self.track_number = Some(u16::from_be_bytes(number));
+                    }
+                }
+                b"astm" => {
+                    if let Ok(duration) = value.try_into() {
+                        self.duration_ms = Some(u32::from_be_bytes(duration));
+                    }
+                }
+                _ => {
+                    let code = String::from_utf8_lossy(&code);
+                    tracing::trace!(%code, %len, "skipped dmap item");
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DmapError, TrackMetadata};
+
+    fn item(code: [u8; 4], value: &[u8]) -> Vec<u8> {
+        let mut item = code.to_vec();
+        item.extend_from_slice(&u32::try_from(value.len()).unwrap().to_be_bytes());
+        item.extend_from_slice(value);
+        item
+    }
+
+    #[test]
+    fn parse_track() {
+        let items = [
+            item(*b"mper", &[0; 8]),
+            item(*b"minm", b"Song"),
+            item(*b"asar", b"Artist"),
+            item(*b"asal", b"Album"),
+            item(*b"zzzz", b"unknown tag"),
+            item(*b"astn", &[0, 7]),
+            item(*b"astm", &[0, 3, 0x0d, 0x40]),
+            // Unexpected length is ignored
+            item(*b"astm", &[1]),
+        ]
+        .concat();
+
+        assert_eq!(
+            TrackMetadata {
+                title: Some("Song".into()),
+                artist: Some("Artist".into()),
+                album: Some("Album".into()),
+                track_number: Some(7),
+                duration_ms: Some(200_000),
+                ..Default::default()
+            },
+            TrackMetadata::parse(&item(*b"mlit", &items)).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_truncated() {
+        let buf = item(*b"mlit", &item(*b"minm", b"Song"));
+
+        assert!(matches!(
+            TrackMetadata::parse(&buf[..buf.len() - 1]),
+            Err(DmapError::Truncated(code)) if &code == b"mlit"
+        ));
+        assert!(matches!(
+            TrackMetadata::parse(&buf[..4]),
+            Err(DmapError::Truncated(_))
+        ));
+    }
+
+    #[test]
+    fn parse_nested() {
+        let nest =
+            |depth| (0..depth).fold(item(*b"minm", b"Song"), |inner, _| item(*b"mlcl", &inner));
+
+        assert_eq!(
+            Some("Song".into()),
+            TrackMetadata::parse(&nest(8)).unwrap().title
+        );
+        assert!(matches!(
+            TrackMetadata::parse(&nest(9)),
+            Err(DmapError::TooDeep)
+        ));
+    }
+}
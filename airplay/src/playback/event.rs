@@ -0,0 +1,190 @@
+use std::sync::Arc;
+
+use plist::{Dictionary, Value};
+use serde::Deserialize;
+use tokio::sync::broadcast;
+
+/// Receiver of messages decoded from the event channel, called in their order of arrival.
+pub trait EventSink: Send + Sync + 'static {
+    fn on_event(&self, event: EventMessage) {
+        let _ = event;
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct NullEventSink;
+
+impl EventSink for NullEventSink {}
+
+impl<T: EventSink + ?Sized> EventSink for Arc<T> {
+    fn on_event(&self, event: EventMessage) {
+        (**self).on_event(event);
+    }
+}
+
+/// Lagging receivers miss the oldest messages, no receivers is fine.
+impl EventSink for broadcast::Sender<EventMessage> {
+    fn on_event(&self, event: EventMessage) {
+        let _ = self.send(event);
+    }
+}
+
+/// Both sinks get every message, the first one a copy.
+impl<A: EventSink, B: EventSink> EventSink for (A, B) {
+    fn on_event(&self, event: EventMessage) {
+        self.0.on_event(event.clone());
+        self.1.on_event(event);
+    }
+}
+
+/// Message sent by the sender over the event channel.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventMessage {
+    /// Volume in dB, where -144 is muted
+    Volume(f32),
+    /// RTP timestamps of the current track
+    Progress {
+        start: u64,
+        current: u64,
+        end: u64,
+    },
+    PlaybackState {
+        playing: bool,
+    },
+    /// Now playing info dictionary as is, keys are `kMRMediaRemoteNowPlayingInfo*`
+    NowPlaying(Dictionary),
+    /// Commands the sender accepts from remote control
+    SupportedCommands(Vec<Value>),
+    /// Command of unknown type
+    Other {
+        ty: String,
+        params: Option<Dictionary>,
+    },
+}
+
+#[derive(Deserialize)]
+struct Command {
+    #[serde(rename = "type")]
+    ty: String,
+    #[serde(default)]
+    params: Option<Dictionary>,
+}
+
+impl EventMessage {
+    /// Decodes a single `command` dictionary.
+    ///
+    /// # Errors
+    ///
+    /// If message isn't a plist dictionary with `type` key.
+    pub fn parse(buf: &[u8]) -> Result<Self, plist::Error> {
+        let Command { ty, params } = plist::from_bytes(buf)?;
+
+        let param = |key: &str| params.as_ref().and_then(|params| params.get(key));
+        let uint = |key: &str| param(key).and_then(Value::as_unsigned_integer);
+
+        let msg = match ty.as_str() {
+            "setVolume" | "volume" => param("volume").and_then(Value::as_real).map(|volume| {
+                #[allow(clippy::cast_possible_truncation)]
+                Self::Volume(volume as f32)
+            }),
+            "setProgress" | "progress" => match (uint("start"), uint("current"), uint("end")) {
+                (Some(start), Some(current), Some(end)) => Some(Self::Progress {
+                    start,
+                    current,
+                    end,
+                }),
+                _ => None,
+            },
+            "playbackState" | "setPlaybackState" => param("playing")
+                .and_then(Value::as_boolean)
+                .or_else(|| {
+                    param("rate")
+                        .and_then(Value::as_real)
+                        .map(|rate| rate > 0.0)
+                })
+                .map(|playing| Self::PlaybackState { playing }),
+            "updateMRNowPlayingInfo" => params.clone().map(Self::NowPlaying),
+            "updateMRSupportedCommands" => param("mrSupportedCommandsFromSender")
+                .and_then(Value::as_array)
+                .cloned()
+                .map(Self::SupportedCommands),
+            _ => None,
+        };
+
+        Ok(msg.unwrap_or(Self::Other { ty, params }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use plist::{Dictionary, Value};
+    use tokio::sync::broadcast;
+
+    use super::{EventMessage, EventSink, NullEventSink};
+
+    fn command(ty: &str, params: &[(&str, Value)]) -> Vec<u8> {
+        let mut dict = Dictionary::new();
+        dict.insert("type".into(), ty.into());
+        dict.insert(
+            "params".into(),
+            Value::Dictionary(
+                params
+                    .iter()
+                    .map(|(key, value)| ((*key).to_string(), value.clone()))
+                    .collect(),
+            ),
+        );
+
+        let mut buf = Vec::new();
+        plist::to_writer_binary(&mut buf, &Value::Dictionary(dict)).unwrap();
+        buf
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(
+            EventMessage::Volume(-12.5),
+            EventMessage::parse(&command("setVolume", &[("volume", (-12.5).into())])).unwrap()
+        );
+        assert_eq!(
+            EventMessage::Progress {
+                start: 1,
+                current: 2,
+                end: 3
+            },
+            EventMessage::parse(&command(
+                "progress",
+                &[
+                    ("start", 1.into()),
+                    ("current", 2.into()),
+                    ("end", 3.into())
+                ]
+            ))
+            .unwrap()
+        );
+        assert_eq!(
+            EventMessage::PlaybackState { playing: false },
+            EventMessage::parse(&command("playbackState", &[("rate", 0.0.into())])).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_unknown_and_malformed() {
+        assert!(matches!(
+            EventMessage::parse(&command("setVolume", &[])),
+            Ok(EventMessage::Other { ty, params: Some(_) }) if ty == "setVolume"
+        ));
+        assert!(EventMessage::parse(b"not a plist").is_err());
+    }
+
+    #[test]
+    fn sink_pair_fans_out() {
+        let (first, mut first_rx) = broadcast::channel(1);
+        let (second, mut second_rx) = broadcast::channel(1);
+
+        (first, (NullEventSink, second)).on_event(EventMessage::Volume(-3.0));
+
+        assert_eq!(EventMessage::Volume(-3.0), first_rx.try_recv().unwrap());
+        assert_eq!(EventMessage::Volume(-3.0), second_rx.try_recv().unwrap());
+    }
+}
@@ -0,0 +1,967 @@
+//! Recording of streams into HLS, i.e. fragmented MP4 segments listed in playlists, e.g. to
+//! keep a mirrored session and play it back later.
+//!
+//! Audio and video are renditions of their own, each with init segments, media segments and
+//! a media playlist, tied together by the master playlist:
+//! ```text
+//! index.m3u8
+//! audio0.m3u8  audio0_init0.mp4  audio0_00000.m4s  audio0_00001.m4s ...
+//! video0.m3u8  video0_init0.mp4  video0_00000.m4s  video0_00001.m4s ...
+//! ```
+//! Playlists are rewritten once a segment is, so the recording can be played while it's made,
+//! and get ended once their stream is. Files are written on a thread of their own, so streams
+//! aren't held up by the disk.
+//!
+//! Tracks are placed on a timeline starting with the first packet of any of them, each one at
+//! the local time its first packet is to be played at and then advanced by its own timestamps.
+//! That time is mapped from the sender's clock, see [`VideoPacket::pts`] and
+//! [`AudioPacket::playout_time`]. Packets without it, e.g. of buffered audio or ones before
+//! the clock is synced, are placed at the time they came instead, so tracks may be off by the
+//! latency of the sender then.
+
+mod mp4;
+
+use std::{
+    error::Error,
+    fmt::Write as _,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use thiserror::Error;
+
+use super::{
+    Stream,
+    audio::{AlacDecoder, AudioPacket, AudioParams, AudioStream, CodecKind, DecodeError},
+    video::{self, AccessUnit, FrameDimensions, PacketKind, VideoCodec, VideoPacket, VideoStream},
+};
+
+/// Of mirrored video, as usual for MPEG
+const VIDEO_TIMESCALE: u32 = 90_000;
+
+#[derive(Debug, Error)]
+pub enum HlsError {
+    #[error("recording: {0}")]
+    Io(#[from] io::Error),
+    #[error("{0:?} audio isn't recorded")]
+    UnsupportedCodec(CodecKind),
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+}
+
+/// Directory streams are recorded into, see the [module](self) for its layout.
+///
+/// Streams of the recorder are created by devices in place of their own ones. Failed writes are
+/// logged and end the recording of the stream rather than the stream.
+///
+/// Files are written once the recorder and all of its streams are dropped at the latest, see
+/// [`Self::sync`] to wait for them earlier.
+#[derive(Clone)]
+pub struct HlsRecorder {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    dir: PathBuf,
+    segment_duration: Duration,
+    /// Local time the timeline starts at, of the first packet of any track
+    start: Mutex<Option<SystemTime>>,
+    master: Mutex<Master>,
+    /// Taken by the writer thread in order, it ends once the sender is dropped
+    writes: mpsc::Sender<WriteJob>,
+}
+
+enum WriteJob {
+    File {
+        path: PathBuf,
+        data: Vec<u8>,
+        /// Of the rendition, set once any of its writes failed
+        failed: Arc<AtomicBool>,
+    },
+    /// Answered once the jobs before are done
+    Sync(mpsc::Sender<()>),
+}
+
+/// Renditions listed in the master playlist, the last ones of each kind
+#[derive(Default)]
+struct Master {
+    audio: Option<Variant>,
+    video: Option<Variant>,
+    audio_count: u32,
+    video_count: u32,
+}
+
+#[derive(Debug, Clone)]
+struct Variant {
+    name: String,
+    codecs: String,
+    /// Peak of segments, in bits per second
+    bandwidth: u64,
+}
+
+impl HlsRecorder {
+    /// Records into `dir`, created if missing. Segments are cut once they're at least
+    /// `segment_duration` long, at the next keyframe for video.
+    ///
+    /// # Errors
+    ///
+    /// If the directory or the writer thread couldn't be created.
+    pub fn create(dir: impl AsRef<Path>, segment_duration: Duration) -> Result<Self, HlsError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let (writes, jobs) = mpsc::channel();
+        thread::Builder::new()
+            .name("hls-writer".to_string())
+            .spawn(move || write_files(jobs))?;
+        tracing::info!(dir = %dir.display(), "hls recording started");
+
+        Ok(Self {
+            shared: Arc::new(Shared {
+                dir,
+                segment_duration,
+                start: Mutex::default(),
+                master: Mutex::default(),
+                writes,
+            }),
+        })
+    }
+
+    /// Blocks until files of everything recorded so far are written, e.g. before they're read.
+    pub fn sync(&self) {
+        let (done, synced) = mpsc::channel();
+        if self.shared.writes.send(WriteJob::Sync(done)).is_ok() {
+            let _ = synced.recv();
+        }
+    }
+
+    /// Records ALAC, or AAC with the `aac` feature.
+    ///
+    /// # Errors
+    ///
+    /// If the codec isn't recorded.
+    pub fn audio_stream(&self, params: &AudioParams) -> Result<HlsAudioStream, HlsError> {
+        let format = params.format;
+        let (entry, codecs) = match format.codec {
+            CodecKind::Alac => {
+                let entry = mp4::SampleEntry::Alac {
+                    channels: format.channels,
+                    sample_rate: format.sample_rate,
+                    bits: u16::try_from(format.bits_per_sample)
+                        .map_err(|_| DecodeError::InvalidParams("bit depth"))?,
+                    cookie: AlacDecoder::magic_cookie(params)?.to_vec(),
+                };
+                (entry, "alac".to_string())
+            }
+            #[cfg(feature = "aac")]
+            CodecKind::AacLc | CodecKind::AacEld => {
+                let entry = mp4::SampleEntry::Aac {
+                    channels: format.channels,
+                    sample_rate: format.sample_rate,
+                    asc: super::audio::AacDecoder::audio_specific_config(params)?,
+                };
+                let object_type = if format.codec == CodecKind::AacLc {
+                    2
+                } else {
+                    39
+                };
+                (entry, format!("mp4a.40.{object_type}"))
+            }
+            codec => return Err(HlsError::UnsupportedCodec(codec)),
+        };
+
+        let mut rendition = Rendition::new(&self.shared, Kind::Audio, format.sample_rate);
+        rendition.set_init(
+            &mp4::Track {
+                timescale: format.sample_rate,
+                entry,
+            },
+            codecs,
+        )?;
+
+        Ok(HlsAudioStream {
+            track: Mutex::new(AudioTrack {
+                rendition,
+                clock: TrackClock::default(),
+                samples_per_frame: params.samples_per_frame,
+            }),
+        })
+    }
+
+    /// Records frames of [`PacketKind::Payload`] packets or ones passed to
+    /// [`VideoStream::on_frame`], starting with the first keyframe after a format.
+    #[must_use]
+    pub fn video_stream(&self) -> HlsVideoStream {
+        HlsVideoStream {
+            track: Mutex::new(VideoTrack {
+                rendition: Rendition::new(&self.shared, Kind::Video, VIDEO_TIMESCALE),
+                clock: TrackClock::default(),
+                config: None,
+                awaiting_keyframe: true,
+                pending: None,
+                // 30 fps until frames tell
+                last_duration: VIDEO_TIMESCALE / 30,
+            }),
+        }
+    }
+}
+
+impl Shared {
+    /// Time of local `at` on the timeline in `timescale` units, the first one asked for starts
+    /// it. Times before the start are placed at it.
+    fn offset(&self, timescale: u32, at: SystemTime) -> u64 {
+        let start = *self
+            .start
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_or_insert(at);
+        let elapsed = at.duration_since(start).unwrap_or_default();
+        let ticks = elapsed.as_nanos() * u128::from(timescale) / 1_000_000_000;
+        u64::try_from(ticks).unwrap_or(u64::MAX)
+    }
+
+    /// Queues `data` to be written to `path` in the directory, unless `failed` is set by then.
+    fn write(&self, path: &str, data: Vec<u8>, failed: &Arc<AtomicBool>) {
+        let job = WriteJob::File {
+            path: self.dir.join(path),
+            data,
+            failed: Arc::clone(failed),
+        };
+        // Writer lives as long as self
+        let _ = self.writes.send(job);
+    }
+
+    fn update_master(&self, kind: Kind, variant: Variant, failed: &Arc<AtomicBool>) {
+        let mut master = self.master.lock().unwrap_or_else(PoisonError::into_inner);
+        match kind {
+            Kind::Audio => master.audio = Some(variant),
+            Kind::Video => master.video = Some(variant),
+        }
+
+        let mut playlist = "#EXTM3U\n#EXT-X-VERSION:7\n#EXT-X-INDEPENDENT-SEGMENTS\n".to_string();
+        match (&master.audio, &master.video) {
+            (Some(audio), Some(video)) => {
+                let _ = write!(
+                    playlist,
+                    "#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"audio\",NAME=\"{0}\",DEFAULT=YES,\
+                     AUTOSELECT=YES,URI=\"{0}.m3u8\"\n\
+                     #EXT-X-STREAM-INF:BANDWIDTH={1},CODECS=\"{2},{3}\",AUDIO=\"audio\"\n\
+                     {4}.m3u8\n",
+                    audio.name,
+                    audio.bandwidth + video.bandwidth,
+                    video.codecs,
+                    audio.codecs,
+                    video.name,
+                );
+            }
+            (Some(variant), None) | (None, Some(variant)) => {
+                let _ = write!(
+                    playlist,
+                    "#EXT-X-STREAM-INF:BANDWIDTH={},CODECS=\"{}\"\n{}.m3u8\n",
+                    variant.bandwidth, variant.codecs, variant.name,
+                );
+            }
+            (None, None) => {}
+        }
+        self.write("index.m3u8", playlist.into_bytes(), failed);
+    }
+}
+
+/// Runs the writer thread until the recorder and all of its streams are dropped.
+fn write_files(jobs: mpsc::Receiver<WriteJob>) {
+    for job in jobs {
+        match job {
+            WriteJob::File { path, data, failed } => {
+                if failed.load(Ordering::Acquire) {
+                    continue;
+                }
+                if let Err(err) = fs::write(&path, data) {
+                    tracing::error!(%err, path = %path.display(), "hls recording stopped");
+                    failed.store(true, Ordering::Release);
+                }
+            }
+            WriteJob::Sync(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Audio,
+    Video,
+}
+
+/// Places timestamps of a track on the recording's timeline.
+#[derive(Debug, Default)]
+struct TrackClock {
+    /// Last timestamp and the time it was placed at
+    last: Option<(u64, u64)>,
+}
+
+impl TrackClock {
+    /// Time of `timestamp`, which is `bits` wide and wraps around, played at local time `at`
+    /// if known. Going back, e.g. on seek, doesn't go back on the timeline, it's at `at` if
+    /// that's later than `step` after the last one.
+    fn place(
+        &mut self,
+        shared: &Shared,
+        timescale: u32,
+        timestamp: u64,
+        bits: u32,
+        step: u64,
+        at: Option<SystemTime>,
+    ) -> u64 {
+        let time = match self.last {
+            None => shared.offset(timescale, at.unwrap_or_else(SystemTime::now)),
+            Some((last_timestamp, last_time)) => {
+                let mask = u64::MAX >> (64 - bits);
+                let diff = timestamp.wrapping_sub(last_timestamp) & mask;
+                if diff > mask / 2 {
+                    let mapped = at.map_or(0, |at| shared.offset(timescale, at));
+                    mapped.max(last_time + step)
+                } else {
+                    last_time + diff
+                }
+            }
+        };
+        self.last = Some((timestamp, time));
+        time
+    }
+}
+
+/// Segments and playlist of a single track.
+struct Rendition {
+    shared: Arc<Shared>,
+    kind: Kind,
+    name: String,
+    timescale: u32,
+    codecs: String,
+    /// Init segments written, the last one is the current one
+    inits: u32,
+    /// Whether the next segment starts with a new init segment
+    init_changed: bool,
+    segments: Vec<Segment>,
+    /// Sequence number of the next fragment
+    seq: u32,
+    /// Fragments of the segment being filled, and their duration
+    filled: Vec<u8>,
+    filled_duration: u64,
+    fragment: Fragment,
+    bandwidth: u64,
+    /// Set by the writer once a write failed, nothing's written afterwards
+    failed: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+struct Fragment {
+    start: u64,
+    samples: Vec<mp4::Sample>,
+    data: Vec<u8>,
+}
+
+struct Segment {
+    uri: String,
+    duration: Duration,
+    /// Init segment it starts, `None` if it's the one of the segment before
+    init: Option<String>,
+}
+
+impl Fragment {
+    fn duration(&self) -> u64 {
+        self.samples
+            .iter()
+            .map(|sample| u64::from(sample.duration))
+            .sum()
+    }
+}
+
+impl Rendition {
+    fn new(shared: &Arc<Shared>, kind: Kind, timescale: u32) -> Self {
+        let mut master = shared.master.lock().unwrap_or_else(PoisonError::into_inner);
+        let name = match kind {
+            Kind::Audio => {
+                master.audio_count += 1;
+                format!("audio{}", master.audio_count - 1)
+            }
+            Kind::Video => {
+                master.video_count += 1;
+                format!("video{}", master.video_count - 1)
+            }
+        };
+
+        Self {
+            shared: Arc::clone(shared),
+            kind,
+            name,
+            timescale,
+            codecs: String::new(),
+            inits: 0,
+            init_changed: false,
+            segments: Vec::new(),
+            seq: 1,
+            filled: Vec::new(),
+            filled_duration: 0,
+            fragment: Fragment::default(),
+            bandwidth: 0,
+            failed: Arc::default(),
+        }
+    }
+
+    fn failed(&self) -> bool {
+        self.failed.load(Ordering::Acquire)
+    }
+
+    /// Segments from now on are of `track`.
+    fn set_init(&mut self, track: &mp4::Track, codecs: String) -> io::Result<()> {
+        self.cut();
+        if self.failed() {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        let uri = format!("{}_init{}.mp4", self.name, self.inits);
+        self.shared
+            .write(&uri, mp4::init_segment(track), &self.failed);
+        self.inits += 1;
+        self.init_changed = true;
+        self.codecs = codecs;
+        Ok(())
+    }
+
+    /// Adds a sample at `time`, a segment is cut before sync ones once it's long enough.
+    fn push(&mut self, time: u64, duration: u32, data: &[u8], is_sync: bool) {
+        if self.failed() {
+            return;
+        }
+
+        let target = self.shared.segment_duration.as_secs_f64() * f64::from(self.timescale);
+        #[allow(clippy::cast_precision_loss)]
+        let filled = (self.filled_duration + self.fragment.duration()) as f64;
+        if is_sync && filled >= target {
+            self.cut();
+        } else if !self.fragment.samples.is_empty()
+            && time != self.fragment.start + self.fragment.duration()
+        {
+            // Gap or overlap, the next fragment starts at the time
+            self.close_fragment();
+        }
+
+        if self.fragment.samples.is_empty() {
+            self.fragment.start = time;
+        }
+        self.fragment.samples.push(mp4::Sample {
+            duration,
+            size: u32::try_from(data.len()).unwrap_or(u32::MAX),
+            is_sync,
+        });
+        self.fragment.data.extend_from_slice(data);
+    }
+
+    fn close_fragment(&mut self) {
+        let fragment = std::mem::take(&mut self.fragment);
+        if fragment.samples.is_empty() {
+            return;
+        }
+        self.filled_duration += fragment.duration();
+        self.filled.extend(mp4::fragment(
+            self.seq,
+            fragment.start,
+            &fragment.samples,
+            &fragment.data,
+        ));
+        self.seq += 1;
+    }
+
+    /// Writes the segment filled so far and lists it.
+    fn cut(&mut self) {
+        self.close_fragment();
+        if self.filled.is_empty() || self.failed() {
+            return;
+        }
+
+        let uri = format!("{}_{:05}.m4s", self.name, self.segments.len());
+        #[allow(clippy::cast_precision_loss)]
+        let duration =
+            Duration::from_secs_f64(self.filled_duration as f64 / f64::from(self.timescale));
+        let data = std::mem::take(&mut self.filled);
+        self.filled_duration = 0;
+
+        #[allow(
+            clippy::cast_possible_truncation,
+            clippy::cast_precision_loss,
+            clippy::cast_sign_loss
+        )]
+        let bandwidth = (data.len() as f64 * 8.0 / duration.as_secs_f64().max(0.001)) as u64;
+        self.bandwidth = self.bandwidth.max(bandwidth);
+        self.shared.write(&uri, data, &self.failed);
+        let init = std::mem::take(&mut self.init_changed)
+            .then(|| format!("{}_init{}.mp4", self.name, self.inits - 1));
+        self.segments.push(Segment {
+            uri,
+            duration,
+            init,
+        });
+        self.write_playlists(false);
+    }
+
+    fn write_playlists(&self, ended: bool) {
+        let target = self
+            .segments
+            .iter()
+            .map(|segment| segment.duration.as_secs_f64().ceil())
+            .fold(self.shared.segment_duration.as_secs_f64().ceil(), f64::max);
+        let mut playlist = format!(
+            "#EXTM3U\n#EXT-X-VERSION:7\n#EXT-X-TARGETDURATION:{target}\n\
+             #EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:EVENT\n#EXT-X-INDEPENDENT-SEGMENTS\n"
+        );
+        for (i, segment) in self.segments.iter().enumerate() {
+            if let Some(init) = &segment.init {
+                if i != 0 {
+                    playlist.push_str("#EXT-X-DISCONTINUITY\n");
+                }
+                let _ = writeln!(playlist, "#EXT-X-MAP:URI=\"{init}\"");
+            }
+            let _ = write!(
+                playlist,
+                "#EXTINF:{:.3},\n{}\n",
+                segment.duration.as_secs_f64(),
+                segment.uri
+            );
+        }
+        if ended {
+            playlist.push_str("#EXT-X-ENDLIST\n");
+        }
+
+        let uri = format!("{}.m3u8", self.name);
+        self.shared.write(&uri, playlist.into_bytes(), &self.failed);
+        let variant = Variant {
+            name: self.name.clone(),
+            codecs: self.codecs.clone(),
+            bandwidth: self.bandwidth,
+        };
+        self.shared.update_master(self.kind, variant, &self.failed);
+    }
+}
+
+impl Drop for Rendition {
+    fn drop(&mut self) {
+        self.cut();
+        if !self.failed() && !self.segments.is_empty() {
+            self.write_playlists(true);
+            tracing::info!(
+                name = %self.name,
+                segments = %self.segments.len(),
+                "hls recording finished"
+            );
+        }
+    }
+}
+
+/// Audio stream of [`HlsRecorder`], packets are recorded as sent.
+pub struct HlsAudioStream {
+    track: Mutex<AudioTrack>,
+}
+
+struct AudioTrack {
+    rendition: Rendition,
+    clock: TrackClock,
+    samples_per_frame: u32,
+}
+
+impl Stream for HlsAudioStream {
+    type Content = AudioPacket;
+
+    fn on_data(&self, packet: Self::Content) {
+        if packet.rtp.len() < AudioPacket::HEADER_LEN {
+            return;
+        }
+        let track = &mut *self.track.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let spf = track.samples_per_frame;
+        let time = track.clock.place(
+            &track.rendition.shared,
+            track.rendition.timescale,
+            packet.header().timestamp.into(),
+            32,
+            spf.into(),
+            packet.playout_time,
+        );
+        track.rendition.push(time, spf, packet.payload(), true);
+    }
+
+    fn on_ok(self) {
+        tracing::debug!("recorded audio stream finished");
+    }
+
+    fn on_err(self, err: Box<dyn Error>) {
+        tracing::debug!(%err, "recorded audio stream failed");
+    }
+}
+
+impl AudioStream for HlsAudioStream {}
+
+/// Video stream of [`HlsRecorder`], frames are recorded as they're sent.
+pub struct HlsVideoStream {
+    track: Mutex<VideoTrack>,
+}
+
+struct VideoTrack {
+    rendition: Rendition,
+    clock: TrackClock,
+    /// Codec record of the current init segment
+    config: Option<Vec<u8>>,
+    awaiting_keyframe: bool,
+    /// Frame waiting for the next one, which tells its duration
+    pending: Option<PendingFrame>,
+    last_duration: u32,
+}
+
+struct PendingFrame {
+    time: u64,
+    data: Vec<u8>,
+    is_keyframe: bool,
+}
+
+impl VideoTrack {
+    fn on_config(&mut self, codec: VideoCodec, record: &[u8], dimensions: Option<FrameDimensions>) {
+        if self.config.as_deref() == Some(record) {
+            return;
+        }
+
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let (width, height) = dimensions.map_or((0, 0), |dimensions| {
+            (dimensions.width as u16, dimensions.height as u16)
+        });
+        let (entry, codecs) = match codec {
+            VideoCodec::H264 => match video::parse_avcc(record) {
+                Ok(config) => (
+                    mp4::SampleEntry::Avc {
+                        width,
+                        height,
+                        avcc: record.to_vec(),
+                    },
+                    format!(
+                        "avc1.{:02x}{:02x}{:02x}",
+                        config.profile, config.profile_compatibility, config.level
+                    ),
+                ),
+                Err(err) => return tracing::warn!(%err, "recorded avcc not parsed"),
+            },
+            VideoCodec::H265 => match video::parse_hvcc(record) {
+                Ok(config) => (
+                    mp4::SampleEntry::Hevc {
+                        width,
+                        height,
+                        hvcc: record.to_vec(),
+                    },
+                    format!(
+                        "hvc1.{}{}.{:x}.{}{}",
+                        ["", "A", "B", "C"][usize::from(config.profile_space & 0b11)],
+                        config.profile,
+                        config.profile_compatibility.reverse_bits(),
+                        if config.tier { 'H' } else { 'L' },
+                        config.level
+                    ),
+                ),
+                Err(err) => return tracing::warn!(%err, "recorded hvcc not parsed"),
+            },
+        };
+
+        self.flush_pending();
+        let track = mp4::Track {
+            timescale: VIDEO_TIMESCALE,
+            entry,
+        };
+        if self.rendition.set_init(&track, codecs).is_ok() {
+            self.config = Some(record.to_vec());
+            self.awaiting_keyframe = true;
+        }
+    }
+
+    fn on_frame(
+        &mut self,
+        timestamp: u64,
+        pts: Option<SystemTime>,
+        data: &[u8],
+        is_keyframe: bool,
+    ) {
+        if self.config.is_none() || (self.awaiting_keyframe && !is_keyframe) {
+            return;
+        }
+        self.awaiting_keyframe = false;
+
+        // NTP time, seconds in the upper half
+        let ticks = u64::try_from((u128::from(timestamp) * u128::from(VIDEO_TIMESCALE)) >> 32)
+            .expect("fits as the timescale is below 2^32");
+        let time = self.clock.place(
+            &self.rendition.shared,
+            VIDEO_TIMESCALE,
+            ticks,
+            64,
+            self.last_duration.into(),
+            pts,
+        );
+
+        if let Some(pending) = &mut self.pending {
+            // Another part of the same frame
+            if pending.time == time {
+                pending.data.extend_from_slice(data);
+                pending.is_keyframe |= is_keyframe;
+                return;
+            }
+            self.last_duration = u32::try_from(time - pending.time).unwrap_or(u32::MAX);
+            self.flush_pending();
+        }
+        self.pending = Some(PendingFrame {
+            time,
+            data: data.to_vec(),
+            is_keyframe,
+        });
+    }
+
+    /// Records the pending frame, lasting as long as the one before it.
+    fn flush_pending(&mut self) {
+        if let Some(frame) = self.pending.take() {
+            self.rendition.push(
+                frame.time,
+                self.last_duration,
+                &frame.data,
+                frame.is_keyframe,
+            );
+        }
+    }
+}
+
+impl Drop for VideoTrack {
+    fn drop(&mut self) {
+        self.flush_pending();
+    }
+}
+
+impl Stream for HlsVideoStream {
+    type Content = VideoPacket;
+
+    fn on_data(&self, packet: Self::Content) {
+        let mut track = self.track.lock().unwrap_or_else(PoisonError::into_inner);
+        match packet.kind {
+            PacketKind::AvcC => {
+                track.on_config(VideoCodec::H264, &packet.payload, packet.dimensions);
+            }
+            PacketKind::HvcC => {
+                track.on_config(VideoCodec::H265, &packet.payload, packet.dimensions);
+            }
+            PacketKind::Payload => {
+                track.on_frame(
+                    packet.timestamp,
+                    packet.pts,
+                    &packet.payload,
+                    packet.is_keyframe,
+                );
+            }
+            PacketKind::Other(_) => {}
+        }
+    }
+
+    fn on_ok(self) {
+        tracing::debug!("recorded video stream finished");
+    }
+
+    fn on_err(self, err: Box<dyn Error>) {
+        tracing::debug!(%err, "recorded video stream failed");
+    }
+}
+
+impl VideoStream for HlsVideoStream {
+    fn on_frame(&self, frame: AccessUnit) {
+        self.track
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .on_frame(frame.timestamp, frame.pts, &frame.data, frame.is_keyframe);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::PathBuf,
+        time::{Duration, SystemTime},
+    };
+
+    use bytes::{Bytes, BytesMut};
+
+    use super::HlsRecorder;
+    use crate::playback::{
+        Stream,
+        audio::{AudioFormat, AudioPacket, AudioParams},
+        video::{FrameDimensions, PacketKind, VideoPacket},
+    };
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rairplay-{}-{name}", std::process::id()))
+    }
+
+    fn alac_params() -> AudioParams {
+        AudioParams {
+            samples_per_frame: 352,
+            format: AudioFormat::from_bits(0x40000).unwrap(),
+            latency: None,
+        }
+    }
+
+    fn audio_packet(timestamp: u32, payload: &[u8]) -> AudioPacket {
+        let mut rtp = BytesMut::from(&[0x80, 0x60, 0, 1][..]);
+        rtp.extend_from_slice(&timestamp.to_be_bytes());
+        rtp.extend_from_slice(&[0; 4]);
+        rtp.extend_from_slice(payload);
+        AudioPacket {
+            rtp,
+            playout_time: None,
+        }
+    }
+
+    fn video_packet(
+        kind: PacketKind,
+        timestamp: u64,
+        is_keyframe: bool,
+        payload: &[u8],
+    ) -> VideoPacket {
+        VideoPacket {
+            kind,
+            option: 0,
+            timestamp,
+            pts: None,
+            dts: None,
+            dimensions: Some(FrameDimensions {
+                source_width: 1920.0,
+                source_height: 1080.0,
+                width: 1280.0,
+                height: 720.0,
+            }),
+            reserved: Bytes::default(),
+            is_keyframe,
+            payload: BytesMut::from(payload),
+        }
+    }
+
+    /// `EXTINF` durations of the media playlist
+    fn durations(playlist: &str) -> Vec<f64> {
+        playlist
+            .lines()
+            .filter_map(|line| line.strip_prefix("#EXTINF:"))
+            .map(|duration| duration.trim_end_matches(',').parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn audio_segments_cut() {
+        let dir = temp_dir("hls-audio");
+        let recorder = HlsRecorder::create(&dir, Duration::from_secs(1)).unwrap();
+        let stream = recorder.audio_stream(&alac_params()).unwrap();
+
+        // 2.5 s across the wrap of timestamps
+        let start = u32::MAX - 352 * 10;
+        for i in 0..313 {
+            stream.on_data(audio_packet(start.wrapping_add(i * 352), &[0x20, 0, 0]));
+        }
+        stream.on_ok();
+        recorder.sync();
+
+        let playlist = fs::read_to_string(dir.join("audio0.m3u8")).unwrap();
+        let durations = durations(&playlist);
+        assert_eq!(3, durations.len(), "{playlist}");
+        assert!(
+            durations[..2]
+                .iter()
+                .all(|&duration| (1.0..1.01).contains(&duration))
+        );
+        assert!(playlist.contains("#EXT-X-MAP:URI=\"audio0_init0.mp4\""));
+        assert!(playlist.ends_with("#EXT-X-ENDLIST\n"));
+
+        let segment = fs::read(dir.join("audio0_00000.m4s")).unwrap();
+        assert_eq!(b"moof", &segment[4..8]);
+        assert_eq!(
+            b"ftyp",
+            &fs::read(dir.join("audio0_init0.mp4")).unwrap()[4..8]
+        );
+
+        let master = fs::read_to_string(dir.join("index.m3u8")).unwrap();
+        assert!(master.contains("CODECS=\"alac\"\naudio0.m3u8"), "{master}");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn video_starts_at_keyframe() {
+        let dir = temp_dir("hls-video");
+        let recorder = HlsRecorder::create(&dir, Duration::from_secs(1)).unwrap();
+        let stream = recorder.video_stream();
+
+        let frame = |i: u64, is_keyframe| {
+            // 30 fps in NTP time
+            let timestamp = (1000 << 32) + i * (1 << 32) / 30;
+            video_packet(
+                PacketKind::Payload,
+                timestamp,
+                is_keyframe,
+                &[0, 0, 0, 1, 0x65],
+            )
+        };
+        // Nothing's recorded before the format and its first keyframe
+        stream.on_data(frame(0, true));
+        let avcc = [1, 0x64, 0, 0x1f, 0xff, 0xe0, 0];
+        stream.on_data(video_packet(PacketKind::AvcC, 0, false, &avcc));
+        stream.on_data(frame(1, false));
+        for i in 2..92 {
+            stream.on_data(frame(i, i % 30 == 2));
+        }
+        drop(stream);
+        recorder.sync();
+
+        let playlist = fs::read_to_string(dir.join("video0.m3u8")).unwrap();
+        let durations = durations(&playlist);
+        assert_eq!(3, durations.len(), "{playlist}");
+        assert!(
+            durations
+                .iter()
+                .all(|&duration| (0.99..1.01).contains(&duration))
+        );
+
+        let init = fs::read(dir.join("video0_init0.mp4")).unwrap();
+        assert!(init.windows(avcc.len()).any(|window| window == avcc));
+        let master = fs::read_to_string(dir.join("index.m3u8")).unwrap();
+        assert!(master.contains("CODECS=\"avc1.64001f\""), "{master}");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn tracks_placed_by_playout_time() {
+        let dir = temp_dir("hls-sync");
+        let recorder = HlsRecorder::create(&dir, Duration::from_secs(1)).unwrap();
+        let audio = recorder.audio_stream(&alac_params()).unwrap();
+        let video = recorder.video_stream();
+
+        // Frame comes right after audio, but it's to be shown half a second later
+        let start = SystemTime::now() + Duration::from_secs(2);
+        let mut packet = audio_packet(0, &[0x20, 0, 0]);
+        packet.playout_time = Some(start);
+        audio.on_data(packet);
+        let avcc = [1, 0x64, 0, 0x1f, 0xff, 0xe0, 0];
+        video.on_data(video_packet(PacketKind::AvcC, 0, false, &avcc));
+        let mut frame = video_packet(PacketKind::Payload, 1 << 32, true, &[0, 0, 0, 1, 0x65]);
+        frame.pts = Some(start + Duration::from_millis(500));
+        video.on_data(frame);
+        drop((audio, video));
+        recorder.sync();
+
+        let segment = fs::read(dir.join("video0_00000.m4s")).unwrap();
+        let tfdt = segment.windows(4).position(|kind| kind == b"tfdt").unwrap();
+        assert_eq!(45_000u64.to_be_bytes(), segment[tfdt + 8..tfdt + 16]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,416 @@
+//! Boxes of fragmented MP4 as HLS takes them, see ISO/IEC 14496-12.
+//!
+//! Every file holds a single track, so the track ID is always 1.
+
+const TRACK_ID: u32 = 1;
+
+const UNITY_MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+/// Track described by the init segment.
+pub(super) struct Track {
+    pub timescale: u32,
+    pub entry: SampleEntry,
+}
+
+/// Codec of the track along with its config, as carried in the sample description.
+pub(super) enum SampleEntry {
+    Avc {
+        width: u16,
+        height: u16,
+        /// `AVCDecoderConfigurationRecord`
+        avcc: Vec<u8>,
+    },
+    Hevc {
+        width: u16,
+        height: u16,
+        /// `HEVCDecoderConfigurationRecord`
+        hvcc: Vec<u8>,
+    },
+    Alac {
+        channels: u8,
+        sample_rate: u32,
+        bits: u16,
+        /// `ALACSpecificConfig`
+        cookie: Vec<u8>,
+    },
+    #[cfg(feature = "aac")]
+    Aac {
+        channels: u8,
+        sample_rate: u32,
+        /// `AudioSpecificConfig`
+        asc: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Sample {
+    pub duration: u32,
+    pub size: u32,
+    /// Decoding can start at the sample
+    pub is_sync: bool,
+}
+
+impl SampleEntry {
+    fn is_audio(&self) -> bool {
+        match self {
+            Self::Avc { .. } | Self::Hevc { .. } => false,
+            Self::Alac { .. } => true,
+            #[cfg(feature = "aac")]
+            Self::Aac { .. } => true,
+        }
+    }
+}
+
+/// `ftyp` and `moov` of the track, played fragments follow.
+pub(super) fn init_segment(track: &Track) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_box(&mut out, b"ftyp", |out| {
+        out.extend_from_slice(b"iso5");
+        put_u32(out, 0);
+        out.extend_from_slice(b"iso5iso6mp41");
+    });
+    write_box(&mut out, b"moov", |out| {
+        full_box(out, b"mvhd", 0, 0, |out| {
+            // Creation and modification time, timescale, duration
+            put_u32s(out, &[0, 0, 1000, 0]);
+            // Rate, volume, reserved
+            put_u32(out, 0x0001_0000);
+            put_u16(out, 0x0100);
+            out.extend_from_slice(&[0; 10]);
+            put_u32s(out, &UNITY_MATRIX);
+            put_u32s(out, &[0; 6]);
+            put_u32(out, TRACK_ID + 1);
+        });
+        write_box(out, b"trak", |out| trak(out, track));
+        write_box(out, b"mvex", |out| {
+            full_box(out, b"trex", 0, 0, |out| {
+                // Track, sample description, then defaults left to fragments
+                put_u32s(out, &[TRACK_ID, 1, 0, 0, 0]);
+            });
+        });
+    });
+    out
+}
+
+fn trak(out: &mut Vec<u8>, track: &Track) {
+    let (width, height) = match track.entry {
+        SampleEntry::Avc { width, height, .. } | SampleEntry::Hevc { width, height, .. } => {
+            (width, height)
+        }
+        SampleEntry::Alac { .. } => (0, 0),
+        #[cfg(feature = "aac")]
+        SampleEntry::Aac { .. } => (0, 0),
+    };
+    let audio = track.entry.is_audio();
+
+    // Enabled and in movie
+    full_box(out, b"tkhd", 0, 0x3, |out| {
+        // Creation and modification time, track, reserved, duration, reserved
+        put_u32s(out, &[0, 0, TRACK_ID, 0, 0, 0, 0]);
+        // Layer, alternate group, volume, reserved
+        put_u16(out, 0);
+        put_u16(out, 0);
+        put_u16(out, if audio { 0x0100 } else { 0 });
+        put_u16(out, 0);
+        put_u32s(out, &UNITY_MATRIX);
+        put_u32(out, u32::from(width) << 16);
+        put_u32(out, u32::from(height) << 16);
+    });
+    write_box(out, b"mdia", |out| {
+        full_box(out, b"mdhd", 0, 0, |out| {
+            put_u32s(out, &[0, 0, track.timescale, 0]);
+            // Language `und`, pre-defined
+            put_u16(out, 0x55c4);
+            put_u16(out, 0);
+        });
+        full_box(out, b"hdlr", 0, 0, |out| {
+            put_u32(out, 0);
+            out.extend_from_slice(if audio { b"soun" } else { b"vide" });
+            put_u32s(out, &[0; 3]);
+            out.extend_from_slice(if audio {
+                b"SoundHandler\0"
+            } else {
+                b"VideoHandler\0"
+            });
+        });
+        write_box(out, b"minf", |out| {
+            if audio {
+                full_box(out, b"smhd", 0, 0, |out| put_u32(out, 0));
+            } else {
+                full_box(out, b"vmhd", 0, 0x1, |out| put_u16s(out, &[0; 4]));
+            }
+            write_box(out, b"dinf", |out| {
+                full_box(out, b"dref", 0, 0, |out| {
+                    put_u32(out, 1);
+                    // Media is in the same file
+                    full_box(out, b"url ", 0, 0x1, |_| {});
+                });
+            });
+            write_box(out, b"stbl", |out| {
+                full_box(out, b"stsd", 0, 0, |out| {
+                    put_u32(out, 1);
+                    sample_entry(out, &track.entry);
+                });
+                // Samples are all in fragments
+                full_box(out, b"stts", 0, 0, |out| put_u32(out, 0));
+                full_box(out, b"stsc", 0, 0, |out| put_u32(out, 0));
+                full_box(out, b"stsz", 0, 0, |out| put_u32s(out, &[0, 0]));
+                full_box(out, b"stco", 0, 0, |out| put_u32(out, 0));
+            });
+        });
+    });
+}
+
+fn sample_entry(out: &mut Vec<u8>, entry: &SampleEntry) {
+    match entry {
+        SampleEntry::Avc {
+            width,
+            height,
+            avcc,
+        } => write_box(out, b"avc1", |out| {
+            visual_entry(out, *width, *height);
+            write_box(out, b"avcC", |out| out.extend_from_slice(avcc));
+        }),
+        SampleEntry::Hevc {
+            width,
+            height,
+            hvcc,
+        } => write_box(out, b"hvc1", |out| {
+            visual_entry(out, *width, *height);
+            write_box(out, b"hvcC", |out| out.extend_from_slice(hvcc));
+        }),
+        SampleEntry::Alac {
+            channels,
+            sample_rate,
+            bits,
+            cookie,
+        } => write_box(out, b"alac", |out| {
+            audio_entry(out, *channels, *bits, *sample_rate);
+            full_box(out, b"alac", 0, 0, |out| out.extend_from_slice(cookie));
+        }),
+        #[cfg(feature = "aac")]
+        SampleEntry::Aac {
+            channels,
+            sample_rate,
+            asc,
+        } => write_box(out, b"mp4a", |out| {
+            audio_entry(out, *channels, 16, *sample_rate);
+            full_box(out, b"esds", 0, 0, |out| esds(out, asc));
+        }),
+    }
+}
+
+fn visual_entry(out: &mut Vec<u8>, width: u16, height: u16) {
+    // Reserved, data reference, pre-defined and reserved
+    out.extend_from_slice(&[0; 6]);
+    put_u16(out, 1);
+    put_u16s(out, &[0; 8]);
+    put_u16(out, width);
+    put_u16(out, height);
+    // 72 dpi both ways, reserved, frame count
+    put_u32s(out, &[0x0048_0000, 0x0048_0000, 0]);
+    put_u16(out, 1);
+    // Compressor name, depth, pre-defined
+    out.extend_from_slice(&[0; 32]);
+    put_u16(out, 0x18);
+    put_u16(out, 0xffff);
+}
+
+fn audio_entry(out: &mut Vec<u8>, channels: u8, bits: u16, sample_rate: u32) {
+    out.extend_from_slice(&[0; 6]);
+    put_u16(out, 1);
+    put_u32s(out, &[0, 0]);
+    put_u16(out, channels.into());
+    put_u16(out, bits);
+    put_u32(out, 0);
+    // 16.16 fixed point, rates not fitting are told by the codec config
+    put_u32(
+        out,
+        u16::try_from(sample_rate).map_or(0, |rate| u32::from(rate) << 16),
+    );
+}
+
+/// `ES_Descriptor` of AAC, see ISO/IEC 14496-1
+#[cfg(feature = "aac")]
+fn esds(out: &mut Vec<u8>, asc: &[u8]) {
+    const ES_DESCR: u8 = 0x03;
+    const DECODER_CONFIG_DESCR: u8 = 0x04;
+    const DECODER_SPECIFIC_DESCR: u8 = 0x05;
+    const SL_CONFIG_DESCR: u8 = 0x06;
+
+    // Short configs only, so lengths fit in a byte
+    let descr = |out: &mut Vec<u8>, tag: u8, body: &[u8]| {
+        out.push(tag);
+        out.push(u8::try_from(body.len()).unwrap());
+        out.extend_from_slice(body);
+    };
+
+    let mut decoder_config = vec![
+        // MPEG-4 audio, audio stream, buffer size, max and average bitrate
+        0x40, 0x15, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    descr(&mut decoder_config, DECODER_SPECIFIC_DESCR, asc);
+
+    // ES ID, flags
+    let mut es = vec![0, 0, 0];
+    descr(&mut es, DECODER_CONFIG_DESCR, &decoder_config);
+    descr(&mut es, SL_CONFIG_DESCR, &[0x02]);
+    descr(out, ES_DESCR, &es);
+}
+
+/// `moof` and `mdat` of samples following each other from `base_time`, `data` holds them
+/// back to back.
+pub(super) fn fragment(seq: u32, base_time: u64, samples: &[Sample], data: &[u8]) -> Vec<u8> {
+    const SYNC: u32 = 0x0200_0000;
+    // Depends on others and isn't sync
+    const NON_SYNC: u32 = 0x0101_0000;
+
+    let mut out = Vec::new();
+    let mut data_offset_at = 0;
+    write_box(&mut out, b"moof", |out| {
+        full_box(out, b"mfhd", 0, 0, |out| put_u32(out, seq));
+        write_box(out, b"traf", |out| {
+            // Default base is moof
+            full_box(out, b"tfhd", 0, 0x02_0000, |out| put_u32(out, TRACK_ID));
+            full_box(out, b"tfdt", 1, 0, |out| {
+                out.extend_from_slice(&base_time.to_be_bytes());
+            });
+            // Data offset, sample duration, size and flags
+            full_box(out, b"trun", 0, 0x701, |out| {
+                put_u32(out, u32::try_from(samples.len()).unwrap());
+                data_offset_at = out.len();
+                put_u32(out, 0);
+                for sample in samples {
+                    let flags = if sample.is_sync { SYNC } else { NON_SYNC };
+                    put_u32s(out, &[sample.duration, sample.size, flags]);
+                }
+            });
+        });
+    });
+    // Data starts right after mdat header
+    let data_offset = u32::try_from(out.len() + 8).unwrap();
+    out[data_offset_at..data_offset_at + 4].copy_from_slice(&data_offset.to_be_bytes());
+    write_box(&mut out, b"mdat", |out| out.extend_from_slice(data));
+    out
+}
+
+fn write_box(out: &mut Vec<u8>, kind: &[u8], body: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    put_u32(out, 0);
+    out.extend_from_slice(kind);
+    body(out);
+    let len = u32::try_from(out.len() - start).expect("box over 4 GiB");
+    out[start..start + 4].copy_from_slice(&len.to_be_bytes());
+}
+
+fn full_box(
+    out: &mut Vec<u8>,
+    kind: &[u8],
+    version: u8,
+    flags: u32,
+    body: impl FnOnce(&mut Vec<u8>),
+) {
+    write_box(out, kind, |out| {
+        put_u32(out, (u32::from(version) << 24) | flags);
+        body(out);
+    });
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u16s(out: &mut Vec<u8>, values: &[u16]) {
+    for &value in values {
+        put_u16(out, value);
+    }
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u32s(out: &mut Vec<u8>, values: &[u32]) {
+    for &value in values {
+        put_u32(out, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sample, SampleEntry, Track, fragment, init_segment};
+
+    /// Kinds and lengths of top level boxes
+    fn boxes(mut buf: &[u8]) -> Vec<(String, usize)> {
+        let mut boxes = Vec::new();
+        while let Some(header) = buf.first_chunk::<8>() {
+            let len = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+            boxes.push((String::from_utf8_lossy(&header[4..]).into_owned(), len));
+            buf = &buf[len..];
+        }
+        boxes
+    }
+
+    #[test]
+    fn init_of_alac() {
+        let init = init_segment(&Track {
+            timescale: 44100,
+            entry: SampleEntry::Alac {
+                channels: 2,
+                sample_rate: 44100,
+                bits: 16,
+                cookie: vec![0xac; 24],
+            },
+        });
+
+        let kinds: Vec<_> = boxes(&init).into_iter().map(|(kind, _)| kind).collect();
+        assert_eq!(["ftyp", "moov"], kinds[..]);
+        let alac = init.windows(4).position(|kind| kind == b"alac").unwrap();
+        // Sample entry holding the full box with the cookie
+        let inner = init[alac + 4..]
+            .windows(4)
+            .position(|kind| kind == b"alac")
+            .unwrap();
+        assert_eq!([0xac; 24], init[alac + 4 + inner + 8..][..24]);
+        assert!(init.windows(4).any(|kind| kind == b"soun"));
+    }
+
+    #[test]
+    fn fragment_points_at_data() {
+        let samples = [
+            Sample {
+                duration: 3000,
+                size: 3,
+                is_sync: true,
+            },
+            Sample {
+                duration: 3000,
+                size: 2,
+                is_sync: false,
+            },
+        ];
+        let frag = fragment(7, 90_000, &samples, &[1, 2, 3, 4, 5]);
+
+        let [(moof, moof_len), (mdat, mdat_len)] = &boxes(&frag)[..] else {
+            panic!("must be moof and mdat");
+        };
+        assert_eq!(("moof", "mdat"), (moof.as_str(), mdat.as_str()));
+        assert_eq!(8 + 5, *mdat_len);
+
+        let trun = frag.windows(4).position(|kind| kind == b"trun").unwrap();
+        let field = |pos: usize| u32::from_be_bytes(frag[pos..pos + 4].try_into().unwrap());
+        // Flags, then sample count and data offset
+        assert_eq!(0x701, field(trun + 4));
+        assert_eq!(2, field(trun + 8));
+        let data_offset = field(trun + 12) as usize;
+        assert_eq!(moof_len + 8, data_offset);
+        assert_eq!([1, 2, 3, 4, 5], frag[data_offset..]);
+        assert_eq!(
+            [3000, 3, 0x0200_0000],
+            [field(trun + 16), field(trun + 20), field(trun + 24)]
+        );
+
+        let tfdt = frag.windows(4).position(|kind| kind == b"tfdt").unwrap();
+        assert_eq!(90_000u64.to_be_bytes(), frag[tfdt + 8..tfdt + 16]);
+    }
+}
@@ -1,9 +1,18 @@
 use std::{error::Error, future::Future, sync::Weak};
 
+pub mod artwork;
 pub mod audio;
+pub mod channel;
+pub mod dmap;
+pub mod event;
+pub mod hls;
 pub mod null;
+pub mod session;
+pub mod stats;
 pub mod video;
 
+pub use crate::streaming::StreamError;
+
 pub trait Device: Send + Sync + 'static {
     type Params;
     type Stream: Stream;
@@ -25,6 +34,17 @@ pub trait Stream: Send + Sync + 'static {
     type Content;
 
     fn on_data(&self, content: Self::Content);
+    /// Called with each packet as read off the network, before it's decrypted and passed to
+    /// [`Stream::on_data`], e.g. to relay it without decrypting and encrypting it again.
+    ///
+    /// Packets of TCP streams come without their length prefix, mirroring ones with their
+    /// 128 bytes header, malformed ones aren't passed. `packet` borrows the receive buffer for
+    /// the call only, copy it to keep it. It's called on the task reading packets, alongside
+    /// [`Stream::on_data`], so it mustn't block.
+    fn on_raw(&self, packet: &[u8]) {
+        let _ = packet;
+    }
     fn on_ok(self);
+    /// `err` is a [`StreamError`], downcast it to tell network failures from protocol ones.
     fn on_err(self, err: Box<dyn Error>);
 }
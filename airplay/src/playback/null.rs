@@ -4,8 +4,8 @@ use std::{
 
 use super::{
     ChannelHandle, Device, Stream,
-    audio::{AudioDevice, AudioPacket, AudioParams},
-    video::{VideoDevice, VideoPacket, VideoParams},
+    audio::{AudioDevice, AudioPacket, AudioParams, AudioStream},
+    video::{VideoDevice, VideoPacket, VideoParams, VideoStream},
 };
 
 pub struct NullDevice<Params, Content>(PhantomData<(Params, Content)>);
@@ -52,6 +52,10 @@ impl AudioDevice for NullDevice<AudioParams, AudioPacket> {
 
 impl VideoDevice for NullDevice<VideoParams, VideoPacket> {}
 
+impl AudioStream for NullStream<AudioPacket> {}
+
+impl VideoStream for NullStream<VideoPacket> {}
+
 pub struct NullStream<C>(PhantomData<C>);
 
 unsafe impl<C> Send for NullStream<C> {}
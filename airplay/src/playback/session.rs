@@ -0,0 +1,211 @@
+use std::time::Duration;
+
+use super::{artwork::Artwork, dmap::TrackMetadata};
+
+/// Callbacks about the whole session rather than a particular stream.
+pub trait SessionHandler: Send + Sync + 'static {
+    /// Volume in dB, either [`MUTED`] or clamped to [`MIN_VOLUME`]..=[`MAX_VOLUME`]
+    fn on_volume(&self, volume: f32) {
+        let _ = volume;
+    }
+
+    fn on_progress(&self, progress: PlaybackProgress) {
+        let _ = progress;
+    }
+
+    fn on_metadata(&self, metadata: TrackMetadata) {
+        let _ = metadata;
+    }
+
+    /// Cover art of the playing track, left for the app to decode
+    fn on_artwork(&self, artwork: Artwork) {
+        let _ = artwork;
+    }
+
+    /// Playing track has no cover art, the previous one is no longer shown.
+    fn on_artwork_cleared(&self) {}
+
+    /// Sender introduced itself while setting the session up, the returned quirks apply to
+    /// streams it sets up afterwards.
+    fn on_sender(&self, sender: &SenderDevice) -> Quirks {
+        let _ = sender;
+        Quirks::default()
+    }
+
+    /// Sender set a media session up, e.g. to show who's playing. A sender taking over the
+    /// session ends the previous one first.
+    fn on_session_begin(&self, sender: SenderDevice) {
+        let _ = sender;
+    }
+
+    /// Session begun last ended, whether it was torn down, the sender stopped
+    /// posting feedback or its connection dropped.
+    fn on_session_end(&self) {}
+}
+
+pub const MUTED: f32 = -144.0;
+pub const MIN_VOLUME: f32 = -30.0;
+pub const MAX_VOLUME: f32 = 0.0;
+
+/// RTP timestamps of the playing track, e.g. to draw a seek bar with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlaybackProgress {
+    pub start: u32,
+    pub current: u32,
+    pub end: u32,
+    /// Of the audio stream timestamps count samples of, zero if none was set up
+    pub sample_rate: u32,
+}
+
+/// Device of the sender as it described itself, e.g. to work around bugs of some of them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SenderDevice {
+    pub name: String,
+    /// Hardware model, e.g. `iPhone14,2`
+    pub model: String,
+    pub device_id: String,
+    pub mac_addr: String,
+    /// E.g. `iPhone OS`
+    pub os_name: Option<String>,
+    /// E.g. `17.5.1`
+    pub os_version: Option<String>,
+    pub os_build_version: Option<String>,
+    /// `User-Agent` header of the setup request, e.g. `AirPlay/770.8.1`
+    pub user_agent: Option<String>,
+}
+
+/// Workarounds for a particular sender, applied on top of [`Config`](crate::config::Config).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Quirks {
+    /// Added to [`Video::decrypted_other_kinds`](crate::config::Video::decrypted_other_kinds)
+    pub decrypted_other_kinds: Vec<u16>,
+}
+
+#[derive(Debug, Default)]
+pub struct NullSession;
+
+impl SessionHandler for NullSession {}
+
+impl PlaybackProgress {
+    const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+    /// Played part of the track, `None` without the sample rate.
+    ///
+    /// Position before the start counts as the start and the one past the end as the end.
+    #[must_use]
+    pub fn elapsed(&self) -> Option<Duration> {
+        // Half of the timestamp space behind the start is considered before it
+        #[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+        let samples = (self.current.wrapping_sub(self.start) as i32).max(0) as u32;
+        self.samples_duration(samples.min(self.samples()))
+    }
+
+    /// Length of the track, `None` without the sample rate.
+    #[must_use]
+    pub fn duration(&self) -> Option<Duration> {
+        self.samples_duration(self.samples())
+    }
+
+    /// Played part of the track in 0.0..=1.0, `None` if it has no length, e.g. a live stream.
+    #[must_use]
+    pub fn fraction(&self) -> Option<f64> {
+        let total = self.samples();
+        if total == 0 {
+            return None;
+        }
+        #[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+        let played = (self.current.wrapping_sub(self.start) as i32).max(0) as u32;
+        Some(f64::from(played.min(total)) / f64::from(total))
+    }
+
+    /// Timestamps wrap around, so the end may be numerically below the start
+    fn samples(&self) -> u32 {
+        self.end.wrapping_sub(self.start)
+    }
+
+    fn samples_duration(&self, samples: u32) -> Option<Duration> {
+        let sample_rate = u64::from(self.sample_rate);
+        (sample_rate != 0)
+            .then(|| Duration::from_nanos(u64::from(samples) * Self::NANOS_PER_SEC / sample_rate))
+    }
+}
+
+/// Maps volume in dB into the range senders use, values at [`MUTED`] and below mean muted.
+#[must_use]
+pub fn normalize_volume(volume: f32) -> f32 {
+    if volume <= MUTED {
+        MUTED
+    } else if volume.is_nan() {
+        MAX_VOLUME
+    } else {
+        volume.clamp(MIN_VOLUME, MAX_VOLUME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{MAX_VOLUME, MIN_VOLUME, MUTED, PlaybackProgress, normalize_volume};
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn volume_range() {
+        assert_eq!(-12.5, normalize_volume(-12.5));
+        assert_eq!(MIN_VOLUME, normalize_volume(-100.0));
+        assert_eq!(MAX_VOLUME, normalize_volume(3.0));
+        assert_eq!(MUTED, normalize_volume(-144.0));
+        assert_eq!(MUTED, normalize_volume(f32::NEG_INFINITY));
+    }
+
+    fn track(start: u32, current: u32, end: u32) -> PlaybackProgress {
+        PlaybackProgress {
+            start,
+            current,
+            end,
+            sample_rate: 44100,
+        }
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn progress_in_seconds() {
+        let progress = track(1000, 1000 + 44100 * 3 / 2, 1000 + 44100 * 6);
+        assert_eq!(Some(Duration::from_millis(1500)), progress.elapsed());
+        assert_eq!(Some(Duration::from_secs(6)), progress.duration());
+        assert_eq!(Some(0.25), progress.fraction());
+
+        // Timestamps wrapped around during the track
+        let progress = track(u32::MAX - 44099, 44100, 44100 * 3);
+        assert_eq!(Some(Duration::from_secs(2)), progress.elapsed());
+        assert_eq!(Some(Duration::from_secs(4)), progress.duration());
+        assert_eq!(Some(0.5), progress.fraction());
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn progress_out_of_track_clamped() {
+        assert_eq!(Some(Duration::ZERO), track(44100, 0, 88200).elapsed());
+        assert_eq!(Some(0.0), track(44100, 0, 88200).fraction());
+        assert_eq!(
+            Some(Duration::from_secs(1)),
+            track(0, 441_000, 44100).elapsed()
+        );
+        assert_eq!(Some(1.0), track(0, 441_000, 44100).fraction());
+    }
+
+    #[test]
+    fn progress_without_length_or_rate() {
+        let live = track(500, 600, 500);
+        assert_eq!(None, live.fraction());
+        assert_eq!(Some(Duration::ZERO), live.elapsed());
+
+        let unknown_rate = PlaybackProgress {
+            sample_rate: 0,
+            ..track(0, 44100, 88200)
+        };
+        assert_eq!(None, unknown_rate.elapsed());
+        assert_eq!(None, unknown_rate.duration());
+        assert_eq!(Some(0.5), unknown_rate.fraction());
+    }
+}
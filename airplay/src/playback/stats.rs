@@ -0,0 +1,137 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub use crate::streaming::{StreamKind, TimingMode};
+
+use super::{audio::AudioParams, video::VideoParams};
+
+/// Counters of a single stream, updated by its processor without locking.
+#[derive(Debug, Default)]
+pub struct StreamStats {
+    packets: AtomicU64,
+    bytes: AtomicU64,
+    decrypt_failures: AtomicU64,
+    seq_gaps: AtomicU64,
+    reassembly_drops: AtomicU64,
+    payload_type_mismatches: AtomicU64,
+    late_drops: AtomicU64,
+    sink_drops: AtomicU64,
+    unsynced_drops: AtomicU64,
+    truncated_drops: AtomicU64,
+}
+
+/// Point in time copy of [`StreamStats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamStatsSnapshot {
+    /// Packets read, including malformed and undecryptable ones
+    pub packets: u64,
+    /// Bytes of read packets
+    pub bytes: u64,
+    pub decrypt_failures: u64,
+    /// Audio packets missing between the received ones
+    pub seq_gaps: u64,
+    /// Incomplete video frames discarded
+    pub reassembly_drops: u64,
+    /// Packets of other RTP payload type than the negotiated one, dropped
+    pub payload_type_mismatches: u64,
+    /// Realtime audio packets arrived after their playout time, dropped
+    pub late_drops: u64,
+    /// Audio packets dropped as the stream fell behind taking them, see
+    /// [`Backpressure`](crate::playback::channel::Backpressure)
+    pub sink_drops: u64,
+    /// Realtime audio packets held while sync of the sender's clock was lost, dropped once
+    /// overdue for too long
+    pub unsynced_drops: u64,
+    /// Realtime audio datagrams longer than
+    /// [`BufferConfig::audio_packet`](crate::config::BufferConfig::audio_packet), dropped
+    pub truncated_drops: u64,
+}
+
+/// Snapshot of a running stream, see [`RouterService::stats`](crate::rtsp::RouterService::stats).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamStatus {
+    pub id: u64,
+    pub kind: StreamKind,
+    pub stats: StreamStatsSnapshot,
+}
+
+/// What was negotiated in setup of a stream, known before its first packet arrives, see
+/// [`RouterService::stream`](crate::rtsp::RouterService::stream).
+#[derive(Debug, Clone, Copy)]
+pub struct StreamDescriptor {
+    pub id: u64,
+    pub kind: StreamKind,
+    /// As passed to the device creating the stream, frame dimensions of video come with its
+    /// packets only
+    pub params: StreamParams,
+    /// Port the sender sends packets to
+    pub local_data_port: u16,
+    /// Port of retransmits and sync packets, of realtime audio only
+    pub local_control_port: Option<u16>,
+    /// Clock of the session when the stream was set up, `None` if sender info set up none
+    pub timing: Option<TimingMode>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum StreamParams {
+    Audio(AudioParams),
+    Video(VideoParams),
+}
+
+impl StreamStats {
+    pub(crate) fn on_packet(&self, len: usize) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_decrypt_failure(&self) {
+        self.decrypt_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_seq_gap(&self, missing: u16) {
+        self.seq_gaps.fetch_add(missing.into(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_reassembly_drop(&self) {
+        self.reassembly_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_payload_type_mismatch(&self) {
+        self.payload_type_mismatches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_late_drop(&self) {
+        self.late_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_sink_drop(&self) {
+        self.sink_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_unsynced_drop(&self) {
+        self.unsynced_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_truncated_drop(&self) {
+        self.truncated_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn packets(&self) -> u64 {
+        self.packets.load(Ordering::Relaxed)
+    }
+
+    /// Counters are read one by one, so they may be off by the packets read meanwhile.
+    pub fn snapshot(&self) -> StreamStatsSnapshot {
+        StreamStatsSnapshot {
+            packets: self.packets.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            decrypt_failures: self.decrypt_failures.load(Ordering::Relaxed),
+            seq_gaps: self.seq_gaps.load(Ordering::Relaxed),
+            reassembly_drops: self.reassembly_drops.load(Ordering::Relaxed),
+            payload_type_mismatches: self.payload_type_mismatches.load(Ordering::Relaxed),
+            late_drops: self.late_drops.load(Ordering::Relaxed),
+            sink_drops: self.sink_drops.load(Ordering::Relaxed),
+            unsynced_drops: self.unsynced_drops.load(Ordering::Relaxed),
+            truncated_drops: self.truncated_drops.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -1,26 +1,635 @@
-use bytes::BytesMut;
+use std::time::{Duration, SystemTime};
 
-use super::{Device, Stream};
+use bytes::{Bytes, BytesMut};
+use thiserror::Error;
+
+use super::{Device, Stream, StreamError, stats::StreamStats};
+
+/// Longest frame, whether sent in a single packet or reassembled, larger ones end the stream
+pub(crate) const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
 
 pub trait VideoDevice: Device<Params = VideoParams, Stream: VideoStream> {}
 
-pub trait VideoStream: Stream<Content = VideoPacket> {}
-impl<T> VideoStream for T where T: Stream<Content = VideoPacket> {}
+/// Hooks of video streams on top of [`Stream`].
+///
+/// Implemented per stream rather than for every `Stream<Content = VideoPacket>`, so the hooks
+/// can be overridden. Streams without interest in them take the defaults with an empty
+/// `impl VideoStream for MyStream {}`.
+pub trait VideoStream: Stream<Content = VideoPacket> {
+    /// Called with parsed [`PacketKind::AvcC`] packet before it's passed to [`Stream::on_data`].
+    fn on_format(&self, config: AvccConfig) {
+        let _ = config;
+    }
+
+    /// Called with parsed [`PacketKind::HvcC`] packet before it's passed to [`Stream::on_data`].
+    fn on_hevc_format(&self, config: HvccConfig) {
+        let _ = config;
+    }
+
+    /// Called with joined payload packets instead of [`Stream::on_data`] if
+    /// [`Video::reassemble_frames`](crate::config::Video::reassemble_frames) is set.
+    fn on_frame(&self, frame: AccessUnit) {
+        let _ = frame;
+    }
+}
 
 #[derive(Debug, Clone, Copy)]
 #[non_exhaustive]
-pub struct VideoParams {}
+pub struct VideoParams {
+    /// Delay the sender expects between capture and display of frames
+    pub latency: Duration,
+    /// Whole screen rather than a single app is mirrored, `None` if the sender didn't tell
+    pub using_screen: Option<bool>,
+}
 
+/// Codec of mirrored frames, told by their codec record. H.265 is sent only if offered with
+/// [`Video::hevc`](crate::config::Video::hevc).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum VideoCodec {
+    #[default]
+    H264,
+    H265,
+}
+
+/// Mirroring packet, read from the 128 bytes header followed by payload:
+/// ```text
+/// | len(32) | kind(16) | option(16) | timestamp(64) | block(112 * 8) |
+/// ```
+/// All fields are little endian. There's neither SSRC nor per-frame nonce in the header,
+/// payloads are decrypted with a keystream running through the whole stream. Position of a
+/// payload in the keystream is the sum of `len` of the encrypted packets before it.
 #[derive(Debug)]
 pub struct VideoPacket {
     pub kind: PacketKind,
+    /// Flags following the kind, e.g. `0x1e` in payload packets, meaning isn't known. None of
+    /// them is known to mark the last packet of a frame
+    pub option: u16,
+    /// NTP time of the sender, when the frame is to be shown.
+    ///
+    /// It's the decode time as well: the header carries no other timestamp and senders encode
+    /// mirrored frames without reordering, so they're decoded in the order they come.
     pub timestamp: u64,
+    /// Local time the frame is to be shown at, [`Self::timestamp`] mapped through the sender's
+    /// clock. `None` until the clock is synced
+    pub pts: Option<SystemTime>,
+    /// Local time the frame is to be decoded at, the same as [`Self::pts`] for the reason above
+    pub dts: Option<SystemTime>,
+    /// Read from the block of [`PacketKind::AvcC`] and [`PacketKind::HvcC`] packets
+    pub dimensions: Option<FrameDimensions>,
+    /// The whole block as is, parts not listed above aren't known
+    pub reserved: Bytes,
+    /// Payload holds an IDR slice, decoding can start there. Set for plaintext frames only
+    pub is_keyframe: bool,
     pub payload: BytesMut,
 }
 
+impl VideoPacket {
+    pub const HEADER_LEN: usize = 128;
+}
+
+/// Single payload packet of the whole frame, for streams taking frames as packets.
+impl From<AccessUnit> for VideoPacket {
+    fn from(frame: AccessUnit) -> Self {
+        Self {
+            kind: PacketKind::Payload,
+            option: 0,
+            timestamp: frame.timestamp,
+            pts: frame.pts,
+            dts: frame.dts,
+            dimensions: None,
+            reserved: Bytes::new(),
+            is_keyframe: frame.is_keyframe,
+            payload: frame.data,
+        }
+    }
+}
+
+/// Sizes of the mirrored screen and of the encoded frames, in pixels
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameDimensions {
+    pub source_width: f32,
+    pub source_height: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+/// All payload packets of the same timestamp.
+#[derive(Debug)]
+pub struct AccessUnit {
+    /// Presentation and decode time at once, see [`VideoPacket::timestamp`]
+    pub timestamp: u64,
+    /// See [`VideoPacket::pts`]
+    pub pts: Option<SystemTime>,
+    /// See [`VideoPacket::dts`]
+    pub dts: Option<SystemTime>,
+    /// Any of the joined packets is a keyframe, see [`VideoPacket::is_keyframe`]
+    pub is_keyframe: bool,
+    /// NAL units prefixed with their length, see [`AvccConfig::nal_length_size`]
+    pub data: BytesMut,
+}
+
+/// Joins payload packets into [`AccessUnit`]s, frame is complete once the next one starts.
+///
+/// The last frame is discarded on drop, as there's no telling whether all of it came.
+#[derive(Debug)]
+pub(crate) struct FrameAssembler<'a> {
+    pending: Option<AccessUnit>,
+    stats: &'a StreamStats,
+}
+
+/// Type of mirroring packet.
+///
+/// Known codes are 0 and 4096 for encrypted payload, 1 for plaintext codec record, which is
+/// avcC or hvcC told apart by its layout; 2 (heartbeat) and 5 (streaming report) carry no
+/// video. Other codes are passed in ciphertext unless listed in
+/// [`Video::decrypted_other_kinds`](crate::config::Video::decrypted_other_kinds).
 #[derive(Debug, Clone, Copy)]
 pub enum PacketKind {
     AvcC,
+    HvcC,
     Payload,
     Other(u16),
 }
+
+/// Parsed `AVCDecoderConfigurationRecord`
+#[derive(Debug, Clone)]
+pub struct AvccConfig {
+    pub profile: u8,
+    pub profile_compatibility: u8,
+    pub level: u8,
+    /// Size of length prefix of every NAL unit in payload packets
+    pub nal_length_size: u8,
+    pub sps: Vec<Bytes>,
+    pub pps: Vec<Bytes>,
+}
+
+/// Parsed `HEVCDecoderConfigurationRecord`
+#[derive(Debug, Clone)]
+pub struct HvccConfig {
+    pub profile_space: u8,
+    pub tier: bool,
+    pub profile: u8,
+    pub profile_compatibility: u32,
+    pub level: u8,
+    /// Size of length prefix of every NAL unit in payload packets
+    pub nal_length_size: u8,
+    pub vps: Vec<Bytes>,
+    pub sps: Vec<Bytes>,
+    pub pps: Vec<Bytes>,
+}
+
+#[derive(Debug, Error)]
+pub enum VideoError {
+    #[error("truncated record")]
+    Truncated,
+    #[error("invalid version: {0}")]
+    InvalidVersion(u8),
+}
+
+/// # Errors
+///
+/// If record is truncated or has unknown version.
+pub fn parse_avcc(buf: &[u8]) -> Result<AvccConfig, VideoError> {
+    let Some(
+        &[
+            version,
+            profile,
+            profile_compatibility,
+            level,
+            length_size,
+            sps_count,
+        ],
+    ) = buf.first_chunk::<6>()
+    else {
+        return Err(VideoError::Truncated);
+    };
+    if version != 1 {
+        return Err(VideoError::InvalidVersion(version));
+    }
+
+    let mut rest = &buf[6..];
+    let sps = parameter_sets(&mut rest, sps_count & 0x1f)?;
+    let Some((&pps_count, tail)) = rest.split_first() else {
+        return Err(VideoError::Truncated);
+    };
+    rest = tail;
+    let pps = parameter_sets(&mut rest, pps_count)?;
+
+    Ok(AvccConfig {
+        profile,
+        profile_compatibility,
+        level,
+        nal_length_size: (length_size & 0b11) + 1,
+        sps,
+        pps,
+    })
+}
+
+/// Codec of a codec record, hvcC has reserved bits set where avcC has its parameter sets.
+pub(crate) fn record_codec(record: &[u8]) -> VideoCodec {
+    // Reserved bits before segmentation, parallelism, chroma format and both bit depths
+    const HVCC_RESERVED: [(usize, u8); 5] =
+        [(13, 0xf0), (15, 0xfc), (16, 0xfc), (17, 0xf8), (18, 0xf8)];
+
+    let reserved_set = HVCC_RESERVED
+        .iter()
+        .all(|&(pos, mask)| record.get(pos).is_some_and(|byte| byte & mask == mask));
+    if reserved_set && parse_hvcc(record).is_ok() {
+        VideoCodec::H265
+    } else {
+        VideoCodec::H264
+    }
+}
+
+/// # Errors
+///
+/// If record is truncated or has unknown version.
+pub fn parse_hvcc(buf: &[u8]) -> Result<HvccConfig, VideoError> {
+    const VPS: u8 = 32;
+    const SPS: u8 = 33;
+    const PPS: u8 = 34;
+
+    let Some(header) = buf.first_chunk::<23>() else {
+        return Err(VideoError::Truncated);
+    };
+    if header[0] != 1 {
+        return Err(VideoError::InvalidVersion(header[0]));
+    }
+    let &[_, _, c0, c1, c2, c3, ..] = header;
+
+    let mut config = HvccConfig {
+        profile_space: header[1] >> 6,
+        tier: header[1] & 0x20 != 0,
+        profile: header[1] & 0x1f,
+        profile_compatibility: u32::from_be_bytes([c0, c1, c2, c3]),
+        level: header[12],
+        nal_length_size: (header[21] & 0b11) + 1,
+        vps: Vec::new(),
+        sps: Vec::new(),
+        pps: Vec::new(),
+    };
+
+    // Arrays of NAL units of the same type, the ones not needed for decoding are skipped
+    let mut rest = &buf[23..];
+    for _ in 0..header[22] {
+        let Some((&[ty, count_hi, count_lo], tail)) = rest.split_first_chunk::<3>() else {
+            return Err(VideoError::Truncated);
+        };
+        rest = tail;
+
+        let count = u16::from_be_bytes([count_hi, count_lo]);
+        let sets = (0..count)
+            .map(|_| parameter_set(&mut rest))
+            .collect::<Result<Vec<_>, _>>()?;
+        match ty & 0x3f {
+            VPS => config.vps.extend(sets),
+            SPS => config.sps.extend(sets),
+            PPS => config.pps.extend(sets),
+            _ => {}
+        }
+    }
+
+    Ok(config)
+}
+
+impl<'a> FrameAssembler<'a> {
+    pub(crate) fn new(stats: &'a StreamStats) -> Self {
+        Self {
+            pending: None,
+            stats,
+        }
+    }
+
+    /// Returns the previous frame if `timestamp` starts a new one.
+    pub(crate) fn push(
+        &mut self,
+        timestamp: u64,
+        pts: Option<SystemTime>,
+        payload: BytesMut,
+        is_keyframe: bool,
+    ) -> Result<Option<AccessUnit>, StreamError> {
+        match &mut self.pending {
+            // Packets carved one after another from the same buffer are joined without copying
+            Some(frame) if frame.timestamp == timestamp => {
+                let len = frame.data.len() + payload.len();
+                if len > MAX_FRAME_LEN {
+                    return Err(StreamError::Reassembly { timestamp, len });
+                }
+                frame.data.unsplit(payload);
+                frame.is_keyframe |= is_keyframe;
+                Ok(None)
+            }
+            pending => Ok(pending.replace(AccessUnit {
+                timestamp,
+                pts,
+                dts: pts,
+                is_keyframe,
+                data: payload,
+            })),
+        }
+    }
+}
+
+impl Drop for FrameAssembler<'_> {
+    fn drop(&mut self) {
+        if let Some(frame) = self.pending.take() {
+            self.stats.on_reassembly_drop();
+            tracing::warn!(
+                timestamp = %frame.timestamp,
+                len = %frame.data.len(),
+                "incomplete frame discarded"
+            );
+        }
+    }
+}
+
+impl FrameDimensions {
+    /// Length of a block following the timestamp
+    pub(crate) const BLOCK_LEN: usize = 112;
+
+    /// Four floats at 24 and 40 bytes into the block.
+    pub(crate) fn parse(block: &[u8; Self::BLOCK_LEN]) -> Self {
+        let float = |pos: usize| f32::from_le_bytes(block[pos..pos + 4].try_into().unwrap());
+
+        Self {
+            source_width: float(24),
+            source_height: float(28),
+            width: float(40),
+            height: float(44),
+        }
+    }
+}
+
+/// Whether payload is already a sequence of NAL units of `codec` prefixed with 4 bytes length,
+/// which ciphertext virtually never is.
+pub(crate) fn is_plain_nal_units(codec: VideoCodec, mut buf: &[u8]) -> bool {
+    if buf.is_empty() {
+        return false;
+    }
+
+    while let Some((len, rest)) = buf.split_first_chunk::<4>() {
+        let len = u32::from_be_bytes(*len) as usize;
+        if len == 0 || rest.len() < len {
+            return false;
+        }
+
+        // Forbidden zero bit of NAL header must be unset, two bytes header of H.265 also has
+        // non-zero temporal ID
+        let header_valid = match codec {
+            VideoCodec::H264 => rest[0] & 0x80 == 0,
+            VideoCodec::H265 => len >= 2 && rest[0] & 0x80 == 0 && rest[1] & 0x07 != 0,
+        };
+        if !header_valid {
+            return false;
+        }
+        buf = &rest[len..];
+    }
+
+    buf.is_empty()
+}
+
+/// Whether NAL units prefixed with 4 bytes length hold an IDR slice, `IDR_W_RADL` or
+/// `IDR_N_LP` of H.265.
+///
+/// Units are walked by their lengths and only their headers are looked at, so neither
+/// emulation prevention bytes nor start code lookalikes in slice data are taken for a header.
+/// Scanning stops at a malformed length, with units before it counted.
+pub(crate) fn contains_keyframe(codec: VideoCodec, mut buf: &[u8]) -> bool {
+    const H264_IDR: u8 = 5;
+    const H265_IDR_W_RADL: u8 = 19;
+    const H265_IDR_N_LP: u8 = 20;
+
+    while let Some((len, rest)) = buf.split_first_chunk::<4>() {
+        let len = u32::from_be_bytes(*len) as usize;
+        let Some(&header) = rest.get(..len).and_then(<[u8]>::first) else {
+            return false;
+        };
+        let idr = match codec {
+            VideoCodec::H264 => header & 0x1f == H264_IDR,
+            VideoCodec::H265 => matches!((header >> 1) & 0x3f, H265_IDR_W_RADL | H265_IDR_N_LP),
+        };
+        if idr && header & 0x80 == 0 {
+            return true;
+        }
+        buf = &rest[len..];
+    }
+
+    false
+}
+
+fn parameter_sets(buf: &mut &[u8], count: u8) -> Result<Vec<Bytes>, VideoError> {
+    (0..count).map(|_| parameter_set(buf)).collect()
+}
+
+fn parameter_set(buf: &mut &[u8]) -> Result<Bytes, VideoError> {
+    let Some((len, rest)) = buf.split_first_chunk::<2>() else {
+        return Err(VideoError::Truncated);
+    };
+    let len = u16::from_be_bytes(*len).into();
+    if rest.len() < len {
+        return Err(VideoError::Truncated);
+    }
+
+    let (set, rest) = rest.split_at(len);
+    *buf = rest;
+    Ok(Bytes::copy_from_slice(set))
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use super::{
+        FrameAssembler, FrameDimensions, MAX_FRAME_LEN, StreamError, VideoCodec, VideoError,
+        contains_keyframe, is_plain_nal_units, parse_avcc, parse_hvcc, record_codec,
+    };
+    use crate::playback::stats::StreamStats;
+
+    const AVCC: &[u8] = &[
+        0x01, 0x64, 0x00, 0x28, 0xff, 0xe1, 0x00, 0x04, 0x67, 0x64, 0x00, 0x28, 0x01, 0x00, 0x03,
+        0x68, 0xee, 0x3c,
+    ];
+
+    #[test]
+    fn parse_avcc_record() {
+        let config = parse_avcc(AVCC).unwrap();
+
+        assert_eq!(0x64, config.profile);
+        assert_eq!(0x28, config.level);
+        assert_eq!(4, config.nal_length_size);
+        assert_eq!(vec![&[0x67, 0x64, 0x00, 0x28][..]], config.sps);
+        assert_eq!(vec![&[0x68, 0xee, 0x3c][..]], config.pps);
+    }
+
+    #[test]
+    fn parse_truncated_avcc_record() {
+        for len in 0..AVCC.len() {
+            assert!(matches!(
+                parse_avcc(&AVCC[..len]),
+                Err(VideoError::Truncated)
+            ));
+        }
+    }
+
+    /// Main profile, level 4, with VPS, SPS, PPS and SEI arrays
+    const HVCC: &[u8] = &[
+        0x01, 0x01, 0x60, 0x00, 0x00, 0x00, 0x90, 0x00, 0x00, 0x00, 0x00, 0x00, 0x78, 0xf0, 0x00,
+        0xfc, 0xfd, 0xf8, 0xf8, 0x00, 0x00, 0x0f, 0x04, // VPS
+        0xa0, 0x00, 0x01, 0x00, 0x03, 0x40, 0x01, 0x0c, // SPS
+        0xa1, 0x00, 0x01, 0x00, 0x04, 0x42, 0x01, 0x01, 0x01, // PPS
+        0xa2, 0x00, 0x01, 0x00, 0x03, 0x44, 0x01, 0xc1, // SEI
+        0x27, 0x00, 0x01, 0x00, 0x02, 0x4e, 0x01,
+    ];
+
+    #[test]
+    fn parse_hvcc_record() {
+        let config = parse_hvcc(HVCC).unwrap();
+
+        assert_eq!(1, config.profile);
+        assert!(!config.tier);
+        assert_eq!(0x6000_0000, config.profile_compatibility);
+        assert_eq!(0x78, config.level);
+        assert_eq!(4, config.nal_length_size);
+        assert_eq!(vec![&[0x40, 0x01, 0x0c][..]], config.vps);
+        assert_eq!(vec![&[0x42, 0x01, 0x01, 0x01][..]], config.sps);
+        assert_eq!(vec![&[0x44, 0x01, 0xc1][..]], config.pps);
+
+        for len in 0..HVCC.len() {
+            assert!(matches!(
+                parse_hvcc(&HVCC[..len]),
+                Err(VideoError::Truncated)
+            ));
+        }
+        assert!(matches!(parse_hvcc(AVCC), Err(VideoError::Truncated)));
+    }
+
+    #[test]
+    fn codec_told_by_record() {
+        assert_eq!(VideoCodec::H265, record_codec(HVCC));
+        assert_eq!(VideoCodec::H264, record_codec(AVCC));
+        // Long avcC with a byte of its SPS where hvcC has reserved bits
+        let mut avcc = AVCC.to_vec();
+        avcc.resize(32, 0xff);
+        assert_eq!(VideoCodec::H264, record_codec(&avcc));
+    }
+
+    #[test]
+    fn detect_plain_hevc_nal_units() {
+        // IDR slice with temporal ID of 1
+        const PLAIN: &[u8] = &[0x00, 0x00, 0x00, 0x03, 0x26, 0x01, 0xaf];
+
+        assert!(is_plain_nal_units(VideoCodec::H265, PLAIN));
+        // Zero temporal ID is invalid
+        assert!(!is_plain_nal_units(
+            VideoCodec::H265,
+            &[0x00, 0x00, 0x00, 0x03, 0x26, 0x00, 0xaf]
+        ));
+        // One byte NAL unit is valid H.264 only
+        let short = &[0x00, 0x00, 0x00, 0x01, 0x09];
+        assert!(is_plain_nal_units(VideoCodec::H264, short));
+        assert!(!is_plain_nal_units(VideoCodec::H265, short));
+    }
+
+    #[test]
+    fn detect_plain_nal_units() {
+        // IDR slice followed by SEI
+        const PLAIN: &[u8] = &[
+            0x00, 0x00, 0x00, 0x03, 0x65, 0x88, 0x84, 0x00, 0x00, 0x00, 0x02, 0x06, 0x05,
+        ];
+
+        let codec = VideoCodec::H264;
+        assert!(is_plain_nal_units(codec, PLAIN));
+        assert!(!is_plain_nal_units(codec, &PLAIN[..PLAIN.len() - 1]));
+        assert!(!is_plain_nal_units(codec, &[]));
+        assert!(!is_plain_nal_units(
+            codec,
+            &[0x9c, 0x3f, 0x01, 0x7a, 0xe2, 0x10]
+        ));
+    }
+
+    #[test]
+    fn detect_keyframes() {
+        // SEI, then non-IDR slice whose data has an emulation prevention byte followed by what
+        // looks like an IDR header
+        const NON_IDR: &[u8] = &[
+            0x00, 0x00, 0x00, 0x02, 0x06, 0x05, 0x00, 0x00, 0x00, 0x06, 0x41, 0x9a, 0x00, 0x00,
+            0x03, 0x65,
+        ];
+        const IDR: &[u8] = &[0x00, 0x00, 0x00, 0x03, 0x65, 0x88, 0x84];
+
+        let codec = VideoCodec::H264;
+        assert!(!contains_keyframe(codec, NON_IDR));
+        assert!(contains_keyframe(codec, &[NON_IDR, IDR].concat()));
+        // Truncated unit isn't looked into
+        assert!(!contains_keyframe(codec, &IDR[..4]));
+        assert!(!contains_keyframe(codec, &[0x00, 0x00, 0x00, 0x09, 0x65]));
+
+        let codec = VideoCodec::H265;
+        // IDR_W_RADL and IDR_N_LP, then TRAIL_R
+        assert!(contains_keyframe(
+            codec,
+            &[0x00, 0x00, 0x00, 0x02, 0x26, 0x01]
+        ));
+        assert!(contains_keyframe(
+            codec,
+            &[0x00, 0x00, 0x00, 0x02, 0x28, 0x01]
+        ));
+        assert!(!contains_keyframe(
+            codec,
+            &[0x00, 0x00, 0x00, 0x02, 0x02, 0x01]
+        ));
+        assert!(!contains_keyframe(codec, IDR));
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn parse_frame_dimensions() {
+        let mut block = [0u8; FrameDimensions::BLOCK_LEN];
+        for (pos, value) in [(24, 2778.0f32), (28, 1284.0), (40, 1920.0), (44, 888.0)] {
+            block[pos..pos + 4].copy_from_slice(&value.to_le_bytes());
+        }
+
+        let dimensions = FrameDimensions::parse(&block);
+        assert_eq!(
+            (2778.0, 1284.0),
+            (dimensions.source_width, dimensions.source_height)
+        );
+        assert_eq!((1920.0, 888.0), (dimensions.width, dimensions.height));
+    }
+
+    #[test]
+    fn assemble_frames() {
+        let stats = StreamStats::default();
+        let mut assembler = FrameAssembler::new(&stats);
+        let mut buf = BytesMut::from(&b"abcdef"[..]);
+        let (first, second, third) = (buf.split_to(2), buf.split_to(2), buf);
+
+        assert!(assembler.push(1, None, first, false).unwrap().is_none());
+        assert!(assembler.push(1, None, second, true).unwrap().is_none());
+        let frame = assembler.push(2, None, third, false).unwrap().unwrap();
+        assert_eq!(1, frame.timestamp);
+        assert!(frame.is_keyframe);
+        assert_eq!(b"abcd", &frame.data[..]);
+
+        assert!(assembler.pending.is_some());
+        drop(assembler);
+        assert_eq!(1, stats.snapshot().reassembly_drops);
+    }
+
+    #[test]
+    fn oversized_frame_rejected() {
+        let stats = StreamStats::default();
+        let mut assembler = FrameAssembler::new(&stats);
+
+        assembler
+            .push(1, None, BytesMut::zeroed(MAX_FRAME_LEN), false)
+            .unwrap();
+        let err = assembler
+            .push(1, None, BytesMut::zeroed(1), false)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            StreamError::Reassembly { timestamp: 1, len } if len == MAX_FRAME_LEN + 1
+        ));
+    }
+}
@@ -0,0 +1,350 @@
+//! DACP remote control of the sender, e.g. to pause playback or skip tracks from the receiver.
+//!
+//! Senders name their DACP service with `DACP-ID` header of RTSP requests and authorize
+//! commands with `Active-Remote` one. The service is published as `iTunes_Ctrl_<DACP-ID>`,
+//! commands are HTTP GETs of `/ctrl-int/1/<command>`. Volume is set with
+//! `/ctrl-int/1/setproperty?dmcp.device-volume=<dB>` instead, in the range senders set it
+//! on the receiver.
+
+use std::{io, net::SocketAddr, time::Duration};
+
+use http::HeaderMap;
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+
+use crate::playback::session::normalize_volume;
+use thiserror::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+/// Identity of the sender's DACP service taken from RTSP headers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteId {
+    pub dacp_id: String,
+    pub active_remote: String,
+}
+
+/// DACP service of a sender, resolved with [`Remote::resolve`].
+#[derive(Debug, Clone)]
+pub struct Remote {
+    id: RemoteId,
+    addr: SocketAddr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Play,
+    Pause,
+    PlayPause,
+    Stop,
+    Next,
+    Previous,
+    VolumeUp,
+    VolumeDown,
+    Mute,
+}
+
+#[derive(Debug, Error)]
+pub enum RemoteError {
+    #[error("sender offered no remote control")]
+    NotOffered,
+    #[error("mdns: {0}")]
+    Mdns(#[from] mdns_sd::Error),
+    /// Service isn't visible from here, e.g. the sender is behind NAT
+    #[error("dacp service {0} not resolved")]
+    NotResolved(String),
+    #[error("command not sent: {0}")]
+    Io(#[from] io::Error),
+    #[error("command refused with {0}")]
+    Status(u16),
+    #[error("malformed response")]
+    MalformedResponse,
+}
+
+impl RemoteId {
+    /// Both headers are needed, either alone is of no use.
+    #[must_use]
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+
+        Some(Self {
+            dacp_id: header("dacp-id")?,
+            active_remote: header("active-remote")?,
+        })
+    }
+}
+
+impl Command {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Play => "play",
+            Self::Pause => "pause",
+            Self::PlayPause => "playpause",
+            Self::Stop => "stop",
+            Self::Next => "nextitem",
+            Self::Previous => "previtem",
+            Self::VolumeUp => "volumeup",
+            Self::VolumeDown => "volumedown",
+            Self::Mute => "mutetoggle",
+        }
+    }
+}
+
+impl Remote {
+    const SERVICE_TYPE: &'static str = "_dacp._tcp.local.";
+    /// Senders answer right away, the ones that don't are likely gone
+    const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+    const MAX_RESPONSE_LEN: usize = 4096;
+
+    /// Remote of the service known to listen on `addr`.
+    #[must_use]
+    pub fn new(id: RemoteId, addr: SocketAddr) -> Self {
+        Self { id, addr }
+    }
+
+    /// Looks the service of `id` up over mDNS for up to `wait`.
+    ///
+    /// # Errors
+    ///
+    /// If mDNS daemon couldn't be started or the service wasn't found in time.
+    pub async fn resolve(id: RemoteId, wait: Duration) -> Result<Self, RemoteError> {
+        let fullname = format!("iTunes_Ctrl_{}.{}", id.dacp_id, Self::SERVICE_TYPE);
+
+        let daemon = ServiceDaemon::new()?;
+        let events = daemon.browse(Self::SERVICE_TYPE)?;
+        let found = timeout(wait, async {
+            while let Ok(event) = events.recv_async().await {
+                let ServiceEvent::ServiceResolved(info) = event else {
+                    continue;
+                };
+                if !info.get_fullname().eq_ignore_ascii_case(&fullname) {
+                    continue;
+                }
+                // IPv4 first, link-local IPv6 would need the scope of the interface
+                let ip = info.get_addresses().iter().min_by_key(|ip| ip.is_ipv6());
+                if let Some(ip) = ip {
+                    return Some(SocketAddr::new(*ip, info.get_port()));
+                }
+            }
+            None
+        })
+        .await;
+        let _ = daemon.shutdown();
+
+        match found {
+            Ok(Some(addr)) => {
+                tracing::debug!(%fullname, %addr, "dacp service resolved");
+                Ok(Self::new(id, addr))
+            }
+            _ => Err(RemoteError::NotResolved(fullname)),
+        }
+    }
+
+    #[must_use]
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// # Errors
+    ///
+    /// See [`Remote::send`].
+    pub async fn play(&self) -> Result<(), RemoteError> {
+        self.send(Command::Play).await
+    }
+
+    /// # Errors
+    ///
+    /// See [`Remote::send`].
+    pub async fn pause(&self) -> Result<(), RemoteError> {
+        self.send(Command::Pause).await
+    }
+
+    /// # Errors
+    ///
+    /// See [`Remote::send`].
+    pub async fn next(&self) -> Result<(), RemoteError> {
+        self.send(Command::Next).await
+    }
+
+    /// # Errors
+    ///
+    /// See [`Remote::send`].
+    pub async fn previous(&self) -> Result<(), RemoteError> {
+        self.send(Command::Previous).await
+    }
+
+    /// Sets volume of the sender to `volume` in dB, mapped into the range senders use as by
+    /// [`normalize_volume`].
+    ///
+    /// # Errors
+    ///
+    /// See [`Remote::send`].
+    pub async fn set_volume(&self, volume: f32) -> Result<(), RemoteError> {
+        let volume = normalize_volume(volume);
+        self.get(&format!("setproperty?dmcp.device-volume={volume:.6}"))
+            .await
+    }
+
+    /// Sends `command` over a connection of its own.
+    ///
+    /// # Errors
+    ///
+    /// If the service couldn't be reached in time or didn't accept the command, e.g. the
+    /// session has ended and the token isn't valid anymore.
+    pub async fn send(&self, command: Command) -> Result<(), RemoteError> {
+        self.get(command.name()).await
+    }
+
+    /// Requests `/ctrl-int/1/<path>` of the service.
+    async fn get(&self, path: &str) -> Result<(), RemoteError> {
+        let request = format!(
+            "GET /ctrl-int/1/{path} HTTP/1.1\r\nHost: {}\r\nActive-Remote: {}\r\nConnection: close\r\n\r\n",
+            self.addr, self.id.active_remote,
+        );
+
+        let status = timeout(Self::COMMAND_TIMEOUT, async {
+            let mut conn = TcpStream::connect(self.addr).await?;
+            conn.write_all(request.as_bytes()).await?;
+            Self::read_status(&mut conn).await
+        })
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        tracing::debug!(%path, %status, "dacp command sent");
+
+        match status {
+            200..=299 => Ok(()),
+            status => Err(RemoteError::Status(status)),
+        }
+    }
+
+    async fn read_status(conn: &mut TcpStream) -> Result<u16, RemoteError> {
+        let mut buf = Vec::new();
+        loop {
+            let mut headers = [httparse::EMPTY_HEADER; 16];
+            let mut resp = httparse::Response::new(&mut headers);
+            match resp.parse(&buf) {
+                Ok(httparse::Status::Complete(_)) => {
+                    return resp.code.ok_or(RemoteError::MalformedResponse);
+                }
+                Ok(httparse::Status::Partial) if buf.len() < Self::MAX_RESPONSE_LEN => {}
+                _ => return Err(RemoteError::MalformedResponse),
+            }
+
+            let mut chunk = [0; 512];
+            let len = conn.read(&mut chunk).await?;
+            if len == 0 {
+                return Err(RemoteError::MalformedResponse);
+            }
+            buf.extend_from_slice(&chunk[..len]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{HeaderMap, HeaderValue};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::{Command, Remote, RemoteError, RemoteId};
+
+    fn remote_id() -> RemoteId {
+        RemoteId {
+            dacp_id: "14413BE4996FEA4D".to_string(),
+            active_remote: "2081565744".to_string(),
+        }
+    }
+
+    #[test]
+    fn remote_id_from_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("DACP-ID", HeaderValue::from_static("14413BE4996FEA4D"));
+        assert_eq!(None, RemoteId::from_headers(&headers));
+
+        headers.insert("Active-Remote", HeaderValue::from_static("2081565744"));
+        assert_eq!(Some(remote_id()), RemoteId::from_headers(&headers));
+    }
+
+    /// Answers a single command with `status_line`, returns the request
+    async fn answer_once(listener: TcpListener, status_line: &'static str) -> String {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let mut req = vec![0; 1024];
+        let len = conn.read(&mut req).await.unwrap();
+        conn.write_all(status_line.as_bytes()).await.unwrap();
+        conn.write_all(b"Content-Length: 0\r\n\r\n").await.unwrap();
+        String::from_utf8(req[..len].to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn commands_sent_with_token() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote = Remote::new(remote_id(), listener.local_addr().unwrap());
+
+        let server = tokio::spawn(answer_once(listener, "HTTP/1.1 204 No Content\r\n"));
+        remote.pause().await.unwrap();
+        let req = server.await.unwrap();
+        assert!(
+            req.starts_with("GET /ctrl-int/1/pause HTTP/1.1\r\n"),
+            "{req}"
+        );
+        assert!(req.contains("\r\nActive-Remote: 2081565744\r\n"), "{req}");
+
+        let listener = TcpListener::bind(remote.addr()).await.unwrap();
+        let server = tokio::spawn(answer_once(listener, "HTTP/1.1 403 Forbidden\r\n"));
+        assert!(matches!(
+            remote.send(Command::Next).await,
+            Err(RemoteError::Status(403))
+        ));
+        assert!(
+            server
+                .await
+                .unwrap()
+                .starts_with("GET /ctrl-int/1/nextitem ")
+        );
+    }
+
+    #[tokio::test]
+    async fn volume_set_in_sender_range() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote = Remote::new(remote_id(), listener.local_addr().unwrap());
+
+        let server = tokio::spawn(answer_once(listener, "HTTP/1.1 204 No Content\r\n"));
+        remote.set_volume(-15.5).await.unwrap();
+        let req = server.await.unwrap();
+        assert!(
+            req.starts_with(
+                "GET /ctrl-int/1/setproperty?dmcp.device-volume=-15.500000 HTTP/1.1\r\n"
+            ),
+            "{req}"
+        );
+        assert!(req.contains("\r\nActive-Remote: 2081565744\r\n"), "{req}");
+
+        // Below the range but not muted
+        let listener = TcpListener::bind(remote.addr()).await.unwrap();
+        let server = tokio::spawn(answer_once(listener, "HTTP/1.1 204 No Content\r\n"));
+        remote.set_volume(-100.0).await.unwrap();
+        let req = server.await.unwrap();
+        assert!(
+            req.starts_with("GET /ctrl-int/1/setproperty?dmcp.device-volume=-30.000000 "),
+            "{req}"
+        );
+    }
+
+    #[tokio::test]
+    async fn unreachable_remote_fails() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote = Remote::new(remote_id(), listener.local_addr().unwrap());
+        drop(listener);
+
+        assert!(matches!(remote.play().await, Err(RemoteError::Io(_))));
+    }
+}
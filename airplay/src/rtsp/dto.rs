@@ -3,6 +3,12 @@
 use bytes::Bytes;
 use macaddr::MacAddr6;
 use serde::{Deserialize, Serialize, Serializer};
+use thiserror::Error;
+
+use crate::{
+    advertise::StatusFlags, config::Features, playback::session::SenderDevice,
+    streaming::StreamKind,
+};
 
 pub struct StreamId;
 
@@ -10,15 +16,34 @@ impl StreamId {
     pub const AUDIO_REALTIME: u32 = 96;
     pub const AUDIO_BUFFERED: u32 = 103;
     pub const VIDEO: u32 = 110;
+
+    pub fn of(kind: StreamKind) -> u32 {
+        match kind {
+            StreamKind::AudioRealtime => Self::AUDIO_REALTIME,
+            StreamKind::AudioBuffered => Self::AUDIO_BUFFERED,
+            StreamKind::Video => Self::VIDEO,
+        }
+    }
+
+    pub fn kind(ty: u32) -> Option<StreamKind> {
+        match ty {
+            Self::AUDIO_REALTIME => Some(StreamKind::AudioRealtime),
+            Self::AUDIO_BUFFERED => Some(StreamKind::AudioBuffered),
+            Self::VIDEO => Some(StreamKind::Video),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Serialize)]
 pub struct InfoResponse {
-    #[serde(rename = "deviceid")]
+    #[serde(rename = "deviceid", serialize_with = "serialize_mac")]
     pub device_id: MacAddr6,
-    #[serde(rename = "macAddress")]
+    #[serde(rename = "macAddress", serialize_with = "serialize_mac")]
     pub mac_addr: MacAddr6,
     pub features: u64,
+    #[serde(rename = "statusFlags")]
+    pub status_flags: u32,
     pub manufacturer: String,
     pub model: String,
     pub name: String,
@@ -31,6 +56,16 @@ pub struct InfoResponse {
     pub displays: Vec<Display>,
 }
 
+/// Plist isn't a human readable format to serde, which would make the address a byte array
+/// rather than the `AA:BB:CC:DD:EE:FF` string senders expect.
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn serialize_mac<S>(addr: &MacAddr6, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_str(addr)
+}
+
 #[derive(Serialize)]
 pub struct Display {
     #[serde(rename = "widthPixels")]
@@ -43,9 +78,223 @@ pub struct Display {
     pub features: u32,
 }
 
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+pub enum InfoError {
+    #[error("advertised features aren't implemented: {0:?}")]
+    Unimplemented(Features),
+    #[error("video advertised without a display")]
+    NoDisplay,
+}
+
+/// Composes [`InfoResponse`] from capabilities instead of raw feature bits.
+///
+/// Starts with no capabilities, [`Self::features`] adds bits not covered by named methods.
+#[derive(Debug, Clone)]
+pub struct InfoResponseBuilder {
+    device_id: MacAddr6,
+    name: String,
+    manufacturer: String,
+    model: String,
+    features: Features,
+    status: StatusFlags,
+    display: Option<(u32, u32, u32)>,
+}
+
+impl InfoResponse {
+    #[must_use]
+    pub fn builder(device_id: MacAddr6, name: impl Into<String>) -> InfoResponseBuilder {
+        InfoResponseBuilder {
+            device_id,
+            name: name.into(),
+            manufacturer: String::new(),
+            model: String::new(),
+            features: Features::empty(),
+            status: StatusFlags::default(),
+            display: None,
+        }
+    }
+}
+
+impl InfoResponseBuilder {
+    const AUDIO_REALTIME: Features = Features::AirPlayAudio
+        .union(Features::ReceiveAudioPCM)
+        .union(Features::ReceiveAudioALAC)
+        .union(Features::ReceiveAudioAAC_LC);
+    const AUDIO_BUFFERED: Features = Features::BufferedAudio;
+    const VIDEO: Features = Features::Video.union(Features::ScreenMirroring);
+    const AUDIO_METADATA: Features = Features::AudioMetaCovers
+        .union(Features::AudioMetaProgress)
+        .union(Features::AudioMetaTxtDAAP);
+
+    /// Bits of no use without any of the ones paired with them, e.g. buffered audio is timed
+    /// by PTP clock only
+    const REQUIREMENTS: [(Features, Features); 4] = [
+        (Features::BufferedAudio, Features::PTPClock),
+        (
+            Self::AUDIO_METADATA,
+            Features::AirPlayAudio.union(Features::BufferedAudio),
+        ),
+        (
+            Features::AirPlayAudio,
+            Features::ReceiveAudioPCM
+                .union(Features::ReceiveAudioALAC)
+                .union(Features::ReceiveAudioAAC_LC),
+        ),
+        (Features::ScreenMultiCodec, Features::ScreenMirroring),
+    ];
+
+    /// Nothing in the crate handles these, senders relying on them would fail mid-session
+    const UNIMPLEMENTED: Features = Features::AudioRedundant
+        .union(Features::RFC2198Redundant)
+        .union(Features::CarPlay)
+        .union(Features::CarPlayControl)
+        .union(Features::TLS_PSK)
+        .union(Features::SystemPairing)
+        .union(Features::HomeKitPairing)
+        .union(Features::TransientPairing)
+        .union(Features::MfiPairSetup)
+        .union(Features::AirPlayVideoV2);
+
+    #[must_use]
+    pub fn manufacturer(mut self, manufacturer: impl Into<String>) -> Self {
+        self.manufacturer = manufacturer.into();
+        self
+    }
+
+    #[must_use]
+    pub fn model(mut self, model: impl Into<String>) -> Self {
+        self.model = model.into();
+        self
+    }
+
+    /// Extra bits merged with the ones of capabilities.
+    #[must_use]
+    pub fn features(mut self, features: Features) -> Self {
+        self.features |= features;
+        self
+    }
+
+    #[must_use]
+    pub fn audio_realtime(self, enabled: bool) -> Self {
+        self.toggle(Self::AUDIO_REALTIME, enabled)
+    }
+
+    #[must_use]
+    pub fn audio_buffered(self, enabled: bool) -> Self {
+        self.toggle(Self::AUDIO_BUFFERED, enabled)
+    }
+
+    /// Screen mirroring, requires [`Self::display`].
+    #[must_use]
+    pub fn video(self, enabled: bool) -> Self {
+        self.toggle(Self::VIDEO, enabled)
+    }
+
+    /// Mirroring in H.265 besides H.264.
+    #[must_use]
+    pub fn hevc(self, enabled: bool) -> Self {
+        self.toggle(Features::ScreenMultiCodec, enabled)
+    }
+
+    #[must_use]
+    pub fn ptp(self, enabled: bool) -> Self {
+        self.toggle(Features::PTPClock, enabled)
+    }
+
+    #[must_use]
+    pub fn ntp(self, enabled: bool) -> Self {
+        self.toggle(Features::NTPClock, enabled)
+    }
+
+    #[must_use]
+    pub fn display(mut self, width: u32, height: u32, fps: u32) -> Self {
+        self.display = Some((width, height, fps));
+        self
+    }
+
+    #[must_use]
+    pub fn status(mut self, status: StatusFlags) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Checks features without building the response, as [`Self::build`] does.
+    ///
+    /// # Errors
+    ///
+    /// If features advertise something the receiver can't serve.
+    pub fn validate(&self) -> Result<(), InfoError> {
+        let mut unimplemented = self.features & Self::UNIMPLEMENTED;
+        if !crate::crypto::fairplay::SUPPORTED {
+            unimplemented |= self.features & Features::MFiSoft_FairPlay;
+        }
+        if !unimplemented.is_empty() {
+            return Err(InfoError::Unimplemented(unimplemented));
+        }
+        if self.features.intersects(Self::VIDEO) && self.display.is_none() {
+            return Err(InfoError::NoDisplay);
+        }
+        Ok(())
+    }
+
+    /// Advertised bits along with the ones they need, none of which is advertised. Senders
+    /// may still get along, so these aren't errors.
+    #[must_use]
+    pub fn unmet_requirements(&self) -> Vec<(Features, Features)> {
+        Self::REQUIREMENTS
+            .into_iter()
+            .filter_map(|(bits, required)| {
+                let advertised = self.features & bits;
+                (!advertised.is_empty() && !self.features.intersects(required))
+                    .then_some((advertised, required))
+            })
+            .collect()
+    }
+
+    /// # Errors
+    ///
+    /// If features advertise something the receiver can't serve.
+    pub fn build(self) -> Result<InfoResponse, InfoError> {
+        self.validate()?;
+
+        // Seems like clients don't respect other displays and pick by maximum resolution
+        let displays = self
+            .display
+            .map(|(width, height, max_fps)| Display {
+                width,
+                height,
+                uuid: format!("{}_display", self.name),
+                max_fps,
+                features: 2,
+            })
+            .into_iter()
+            .collect();
+
+        Ok(InfoResponse {
+            device_id: self.device_id,
+            mac_addr: self.device_id,
+            features: self.features.bits(),
+            status_flags: self.status.bits(),
+            manufacturer: self.manufacturer,
+            model: self.model,
+            name: self.name,
+            protocol_version: super::PROTOCOL_VERSION.to_string(),
+            source_version: super::SOURCE_VERSION.to_string(),
+            displays,
+        })
+    }
+
+    fn toggle(mut self, features: Features, enabled: bool) -> Self {
+        self.features.set(features, enabled);
+        self
+    }
+}
+
 #[derive(Deserialize)]
 #[serde(untagged)]
 pub enum SetupRequest {
+    /// Tried first, as sender info doesn't mind the flag
+    RemoteControl(RemoteControlInfo),
     SenderInfo(Box<SenderInfo>),
     Streams {
         #[serde(rename = "streams")]
@@ -53,6 +302,36 @@ pub enum SetupRequest {
     },
 }
 
+/// Session of a sender only offering its playback to be remote controlled, without media nor
+/// timing. Senders upgrade it to a media session with sender info afterwards.
+#[derive(Deserialize)]
+pub struct RemoteControlInfo {
+    #[serde(rename = "isRemoteControlOnly")]
+    _remote_control_only: RemoteControlOnly,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(rename = "deviceID", default)]
+    pub device_id: Option<String>,
+}
+
+/// `isRemoteControlOnly` set, sessions with it cleared are told apart by their sender info.
+struct RemoteControlOnly;
+
+impl<'de> Deserialize<'de> for RemoteControlOnly {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        if bool::deserialize(deserializer)? {
+            Ok(Self)
+        } else {
+            Err(serde::de::Error::custom(
+                "not a remote control only session",
+            ))
+        }
+    }
+}
+
 #[derive(Deserialize)]
 pub struct SenderInfo {
     pub name: String,
@@ -67,24 +346,40 @@ pub struct SenderInfo {
     pub os_version: Option<String>,
     #[serde(rename = "osBuildVersion")]
     pub os_build_version: Option<String>,
-    #[serde(rename = "ekey")]
+    /// Both empty if the sender negotiated no encryption
+    #[serde(rename = "ekey", default)]
     pub ekey: Bytes,
-    #[serde(rename = "eiv")]
+    #[serde(rename = "eiv", default)]
     pub eiv: Bytes,
 
     #[serde(flatten)]
     pub timing_proto: TimingProtocol,
 }
 
+impl SenderInfo {
+    pub fn device(&self, user_agent: Option<String>) -> SenderDevice {
+        SenderDevice {
+            name: self.name.clone(),
+            model: self.model.clone(),
+            device_id: self.device_id.clone(),
+            mac_addr: self.mac_addr.clone(),
+            os_name: self.os_name.clone(),
+            os_version: self.os_version.clone(),
+            os_build_version: self.os_build_version.clone(),
+            user_agent,
+        }
+    }
+}
+
 #[derive(Deserialize)]
 #[serde(tag = "timingProtocol")]
 pub enum TimingProtocol {
     #[serde(rename = "PTP")]
     Ptp {
-        //#[serde(flatten, rename = "timingPeerInfo")]
-        //peer_info: (),
-        //#[serde(rename = "timingPeerList")]
-        //peer_list: (),
+        #[serde(rename = "timingPeerInfo", default)]
+        peer_info: Option<TimingPeer>,
+        #[serde(rename = "timingPeerList", default)]
+        peer_list: Option<Vec<TimingPeer>>,
     },
     #[serde(rename = "NTP")]
     Ntp {
@@ -93,6 +388,21 @@ pub enum TimingProtocol {
     },
 }
 
+/// PTP clock of either sender or receiver.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TimingPeer {
+    #[serde(rename = "ID")]
+    pub id: String,
+    #[serde(rename = "Addresses", default)]
+    pub addresses: Vec<String>,
+    #[serde(
+        rename = "SupportsClockPortMatchingOverride",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub supports_clock_port_matching_override: Option<bool>,
+}
+
 pub enum StreamRequest {
     // #[serde(rename = "96")]
     AudioRealtime(AudioRealtimeRequest),
@@ -100,6 +410,13 @@ pub enum StreamRequest {
     AudioBuffered(AudioBufferedRequest),
     // #[serde(rename = "110")]
     Video(VideoRequest),
+    /// Stream of unknown type, answered without being set up
+    Unsupported(UnsupportedStream),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnsupportedStream {
+    pub ty: u64,
 }
 
 impl<'de> Deserialize<'de> for StreamRequest {
@@ -145,13 +462,14 @@ impl<'de> Deserialize<'de> for StreamRequest {
                 Ok(Self::Video(inner))
             }
 
-            other => Err(serde::de::Error::custom(format!("unknown type: {other}"))),
+            ty => Ok(Self::Unsupported(UnsupportedStream { ty })),
         }
     }
 }
 
 #[derive(Deserialize)]
 pub struct AudioRealtimeRequest {
+    /// Codec of packets, see [`CodecKind::from_content_type`](crate::playback::audio::CodecKind::from_content_type)
     #[serde(rename = "ct")]
     pub content_type: u8,
     #[serde(rename = "audioFormat")]
@@ -170,6 +488,7 @@ pub struct AudioRealtimeRequest {
 
 #[derive(Deserialize)]
 pub struct AudioBufferedRequest {
+    /// Codec of packets, see [`CodecKind::from_content_type`](crate::playback::audio::CodecKind::from_content_type)
     #[serde(rename = "ct")]
     pub content_type: u8,
     #[serde(rename = "audioFormat")]
@@ -178,31 +497,55 @@ pub struct AudioBufferedRequest {
     pub audio_format_index: Option<u8>,
     #[serde(rename = "spf")]
     pub samples_per_frame: u32,
-    #[serde(rename = "shk")]
+    /// Layout of lossless streams beyond the table format, e.g. 24 bits at 96 kHz
+    #[serde(rename = "sr")]
+    pub sample_rate: Option<u32>,
+    #[serde(rename = "ss")]
+    pub bits_per_sample: Option<u32>,
+    #[serde(rename = "ch")]
+    pub channels: Option<u8>,
+    /// Empty if the sender negotiated no encryption
+    #[serde(rename = "shk", default)]
     pub shared_key: Bytes,
     #[serde(rename = "clientID")]
     pub client_id: Option<String>,
 }
 
+/// Screen mirroring stream, set up after sender info which negotiated the timing clock and
+/// the key. Payloads are keyed with that key and `stream_connection_id`.
 #[derive(Deserialize)]
 pub struct VideoRequest {
     #[serde(rename = "streamConnectionID")]
     pub stream_connection_id: i64,
     #[serde(rename = "latencyMs")]
     pub latency_ms: u32,
+    /// Whether the whole screen is mirrored rather than a single app
+    #[serde(rename = "usingScreen", default)]
+    pub using_screen: Option<bool>,
+    /// Names of timestamps sent in packet headers, e.g. `SubSu` or `BePxT`
+    #[serde(rename = "timestampInfo", default)]
+    pub timestamp_info: Vec<TimestampInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TimestampInfo {
+    pub name: String,
 }
 
 #[derive(Serialize)]
 #[serde(untagged)]
 pub enum SetupResponse {
+    /// Of remote control only sessions, whose events are all there is
+    Events {
+        #[serde(rename = "eventPort")]
+        event_port: u16,
+    },
     Info {
         #[serde(rename = "eventPort")]
         event_port: u16,
 
-        // TODO : this may be moved to NTP branch, because it's always zero for PTP and PTP
-        // sometimes requires (or not) timingPeerInfo
-        #[serde(rename = "timingPort")]
-        timing_port: u16,
+        #[serde(flatten)]
+        timing: TimingResponse,
     },
     Streams {
         #[serde(rename = "streams")]
@@ -210,6 +553,24 @@ pub enum SetupResponse {
     },
 }
 
+/// Timing of the session, as the protocol the sender picked needs it.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum TimingResponse {
+    Ntp {
+        /// Port of the socket answering the sender's timing requests
+        #[serde(rename = "timingPort")]
+        timing_port: u16,
+    },
+    Ptp {
+        /// Always zero, the clock runs on the fixed PTP ports shared by sessions
+        #[serde(rename = "timingPort")]
+        timing_port: u16,
+        #[serde(rename = "timingPeerInfo")]
+        timing_peer_info: TimingPeer,
+    },
+}
+
 #[derive(Debug)]
 pub enum StreamResponse {
     AudioRealtime {
@@ -228,6 +589,16 @@ pub enum StreamResponse {
     },
 }
 
+impl StreamResponse {
+    pub fn id(&self) -> u64 {
+        match self {
+            Self::AudioRealtime { id, .. }
+            | Self::AudioBuffered { id, .. }
+            | Self::Video { id, .. } => *id,
+        }
+    }
+}
+
 impl Serialize for StreamResponse {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -289,6 +660,26 @@ impl Serialize for StreamResponse {
     }
 }
 
+/// Body of `/feedback`, empty unless the sender wants status of its streams.
+#[derive(Deserialize)]
+pub struct Feedback {
+    pub streams: Option<plist::Value>,
+}
+
+#[derive(Serialize)]
+pub struct FeedbackResponse {
+    pub streams: Vec<FeedbackStream>,
+}
+
+#[derive(Serialize)]
+pub struct FeedbackStream {
+    #[serde(rename = "type")]
+    pub ty: u32,
+    #[serde(rename = "streamID")]
+    pub id: u64,
+}
+
+/// Tears down the whole session without `streams`, else just the streams listed.
 #[derive(Deserialize)]
 pub struct Teardown {
     #[serde(rename = "streams")]
@@ -297,8 +688,810 @@ pub struct Teardown {
 
 #[derive(Deserialize)]
 pub struct TeardownRequest {
+    /// Every stream of the type if missing
     #[serde(rename = "streamID")]
     pub id: Option<u64>,
     #[serde(rename = "type")]
     pub ty: u32,
 }
+
+/// `RTP-Info` header of realtime audio FLUSH and RECORD, e.g. `seq=1234;rtptime=5678`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtpInfo {
+    /// The first packet not flushed or the first one recorded
+    pub seq: u16,
+    pub rtptime: Option<u32>,
+}
+
+impl RtpInfo {
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut seq = None;
+        let mut rtptime = None;
+        for param in value.split(';') {
+            match param.trim().split_once('=') {
+                Some(("seq", value)) => seq = Some(value.parse().ok()?),
+                Some(("rtptime", value)) => rtptime = Some(value.parse().ok()?),
+                _ => {}
+            }
+        }
+
+        Some(Self { seq: seq?, rtptime })
+    }
+}
+
+/// Buffered audio FLUSHBUFFERED, flushing either everything up to `until_seq` or only packets
+/// starting at `from_seq`
+#[derive(Debug, Deserialize)]
+pub struct FlushBuffered {
+    #[serde(rename = "flushFromSeq")]
+    pub from_seq: Option<u32>,
+    #[serde(rename = "flushFromTS")]
+    pub from_ts: Option<u32>,
+    #[serde(rename = "flushUntilSeq")]
+    pub until_seq: u32,
+    #[serde(rename = "flushUntilTS")]
+    pub until_ts: Option<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use macaddr::MacAddr6;
+    use plist::{Dictionary, Value};
+
+    use serde::Serialize;
+
+    use super::{
+        FeedbackResponse, FeedbackStream, FlushBuffered, InfoError, InfoResponse, RtpInfo,
+        SetupRequest, SetupResponse, StreamRequest, StreamResponse, Teardown, TimingPeer,
+        TimingProtocol, TimingResponse, UnsupportedStream,
+    };
+    use crate::{config::Features, playback::session::SenderDevice};
+
+    fn sender_info(timing: &[(&str, Value)]) -> Value {
+        let mut dict = Dictionary::new();
+        dict.insert("name".into(), "iPhone".into());
+        dict.insert("model".into(), "iPhone14,2".into());
+        dict.insert("deviceID".into(), "AA:BB:CC:DD:EE:FF".into());
+        dict.insert("macAddress".into(), "AA:BB:CC:DD:EE:F0".into());
+        dict.insert("ekey".into(), Value::Data(vec![0; 72]));
+        dict.insert("eiv".into(), Value::Data(vec![0; 16]));
+        for (key, value) in timing {
+            dict.insert((*key).into(), value.clone());
+        }
+        Value::Dictionary(dict)
+    }
+
+    /// Parsed from binary plist, as senders send it.
+    fn setup_request(dict: Dictionary) -> SetupRequest {
+        let mut buf = Vec::new();
+        plist::to_writer_binary(&mut buf, &Value::Dictionary(dict)).unwrap();
+        plist::from_bytes(&buf).unwrap()
+    }
+
+    fn setup_response(resp: &SetupResponse) -> Dictionary {
+        let mut buf = Vec::new();
+        plist::to_writer_binary(&mut buf, resp).unwrap();
+        plist::from_bytes(&buf).unwrap()
+    }
+
+    fn streams(stream: Dictionary) -> Dictionary {
+        Dictionary::from_iter([("streams", Value::Array(vec![Value::Dictionary(stream)]))])
+    }
+
+    fn peer(addr: &str) -> Value {
+        let mut dict = Dictionary::new();
+        dict.insert("ID".into(), addr.into());
+        dict.insert("Addresses".into(), Value::Array(vec![addr.into()]));
+        Value::Dictionary(dict)
+    }
+
+    #[test]
+    fn sender_info_ptp_peers() {
+        let value = sender_info(&[
+            ("timingProtocol", "PTP".into()),
+            ("timingPeerInfo", peer("192.168.1.2")),
+            (
+                "timingPeerList",
+                Value::Array(vec![peer("192.168.1.2"), peer("192.168.1.3")]),
+            ),
+        ]);
+
+        let Ok(SetupRequest::SenderInfo(info)) = plist::from_value(&value) else {
+            panic!("must be sender info");
+        };
+        let TimingProtocol::Ptp {
+            peer_info: Some(peer_info),
+            peer_list: Some(peer_list),
+        } = info.timing_proto
+        else {
+            panic!("must be ptp with peers");
+        };
+        assert_eq!("192.168.1.2", peer_info.id);
+        assert_eq!(vec!["192.168.1.2"], peer_info.addresses);
+        assert_eq!(2, peer_list.len());
+    }
+
+    #[test]
+    fn sender_info_without_peers() {
+        let value = sender_info(&[("timingProtocol", "PTP".into())]);
+        let Ok(SetupRequest::SenderInfo(info)) = plist::from_value(&value) else {
+            panic!("must be sender info");
+        };
+        assert!(matches!(
+            info.timing_proto,
+            TimingProtocol::Ptp {
+                peer_info: None,
+                peer_list: None
+            }
+        ));
+
+        let value = sender_info(&[
+            ("timingProtocol", "NTP".into()),
+            ("timingPort", 7010.into()),
+        ]);
+        let Ok(SetupRequest::SenderInfo(info)) = plist::from_value(&value) else {
+            panic!("must be sender info");
+        };
+        assert!(matches!(
+            info.timing_proto,
+            TimingProtocol::Ntp { remote_port: 7010 }
+        ));
+    }
+
+    #[test]
+    fn remote_control_only_session() {
+        let mut dict = Dictionary::new();
+        dict.insert("isRemoteControlOnly".into(), true.into());
+        dict.insert("name".into(), "iPhone".into());
+        let Ok(SetupRequest::RemoteControl(info)) = plist::from_value(&Value::Dictionary(dict))
+        else {
+            panic!("must be remote control");
+        };
+        assert_eq!(Some("iPhone"), info.name.as_deref());
+        assert_eq!(None, info.device_id);
+
+        // Flag cleared is just sender info
+        let Value::Dictionary(mut dict) = sender_info(&[("timingProtocol", "PTP".into())]) else {
+            unreachable!();
+        };
+        dict.insert("isRemoteControlOnly".into(), false.into());
+        let Ok(SetupRequest::SenderInfo(info)) = plist::from_value(&Value::Dictionary(dict)) else {
+            panic!("must be sender info");
+        };
+        assert_eq!("iPhone", info.name);
+    }
+
+    #[test]
+    fn sender_device_of_info() {
+        let value = sender_info(&[
+            ("timingProtocol", "PTP".into()),
+            ("osName", "iPhone OS".into()),
+            ("osVersion", "17.5.1".into()),
+        ]);
+        let Ok(SetupRequest::SenderInfo(info)) = plist::from_value(&value) else {
+            panic!("must be sender info");
+        };
+
+        let device = info.device(Some("AirPlay/770.8.1".to_string()));
+        assert_eq!(
+            SenderDevice {
+                name: "iPhone".to_string(),
+                model: "iPhone14,2".to_string(),
+                device_id: "AA:BB:CC:DD:EE:FF".to_string(),
+                mac_addr: "AA:BB:CC:DD:EE:F0".to_string(),
+                os_name: Some("iPhone OS".to_string()),
+                os_version: Some("17.5.1".to_string()),
+                os_build_version: None,
+                user_agent: Some("AirPlay/770.8.1".to_string()),
+            },
+            device
+        );
+    }
+
+    #[test]
+    fn streams_with_unknown_type() {
+        let stream = |ty: u64| {
+            let mut dict = Dictionary::new();
+            dict.insert("type".into(), ty.into());
+            dict.insert("streamConnectionID".into(), 1.into());
+            dict.insert("latencyMs".into(), 100.into());
+            Value::Dictionary(dict)
+        };
+        let mut dict = Dictionary::new();
+        dict.insert(
+            "streams".into(),
+            Value::Array(vec![stream(130), stream(110)]),
+        );
+
+        let Ok(SetupRequest::Streams { requests }) = plist::from_value(&Value::Dictionary(dict))
+        else {
+            panic!("must be streams");
+        };
+        assert!(matches!(
+            requests[..],
+            [
+                StreamRequest::Unsupported(UnsupportedStream { ty: 130 }),
+                StreamRequest::Video(_)
+            ]
+        ));
+    }
+
+    #[test]
+    fn mirroring_stream_request() {
+        let mut dict = Dictionary::new();
+        dict.insert("type".into(), 110.into());
+        dict.insert("streamConnectionID".into(), Value::Integer((-42).into()));
+        dict.insert("latencyMs".into(), 70.into());
+        dict.insert("usingScreen".into(), true.into());
+        let timestamps = ["SubSu", "BePxT", "AfPxT"]
+            .map(|name| Value::Dictionary(Dictionary::from_iter([("name", Value::from(name))])));
+        dict.insert("timestampInfo".into(), Value::Array(timestamps.to_vec()));
+
+        let Ok(StreamRequest::Video(req)) = plist::from_value(&Value::Dictionary(dict)) else {
+            panic!("must be video");
+        };
+        assert_eq!(-42, req.stream_connection_id);
+        assert_eq!(70, req.latency_ms);
+        assert_eq!(Some(true), req.using_screen);
+        assert_eq!("BePxT", req.timestamp_info[1].name);
+    }
+
+    #[test]
+    fn info_features_of_capabilities() {
+        let info = InfoResponse::builder(MacAddr6::nil(), "rairplay")
+            .audio_realtime(true)
+            .audio_buffered(true)
+            .video(true)
+            .display(1920, 1080, 30)
+            .ptp(true)
+            .hevc(true)
+            .video(false)
+            .build()
+            .unwrap();
+
+        let features = Features::from_bits_retain(info.features);
+        assert!(features.contains(Features::BufferedAudio | Features::PTPClock));
+        assert!(features.contains(Features::ScreenMultiCodec));
+        assert!(features.contains(Features::AirPlayAudio | Features::ReceiveAudioALAC));
+        assert!(!features.intersects(Features::ScreenMirroring | Features::NTPClock));
+        assert_eq!("rairplay_display", info.displays[0].uuid);
+    }
+
+    #[test]
+    fn info_unserved_features() {
+        assert_eq!(
+            Some(InfoError::NoDisplay),
+            InfoResponse::builder(MacAddr6::nil(), "rairplay")
+                .video(true)
+                .build()
+                .err()
+        );
+        assert_eq!(
+            Some(InfoError::Unimplemented(Features::HomeKitPairing)),
+            InfoResponse::builder(MacAddr6::nil(), "rairplay")
+                .features(Features::LegacyPairing | Features::HomeKitPairing)
+                .build()
+                .err()
+        );
+    }
+
+    #[test]
+    fn info_unmet_requirements() {
+        let builder = InfoResponse::builder(MacAddr6::nil(), "rairplay");
+        assert!(
+            builder
+                .clone()
+                .features(Features::default())
+                .unmet_requirements()
+                .is_empty()
+        );
+
+        // Buffered audio carries metadata too
+        assert_eq!(
+            vec![(Features::BufferedAudio, Features::PTPClock)],
+            builder
+                .clone()
+                .features(Features::BufferedAudio | Features::AudioMetaCovers)
+                .unmet_requirements()
+        );
+        assert_eq!(
+            vec![
+                (
+                    Features::AudioMetaCovers,
+                    Features::AirPlayAudio | Features::BufferedAudio
+                ),
+                (Features::ScreenMultiCodec, Features::ScreenMirroring),
+            ],
+            builder
+                .features(Features::AudioMetaCovers | Features::ScreenMultiCodec)
+                .unmet_requirements()
+        );
+    }
+
+    #[test]
+    fn rtp_info() {
+        assert_eq!(
+            Some(RtpInfo {
+                seq: 1234,
+                rtptime: Some(5678)
+            }),
+            RtpInfo::parse("seq=1234;rtptime=5678")
+        );
+        assert_eq!(
+            Some(RtpInfo {
+                seq: 1,
+                rtptime: None
+            }),
+            RtpInfo::parse("url=rtsp://host/1; seq=1")
+        );
+        assert_eq!(None, RtpInfo::parse("rtptime=5678"));
+        assert_eq!(None, RtpInfo::parse("seq=70000"));
+    }
+
+    #[test]
+    fn flush_buffered() {
+        let mut dict = Dictionary::new();
+        dict.insert("flushUntilSeq".into(), 0x12_3456.into());
+        dict.insert("flushUntilTS".into(), 1000.into());
+
+        let req: FlushBuffered = plist::from_value(&Value::Dictionary(dict)).unwrap();
+        assert_eq!(None, req.from_seq);
+        assert_eq!(0x12_3456, req.until_seq);
+    }
+
+    #[test]
+    fn setup_info_timing_port_of_protocol() {
+        let info = |timing| {
+            let mut buf = Vec::new();
+            let resp = SetupResponse::Info {
+                event_port: 7001,
+                timing,
+            };
+            plist::to_writer_binary(&mut buf, &resp).unwrap();
+            plist::from_bytes::<Dictionary>(&buf).unwrap()
+        };
+
+        let ntp = info(TimingResponse::Ntp { timing_port: 7002 });
+        assert_eq!(Some(7001), ntp["eventPort"].as_unsigned_integer());
+        assert_eq!(Some(7002), ntp["timingPort"].as_unsigned_integer());
+        assert!(!ntp.contains_key("timingPeerInfo"));
+
+        let ptp = info(TimingResponse::Ptp {
+            timing_port: 0,
+            timing_peer_info: TimingPeer {
+                id: "10.0.0.2".to_string(),
+                addresses: vec!["10.0.0.2".to_string()],
+                supports_clock_port_matching_override: None,
+            },
+        });
+        assert_eq!(Some(0), ptp["timingPort"].as_unsigned_integer());
+        let peer = ptp["timingPeerInfo"].as_dictionary().unwrap();
+        assert_eq!(Some("10.0.0.2"), peer["ID"].as_string());
+    }
+
+    #[test]
+    fn audio_realtime_setup() {
+        let stream = Dictionary::from_iter([
+            ("type", Value::from(96)),
+            ("ct", 2.into()),
+            ("audioFormat", 0x40000.into()),
+            ("spf", 352.into()),
+            ("sr", 44100.into()),
+            ("latencyMin", 11025.into()),
+            ("latencyMax", 88200.into()),
+            ("controlPort", 58913.into()),
+            ("isMedia", true.into()),
+            ("audioMode", "default".into()),
+        ]);
+
+        let SetupRequest::Streams { requests } = setup_request(streams(stream)) else {
+            panic!("must be streams");
+        };
+        let [StreamRequest::AudioRealtime(req)] = &requests[..] else {
+            panic!("must be realtime audio");
+        };
+        assert_eq!(2, req.content_type);
+        assert_eq!(0x40000, req.audio_format);
+        assert_eq!(352, req.samples_per_frame);
+        assert_eq!(44100, req.sample_rate);
+        assert_eq!(
+            (11025, 88200),
+            (req.min_latency_samples, req.max_latency_samples)
+        );
+        assert_eq!(58913, req.remote_control_port);
+    }
+
+    #[test]
+    fn audio_buffered_setup() {
+        let stream = Dictionary::from_iter([
+            ("type", Value::from(103)),
+            ("ct", 8.into()),
+            ("audioFormat", 0x100_0000.into()),
+            ("spf", 1024.into()),
+            ("shk", Value::Data((0..32).collect())),
+            ("clientID", "4F2D5A1C-0B8E-4B6A-9E3D-2C7F1A6B8D90".into()),
+            ("supportsDynamicStreamID", true.into()),
+        ]);
+
+        let SetupRequest::Streams { requests } = setup_request(streams(stream)) else {
+            panic!("must be streams");
+        };
+        let [StreamRequest::AudioBuffered(req)] = &requests[..] else {
+            panic!("must be buffered audio");
+        };
+        assert_eq!(8, req.content_type);
+        assert_eq!(0x100_0000, req.audio_format);
+        assert_eq!(None, req.audio_format_index);
+        assert_eq!(1024, req.samples_per_frame);
+        assert_eq!(
+            (None, None, None),
+            (req.sample_rate, req.bits_per_sample, req.channels)
+        );
+        assert_eq!((0..32).collect::<Vec<u8>>(), req.shared_key);
+        assert_eq!(
+            Some("4F2D5A1C-0B8E-4B6A-9E3D-2C7F1A6B8D90"),
+            req.client_id.as_deref()
+        );
+
+        // Lossless beyond the table format
+        let stream = Dictionary::from_iter([
+            ("type", Value::from(103)),
+            ("ct", 2.into()),
+            ("audioFormat", 0x40000.into()),
+            ("audioFormatIndex", 18.into()),
+            ("spf", 352.into()),
+            ("sr", 96000.into()),
+            ("ss", 24.into()),
+            ("ch", 2.into()),
+            ("shk", Value::Data(vec![0; 32])),
+        ]);
+        let SetupRequest::Streams { requests } = setup_request(streams(stream)) else {
+            panic!("must be streams");
+        };
+        let [StreamRequest::AudioBuffered(req)] = &requests[..] else {
+            panic!("must be buffered audio");
+        };
+        assert_eq!(Some(18), req.audio_format_index);
+        assert_eq!(
+            (Some(96000), Some(24), Some(2)),
+            (req.sample_rate, req.bits_per_sample, req.channels)
+        );
+        assert_eq!(None, req.client_id);
+    }
+
+    #[test]
+    fn video_setup() {
+        let stream = Dictionary::from_iter([
+            ("type", Value::from(110)),
+            (
+                "streamConnectionID",
+                Value::Integer((-6_917_529_027_641_081_856_i64).into()),
+            ),
+            ("latencyMs", 90.into()),
+        ]);
+
+        let SetupRequest::Streams { requests } = setup_request(streams(stream)) else {
+            panic!("must be streams");
+        };
+        let [StreamRequest::Video(req)] = &requests[..] else {
+            panic!("must be video");
+        };
+        assert_eq!(-6_917_529_027_641_081_856, req.stream_connection_id);
+        assert_eq!(90, req.latency_ms);
+        assert_eq!(None, req.using_screen);
+        assert!(req.timestamp_info.is_empty());
+    }
+
+    #[test]
+    fn setup_requests_told_apart() {
+        let Value::Dictionary(info) = sender_info(&[("timingProtocol", "PTP".into())]) else {
+            unreachable!();
+        };
+        assert!(matches!(setup_request(info), SetupRequest::SenderInfo(_)));
+
+        let remote = Dictionary::from_iter([("isRemoteControlOnly", Value::from(true))]);
+        assert!(matches!(
+            setup_request(remote),
+            SetupRequest::RemoteControl(_)
+        ));
+
+        let empty = Dictionary::from_iter([("streams", Value::Array(Vec::new()))]);
+        let SetupRequest::Streams { requests } = setup_request(empty) else {
+            panic!("must be streams");
+        };
+        assert!(requests.is_empty());
+    }
+
+    #[test]
+    fn stream_responses() {
+        let resp = setup_response(&SetupResponse::Streams {
+            responses: vec![
+                StreamResponse::AudioRealtime {
+                    id: 1,
+                    local_data_port: 6000,
+                    local_control_port: 6001,
+                },
+                StreamResponse::AudioBuffered {
+                    id: 2,
+                    local_data_port: 6002,
+                    audio_buffer_size: 8 * 1024 * 1024,
+                },
+                StreamResponse::Video {
+                    id: 3,
+                    local_data_port: 7100,
+                },
+            ],
+        });
+
+        assert_eq!(["streams"], resp.keys().collect::<Vec<_>>()[..]);
+        let streams: Vec<_> = resp["streams"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|stream| stream.as_dictionary().unwrap())
+            .collect();
+        let field = |stream: &Dictionary, key| stream.get(key).and_then(Value::as_unsigned_integer);
+
+        let [realtime, buffered, video] = streams[..] else {
+            panic!("must be three streams");
+        };
+        assert_eq!(Some(96), field(realtime, "type"));
+        assert_eq!(Some(1), field(realtime, "streamID"));
+        assert_eq!(Some(6000), field(realtime, "dataPort"));
+        assert_eq!(Some(6001), field(realtime, "controlPort"));
+        assert_eq!(None, field(realtime, "audioBufferSize"));
+
+        assert_eq!(Some(103), field(buffered, "type"));
+        assert_eq!(Some(2), field(buffered, "streamID"));
+        assert_eq!(Some(6002), field(buffered, "dataPort"));
+        assert_eq!(Some(8 * 1024 * 1024), field(buffered, "audioBufferSize"));
+        assert_eq!(None, field(buffered, "controlPort"));
+
+        assert_eq!(Some(110), field(video, "type"));
+        assert_eq!(Some(3), field(video, "streamID"));
+        assert_eq!(Some(7100), field(video, "dataPort"));
+        assert_eq!(3, video.len());
+    }
+
+    #[test]
+    fn setup_events_only() {
+        let resp = setup_response(&SetupResponse::Events { event_port: 7001 });
+        assert_eq!(1, resp.len());
+        assert_eq!(Some(7001), resp["eventPort"].as_unsigned_integer());
+    }
+
+    // Bodies under `fixtures/` are reconstructed from known sender keys, not captured, see its
+    // README.
+
+    #[test]
+    fn sender_info_fixture() {
+        let req = plist::from_bytes(include_bytes!("fixtures/setup_sender_info.bplist")).unwrap();
+        let SetupRequest::SenderInfo(info) = req else {
+            panic!("must be sender info");
+        };
+        assert_eq!(
+            SenderDevice {
+                name: "iPhone".to_string(),
+                model: "iPhone14,2".to_string(),
+                device_id: "AA:BB:CC:DD:EE:FF".to_string(),
+                mac_addr: "AA:BB:CC:DD:EE:F0".to_string(),
+                os_name: Some("iPhone OS".to_string()),
+                os_version: Some("17.5.1".to_string()),
+                os_build_version: Some("21F90".to_string()),
+                user_agent: None,
+            },
+            info.device(None)
+        );
+        assert_eq!((0..72).collect::<Vec<u8>>(), info.ekey);
+        assert_eq!((0..16).collect::<Vec<u8>>(), info.eiv);
+
+        let TimingProtocol::Ptp {
+            peer_info: Some(peer_info),
+            peer_list: Some(peer_list),
+        } = info.timing_proto
+        else {
+            panic!("must be ptp with peers");
+        };
+        assert_eq!("192.168.1.2", peer_info.id);
+        assert_eq!(
+            vec!["192.168.1.2", "fe80::1c2a:3bff:fe4d:5e6f"],
+            peer_info.addresses
+        );
+        assert_eq!(Some(true), peer_info.supports_clock_port_matching_override);
+        let ids: Vec<_> = peer_list.iter().map(|peer| peer.id.as_str()).collect();
+        assert_eq!(["192.168.1.2", "192.168.1.3"], ids[..]);
+    }
+
+    #[test]
+    fn stream_fixtures() {
+        let requests = |body: &[u8]| {
+            let Ok(SetupRequest::Streams { requests }) = plist::from_bytes(body) else {
+                panic!("must be streams");
+            };
+            requests
+        };
+
+        let [StreamRequest::AudioRealtime(req)] =
+            &requests(include_bytes!("fixtures/setup_audio_realtime.bplist"))[..]
+        else {
+            panic!("must be realtime audio");
+        };
+        assert_eq!(
+            (2, 0x40000, 352, 44100),
+            (
+                req.content_type,
+                req.audio_format,
+                req.samples_per_frame,
+                req.sample_rate
+            )
+        );
+        assert_eq!(
+            (11025, 88200),
+            (req.min_latency_samples, req.max_latency_samples)
+        );
+        assert_eq!(58913, req.remote_control_port);
+
+        let [StreamRequest::AudioBuffered(req)] =
+            &requests(include_bytes!("fixtures/setup_audio_buffered.bplist"))[..]
+        else {
+            panic!("must be buffered audio");
+        };
+        assert_eq!(
+            (8, 0x100_0000, 1024),
+            (req.content_type, req.audio_format, req.samples_per_frame)
+        );
+        assert_eq!((0..32).collect::<Vec<u8>>(), req.shared_key);
+        assert_eq!(
+            Some("4F2D5A1C-0B8E-4B6A-9E3D-2C7F1A6B8D90"),
+            req.client_id.as_deref()
+        );
+
+        let [StreamRequest::Video(req)] =
+            &requests(include_bytes!("fixtures/setup_video.bplist"))[..]
+        else {
+            panic!("must be video");
+        };
+        assert_eq!(-6_917_529_027_641_081_856, req.stream_connection_id);
+        assert_eq!(90, req.latency_ms);
+        assert_eq!(Some(true), req.using_screen);
+        let names: Vec<_> = req
+            .timestamp_info
+            .iter()
+            .map(|ts| ts.name.as_str())
+            .collect();
+        assert_eq!(["SubSu", "BePxT", "AfPxT", "BefEn", "EmEnc"], names[..]);
+    }
+
+    #[test]
+    fn flush_and_teardown_fixtures() {
+        let flush: FlushBuffered =
+            plist::from_bytes(include_bytes!("fixtures/flush_buffered.bplist")).unwrap();
+        assert_eq!(
+            (Some(1200), Some(250_000), 1500, Some(310_000)),
+            (
+                flush.from_seq,
+                flush.from_ts,
+                flush.until_seq,
+                flush.until_ts
+            )
+        );
+
+        let teardown: Teardown =
+            plist::from_bytes(include_bytes!("fixtures/teardown_streams.bplist")).unwrap();
+        let streams: Vec<_> = teardown
+            .requests
+            .unwrap()
+            .iter()
+            .map(|req| (req.ty, req.id))
+            .collect();
+        assert_eq!([(110, Some(3)), (103, None)], streams[..]);
+    }
+
+    /// Serialized responses read back as plist values, so both key names and value types are
+    /// checked as a sender would see them.
+    fn round_trip(resp: &impl Serialize) -> Value {
+        let mut buf = Vec::new();
+        plist::to_writer_binary(&mut buf, resp).unwrap();
+        plist::from_bytes(&buf).unwrap()
+    }
+
+    fn dict<const N: usize>(entries: [(&str, Value); N]) -> Value {
+        Value::Dictionary(Dictionary::from_iter(entries))
+    }
+
+    #[test]
+    fn info_response_round_trip() {
+        let info = InfoResponse::builder("02:00:00:AA:BB:CC".parse().unwrap(), "rairplay")
+            .audio_realtime(true)
+            .display(1920, 1080, 60)
+            .video(true)
+            .build()
+            .unwrap();
+        let features = info.features;
+        let status_flags = info.status_flags;
+        let versions = (info.protocol_version.clone(), info.source_version.clone());
+        let display_features = info.displays[0].features;
+
+        assert_eq!(
+            dict([
+                ("deviceid", "02:00:00:AA:BB:CC".into()),
+                ("macAddress", "02:00:00:AA:BB:CC".into()),
+                ("features", features.into()),
+                ("statusFlags", status_flags.into()),
+                ("manufacturer", String::new().into()),
+                ("model", String::new().into()),
+                ("name", "rairplay".into()),
+                ("protocolVersion", versions.0.into()),
+                ("sourceVersion", versions.1.into()),
+                (
+                    "displays",
+                    Value::Array(vec![dict([
+                        ("widthPixels", 1920.into()),
+                        ("heightPixels", 1080.into()),
+                        ("uuid", "rairplay_display".into()),
+                        ("maxFPS", 60.into()),
+                        ("features", display_features.into()),
+                    ])])
+                ),
+            ]),
+            round_trip(&info)
+        );
+    }
+
+    #[test]
+    fn setup_responses_round_trip() {
+        assert_eq!(
+            dict([
+                ("eventPort", 7001.into()),
+                ("timingPort", 0.into()),
+                (
+                    "timingPeerInfo",
+                    dict([
+                        ("ID", "10.0.0.2".into()),
+                        ("Addresses", Value::Array(vec!["10.0.0.2".into()])),
+                    ])
+                ),
+            ]),
+            round_trip(&SetupResponse::Info {
+                event_port: 7001,
+                timing: TimingResponse::Ptp {
+                    timing_port: 0,
+                    timing_peer_info: TimingPeer {
+                        id: "10.0.0.2".to_string(),
+                        addresses: vec!["10.0.0.2".to_string()],
+                        supports_clock_port_matching_override: None,
+                    },
+                },
+            })
+        );
+
+        assert_eq!(
+            dict([(
+                "streams",
+                Value::Array(vec![dict([
+                    ("type", 103.into()),
+                    ("streamID", 2.into()),
+                    ("dataPort", 6002.into()),
+                    ("audioBufferSize", 8_388_608.into()),
+                ])])
+            )]),
+            round_trip(&SetupResponse::Streams {
+                responses: vec![StreamResponse::AudioBuffered {
+                    id: 2,
+                    local_data_port: 6002,
+                    audio_buffer_size: 8 * 1024 * 1024,
+                }],
+            })
+        );
+
+        assert_eq!(
+            dict([(
+                "streams",
+                Value::Array(vec![dict([("type", 110.into()), ("streamID", 3.into())])])
+            )]),
+            round_trip(&FeedbackResponse {
+                streams: vec![FeedbackStream { ty: 110, id: 3 }],
+            })
+        );
+    }
+}
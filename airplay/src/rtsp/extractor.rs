@@ -1,20 +1,74 @@
-use std::ops::Deref;
+use std::{convert::Infallible, ops::Deref};
 
 use axum::{
-    extract::{FromRequest, Request, rejection::BytesRejection},
+    extract::{FromRequest, FromRequestParts, Request, rejection::BytesRejection},
     http::HeaderValue,
     response::{IntoResponse, Response},
 };
 use bytes::{BufMut, Bytes, BytesMut};
-use http::{header::CONTENT_TYPE, status::StatusCode};
+use http::{
+    HeaderMap,
+    header::{ACCEPT, CONTENT_TYPE},
+    request::Parts,
+    status::StatusCode,
+};
 use serde::{Serialize, de::DeserializeOwned};
 use thiserror::Error;
 
 const APPLE_BPLIST_MIME: &str = "application/x-apple-binary-plist";
+const APPLE_XML_PLIST_MIME: &str = "text/x-apple-plist+xml";
 
+/// Plist body of requests, either format is read. Responses are binary.
 #[derive(Debug, Clone, Copy, Default)]
 pub struct BinaryPlist<T>(pub T);
 
+/// Format of plist responses the sender expects, binary unless it asked for XML.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PlistFormat {
+    #[default]
+    Binary,
+    Xml,
+}
+
+/// Plist response in the given format.
+#[derive(Debug, Clone, Copy)]
+pub struct Plist<T>(pub PlistFormat, pub T);
+
+impl PlistFormat {
+    /// Of the first plist type in `Accept`, or else of the request's own `Content-Type`.
+    pub fn of_request(headers: &HeaderMap) -> Self {
+        let of_header = |name| {
+            headers
+                .get_all(name)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .find_map(|mime| Self::of_mime(mime.split(';').next().unwrap_or_default().trim()))
+        };
+        of_header(ACCEPT)
+            .or_else(|| of_header(CONTENT_TYPE))
+            .unwrap_or_default()
+    }
+
+    fn of_mime(mime: &str) -> Option<Self> {
+        match mime.to_ascii_lowercase().as_str() {
+            APPLE_BPLIST_MIME => Some(Self::Binary),
+            APPLE_XML_PLIST_MIME | "application/x-apple-plist" | "application/xml" | "text/xml" => {
+                Some(Self::Xml)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for PlistFormat {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self::of_request(&parts.headers))
+    }
+}
+
 impl<T> BinaryPlist<T>
 where
     T: DeserializeOwned,
@@ -56,10 +110,24 @@ where
     T: Serialize,
 {
     fn into_response(self) -> Response {
+        Plist(PlistFormat::Binary, self.0).into_response()
+    }
+}
+
+impl<T> IntoResponse for Plist<T>
+where
+    T: Serialize,
+{
+    fn into_response(self) -> Response {
+        let Self(format, value) = self;
         let mut buf = BytesMut::with_capacity(1024).writer();
-        match plist::to_writer_binary(&mut buf, &self.0) {
+        let (res, mime) = match format {
+            PlistFormat::Binary => (plist::to_writer_binary(&mut buf, &value), APPLE_BPLIST_MIME),
+            PlistFormat::Xml => (plist::to_writer_xml(&mut buf, &value), APPLE_XML_PLIST_MIME),
+        };
+        match res {
             Ok(()) => (
-                [(CONTENT_TYPE, HeaderValue::from_static(APPLE_BPLIST_MIME))],
+                [(CONTENT_TYPE, HeaderValue::from_static(mime))],
                 buf.into_inner().freeze(),
             )
                 .into_response(),
@@ -78,6 +146,12 @@ pub enum PlistRejection {
 
 impl IntoResponse for PlistRejection {
     fn into_response(self) -> Response {
-        (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
+        match self {
+            // E.g. 413 of bodies over the limit
+            Self::Bytes(rejection) => rejection.into_response(),
+            Self::Plist(err) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+            }
+        }
     }
 }
@@ -1,71 +1,225 @@
 use std::{
+    io,
     net::SocketAddr,
     sync::{Arc, Weak, atomic::Ordering},
+    time::Duration,
 };
 
 use crate::{
+    config::Config,
     crypto::{
-        AesIv128, fairplay, hash_aes_key,
+        fairplay, hash_aes_key,
         pairing::legacy::{SIGNATURE_LENGTH, X25519_KEY_LEN},
-        streaming::{AudioBufferedCipher, AudioRealtimeCipher, VideoCipher},
+        rsa,
+        streaming::{
+            AeadCipher as _, AudioBufferedCipher, AudioRealtimeCipher, EkeyError, KeyWrap,
+            VideoCipher,
+        },
     },
     playback::{
         ChannelHandle,
-        audio::{AUDIO_FORMATS, AudioDevice, AudioParams},
+        artwork::Artwork,
+        audio::{AudioDevice, AudioFormat, AudioParams, Latency},
+        dmap::{self, TrackMetadata},
+        session::{self, Quirks, SenderDevice},
+        stats::{StreamDescriptor, StreamParams},
         video::{VideoDevice, VideoParams},
     },
+    remote::RemoteId,
     streaming::{
-        AudioBufferedChannel, AudioRealtimeChannel, EventChannel, SharedData, VideoChannel,
+        AudioBufferedChannel, AudioRealtimeChannel, AudioRealtimeParams, ChannelCtx,
+        ControlChannel, EventChannel, FlushRange, NtpTimingClient, PlayoutScheduler, Priority,
+        PtpTimingClient, QueuedAudioStream, RecordStart, ReorderBuffer, SenderTimeline, SharedData,
+        StreamKind, TimingMode, TimingState, TokioClock, VideoChannel,
     },
+    util::{net, task::TaskSet},
 };
 
 use axum::{
-    extract::{ConnectInfo, State},
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
     response::{IntoResponse, Response},
 };
+use base64::{
+    Engine as _, alphabet,
+    engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+};
 use bytes::Bytes;
-use http::{header::CONTENT_TYPE, status::StatusCode};
+use http::{
+    HeaderMap, HeaderName, HeaderValue,
+    header::{CONNECTION, CONTENT_TYPE, USER_AGENT, WARNING},
+    status::StatusCode,
+};
+use hyper::ext::ReasonPhrase;
 
 use super::{
     dto::{
-        AudioBufferedRequest, AudioRealtimeRequest, Display, InfoResponse, SenderInfo,
-        SetupRequest, SetupResponse, StreamId, StreamRequest, StreamResponse, Teardown,
-        VideoRequest,
+        AudioBufferedRequest, AudioRealtimeRequest, Feedback, FeedbackResponse, FeedbackStream,
+        FlushBuffered, InfoResponse, InfoResponseBuilder, RemoteControlInfo, RtpInfo, SenderInfo,
+        SetupRequest, SetupResponse, StreamId, StreamRequest, StreamResponse, Teardown, TimingPeer,
+        TimingProtocol, TimingResponse, UnsupportedStream, VideoRequest,
     },
-    extractor::BinaryPlist,
+    extractor::{BinaryPlist, Plist, PlistFormat},
+    method::Method,
+    parameters::{self, Parameters, Query},
+    session::{ConnectionId, PeerAddr},
     state::SharedState,
 };
 
+/// Longest teardown waits for closed streams to release their ports, so a stuck processor
+/// doesn't hold the response back
+const TEARDOWN_WAIT: Duration = Duration::from_secs(2);
+
+/// Closes RTSP connections of a shut down receiver, senders see it on their next request.
+pub async fn refuse_after_shutdown(
+    State(tasks): State<Arc<TaskSet>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if tasks.is_cancelled() {
+        let close = [(CONNECTION, HeaderValue::from_static("close"))];
+        return (StatusCode::SERVICE_UNAVAILABLE, close).into_response();
+    }
+    next.run(req).await
+}
+
+/// Gives slots to sessions announced or set up, the ones over capacity are told the receiver
+/// is busy. Other requests keep the session of their connection from looking abandoned.
+pub async fn limit_sessions<A, V>(
+    State(state): State<SharedState<A, V>>,
+    conn: ConnectionId,
+    req: Request,
+    next: Next,
+) -> Response {
+    match Method::parse(req.method()) {
+        Some(Method::Announce | Method::Setup) => {
+            if let Err(busy) = state.sessions.acquire(conn) {
+                tracing::warn!(?conn, "session refused, receiver busy");
+                return busy.into_response();
+            }
+        }
+        _ => state.sessions.touch(conn),
+    }
+    next.run(req).await
+}
+
+/// Remembers DACP service of the sender, so it can be remote controlled.
+pub async fn remember_remote<A, V>(
+    State(state): State<SharedState<A, V>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if let Some(id) = RemoteId::from_headers(req.headers()) {
+        let mut remote = state.remote.lock().unwrap();
+        if remote.as_ref() != Some(&id) {
+            tracing::debug!(dacp_id = %id.dacp_id, "sender offers remote control");
+            *remote = Some(id);
+        }
+    }
+    next.run(req).await
+}
+
+/// Answers `Apple-Challenge` of legacy RAOP senders, other requests pass as is.
+pub async fn apple_challenge<A, V>(
+    State(state): State<SharedState<A, V>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    const APPLE_CHALLENGE: HeaderName = HeaderName::from_static("apple-challenge");
+    const APPLE_RESPONSE: HeaderName = HeaderName::from_static("apple-response");
+    // Senders strip padding from challenge and expect it stripped from response
+    const BASE64: GeneralPurpose = GeneralPurpose::new(
+        &alphabet::STANDARD,
+        GeneralPurposeConfig::new()
+            .with_encode_padding(false)
+            .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+    );
+
+    let challenge = req.headers().get(APPLE_CHALLENGE).cloned();
+    let local_ip = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let mut resp = next.run(req).await;
+
+    let (Some(challenge), Some(local_ip)) = (challenge, local_ip) else {
+        return resp;
+    };
+    let Some(key) = &state.cfg.pairing.challenge_key else {
+        tracing::debug!("no key to answer challenge");
+        return resp;
+    };
+
+    let signature = BASE64
+        .decode(challenge.as_bytes())
+        .map_err(|err| err.to_string())
+        .and_then(|challenge| {
+            rsa::sign_challenge(key, &challenge, local_ip, state.cfg.mac_addr.into_array())
+                .map_err(|err| err.to_string())
+        });
+    match signature {
+        Ok(signature) => {
+            let value = HeaderValue::try_from(BASE64.encode(signature))
+                .expect("base64 is a valid header value");
+            resp.headers_mut().insert(APPLE_RESPONSE, value);
+        }
+        Err(err) => tracing::warn!(%err, ?challenge, "challenge not answered"),
+    }
+    resp
+}
+
+/// Keepalive of the session, answered with status of streams if the sender asks for it.
+pub async fn feedback<A, V>(State(state): State<SharedState<A, V>>, body: Bytes) -> Response {
+    state.liveness.on_feedback();
+    state.streams.keepalive();
+
+    if body.is_empty() {
+        return StatusCode::OK.into_response();
+    }
+    match BinaryPlist::<Feedback>::from_bytes(&body) {
+        Ok(BinaryPlist(Feedback { streams: Some(_) })) => {
+            let streams = state
+                .streams
+                .stats()
+                .into_iter()
+                .map(|status| FeedbackStream {
+                    ty: StreamId::of(status.kind),
+                    id: status.id,
+                })
+                .collect();
+            BinaryPlist(FeedbackResponse { streams }).into_response()
+        }
+        Ok(_) => StatusCode::OK.into_response(),
+        Err(err) => {
+            tracing::debug!(%err, "malformed feedback");
+            StatusCode::OK.into_response()
+        }
+    }
+}
+
 pub async fn generic(bytes: Bytes) {
     tracing::trace!(?bytes, "generic handler");
 }
 
-pub async fn info<A, V>(State(state): State<SharedState<A, V>>) -> impl IntoResponse {
-    const PROTOVERS: &str = "1.1";
-    const SRCVERS: &str = "770.8.1";
-
-    let response = InfoResponse {
-        device_id: state.cfg.mac_addr,
-        mac_addr: state.cfg.mac_addr,
-        features: state.cfg.features.bits(),
-        protocol_version: PROTOVERS.to_string(),
-        source_version: SRCVERS.to_string(),
-
-        manufacturer: state.cfg.manufacturer.clone(),
-        model: state.cfg.model.clone(),
-        name: state.cfg.name.clone(),
-
-        // Seems like clients don't respect other displays and pick by maximum resolution
-        displays: vec![Display {
-            width: state.cfg.video.width,
-            height: state.cfg.video.height,
-            uuid: format!("{}_display", state.cfg.name),
-            max_fps: state.cfg.video.fps,
-            features: 2,
-        }],
-    };
+pub async fn info<A, V>(
+    State(state): State<SharedState<A, V>>,
+    format: PlistFormat,
+) -> impl IntoResponse {
+    info_builder(&state.cfg)
+        .build()
+        .map(|info| Plist(format, info))
+        .inspect_err(|err| tracing::error!(%err, "invalid info"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
 
-    BinaryPlist(response)
+/// Info of the receiver as configured, checked once served too.
+pub fn info_builder<A, V>(cfg: &Config<A, V>) -> InfoResponseBuilder {
+    InfoResponse::builder(cfg.mac_addr, &cfg.name)
+        .manufacturer(&cfg.manufacturer)
+        .model(&cfg.model)
+        .features(cfg.features)
+        .display(cfg.video.width, cfg.video.height, cfg.video.fps)
+        .hevc(cfg.video.hevc)
 }
 
 /// Don't really need request body here, because it duplicates signing key of counterparty got in
@@ -114,142 +268,588 @@ pub async fn fp_setup<A, V>(
     State(state): State<SharedState<A, V>>,
     body: Bytes,
 ) -> impl IntoResponse {
+    if !fairplay::SUPPORTED {
+        let err = fairplay::DecodingError::Unsupported;
+        tracing::error!(%err, "fairplay setup refused");
+        return Err((StatusCode::NOT_IMPLEMENTED, err.to_string()));
+    }
+
     fairplay::decode_buf(&body)
         .inspect(|_| {
-            // Magic number somehow. Hate em.
-            if body.len() == 164 {
+            // Stage-2 message, which the key is derived from
+            if body.len() == fairplay::KEY_MSG_LEN {
                 *state.fp_last_msg.lock().unwrap() = body;
             }
         })
         .inspect_err(|err| tracing::error!(%err, "failed to decode fairplay"))
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
 }
 
 pub async fn get_parameter<A: AudioDevice, V>(
     State(state): State<SharedState<A, V>>,
     body: String,
 ) -> impl IntoResponse {
-    match body.as_str() {
-        "volume\r\n" => {
-            let volume = state.cfg.audio.device.get_volume();
-            Ok((
-                [(CONTENT_TYPE, "text/parameters")],
-                format!("volume: {volume}\r\n"),
-            ))
+    let queries = match Query::parse_all(&body) {
+        Ok(queries) if !queries.is_empty() => queries,
+        Ok(_) => {
+            tracing::warn!("no parameter requested");
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        Err(param) => {
+            tracing::warn!(%param, "unknown parameter requested");
+            return Err(StatusCode::BAD_REQUEST);
         }
-        param => {
-            tracing::error!(?param, "unimplemented parameter");
-            Err(StatusCode::NOT_IMPLEMENTED)
+    };
+
+    let mut params = Parameters::default();
+    for query in queries {
+        match query {
+            Query::Volume => {
+                params.volume = Some(session::normalize_volume(
+                    state.cfg.audio.device.get_volume(),
+                ));
+            }
+        }
+    }
+    Ok(([(CONTENT_TYPE, parameters::MIME)], params.to_string()))
+}
+
+pub async fn set_parameter<A: AudioDevice, V>(
+    State(state): State<SharedState<A, V>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> StatusCode {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+
+    match content_type {
+        parameters::MIME => {
+            let Ok(body) = std::str::from_utf8(&body) else {
+                return StatusCode::BAD_REQUEST;
+            };
+            let params = Parameters::parse(body);
+
+            if let Some(volume) = params.volume {
+                let volume = session::normalize_volume(volume);
+                state.cfg.audio.device.set_volume(volume);
+                state.cfg.session.on_volume(volume);
+            }
+            if let Some(mut progress) = params.progress {
+                progress.sample_rate = state.audio_sample_rate.load(Ordering::Relaxed);
+                state.cfg.session.on_progress(progress);
+            }
+            if let Some(name) = params.name {
+                tracing::debug!(%name, "sender name");
+            }
+            if let Some(samples) = params.latency {
+                renegotiate_latency(&state, samples);
+            }
         }
+        dmap::MIME => match TrackMetadata::parse(&body) {
+            Ok(metadata) => state.cfg.session.on_metadata(metadata),
+            Err(err) => {
+                tracing::warn!(%err, "malformed track metadata");
+                return StatusCode::BAD_REQUEST;
+            }
+        },
+        mime if mime.starts_with("image/") => match Artwork::new(mime, body) {
+            Some(artwork) => state.cfg.session.on_artwork(artwork),
+            None => state.cfg.session.on_artwork_cleared(),
+        },
+        content_type => tracing::debug!(%content_type, len = %body.len(), "unhandled parameter"),
     }
+
+    StatusCode::OK
+}
+
+/// Moves playout of realtime audio to `samples` of latency, as the sender asked mid-session.
+///
+/// Buffered audio and video aren't scheduled by the receiver: the former is played at the
+/// anchor of `SETRATEANCHORTIME`, which already carries the latency of the sender, and the
+/// `latencyMs` of the latter is only sent in SETUP and passed on to its device.
+fn renegotiate_latency<A, V>(state: &SharedState<A, V>, samples: u32) {
+    let sample_rate = state.audio_sample_rate.load(Ordering::Relaxed);
+    if sample_rate == 0 {
+        tracing::warn!(%samples, "latency renegotiated without audio stream");
+        return;
+    }
+    let old = state.audio_latency.swap(samples, Ordering::Relaxed);
+    tracing::info!(old_samples = %old, new_samples = %samples, "sender renegotiated latency");
+
+    let nanos = u64::from(samples) * 1_000_000_000 / u64::from(sample_rate);
+    state
+        .streams
+        .set_latency_kind(StreamKind::AudioRealtime, Duration::from_nanos(nanos));
+}
+
+pub async fn flush<A, V>(State(state): State<SharedState<A, V>>, headers: HeaderMap) -> StatusCode {
+    let Some(info) = headers
+        .get("rtp-info")
+        .and_then(|value| value.to_str().ok())
+        .and_then(RtpInfo::parse)
+    else {
+        tracing::warn!("flush without rtp info");
+        return StatusCode::BAD_REQUEST;
+    };
+
+    state.streams.flush_kind(
+        StreamKind::AudioRealtime,
+        FlushRange {
+            from_seq: None,
+            until_seq: info.seq.into(),
+        },
+    );
+    StatusCode::OK
+}
+
+/// Starts realtime audio at `RTP-Info`, answered with the latency it's played with.
+pub async fn record<A, V>(State(state): State<SharedState<A, V>>, headers: HeaderMap) -> HeaderMap {
+    if let Some(info) = headers
+        .get("rtp-info")
+        .and_then(|value| value.to_str().ok())
+        .and_then(RtpInfo::parse)
+    {
+        state.streams.record_kind(
+            StreamKind::AudioRealtime,
+            RecordStart {
+                seq: info.seq,
+                rtptime: info.rtptime,
+            },
+        );
+    } else {
+        // Started by the first packet instead
+        tracing::debug!("record without rtp info");
+    }
+
+    let mut headers = HeaderMap::new();
+    let latency = state.audio_latency.load(Ordering::Relaxed);
+    if latency != 0 {
+        headers.insert(
+            HeaderName::from_static("audio-latency"),
+            HeaderValue::from(latency),
+        );
+    }
+    headers
 }
 
-pub async fn set_parameter(_body: Bytes) {}
+pub async fn flush_buffered<A, V>(
+    State(state): State<SharedState<A, V>>,
+    BinaryPlist(req): BinaryPlist<FlushBuffered>,
+) {
+    tracing::debug!(?req, "buffered flush");
+    state.streams.flush_kind(
+        StreamKind::AudioBuffered,
+        FlushRange {
+            from_seq: req.from_seq,
+            until_seq: req.until_seq,
+        },
+    );
+}
 
 pub async fn teardown<A, V>(
     State(state): State<SharedState<A, V>>,
+    conn: ConnectionId,
     BinaryPlist(req): BinaryPlist<Teardown>,
 ) {
-    let mut audio_realtime_channels = state.audio_realtime_channels.lock().unwrap();
-    let mut audio_buffered_channels = state.audio_buffered_channels.lock().unwrap();
-    let mut video_channels = state.video_channels.lock().unwrap();
-    if let Some(requests) = req.requests {
-        for req in requests {
-            if let Some(id) = req.id {
-                if let Some(chan) = audio_realtime_channels.remove(&id) {
-                    chan.close();
-                }
-                if let Some(chan) = audio_buffered_channels.remove(&id) {
-                    chan.close();
-                }
-                if let Some(chan) = video_channels.remove(&id) {
-                    chan.close();
-                }
-            } else {
-                match req.ty {
-                    StreamId::AUDIO_REALTIME => {
-                        audio_realtime_channels.drain().for_each(|(_, c)| c.close());
-                    }
-                    StreamId::AUDIO_BUFFERED => {
-                        audio_buffered_channels.drain().for_each(|(_, c)| c.close());
-                    }
-                    StreamId::VIDEO => video_channels.drain().for_each(|(_, c)| c.close()),
-                    _ => {}
-                }
-            }
+    let Some(requests) = req.requests else {
+        tracing::debug!("session teardown");
+        state.sessions.release(conn);
+        end_session(&state).await;
+        return;
+    };
+
+    let mut closed = Vec::new();
+    for req in requests {
+        let Some(kind) = StreamId::kind(req.ty) else {
+            tracing::debug!(ty = %req.ty, "teardown of unknown stream type");
+            continue;
+        };
+        let streams = state.streams.close_matching(kind, req.id);
+        if streams.is_empty() {
+            tracing::debug!(?kind, id = ?req.id, "teardown of unknown stream");
         }
-    } else {
-        audio_realtime_channels.drain().for_each(|(_, c)| c.close());
-        audio_buffered_channels.drain().for_each(|(_, c)| c.close());
-        video_channels.drain().for_each(|(_, c)| c.close());
+        closed.extend(streams);
+    }
+
+    // Ports of the streams are free for the next setup once answered
+    let deadline = tokio::time::Instant::now() + TEARDOWN_WAIT;
+    for stream in closed {
+        if tokio::time::timeout_at(deadline, &stream.finished)
+            .await
+            .is_err()
+        {
+            tracing::warn!(kind = ?stream.kind, "stream not finished by teardown, answered anyway");
+        }
+    }
+}
+
+/// Ends the session set up over `conn`, e.g. once the connection dropped.
+pub async fn connection_closed<A, V>(state: &SharedState<A, V>, conn: ConnectionId) {
+    let owned = *state.session_conn.lock().unwrap() == Some(conn);
+    if owned {
+        tracing::debug!(?conn, "session connection dropped");
+        end_session(state).await;
+    }
+}
+
+/// Forgets the session as if torn down, the app is told it ended if a sender set it up.
+async fn end_session<A, V>(state: &SharedState<A, V>) {
+    if let Some(conn) = state.session_conn.lock().unwrap().take() {
+        state.sessions.release(conn);
+    }
+    state.liveness.reset();
+    state.remote.lock().unwrap().take();
+    let sender = state.sender.lock().unwrap().take();
+    state.timing_mode.lock().unwrap().take();
+    *state.quirks.lock().unwrap() = Quirks::default();
+    state.streams.close_all();
+    state.event_channel.lock().await.take();
+    state.remote_control_only.store(false, Ordering::Release);
+    // PTP client outlives sessions, so it keeps reporting on its own
+    if let Some(client) = &*state.ptp_client.lock().await {
+        client.follow_peers([]);
+    }
+    if state.timing_client.lock().unwrap().take().is_some() {
+        state.timing.set(TimingState::Unsynced);
+    }
+    if let Some(sender) = sender {
+        tracing::info!(name = %sender.name, "sender session ended");
+        state.cfg.session.on_session_end();
     }
 }
 
+/// Ends the session of `conn` once its sender stops posting `/feedback`, as it's gone without
+/// tearing the session down.
+fn watch_liveness<A: AudioDevice, V: VideoDevice>(state: &SharedState<A, V>, conn: ConnectionId) {
+    let cancelled = state.tasks.cancelled();
+    // Checked twice per timeout, so stale sessions end within half of it
+    let period = (state.cfg.feedback_timeout / 2).max(Duration::from_millis(10));
+    let state = state.clone();
+    Arc::clone(&state.tasks).spawn(async move {
+        tokio::pin!(cancelled);
+        loop {
+            tokio::select! {
+                () = &mut cancelled => return,
+                () = tokio::time::sleep(period) => {}
+            }
+            if *state.session_conn.lock().unwrap() != Some(conn) {
+                return;
+            }
+            if state.liveness.is_stale() {
+                tracing::info!(?conn, "sender stopped posting feedback, session timed out");
+                end_session(&state).await;
+                return;
+            }
+        }
+    });
+}
+
 pub async fn setup<A: AudioDevice, V: VideoDevice>(
     state: State<SharedState<A, V>>,
+    conn: ConnectionId,
     connect_info: ConnectInfo<SocketAddr>,
+    peer: PeerAddr,
+    headers: HeaderMap,
+    format: PlistFormat,
     BinaryPlist(req): BinaryPlist<SetupRequest>,
 ) -> impl IntoResponse {
+    // Rather than allocating whatever was configured
+    if let Err(err) = state.cfg.buffers.validate() {
+        tracing::error!(%err, "setup refused");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
     match req {
-        SetupRequest::SenderInfo(info) => {
-            setup_info(state, connect_info, *info).await.into_response()
-        }
-        SetupRequest::Streams { requests } => setup_streams(state, connect_info, requests)
+        SetupRequest::RemoteControl(info) => setup_remote_control(state, connect_info, info)
             .await
+            .map(|resp| Plist(format, resp))
             .into_response(),
+        SetupRequest::SenderInfo(info) => {
+            let user_agent = headers
+                .get(USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string);
+            setup_info(state, conn, connect_info, peer, *info, user_agent)
+                .await
+                .map(|resp| Plist(format, resp))
+                .into_response()
+        }
+        SetupRequest::Streams { requests } => {
+            setup_streams(state, connect_info, peer, requests, format).await
+        }
     }
 }
 
-async fn setup_info<A, V>(
+/// Only events are set up, sender info following later on upgrades the session to media.
+async fn setup_remote_control<A, V>(
     State(state): State<SharedState<A, V>>,
     ConnectInfo(local_addr): ConnectInfo<SocketAddr>,
-    SenderInfo { ekey, eiv, .. }: SenderInfo,
-) -> impl IntoResponse {
+    info: RemoteControlInfo,
+) -> Result<SetupResponse, StatusCode> {
     let mut lock = state.event_channel.lock().await;
-    let event_channel = match &mut *lock {
-        Some(chan) => chan,
-        event_channel @ None => EventChannel::create(SocketAddr::new(local_addr.ip(), 0))
+    let event_port = event_channel(&state, &mut lock, local_addr)
+        .await?
+        .local_addr()
+        .port();
+
+    // Media session already set up keeps its streams
+    if state.sender.lock().unwrap().is_none() {
+        state.remote_control_only.store(true, Ordering::Release);
+    }
+    tracing::info!(name = ?info.name, device_id = ?info.device_id, %event_port, "remote control set up");
+    Ok(SetupResponse::Events { event_port })
+}
+
+/// Event channel of the session, created on its first setup.
+async fn event_channel<'a, A, V>(
+    state: &SharedState<A, V>,
+    lock: &'a mut Option<EventChannel>,
+    local_addr: SocketAddr,
+) -> Result<&'a mut EventChannel, StatusCode> {
+    match lock {
+        Some(chan) => Ok(chan),
+        event_channel @ None => net::bind_tcp(&state.cfg.sockets, local_addr.ip())
             .await
+            .and_then(|listener| {
+                let sink = (state.events.clone(), Arc::clone(&state.cfg.events));
+                EventChannel::create(listener, state.cfg.buffers, sink, &state.tasks)
+            })
             .inspect_err(|err| tracing::error!(%err, "failed creating event listener"))
             .map(|chan| event_channel.insert(chan))
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+async fn setup_info<A: AudioDevice, V: VideoDevice>(
+    State(state): State<SharedState<A, V>>,
+    conn: ConnectionId,
+    ConnectInfo(local_addr): ConnectInfo<SocketAddr>,
+    PeerAddr(peer): PeerAddr,
+    info: SenderInfo,
+    user_agent: Option<String>,
+) -> Result<SetupResponse, StatusCode> {
+    let sender = info.device(user_agent);
+    let SenderInfo {
+        ekey,
+        eiv,
+        timing_proto,
+        ..
+    } = info;
+
+    let mut lock = state.event_channel.lock().await;
+    let event_channel = event_channel(&state, &mut lock, local_addr).await?;
+
+    negotiate_key(&state, &ekey, &eiv)?;
+
+    remember_sender(&state, sender, conn);
+    if state.remote_control_only.swap(false, Ordering::AcqRel) {
+        tracing::debug!("remote control session upgraded to media");
+    } else {
+        tracing::debug!("media session set up");
+    }
+
+    let mode = match timing_proto {
+        TimingProtocol::Ptp { .. } => TimingMode::Ptp,
+        TimingProtocol::Ntp { .. } => TimingMode::Ntp,
     };
+    let timing = match timing_proto {
+        TimingProtocol::Ptp {
+            peer_info,
+            peer_list,
+        } => {
+            // Malformed addresses are skipped rather than failing the whole setup
+            let peers = peer_info
+                .iter()
+                .chain(peer_list.iter().flatten())
+                .flat_map(|peer| &peer.addresses)
+                .filter_map(|addr| {
+                    net::parse_peer_addr(addr, local_addr)
+                        .inspect_err(|err| {
+                            tracing::warn!(%err, %addr, "malformed timing peer address");
+                        })
+                        .ok()
+                })
+                .collect::<Vec<_>>();
+            tracing::debug!(?peers, "sender timing peers");
 
-    let Ok(eiv) = AesIv128::try_from(eiv.as_ref()) else {
-        tracing::error!(len=%eiv.len(), "invalid length of passed iv");
-        return Err(StatusCode::BAD_REQUEST);
+            // Single clock serves every session, as its ports are fixed
+            let mut ptp_client = state.ptp_client.lock().await;
+            if ptp_client.is_none() {
+                let clock = state.ptp_clock.clone();
+                match PtpTimingClient::create(local_addr, state.cfg.mac_addr, clock, &state.tasks)
+                    .await
+                {
+                    Ok(client) => *ptp_client = Some(client),
+                    // Streams are still played, only not in sync with other receivers
+                    Err(err) => tracing::warn!(%err, "ptp clock not started"),
+                }
+            }
+            if let Some(client) = &*ptp_client {
+                client.follow_peers(peers.iter().map(SocketAddr::ip));
+            }
+
+            // Our own clock is identified by the address senders connected to
+            let addr = local_addr.ip().to_string();
+            TimingResponse::Ptp {
+                timing_port: 0,
+                timing_peer_info: TimingPeer {
+                    id: addr.clone(),
+                    addresses: vec![addr],
+                    supports_clock_port_matching_override: None,
+                },
+            }
+        }
+        TimingProtocol::Ntp { remote_port } => {
+            let client = net::bind_udp(&state.cfg.sockets, local_addr.ip())
+                .await
+                .and_then(|socket| {
+                    let timing = state.timing.clone();
+                    NtpTimingClient::create(
+                        socket,
+                        peer,
+                        remote_port,
+                        timing,
+                        TokioClock,
+                        &state.tasks,
+                    )
+                })
+                .inspect_err(|err| tracing::error!(%err, "failed creating timing client"))
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let timing_port = client.local_addr().port();
+            *state.timing_client.lock().unwrap() = Some(client);
+
+            TimingResponse::Ntp { timing_port }
+        }
     };
 
+    let event_port = event_channel.local_addr().port();
+    tracing::debug!(
+        %event_port,
+        ?timing,
+        ports = ?state.cfg.sockets.ports,
+        "session ports allocated"
+    );
+    *state.timing_mode.lock().unwrap() = Some(mode);
+    Ok(SetupResponse::Info { event_port, timing })
+}
+
+/// Keys streams set up afterwards with `ekey` and `eiv` of sender info, or passes them as is if
+/// both are empty.
+fn negotiate_key<A, V>(
+    state: &SharedState<A, V>,
+    ekey: &[u8],
+    eiv: &[u8],
+) -> Result<(), StatusCode> {
     let Some(shared_secret) = state.pairing.lock().unwrap().shared_secret() else {
         tracing::error!("must be paired before setup call");
         return Err(StatusCode::FORBIDDEN);
     };
 
-    let aes_key = fairplay::decrypt_key(state.fp_last_msg.lock().unwrap().as_ref(), ekey);
-    let aes_digest = hash_aes_key(aes_key, shared_secret);
+    // Streams set up afterwards are sent in the clear
+    let unencrypted = ekey.is_empty() && eiv.is_empty();
+    if unencrypted && !state.cfg.pairing.allow_unencrypted {
+        tracing::error!("sender negotiated no encryption, which isn't allowed");
+        return Err(StatusCode::FORBIDDEN);
+    }
+    state.unencrypted.store(unencrypted, Ordering::Release);
+    if unencrypted {
+        tracing::info!("sender negotiated no encryption");
+    } else {
+        // Senders that skipped FairPlay setup wrap the key to the challenge one
+        let fp_last_msg = state.fp_last_msg.lock().unwrap().clone();
+        let wrap = match &state.cfg.pairing.challenge_key {
+            Some(key) if fp_last_msg.is_empty() => KeyWrap::Rsa(key),
+            _ => KeyWrap::FairPlay(&fp_last_msg),
+        };
+        let status = |err: EkeyError| {
+            tracing::error!(%err, "passed key couldn't be decrypted");
+            match err {
+                EkeyError::FairPlay(fairplay::DecodingError::Unsupported) => {
+                    StatusCode::NOT_IMPLEMENTED
+                }
+                _ => StatusCode::BAD_REQUEST,
+            }
+        };
+        let realtime = AudioRealtimeCipher::from_ekey_eiv(ekey, eiv, wrap, Some(&shared_secret))
+            .map_err(status)?;
+        // Video takes the key alone, unwrapped anew as ciphers don't give it back
+        let aes_key = wrap.decrypt(ekey).map_err(status)?;
 
-    *state.ekey.lock().unwrap() = aes_digest;
-    *state.eiv.lock().unwrap() = eiv;
+        *state.ekey.lock().unwrap() = hash_aes_key(aes_key, shared_secret);
+        *state.realtime_cipher.lock().unwrap() = realtime;
+    }
+    Ok(())
+}
 
-    // TODO : log more info from SenderInfo
+/// Remembers the sender along with its quirks, for streams set up afterwards, and begins its
+/// session over `conn`.
+fn remember_sender<A: AudioDevice, V: VideoDevice>(
+    state: &SharedState<A, V>,
+    sender: SenderDevice,
+    conn: ConnectionId,
+) {
+    tracing::info!(
+        name = %sender.name,
+        model = %sender.model,
+        os_name = ?sender.os_name,
+        os_version = ?sender.os_version,
+        os_build_version = ?sender.os_build_version,
+        user_agent = ?sender.user_agent,
+        "sender set up"
+    );
+    let quirks = state.cfg.session.on_sender(&sender);
+    if quirks != Quirks::default() {
+        tracing::debug!(?quirks, "sender quirks");
+    }
+    *state.quirks.lock().unwrap() = quirks;
 
-    Ok(BinaryPlist(SetupResponse::Info {
-        event_port: event_channel.local_addr().port(),
-        timing_port: 0,
-    }))
+    let previous = state.sender.lock().unwrap().replace(sender.clone());
+    match previous {
+        // Same sender setting up again, e.g. after its streams were torn down
+        Some(previous) if previous.device_id == sender.device_id => {}
+        // Sender taking the session over ends the one before, along with its streams
+        Some(previous) => {
+            tracing::info!(from = %previous.name, to = %sender.name, "sender took session over");
+            state.streams.close_all();
+            state.cfg.session.on_session_end();
+            state.cfg.session.on_session_begin(sender);
+        }
+        None => state.cfg.session.on_session_begin(sender),
+    }
+    if state.session_conn.lock().unwrap().replace(conn) != Some(conn) {
+        watch_liveness(state, conn);
+    }
 }
 
 async fn setup_streams<A: AudioDevice, V: VideoDevice>(
     State(state): State<SharedState<A, V>>,
     ConnectInfo(local_addr): ConnectInfo<SocketAddr>,
+    PeerAddr(peer): PeerAddr,
     requests: Vec<StreamRequest>,
+    format: PlistFormat,
 ) -> Response {
+    if state.remote_control_only.load(Ordering::Acquire) {
+        tracing::warn!("streams of remote control only session");
+        let status = StatusCode::from_u16(455).expect("status is valid");
+        let mut resp = status.into_response();
+        resp.extensions_mut()
+            .insert(ReasonPhrase::from_static(b"Method Not Valid in This State"));
+        return resp;
+    }
+    if requests.len() > state.cfg.max_setup_streams {
+        tracing::warn!(count = %requests.len(), "too many streams to set up");
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    }
+
     let mut responses = Vec::with_capacity(requests.len());
+    let mut unsupported = Vec::new();
     for stream in requests {
         let id = state.last_stream_id.fetch_add(1, Ordering::AcqRel);
         match match stream {
             StreamRequest::AudioRealtime(request) => {
-                setup_realtime_audio(state.clone(), local_addr, request, id).await
+                setup_realtime_audio(state.clone(), local_addr, peer, request, id).await
             }
             StreamRequest::AudioBuffered(request) => {
                 setup_buffered_audio(state.clone(), local_addr, request, id).await
@@ -257,39 +857,170 @@ async fn setup_streams<A: AudioDevice, V: VideoDevice>(
             StreamRequest::Video(request) => {
                 setup_video(state.clone(), local_addr, request, id).await
             }
+            StreamRequest::Unsupported(stream) => {
+                tracing::warn!(ty = %stream.ty, "unsupported stream type");
+                unsupported.push(stream);
+                continue;
+            }
         } {
             Ok(response) => responses.push(response),
-            Err(err) => return err,
+            Err(err) => {
+                // Nobody would tear down streams of a failed setup
+                for response in &responses {
+                    state.streams.close(response.id());
+                }
+                return err;
+            }
         }
     }
 
-    BinaryPlist(SetupResponse::Streams { responses }).into_response()
+    let warning = unsupported_warning(&unsupported);
+    if responses.is_empty() {
+        return (StatusCode::BAD_REQUEST, warning).into_response();
+    }
+
+    (warning, Plist(format, SetupResponse::Streams { responses })).into_response()
+}
+
+/// `Warning` header naming every skipped stream type, so senders see why they weren't set up.
+fn unsupported_warning(unsupported: &[UnsupportedStream]) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if unsupported.is_empty() {
+        return headers;
+    }
+
+    let warning = unsupported
+        .iter()
+        .map(|stream| format!("199 - \"unsupported stream type {}\"", stream.ty))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if let Ok(value) = HeaderValue::try_from(warning) {
+        headers.insert(WARNING, value);
+    }
+    headers
+}
+
+/// Data of a stream about to be set up, its packets impaired and kept as configured.
+#[cfg_attr(
+    not(any(feature = "testing", feature = "diagnostics")),
+    allow(unused_variables)
+)]
+fn stream_data<A, V>(state: &SharedState<A, V>, kind: StreamKind) -> Arc<SharedData> {
+    #[cfg_attr(
+        not(any(feature = "testing", feature = "diagnostics")),
+        allow(unused_mut)
+    )]
+    let mut data = SharedData::new(kind);
+    #[cfg(feature = "testing")]
+    if let Some(shaping) = state.cfg.ingest_shaping {
+        data.shaper = crate::streaming::IngestShaper::new(shaping);
+    }
+    #[cfg(feature = "diagnostics")]
+    {
+        data.history = crate::streaming::PacketHistory::new(state.cfg.packet_history);
+    }
+    Arc::new(data)
+}
+
+/// Channel of a stream decrypted at `priority`, as the config lays it out.
+fn channel_ctx<'a, A, V, C, S>(
+    state: &'a SharedState<A, V>,
+    shared_data: &Arc<SharedData>,
+    priority: Priority,
+    cipher: C,
+    stream: S,
+) -> ChannelCtx<'a, C, S> {
+    ChannelCtx {
+        shared_data: Arc::clone(shared_data),
+        buffers: state.cfg.buffers,
+        offload: state.offload(priority),
+        idle_timeout: state.cfg.idle_timeout,
+        cipher,
+        stream,
+        tasks: &state.tasks,
+    }
 }
 
+/// Describes the stream just set up and lets requests of the session reach it.
+fn register<A, V>(
+    state: &SharedState<A, V>,
+    id: u64,
+    shared_data: Arc<SharedData>,
+    params: StreamParams,
+    local_data_port: u16,
+    local_control_port: Option<u16>,
+) {
+    let descriptor = StreamDescriptor {
+        id,
+        kind: shared_data.kind,
+        params,
+        local_data_port,
+        local_control_port,
+        timing: *state.timing_mode.lock().unwrap(),
+    };
+    tracing::debug!(?descriptor, "stream set up");
+    let _ = shared_data.descriptor.set(descriptor);
+    state.streams.insert(id, shared_data);
+}
+
+/// Refuses formats whose packets can't be told apart or that the device can't play.
+fn check_audio_format<A, V>(
+    state: &SharedState<A, V>,
+    format: &AudioFormat,
+    content_type: u8,
+) -> Result<(), (StatusCode, String)> {
+    // Framing of packets follows `ct`, so it mustn't disagree with the format
+    format
+        .check_content_type(content_type)
+        .inspect_err(|err| tracing::error!(%err, "audio content type mismatch"))
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+    state
+        .cfg
+        .audio
+        .supported_formats
+        .check(format)
+        .inspect_err(|err| tracing::warn!(%err, "audio format refused"))
+        .map_err(|err| (StatusCode::UNSUPPORTED_MEDIA_TYPE, err.to_string()))
+}
+
+#[allow(clippy::too_many_lines)]
 async fn setup_realtime_audio<A: AudioDevice, V>(
     state: SharedState<A, V>,
     local_addr: SocketAddr,
+    peer: Option<SocketAddr>,
     AudioRealtimeRequest {
+        content_type,
         audio_format,
         samples_per_frame,
+        sample_rate,
+        min_latency_samples,
+        max_latency_samples,
+        remote_control_port,
         ..
     }: AudioRealtimeRequest,
     id: u64,
 ) -> Result<StreamResponse, Response> {
-    let Some(codec) = AUDIO_FORMATS
-        .get(audio_format.trailing_zeros() as usize)
-        .copied()
-    else {
+    let Some(format) = AudioFormat::from_bits(audio_format) else {
         tracing::error!(%audio_format, "unknown audio codec");
         return Err(StatusCode::BAD_REQUEST.into_response());
     };
+    check_audio_format(&state, &format, content_type).map_err(IntoResponse::into_response)?;
+
+    let latency = Latency::new(min_latency_samples, max_latency_samples, sample_rate)
+        .inspect_err(|err| tracing::error!(%err, "invalid audio latency"))
+        .map_err(|_| StatusCode::BAD_REQUEST.into_response())?;
 
-    let cipher = AudioRealtimeCipher::new(*state.ekey.lock().unwrap(), *state.eiv.lock().unwrap());
+    let cipher = if state.unencrypted.load(Ordering::Acquire) {
+        AudioRealtimeCipher::unencrypted()
+    } else {
+        state.realtime_cipher.lock().unwrap().clone()
+    };
 
-    let shared_data = Arc::new(SharedData::default());
+    let shared_data = stream_data(&state, StreamKind::AudioRealtime);
     let params = AudioParams {
         samples_per_frame,
-        codec,
+        format,
+        latency: Some(latency),
     };
     let stream = state
         .cfg
@@ -304,21 +1035,79 @@ async fn setup_realtime_audio<A: AudioDevice, V>(
         .inspect_err(|err| tracing::error!(%err, ?params, "stream couldn't be created"))
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
 
-    AudioRealtimeChannel::create(
-        SocketAddr::new(local_addr.ip(), 0),
-        SocketAddr::new(local_addr.ip(), 0),
-        state.cfg.audio.buf_size,
-        shared_data.clone(),
-        cipher,
+    // Packets held longer than the playout delay would be played late anyway
+    let reorder_depth = state
+        .cfg
+        .audio
+        .reorder_depth
+        .min(latency.target_depth(samples_per_frame));
+    let reorder_deadline = state
+        .cfg
+        .audio
+        .reorder_deadline
+        .min(latency.playout_delay());
+
+    let clock = state
+        .timing_client
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(NtpTimingClient::clock);
+    let playout = state
+        .cfg
+        .audio
+        .playout_scheduling
+        .then(|| PlayoutScheduler::new(latency.sample_rate, latency.playout_delay()));
+    let bind = async {
+        let data = net::bind_udp(&state.cfg.sockets, local_addr.ip()).await?;
+        let control = net::bind_udp(&state.cfg.sockets, local_addr.ip()).await?;
+        io::Result::Ok((data, control))
+    };
+    let (data_socket, control_socket) = bind
+        .await
+        .inspect_err(|err| tracing::error!(%err, "realtime audio sockets not bound"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+
+    let realtime = AudioRealtimeParams {
+        control: ControlChannel::new(
+            control_socket,
+            peer.map(|peer| peer.ip()),
+            remote_control_port,
+            state.cfg.audio.retransmit_window,
+            Arc::clone(&shared_data),
+        ),
+        payload_type: StreamId::AUDIO_REALTIME,
+        reorder: ReorderBuffer::new(reorder_depth, reorder_deadline),
+        clock,
+        local_clock: TokioClock,
+        timing: state.timing.clone(),
+        playout,
+    };
+    QueuedAudioStream::spawn(
         stream,
+        state.cfg.audio.queue_len,
+        state.cfg.audio.realtime_backpressure,
+        Arc::clone(&shared_data),
     )
-    .await
-    .inspect(|_| {
+    .and_then(|stream| {
+        let ctx = channel_ctx(&state, &shared_data, Priority::Audio, cipher, stream);
+        AudioRealtimeChannel::create(data_socket, realtime, ctx)
+    })
+    .inspect(|chan| {
+        register(
+            &state,
+            id,
+            shared_data,
+            StreamParams::Audio(params),
+            chan.local_data_addr.port(),
+            Some(chan.local_control_addr.port()),
+        );
         state
-            .audio_realtime_channels
-            .lock()
-            .unwrap()
-            .insert(id, shared_data);
+            .audio_sample_rate
+            .store(sample_rate, Ordering::Relaxed);
+        state
+            .audio_latency
+            .store(latency.target_samples(), Ordering::Relaxed);
     })
     .inspect_err(|err| tracing::error!(%err, "realtime audio listener not created"))
     .map(|chan| StreamResponse::AudioRealtime {
@@ -333,41 +1122,38 @@ async fn setup_buffered_audio<A: AudioDevice, V>(
     state: SharedState<A, V>,
     local_addr: SocketAddr,
     AudioBufferedRequest {
+        content_type,
         samples_per_frame,
         audio_format,
         audio_format_index,
+        sample_rate,
+        bits_per_sample,
+        channels,
         shared_key,
         ..
     }: AudioBufferedRequest,
     id: u64,
 ) -> Result<StreamResponse, Response> {
-    let Some(codec) = AUDIO_FORMATS
-        .get(audio_format_index.map_or_else(|| audio_format.trailing_zeros() as usize, usize::from))
-        .copied()
-    else {
+    let format = AudioFormat::select(audio_format, audio_format_index)
+        .and_then(|format| format.with_layout(sample_rate, bits_per_sample, channels))
+        .inspect_err(|err| tracing::error!(%err, "audio format couldn't be chosen"))
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()).into_response())?;
+    check_audio_format(&state, &format, content_type).map_err(IntoResponse::into_response)?;
+
+    let Some(cipher) = AudioBufferedCipher::from_shared_key(&shared_key) else {
         tracing::error!(
-            %audio_format,
-            ?audio_format_index,
-            "unknown audio codec"
+            len = shared_key.len(),
+            "no cipher suite for length of buffered audio key"
         );
         return Err(StatusCode::BAD_REQUEST.into_response());
     };
+    tracing::debug!(config = ?cipher.config(), "buffered audio cipher");
 
-    let cipher = AudioBufferedCipher::new(
-        <[u8; AudioBufferedCipher::KEY_LEN]>::try_from(shared_key.as_ref())
-            .inspect_err(|_| {
-                tracing::error!(
-                    len = shared_key.len(),
-                    "insufficient length of key for buffered audio's decryption"
-                );
-            })
-            .map_err(|_| StatusCode::BAD_REQUEST.into_response())?,
-    );
-
-    let shared_data = Arc::new(SharedData::default());
+    let shared_data = stream_data(&state, StreamKind::AudioBuffered);
     let params = AudioParams {
         samples_per_frame,
-        codec,
+        format,
+        latency: None,
     };
     let stream = state
         .cfg
@@ -382,20 +1168,38 @@ async fn setup_buffered_audio<A: AudioDevice, V>(
         .inspect_err(|err| tracing::error!(%err, ?params, "stream couldn't be created"))
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
 
-    AudioBufferedChannel::create(
-        SocketAddr::new(local_addr.ip(), 0),
-        state.cfg.audio.buf_size,
-        shared_data.clone(),
-        cipher,
+    let listener = net::bind_tcp(&state.cfg.sockets, local_addr.ip())
+        .await
+        .inspect_err(|err| tracing::error!(%err, "buffered audio listener not bound"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+
+    QueuedAudioStream::spawn(
         stream,
+        state.cfg.audio.queue_len,
+        state.cfg.audio.buffered_backpressure,
+        Arc::clone(&shared_data),
     )
-    .await
-    .inspect(|_| {
+    .and_then(|stream| {
+        let ctx = channel_ctx(&state, &shared_data, Priority::Audio, cipher, stream);
+        AudioBufferedChannel::create(
+            listener,
+            state.cfg.audio.max_decrypt_failures,
+            state.timing.clone(),
+            ctx,
+        )
+    })
+    .inspect(|chan| {
+        register(
+            &state,
+            id,
+            shared_data,
+            StreamParams::Audio(params),
+            chan.local_addr.port(),
+            None,
+        );
         state
-            .audio_buffered_channels
-            .lock()
-            .unwrap()
-            .insert(id, shared_data);
+            .audio_sample_rate
+            .store(format.sample_rate, Ordering::Relaxed);
     })
     .inspect_err(|err| tracing::error!(%err, "buffered audio listener not created"))
     .map(|chan| StreamResponse::AudioBuffered {
@@ -406,44 +1210,82 @@ async fn setup_buffered_audio<A: AudioDevice, V>(
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())
 }
 
+/// Clock of the session's timestamps, `None` until the sender tells which one it is.
+fn timeline<A, V>(state: &SharedState<A, V>) -> Option<SenderTimeline> {
+    match (*state.timing_mode.lock().unwrap())? {
+        TimingMode::Ntp => state
+            .timing_client
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|client| SenderTimeline::Ntp(client.clock())),
+        TimingMode::Ptp => Some(SenderTimeline::Ptp(state.ptp_clock.clone())),
+    }
+}
+
 async fn setup_video<A, V: VideoDevice>(
     state: SharedState<A, V>,
     local_addr: SocketAddr,
     VideoRequest {
         stream_connection_id,
-        ..
+        latency_ms,
+        using_screen,
+        timestamp_info,
     }: VideoRequest,
     id: u64,
 ) -> Result<StreamResponse, Response> {
+    tracing::debug!(%stream_connection_id, ?using_screen, ?timestamp_info, "mirroring setup");
+
     // This must work like that
     #[allow(clippy::cast_sign_loss)]
-    let cipher = VideoCipher::new(*state.ekey.lock().unwrap(), stream_connection_id as u64);
+    let cipher = if state.unencrypted.load(Ordering::Acquire) {
+        VideoCipher::unencrypted()
+    } else {
+        VideoCipher::new(*state.ekey.lock().unwrap(), stream_connection_id as u64)
+    };
 
-    let shared_data = Arc::new(SharedData::default());
-    let params = VideoParams {};
+    let shared_data = stream_data(&state, StreamKind::Video);
+    let params = VideoParams {
+        latency: Duration::from_millis(latency_ms.into()),
+        using_screen,
+    };
     let stream = state
         .cfg
         .video
         .device
         .create(
             id,
-            VideoParams {},
+            params,
             Arc::downgrade(&shared_data) as Weak<dyn ChannelHandle>,
         )
         .await
         .inspect_err(|err| tracing::error!(%err, ?params, "stream couldn't be created"))
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
 
+    let mut decrypted_other_kinds = state.cfg.video.decrypted_other_kinds.clone();
+    decrypted_other_kinds.extend(&state.quirks.lock().unwrap().decrypted_other_kinds);
+
+    let listener = net::bind_tcp(&state.cfg.sockets, local_addr.ip())
+        .await
+        .inspect_err(|err| tracing::error!(%err, "video listener not bound"))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+
     VideoChannel::create(
-        SocketAddr::new(local_addr.ip(), 0),
-        state.cfg.video.buf_size,
-        shared_data.clone(),
-        cipher,
-        stream,
+        listener,
+        decrypted_other_kinds,
+        state.cfg.video.reassemble_frames,
+        timeline(&state),
+        channel_ctx(&state, &shared_data, Priority::Video, cipher, stream),
     )
-    .await
-    .inspect(|_| {
-        state.video_channels.lock().unwrap().insert(id, shared_data);
+    .inspect(|chan| {
+        register(
+            &state,
+            id,
+            shared_data,
+            StreamParams::Video(params),
+            chan.local_addr.port(),
+            None,
+        );
     })
     .inspect_err(|err| tracing::error!(%err, "video listener not created"))
     .map(|chan| StreamResponse::Video {
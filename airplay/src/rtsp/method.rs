@@ -0,0 +1,127 @@
+use axum::{
+    extract::{Request, State},
+    handler::Handler,
+    response::{IntoResponse, Response},
+};
+use http::{HeaderName, HeaderValue, StatusCode};
+
+use super::{handlers, state::SharedState};
+use crate::playback::{audio::AudioDevice, video::VideoDevice};
+
+/// RTSP methods served by the receiver, axum's method routing only knows HTTP ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Options,
+    Announce,
+    Setup,
+    Record,
+    GetParameter,
+    SetParameter,
+    Flush,
+    FlushBuffered,
+    Teardown,
+}
+
+impl Method {
+    const ALL: [Self; 9] = [
+        Self::Options,
+        Self::Announce,
+        Self::Setup,
+        Self::Record,
+        Self::GetParameter,
+        Self::SetParameter,
+        Self::Flush,
+        Self::FlushBuffered,
+        Self::Teardown,
+    ];
+
+    pub fn parse(method: &http::Method) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|known| known.as_str() == method.as_str())
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Options => "OPTIONS",
+            Self::Announce => "ANNOUNCE",
+            Self::Setup => "SETUP",
+            Self::Record => "RECORD",
+            Self::GetParameter => "GET_PARAMETER",
+            Self::SetParameter => "SET_PARAMETER",
+            Self::Flush => "FLUSH",
+            Self::FlushBuffered => "FLUSHBUFFERED",
+            Self::Teardown => "TEARDOWN",
+        }
+    }
+
+    /// Value of `Public` header in response to `OPTIONS`.
+    fn public() -> String {
+        let mut methods = Self::ALL.map(Self::as_str).to_vec();
+        // Served by axum routes
+        methods.extend(["GET", "POST"]);
+        methods.join(", ")
+    }
+}
+
+/// Handles RTSP methods of a stream url, unknown ones are answered with `501 Not Implemented`.
+pub async fn dispatch<A: AudioDevice, V: VideoDevice>(
+    State(state): State<SharedState<A, V>>,
+    req: Request,
+) -> Response {
+    let Some(method) = Method::parse(req.method()) else {
+        return not_implemented(&req).into_response();
+    };
+
+    match method {
+        Method::Options => options().into_response(),
+        // Legacy RAOP describes the stream with SDP here, nothing is taken from it
+        Method::Announce => handlers::generic.call(req, state).await,
+        Method::Record => handlers::record.call(req, state).await,
+        Method::Setup => handlers::setup.call(req, state).await,
+        Method::GetParameter => handlers::get_parameter.call(req, state).await,
+        Method::SetParameter => handlers::set_parameter.call(req, state).await,
+        Method::Flush => handlers::flush.call(req, state).await,
+        Method::FlushBuffered => handlers::flush_buffered.call(req, state).await,
+        Method::Teardown => handlers::teardown.call(req, state).await,
+    }
+}
+
+/// Handles requests not matching any route, e.g. `OPTIONS *`.
+pub async fn fallback(req: Request) -> Response {
+    match Method::parse(req.method()) {
+        Some(Method::Options) => options().into_response(),
+        _ if matches!(*req.method(), http::Method::GET | http::Method::POST) => {
+            handlers::generic.call(req, ()).await
+        }
+        _ => not_implemented(&req).into_response(),
+    }
+}
+
+fn options() -> impl IntoResponse {
+    let public = HeaderValue::try_from(Method::public()).expect("method names are valid");
+    [(HeaderName::from_static("public"), public)]
+}
+
+fn not_implemented(req: &Request) -> StatusCode {
+    tracing::warn!(method = ?req.method(), path = ?req.uri(), "unknown method");
+    StatusCode::NOT_IMPLEMENTED
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Method;
+
+    #[test]
+    fn parse_methods() {
+        for method in Method::ALL {
+            let parsed = http::Method::from_bytes(method.as_str().as_bytes()).unwrap();
+            assert_eq!(Some(method), Method::parse(&parsed));
+        }
+        let unknown = http::Method::from_bytes(b"PLAY").unwrap();
+        assert_eq!(None, Method::parse(&unknown));
+        assert_eq!(None, Method::parse(&http::Method::GET));
+
+        assert!(Method::public().starts_with("OPTIONS, ANNOUNCE, SETUP"));
+    }
+}
@@ -1,39 +1,141 @@
 use std::{
+    future::Future,
+    io,
     net::SocketAddr,
+    sync::{Arc, Mutex},
     task::{Context, Poll},
+    time::Duration,
 };
 
 use axum::{
     Router,
-    extract::{Request, connect_info::IntoMakeServiceWithConnectInfo},
-    handler::Handler,
+    extract::{DefaultBodyLimit, connect_info::IntoMakeServiceWithConnectInfo},
     http::HeaderName,
+    middleware,
     routing::{any, get, post},
 };
-use state::SharedState;
+use futures::{FutureExt as _, future::BoxFuture};
+use session::{ConnectionId, SessionRegistry};
+use state::{Liveness, SharedState};
+use thiserror::Error;
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, watch},
+};
 use tower::Service;
 use tower_http::propagate_header::PropagateHeaderLayer;
 
 use crate::{
-    config::Config,
-    playback::{audio::AudioDevice, video::VideoDevice},
+    advertise::{AdvertiseError, Advertisement, PendingAdvertisement},
+    config::{BufferConfig, BufferConfigError, Config},
+    crypto::rsa::{self, RsaError},
+    playback::{
+        audio::AudioDevice,
+        event::EventMessage,
+        session::SenderDevice,
+        stats::{StreamDescriptor, StreamStatus},
+        video::VideoDevice,
+    },
+    remote::{Remote, RemoteError, RemoteId},
+    streaming::{StreamRegistry, TimingMonitor},
+    util::task::TaskSet,
 };
 
 mod dto;
 mod extractor;
 mod handlers;
+mod method;
+mod parameters;
+mod session;
 mod state;
+#[cfg(feature = "testing")]
+pub mod test_sender;
+mod transport;
+
+pub use crate::streaming::{PtpClock, PtpLock, TimingMode, TimingState, TimingStatus};
+pub use dto::{Display, InfoError, InfoResponse, InfoResponseBuilder};
 
+pub(crate) const PROTOCOL_VERSION: &str = "1.1";
+pub(crate) const SOURCE_VERSION: &str = "770.8.1";
+
+#[derive(Clone)]
 pub struct RouterService {
     inner: IntoMakeServiceWithConnectInfo<Router<()>, SocketAddr>,
+    events: broadcast::Sender<EventMessage>,
+    streams: Arc<StreamRegistry>,
+    ptp_clock: PtpClock,
+    timing: TimingMonitor,
+    tasks: Arc<TaskSet>,
+    liveness: Arc<Liveness>,
+    remote: Arc<Mutex<Option<RemoteId>>>,
+    sessions: Arc<SessionRegistry>,
+    sender: Arc<Mutex<Option<SenderDevice>>>,
+    /// Ends the session of a connection once it's closed
+    on_disconnect: OnDisconnect,
+}
+
+type OnDisconnect = Arc<dyn Fn(ConnectionId) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// Owner of every task spawned for senders, e.g. of streams and timing.
+///
+/// Dropping it cancels them without waiting, [`Receiver::shutdown`] waits for them too.
+pub struct Receiver {
+    service: RouterService,
+    rtsp_addr: SocketAddr,
+    buffers: BufferConfig,
+    /// Checked at construction, reported once served
+    challenge_key: Result<(), RsaError>,
+    /// Features checked once served
+    info: InfoResponseBuilder,
+    /// Taken once served
+    advertisement: Option<PendingAdvertisement>,
+}
+
+/// Stops [`Receiver::serve`] from another task.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    tasks: Arc<TaskSet>,
+}
+
+#[derive(Debug, Error)]
+pub enum ServeError {
+    #[error("rtsp listener: {0}")]
+    Listen(#[from] io::Error),
+    #[error(transparent)]
+    Advertise(#[from] AdvertiseError),
+    #[error(transparent)]
+    Buffers(#[from] BufferConfigError),
+    #[error("challenge key: {0}")]
+    ChallengeKey(#[from] RsaError),
+    #[error(transparent)]
+    Info(#[from] InfoError),
 }
 
 impl RouterService {
+    const REMOTE_RESOLVE_TIMEOUT: Duration = Duration::from_secs(3);
+
     pub fn serve<ADev: AudioDevice, VDev: VideoDevice>(cfg: Config<ADev, VDev>) -> Self {
         let state = SharedState::with_config(cfg);
+        let events = state.events.clone();
+        let streams = Arc::clone(&state.streams);
+        let ptp_clock = state.ptp_clock.clone();
+        let timing = state.timing.clone();
+        let tasks = Arc::clone(&state.tasks);
+        let liveness = Arc::clone(&state.liveness);
+        let remote = Arc::clone(&state.remote);
+        let sessions = Arc::clone(&state.sessions);
+        let sender = Arc::clone(&state.sender);
+        let on_disconnect: OnDisconnect = {
+            let state = state.clone();
+            Arc::new(move |conn| {
+                let state = state.clone();
+                async move { handlers::connection_closed(&state, conn).await }.boxed()
+            })
+        };
+        let body_limit = DefaultBodyLimit::max(state.cfg.max_body_len);
         let inner = Router::new()
             // Heartbeat
-            .route("/feedback", post(()))
+            .route("/feedback", post(handlers::feedback))
             // I guess it will never be used
             .route("/command", post(()))
             // General info about server
@@ -43,33 +145,240 @@ impl RouterService {
             .route("/pair-verify", post(handlers::pair_verify))
             // Fair play, for additional encryption of keys
             .route("/fp-setup", post(handlers::fp_setup))
-            // Unknown handlers' response will be just traced
-            .fallback(handlers::generic)
-            // State cloned here, because it will be moved below
-            .with_state(state.clone())
             // Custom RTSP methods
-            .route(
-                "/{media_id}",
-                any(|req: Request| async move {
-                    match req.method().as_str() {
-                        // This is empty and useless
-                        "RECORD" => handlers::generic.call(req, state).await,
-                        "SETUP" => handlers::setup.call(req, state).await,
-                        "GET_PARAMETER" => handlers::get_parameter.call(req, state).await,
-                        "SET_PARAMETER" => handlers::set_parameter.call(req, state).await,
-                        "TEARDOWN" => handlers::teardown.call(req, state).await,
-                        method => {
-                            tracing::warn!(?method, path = ?req.uri(), "unknown method");
-                            handlers::generic.call(req, state).await
-                        }
-                    }
-                }),
-            )
+            .route("/{media_id}", any(method::dispatch))
+            // Unknown handlers' response will be just traced
+            .fallback(method::fallback)
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                handlers::apple_challenge,
+            ))
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                handlers::remember_remote,
+            ))
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                handlers::limit_sessions,
+            ))
+            .layer(body_limit)
+            .with_state(state)
+            .layer(middleware::from_fn_with_state(
+                Arc::clone(&tasks),
+                handlers::refuse_after_shutdown,
+            ))
             // CSeq is required for RTSP protocol
             .layer(PropagateHeaderLayer::new(HeaderName::from_static("cseq")))
             .into_make_service_with_connect_info::<SocketAddr>();
 
-        Self { inner }
+        Self {
+            inner,
+            events,
+            streams,
+            ptp_clock,
+            timing,
+            tasks,
+            liveness,
+            remote,
+            sessions,
+            sender,
+            on_disconnect,
+        }
+    }
+
+    /// Messages sent by senders over event channel, lagging receivers miss the oldest ones.
+    #[must_use]
+    pub fn subscribe_events(&self) -> broadcast::Receiver<EventMessage> {
+        self.events.subscribe()
+    }
+
+    /// Counters of every running stream, cheap enough to be polled.
+    #[must_use]
+    pub fn stats(&self) -> Vec<StreamStatus> {
+        self.streams.stats()
+    }
+
+    /// What was negotiated in setup of the stream of `id`, e.g. to configure the output before
+    /// its first packet. `None` once the stream is finished or torn down.
+    #[must_use]
+    pub fn stream(&self, id: u64) -> Option<StreamDescriptor> {
+        self.streams.descriptor(id)
+    }
+
+    /// Clock of PTP senders, lock on it before scheduling buffered audio by their timestamps.
+    #[must_use]
+    pub fn ptp_clock(&self) -> PtpClock {
+        self.ptp_clock.clone()
+    }
+
+    /// Raw packets the stream of `id` read last, at most [`Config::packet_history`] of them
+    /// and oldest first, e.g. to look into what it choked on. `None` once the stream is
+    /// finished or torn down, its packets are logged then if it failed.
+    #[cfg(feature = "diagnostics")]
+    #[must_use]
+    pub fn recent_packets(&self, id: u64) -> Option<Vec<bytes::Bytes>> {
+        self.streams.recent_packets(id)
+    }
+
+    /// Sync of the sender's clock, audio streams hold their packets while it's
+    /// [`TimingState::Lost`].
+    #[must_use]
+    pub fn timing(&self) -> watch::Receiver<TimingStatus> {
+        self.timing.subscribe()
+    }
+
+    /// Whether the sender stopped posting `/feedback` for longer than
+    /// [`Config::feedback_timeout`], e.g. it's gone without tearing the session down.
+    #[must_use]
+    pub fn is_stale(&self) -> bool {
+        self.liveness.is_stale()
+    }
+
+    /// Count of senders holding a session, see [`Config::session_policy`].
+    #[must_use]
+    pub fn active_sessions(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// DACP service the sender offered, forgotten on session teardown.
+    ///
+    /// # Panics
+    ///
+    /// If a request handler panicked while remembering it.
+    #[must_use]
+    pub fn remote_id(&self) -> Option<RemoteId> {
+        self.remote.lock().unwrap().clone()
+    }
+
+    /// Sender of the session as it described itself during setup, forgotten on session
+    /// teardown.
+    ///
+    /// # Panics
+    ///
+    /// If a request handler panicked while remembering it.
+    #[must_use]
+    pub fn sender(&self) -> Option<SenderDevice> {
+        self.sender.lock().unwrap().clone()
+    }
+
+    /// Resolves DACP service of the sender to send it playback commands.
+    ///
+    /// # Errors
+    ///
+    /// If the sender offered none or it couldn't be resolved, e.g. the sender is behind NAT.
+    pub async fn remote(&self) -> Result<Remote, RemoteError> {
+        let id = self.remote_id().ok_or(RemoteError::NotOffered)?;
+        Remote::resolve(id, Self::REMOTE_RESOLVE_TIMEOUT).await
+    }
+}
+
+impl Receiver {
+    pub fn new<ADev: AudioDevice, VDev: VideoDevice>(cfg: Config<ADev, VDev>) -> Self {
+        let rtsp_addr = SocketAddr::new(cfg.sockets.bind_ip, cfg.rtsp_port);
+        let buffers = cfg.buffers;
+        let challenge_key = cfg
+            .pairing
+            .challenge_key
+            .as_ref()
+            .map_or(Ok(()), rsa::check_challenge_key);
+        let advertisement = cfg
+            .advertise
+            .then(|| Advertisement::pending(&cfg, cfg.mac_addr));
+        let info = handlers::info_builder(&cfg);
+        Self {
+            service: RouterService::serve(cfg),
+            rtsp_addr,
+            buffers,
+            challenge_key,
+            info,
+            advertisement,
+        }
+    }
+
+    /// Listens on [`Config::rtsp_port`] and serves senders until stopped with
+    /// [`ShutdownHandle::shutdown`], then shuts down as [`Receiver::shutdown`] does.
+    ///
+    /// # Errors
+    ///
+    /// If [`Config::buffers`] are out of their bounds, the challenge key is too short,
+    /// [`Config::features`] advertise what can't be served, the port couldn't be bound or the
+    /// receiver couldn't be advertised.
+    pub async fn serve(self) -> Result<(), ServeError> {
+        let listener = TcpListener::bind(self.rtsp_addr).await?;
+        self.serve_on(listener).await
+    }
+
+    /// Serves senders connecting to `listener`, see [`Receiver::serve`].
+    ///
+    /// # Errors
+    ///
+    /// If [`Config::buffers`] are out of their bounds, the challenge key is too short,
+    /// [`Config::features`] advertise what can't be served or the receiver couldn't be
+    /// advertised.
+    pub async fn serve_on(mut self, listener: TcpListener) -> Result<(), ServeError> {
+        self.buffers.validate()?;
+        self.challenge_key.clone()?;
+        self.info.validate()?;
+        for (advertised, required) in self.info.unmet_requirements() {
+            tracing::warn!(
+                ?advertised,
+                ?required,
+                "features advertised without ones they need"
+            );
+        }
+        let local_addr = listener.local_addr()?;
+        let advertisement = self
+            .advertisement
+            .take()
+            .map(|pending| pending.register(local_addr.port()))
+            .transpose()?;
+        tracing::info!(%local_addr, "serving rtsp");
+
+        let tasks = Arc::clone(&self.service.tasks);
+        transport::serve(listener, self.service(), &tasks, self.closed()).await;
+
+        // Senders aren't to find the receiver being shut down
+        drop(advertisement);
+        self.shutdown().await;
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            tasks: Arc::clone(&self.service.tasks),
+        }
+    }
+
+    /// Service of RTSP connections, closing them on the next request once shut down.
+    #[must_use]
+    pub fn service(&self) -> RouterService {
+        self.service.clone()
+    }
+
+    /// Resolves once shutdown has started, so no more RTSP connections are to be accepted.
+    pub fn closed(&self) -> impl Future<Output = ()> + Send + 'static {
+        self.service.tasks.cancelled()
+    }
+
+    /// Closes every stream and waits for all tasks to finish.
+    pub async fn shutdown(self) {
+        self.service.streams.close_all();
+        self.service.tasks.shutdown().await;
+    }
+}
+
+impl ShutdownHandle {
+    /// Stops accepting RTSP connections, the serving receiver then closes the running ones.
+    pub fn shutdown(&self) {
+        self.tasks.cancel();
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.service.streams.close_all();
+        self.service.tasks.cancel();
     }
 }
 
@@ -89,3 +398,1084 @@ impl Service<SocketAddr> for RouterService {
         self.inner.call(req)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        future::{Future, poll_fn},
+        net::SocketAddr,
+        sync::{Arc, Mutex, Weak, atomic::Ordering},
+        time::Duration,
+    };
+
+    use axum::{
+        body::{Body, to_bytes},
+        extract::Request,
+    };
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    use chacha20poly1305::{AeadInPlace, ChaCha20Poly1305, Key, KeyInit, Nonce};
+    use http::{
+        StatusCode,
+        header::{CONNECTION, CONTENT_TYPE},
+    };
+    use plist::{Dictionary, Value};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream, UdpSocket},
+        time::timeout,
+    };
+    use tower::Service;
+
+    use super::{InfoError, InfoResponse, Receiver, ServeError};
+    use crate::{
+        config::{BufferConfig, Config, Features, MacAddr6, Pairing},
+        crypto::{
+            fairplay,
+            rsa::{
+                RsaError, RsaPrivateKey,
+                tests::{TEST_KEY, generated_key},
+            },
+        },
+        playback::{
+            ChannelHandle, Device,
+            artwork::Artwork,
+            audio::{AudioDevice, AudioPacket, AudioParams},
+            channel::{Backpressure, ChannelReceiver, ChannelStream, channel},
+            event::NullEventSink,
+            null::NullDevice,
+            session::{SenderDevice, SessionHandler},
+            stats::StreamParams,
+            video::{VideoPacket, VideoParams},
+        },
+        remote::RemoteError,
+        streaming::{EventChannel, SharedData, StreamKind},
+    };
+
+    type NullConfig =
+        Config<NullDevice<AudioParams, AudioPacket>, NullDevice<VideoParams, VideoPacket>>;
+
+    async fn request(receiver: &Receiver, uri: &str) -> http::Response<Body> {
+        send(receiver, Request::get(uri).body(Body::empty()).unwrap()).await
+    }
+
+    async fn send(receiver: &Receiver, req: Request) -> http::Response<Body> {
+        let mut make_service = receiver.service();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 7000));
+        let Ok(mut service) = make_service.call(addr).await;
+        poll_fn(|cx| Service::<Request>::poll_ready(&mut service, cx))
+            .await
+            .unwrap();
+        service.call(req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn shutdown_joins_tasks() {
+        let receiver = Receiver::new(NullConfig::default());
+        let tasks = Arc::clone(&receiver.service.tasks);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _chan =
+            EventChannel::create(listener, BufferConfig::default(), NullEventSink, &tasks).unwrap();
+        assert_eq!(1, tasks.len());
+        assert_eq!(StatusCode::OK, request(&receiver, "/info").await.status());
+
+        let closed = receiver.closed();
+        let service = receiver.service();
+        receiver.shutdown().await;
+        closed.await;
+
+        assert_eq!(0, tasks.len());
+        assert!(TcpStream::connect(addr).await.is_err());
+
+        // Connections left are closed on the next request
+        let receiver = Receiver {
+            service,
+            rtsp_addr: addr,
+            buffers: BufferConfig::default(),
+            challenge_key: Ok(()),
+            info: InfoResponse::builder(MacAddr6::nil(), "rairplay"),
+            advertisement: None,
+        };
+        let resp = request(&receiver, "/info").await;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, resp.status());
+        assert_eq!("close", resp.headers()[CONNECTION]);
+    }
+
+    #[tokio::test]
+    async fn unknown_methods_not_implemented() {
+        let receiver = Receiver::new(NullConfig::default());
+        let rtsp_req = |method: &str, uri: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("CSeq", "7")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        for uri in ["/1234", "*"] {
+            let resp = send(&receiver, rtsp_req("PLAY", uri)).await;
+            assert_eq!(StatusCode::NOT_IMPLEMENTED, resp.status());
+            assert_eq!("7", resp.headers()["cseq"]);
+        }
+
+        let resp = send(&receiver, rtsp_req("OPTIONS", "*")).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!("7", resp.headers()["cseq"]);
+        assert!(resp.headers()["public"].to_str().unwrap().contains("SETUP"));
+    }
+
+    #[tokio::test]
+    async fn serves_rtsp_until_shut_down() {
+        let receiver = Receiver::new(NullConfig {
+            advertise: false,
+            ..Default::default()
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = receiver.shutdown_handle();
+        let served = tokio::spawn(receiver.serve_on(listener));
+
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        conn.write_all(b"OPTIONS * RTSP/1.0\r\nCSeq: 3\r\n\r\n")
+            .await
+            .unwrap();
+        let mut resp = vec![0; 1024];
+        let len = timeout(Duration::from_secs(5), conn.read(&mut resp))
+            .await
+            .unwrap()
+            .unwrap();
+        let resp = std::str::from_utf8(&resp[..len]).unwrap();
+        assert!(resp.starts_with("RTSP/1.0 200 OK\r\n"), "{resp}");
+        assert!(resp.contains("Cseq: 3\r\n"), "{resp}");
+
+        handle.shutdown();
+        timeout(Duration::from_secs(5), served)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(TcpStream::connect(addr).await.is_err());
+        // Connection is closed along with the receiver
+        assert_eq!(0, conn.read(&mut [0; 16]).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_answered_in_order() {
+        let receiver = Receiver::new(NullConfig {
+            advertise: false,
+            ..Default::default()
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = receiver.shutdown_handle();
+        let served = tokio::spawn(receiver.serve_on(listener));
+
+        // All in a single segment, the middle one with a body
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        conn.write_all(
+            b"OPTIONS * RTSP/1.0\r\nCSeq: 1\r\n\r\n\
+              SET_PARAMETER rtsp://127.0.0.1/1234 RTSP/1.0\r\nCSeq: 2\r\n\
+              Content-Type: text/parameters\r\nContent-Length: 13\r\n\r\nvolume: -15.0\
+              OPTIONS * RTSP/1.0\r\nCSeq: 3\r\n\r\n",
+        )
+        .await
+        .unwrap();
+
+        let mut resp = Vec::new();
+        while resp.windows(9).filter(|w| w == b"RTSP/1.0 ").count() < 3
+            || !resp.ends_with(b"\r\n\r\n")
+        {
+            let mut buf = [0; 1024];
+            let len = timeout(Duration::from_secs(5), conn.read(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_ne!(0, len, "{}", String::from_utf8_lossy(&resp));
+            resp.extend_from_slice(&buf[..len]);
+        }
+        let resp = std::str::from_utf8(&resp).unwrap();
+        let cseqs = resp
+            .split("RTSP/1.0 ")
+            .skip(1)
+            .map(|resp| {
+                assert!(resp.starts_with("200 OK\r\n"), "{resp}");
+                resp.split("Cseq: ").nth(1).unwrap()[..1].to_string()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["1", "2", "3"], cseqs);
+        assert_eq!(2, resp.matches("Public: ").count(), "{resp}");
+
+        handle.shutdown();
+        timeout(Duration::from_secs(5), served)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn long_bodies_refused() {
+        let receiver = Receiver::new(NullConfig {
+            advertise: false,
+            max_body_len: 1024,
+            ..Default::default()
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = receiver.shutdown_handle();
+        let served = tokio::spawn(receiver.serve_on(listener));
+
+        // Refused once past the limit, the rest isn't waited for
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        conn.write_all(b"SETUP rtsp://127.0.0.1/1234 RTSP/1.0\r\nCSeq: 3\r\n")
+            .await
+            .unwrap();
+        conn.write_all(b"Content-Length: 1000000000\r\n\r\n")
+            .await
+            .unwrap();
+        conn.write_all(&[0; 2048]).await.unwrap();
+        let mut resp = vec![0; 1024];
+        let len = timeout(Duration::from_secs(5), conn.read(&mut resp))
+            .await
+            .unwrap()
+            .unwrap();
+        let resp = std::str::from_utf8(&resp[..len]).unwrap();
+        assert!(resp.starts_with("RTSP/1.0 413 "), "{resp}");
+
+        handle.shutdown();
+        timeout(Duration::from_secs(5), served)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn second_session_refused_while_busy() {
+        async fn announce(conn: &mut TcpStream) -> String {
+            conn.write_all(b"ANNOUNCE rtsp://127.0.0.1/1 RTSP/1.0\r\nCSeq: 1\r\n\r\n")
+                .await
+                .unwrap();
+            let mut resp = vec![0; 1024];
+            let len = timeout(Duration::from_secs(5), conn.read(&mut resp))
+                .await
+                .unwrap()
+                .unwrap();
+            String::from_utf8(resp[..len].to_vec()).unwrap()
+        }
+
+        let receiver = Receiver::new(NullConfig {
+            advertise: false,
+            ..Default::default()
+        });
+        let service = receiver.service();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = receiver.shutdown_handle();
+        let served = tokio::spawn(receiver.serve_on(listener));
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        assert!(announce(&mut first).await.starts_with("RTSP/1.0 200 "));
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        let resp = announce(&mut second).await;
+        assert!(
+            resp.starts_with("RTSP/1.0 453 Not Enough Bandwidth\r\n"),
+            "{resp}"
+        );
+        assert_eq!(1, service.active_sessions());
+
+        // Slot is freed along with the connection
+        drop(first);
+        timeout(Duration::from_secs(5), async {
+            while service.active_sessions() != 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(announce(&mut second).await.starts_with("RTSP/1.0 200 "));
+
+        handle.shutdown();
+        timeout(Duration::from_secs(5), served)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn streams_per_setup_capped() {
+        let receiver = Receiver::new(NullConfig {
+            max_setup_streams: 1,
+            ..Default::default()
+        });
+        let stream = Value::Dictionary(Dictionary::from_iter([("type", Value::from(130))]));
+        let streams = Value::Array(vec![stream.clone(), stream]);
+        let req = plist_request("SETUP", &Dictionary::from_iter([("streams", streams)]));
+
+        let resp = send(&receiver, req).await;
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, resp.status());
+    }
+
+    #[tokio::test]
+    async fn remote_control_only_session() {
+        let receiver = Receiver::new(NullConfig::default());
+        let setup = Dictionary::from_iter([
+            ("isRemoteControlOnly", Value::from(true)),
+            ("name", "iPhone".into()),
+        ]);
+        let event_port = || async {
+            let resp = send(&receiver, plist_request("SETUP", &setup)).await;
+            assert_eq!(StatusCode::OK, resp.status());
+            let body = to_bytes(resp.into_body(), 1024).await.unwrap();
+            let resp: Dictionary = plist::from_bytes(&body).unwrap();
+            assert!(!resp.contains_key("timingPort"));
+            resp["eventPort"].as_unsigned_integer().unwrap()
+        };
+        let port = event_port().await;
+        assert_eq!(port, event_port().await);
+
+        // Media waits for sender info
+        let stream = Value::Dictionary(Dictionary::from_iter([("type", Value::from(130))]));
+        let streams = Dictionary::from_iter([("streams", Value::Array(vec![stream]))]);
+        let resp = send(&receiver, plist_request("SETUP", &streams)).await;
+        assert_eq!(455, resp.status().as_u16());
+
+        let teardown = plist_request("TEARDOWN", &Dictionary::new());
+        assert_eq!(StatusCode::OK, send(&receiver, teardown).await.status());
+        let resp = send(&receiver, plist_request("SETUP", &streams)).await;
+        assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+
+        receiver.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn absurd_buffers_not_served() {
+        let receiver = Receiver::new(NullConfig {
+            advertise: false,
+            buffers: BufferConfig {
+                video: u32::MAX,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+
+        let resp = send(&receiver, plist_request("SETUP", &Dictionary::new())).await;
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, resp.status());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let err = receiver.serve_on(listener).await.unwrap_err();
+        assert!(matches!(err, ServeError::Buffers(err) if err.buffer == "video"));
+    }
+
+    #[tokio::test]
+    async fn unimplemented_features_not_served() {
+        let receiver = Receiver::new(NullConfig {
+            advertise: false,
+            features: Features::default() | Features::HomeKitPairing,
+            ..Default::default()
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let err = receiver.serve_on(listener).await.unwrap_err();
+        assert!(matches!(
+            err,
+            ServeError::Info(InfoError::Unimplemented(Features::HomeKitPairing))
+        ));
+    }
+
+    #[tokio::test]
+    async fn short_challenge_key_not_served() {
+        let mut cfg = NullConfig {
+            advertise: false,
+            ..Default::default()
+        };
+        cfg.pairing.challenge_key = Some(generated_key(256));
+        let receiver = Receiver::new(cfg);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let err = receiver.serve_on(listener).await.unwrap_err();
+        assert!(matches!(
+            err,
+            ServeError::ChallengeKey(RsaError::KeyTooShort(32))
+        ));
+    }
+
+    #[tokio::test]
+    async fn info_plist_in_format_asked_for() {
+        let receiver = Receiver::new(NullConfig::default());
+        let info = |header: Option<(&str, &str)>| {
+            let mut req = Request::get("/info");
+            if let Some((name, value)) = header {
+                req = req.header(name, value);
+            }
+            send(&receiver, req.body(Body::empty()).unwrap())
+        };
+
+        for (header, content_type, magic) in [
+            (None, "application/x-apple-binary-plist", &b"bplist"[..]),
+            (
+                Some(("Accept", "text/x-apple-plist+xml")),
+                "text/x-apple-plist+xml",
+                b"<?xml",
+            ),
+            (
+                Some(("Content-Type", "application/x-apple-plist; charset=utf-8")),
+                "text/x-apple-plist+xml",
+                b"<?xml",
+            ),
+        ] {
+            let resp = info(header).await;
+            assert_eq!(StatusCode::OK, resp.status());
+            assert_eq!(content_type, resp.headers()[CONTENT_TYPE]);
+            let body = to_bytes(resp.into_body(), 64 * 1024).await.unwrap();
+            assert!(body.starts_with(magic));
+            let info: Dictionary = plist::from_bytes(&body).unwrap();
+            assert!(info.contains_key("features"));
+        }
+    }
+
+    #[tokio::test]
+    async fn fp_setup_follows_feature() {
+        const STAGE1: &[u8] = &[70, 80, 76, 89, 3, 1, 1, 0, 0, 0, 0, 4, 2, 0, 0, 187];
+
+        let receiver = Receiver::new(NullConfig::default());
+        let req = Request::builder()
+            .method("POST")
+            .uri("/fp-setup")
+            .header("CSeq", "2")
+            .body(Body::from(STAGE1))
+            .unwrap();
+        let resp = send(&receiver, req).await;
+
+        if fairplay::SUPPORTED {
+            assert_eq!(StatusCode::OK, resp.status());
+        } else {
+            assert_eq!(StatusCode::NOT_IMPLEMENTED, resp.status());
+        }
+        assert_eq!(
+            fairplay::SUPPORTED,
+            Features::default().contains(Features::MFiSoft_FairPlay)
+        );
+    }
+
+    #[tokio::test]
+    async fn apple_challenge_answered() {
+        let mut cfg = NullConfig::default();
+        let der = STANDARD.decode(TEST_KEY).unwrap();
+        cfg.pairing.challenge_key = Some(RsaPrivateKey::from_pkcs1_der(&der).unwrap());
+        let receiver = Receiver::new(cfg);
+
+        let options = |challenge: Option<&str>| {
+            let mut req = Request::builder().method("OPTIONS").uri("*");
+            if let Some(challenge) = challenge {
+                req = req.header("Apple-Challenge", challenge);
+            }
+            req.body(Body::empty()).unwrap()
+        };
+
+        let resp = send(&receiver, options(Some("AAECAwQFBgcICQoLDA0ODw"))).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        // Unpadded base64 of 128 bytes signature
+        assert_eq!(171, resp.headers()["apple-response"].len());
+
+        let resp = send(&receiver, options(None)).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert!(!resp.headers().contains_key("apple-response"));
+    }
+
+    #[tokio::test]
+    async fn feedback_keeps_session_alive() {
+        let receiver = Receiver::new(NullConfig {
+            feedback_timeout: Duration::from_millis(50),
+            ..Default::default()
+        });
+        let stream = Arc::new(SharedData::new(StreamKind::Video));
+        receiver.service.streams.insert(5, Arc::clone(&stream));
+
+        let feedback = |body: Vec<u8>| Request::post("/feedback").body(Body::from(body)).unwrap();
+
+        assert!(!receiver.service().is_stale());
+        let resp = send(&receiver, feedback(Vec::new())).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert!(to_bytes(resp.into_body(), 1024).await.unwrap().is_empty());
+        assert!(!receiver.service().is_stale());
+
+        let mut status_req = Vec::new();
+        let streams = plist::Dictionary::from_iter([("streams", plist::Value::Array(vec![]))]);
+        plist::to_writer_binary(&mut status_req, &streams).unwrap();
+        let resp = send(&receiver, feedback(status_req)).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        let body = to_bytes(resp.into_body(), 1024).await.unwrap();
+        let status: plist::Dictionary = plist::from_bytes(&body).unwrap();
+        let streams = status["streams"].as_array().unwrap();
+        let stream_status = streams[0].as_dictionary().unwrap();
+        assert_eq!(Some(110), stream_status["type"].as_unsigned_integer());
+        assert_eq!(Some(5), stream_status["streamID"].as_unsigned_integer());
+        assert_eq!(2, stream.keepalives.load(Ordering::Relaxed));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(receiver.service().is_stale());
+    }
+
+    #[tokio::test]
+    async fn teardown_answered_past_stuck_stream() {
+        let receiver = Receiver::new(NullConfig::default());
+        // Never finishes, as there's no processor to stop
+        let stream = Arc::new(SharedData::new(StreamKind::Video));
+        receiver.service.streams.insert(5, Arc::clone(&stream));
+
+        let stream =
+            Dictionary::from_iter([("type", Value::from(110)), ("streamID", Value::from(5))]);
+        let streams = Value::Array(vec![stream.into()]);
+        let req = plist_request("TEARDOWN", &Dictionary::from_iter([("streams", streams)]));
+        let resp = timeout(Duration::from_secs(5), send(&receiver, req)).await;
+        assert_eq!(StatusCode::OK, resp.unwrap().status());
+        assert!(receiver.service().stats().is_empty());
+    }
+
+    #[tokio::test]
+    async fn remote_remembered_until_teardown() {
+        let receiver = Receiver::new(NullConfig::default());
+        assert!(matches!(
+            receiver.service().remote().await,
+            Err(RemoteError::NotOffered)
+        ));
+
+        let req = Request::post("/feedback")
+            .header("DACP-ID", "14413BE4996FEA4D")
+            .header("Active-Remote", "2081565744")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(StatusCode::OK, send(&receiver, req).await.status());
+        let id = receiver.service().remote_id().unwrap();
+        assert_eq!("14413BE4996FEA4D", id.dacp_id);
+        assert_eq!("2081565744", id.active_remote);
+
+        let teardown = plist_request("TEARDOWN", &Dictionary::new());
+        assert_eq!(StatusCode::OK, send(&receiver, teardown).await.status());
+        assert_eq!(None, receiver.service().remote_id());
+    }
+
+    /// Records begins and ends of sessions, by the sender's name, and artwork by its type
+    #[derive(Default)]
+    struct SessionLog(Mutex<Vec<String>>);
+
+    impl SessionHandler for SessionLog {
+        fn on_artwork(&self, artwork: Artwork) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("artwork {}", artwork.mime));
+        }
+
+        fn on_artwork_cleared(&self) {
+            self.0.lock().unwrap().push("artwork cleared".into());
+        }
+
+        fn on_session_begin(&self, sender: SenderDevice) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("begin {}", sender.name));
+        }
+
+        fn on_session_end(&self) {
+            self.0.lock().unwrap().push("end".into());
+        }
+    }
+
+    impl SessionLog {
+        async fn wait_for(&self, events: &[&str]) {
+            timeout(Duration::from_secs(5), async {
+                while *self.0.lock().unwrap() != events {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .unwrap_or_else(|_| panic!("{:?}", self.0.lock().unwrap()));
+        }
+    }
+
+    /// First legacy pair-verify message, which setup of sender info needs to have been sent.
+    fn pair_verify() -> Vec<u8> {
+        let verify = ed25519_dalek::SigningKey::from_bytes(&[1; 32]).verifying_key();
+        let mut body = vec![1, 0, 0, 0];
+        body.extend([9; 32]);
+        body.extend(verify.to_bytes());
+        body
+    }
+
+    /// Takes sender info without keys, as the ones of [`sender_info`].
+    fn unencrypted() -> Pairing {
+        Pairing {
+            allow_unencrypted: true,
+            ..Default::default()
+        }
+    }
+
+    fn sender_info(name: &str) -> Dictionary {
+        Dictionary::from_iter([
+            ("name", Value::from(name)),
+            ("model", "iPhone14,2".into()),
+            ("deviceID", format!("{name}-device").into()),
+            ("macAddress", "AA:BB:CC:DD:EE:F0".into()),
+            ("timingProtocol", "NTP".into()),
+            ("timingPort", 9.into()),
+        ])
+    }
+
+    #[tokio::test]
+    async fn unencrypted_setup_refused_unless_allowed() {
+        let log = Arc::new(SessionLog::default());
+        let receiver = Receiver::new(NullConfig {
+            session: Arc::clone(&log) as _,
+            ..Default::default()
+        });
+        let req = Request::post("/pair-verify").body(Body::from(pair_verify()));
+        assert_eq!(StatusCode::OK, send(&receiver, req.unwrap()).await.status());
+
+        let setup = plist_request("SETUP", &sender_info("iPhone"));
+        assert_eq!(StatusCode::FORBIDDEN, send(&receiver, setup).await.status());
+        assert_eq!(None, receiver.service().sender());
+        assert!(log.0.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn session_ends_on_teardown_and_timeout() {
+        let log = Arc::new(SessionLog::default());
+        let receiver = Receiver::new(NullConfig {
+            session: Arc::clone(&log) as _,
+            feedback_timeout: Duration::from_millis(50),
+            pairing: unencrypted(),
+            ..Default::default()
+        });
+        let req = Request::post("/pair-verify").body(Body::from(pair_verify()));
+        assert_eq!(StatusCode::OK, send(&receiver, req.unwrap()).await.status());
+
+        let setup = || plist_request("SETUP", &sender_info("iPhone"));
+        assert_eq!(StatusCode::OK, send(&receiver, setup()).await.status());
+        assert_eq!("iPhone", receiver.service().sender().unwrap().name);
+        log.wait_for(&["begin iPhone"]).await;
+
+        let teardown = plist_request("TEARDOWN", &Dictionary::new());
+        assert_eq!(StatusCode::OK, send(&receiver, teardown).await.status());
+        log.wait_for(&["begin iPhone", "end"]).await;
+
+        // Setting up again keeps the session, another sender takes it over along with streams
+        assert_eq!(StatusCode::OK, send(&receiver, setup()).await.status());
+        assert_eq!(StatusCode::OK, send(&receiver, setup()).await.status());
+        let stream = Arc::new(SharedData::new(StreamKind::Video));
+        receiver.service.streams.insert(5, Arc::clone(&stream));
+        let setup = plist_request("SETUP", &sender_info("iPad"));
+        assert_eq!(StatusCode::OK, send(&receiver, setup).await.status());
+        assert!(receiver.service().stats().is_empty());
+        let feedback = Request::post("/feedback").body(Body::empty()).unwrap();
+        assert_eq!(StatusCode::OK, send(&receiver, feedback).await.status());
+        log.wait_for(&[
+            "begin iPhone",
+            "end",
+            "begin iPhone",
+            "end",
+            "begin iPad",
+            "end",
+        ])
+        .await;
+        assert_eq!(None, receiver.service().sender());
+
+        receiver.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn session_ends_with_its_connection() {
+        async fn post(conn: &mut TcpStream, head: &str, body: &[u8]) -> String {
+            let head = format!(
+                "{head}\r\nCSeq: 1\r\nContent-Length: {}\r\n\r\n",
+                body.len()
+            );
+            conn.write_all(head.as_bytes()).await.unwrap();
+            conn.write_all(body).await.unwrap();
+            let mut resp = vec![0; 1024];
+            let len = timeout(Duration::from_secs(5), conn.read(&mut resp))
+                .await
+                .unwrap()
+                .unwrap();
+            String::from_utf8_lossy(&resp[..len]).into_owned()
+        }
+
+        let log = Arc::new(SessionLog::default());
+        let receiver = Receiver::new(NullConfig {
+            session: Arc::clone(&log) as _,
+            advertise: false,
+            pairing: unencrypted(),
+            ..Default::default()
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = receiver.shutdown_handle();
+        let served = tokio::spawn(receiver.serve_on(listener));
+
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        let resp = post(&mut conn, "POST /pair-verify RTSP/1.0", &pair_verify()).await;
+        assert!(resp.starts_with("RTSP/1.0 200 "), "{resp}");
+        let mut setup = Vec::new();
+        plist::to_writer_binary(&mut setup, &sender_info("iPhone")).unwrap();
+        let resp = post(&mut conn, "SETUP rtsp://127.0.0.1/1 RTSP/1.0", &setup).await;
+        assert!(resp.starts_with("RTSP/1.0 200 "), "{resp}");
+        log.wait_for(&["begin iPhone"]).await;
+
+        // Other connections leave it be
+        drop(TcpStream::connect(addr).await.unwrap());
+        drop(conn);
+        log.wait_for(&["begin iPhone", "end"]).await;
+
+        handle.shutdown();
+        timeout(Duration::from_secs(5), served)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(["begin iPhone", "end"], log.0.lock().unwrap().as_slice());
+    }
+
+    #[tokio::test]
+    async fn artwork_passed_on_or_cleared() {
+        let log = Arc::new(SessionLog::default());
+        let receiver = Receiver::new(NullConfig {
+            session: Arc::clone(&log) as _,
+            ..Default::default()
+        });
+        for (content_type, body) in [
+            ("image/jpeg", &b"\xff\xd8\xff\xe0"[..]),
+            ("image/jpeg", b"\x89PNG\r\n\x1a\n"),
+            ("image/none", b""),
+            ("image/png", b""),
+        ] {
+            let req = Request::builder()
+                .method("SET_PARAMETER")
+                .uri("/1234")
+                .header(CONTENT_TYPE, content_type)
+                .body(Body::from(body))
+                .unwrap();
+            assert_eq!(StatusCode::OK, send(&receiver, req).await.status());
+        }
+        assert_eq!(
+            [
+                "artwork image/jpeg",
+                "artwork image/png",
+                "artwork cleared",
+                "artwork cleared"
+            ],
+            log.0.lock().unwrap().as_slice()
+        );
+    }
+
+    type CreatedStreams = Arc<Mutex<Vec<(u64, ChannelReceiver<AudioPacket>)>>>;
+
+    /// Hands receivers of created streams to the test
+    #[derive(Default)]
+    struct ChannelDevice {
+        streams: CreatedStreams,
+    }
+
+    impl Device for ChannelDevice {
+        type Params = AudioParams;
+        type Stream = ChannelStream<AudioPacket>;
+        type Error = Infallible;
+
+        fn create(
+            &self,
+            id: u64,
+            _: AudioParams,
+            _: Weak<dyn ChannelHandle>,
+        ) -> impl Future<Output = Result<Self::Stream, Self::Error>> + Send {
+            let (stream, rx) = channel(16, Backpressure::Block);
+            self.streams.lock().unwrap().push((id, rx));
+            async { Ok(stream) }
+        }
+    }
+
+    impl AudioDevice for ChannelDevice {
+        fn get_volume(&self) -> f32 {
+            0.0
+        }
+
+        fn set_volume(&self, _: f32) {}
+    }
+
+    fn plist_request(method: &str, body: &Dictionary) -> Request {
+        let mut buf = Vec::new();
+        plist::to_writer_binary(&mut buf, body).unwrap();
+        Request::builder()
+            .method(method)
+            .uri("/1234")
+            .body(Body::from(buf))
+            .unwrap()
+    }
+
+    fn data_port(stream: &Dictionary) -> u16 {
+        u16::try_from(stream["dataPort"].as_unsigned_integer().unwrap()).unwrap()
+    }
+
+    /// Sets up realtime and buffered audio in one request, returns their setup responses.
+    async fn setup_audio(receiver: &Receiver, shk: [u8; 32]) -> (Dictionary, Dictionary) {
+        const ALAC_44100_16_2: u32 = 1 << 18;
+
+        let realtime = Dictionary::from_iter([
+            ("type", Value::from(96)),
+            ("ct", 2.into()),
+            ("audioFormat", ALAC_44100_16_2.into()),
+            ("spf", 352.into()),
+            ("sr", 44100.into()),
+            ("latencyMin", 11025.into()),
+            ("latencyMax", 88200.into()),
+            ("controlPort", 9.into()),
+        ]);
+        let buffered = Dictionary::from_iter([
+            ("type", Value::from(103)),
+            ("ct", 2.into()),
+            ("audioFormat", ALAC_44100_16_2.into()),
+            ("spf", 352.into()),
+            ("shk", Value::Data(shk.to_vec())),
+        ]);
+        let setup = Dictionary::from_iter([(
+            "streams",
+            Value::Array(vec![realtime.into(), buffered.into()]),
+        )]);
+
+        let resp = send(receiver, plist_request("SETUP", &setup)).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        let body = to_bytes(resp.into_body(), 1024).await.unwrap();
+        let resp: Dictionary = plist::from_bytes(&body).unwrap();
+        let [realtime, buffered] = resp["streams"].as_array().unwrap().as_slice() else {
+            panic!("must be two streams");
+        };
+        let (realtime, buffered) = (
+            realtime.as_dictionary().unwrap().clone(),
+            buffered.as_dictionary().unwrap().clone(),
+        );
+        assert_ne!(realtime["streamID"], buffered["streamID"]);
+        (realtime, buffered)
+    }
+
+    async fn send_realtime(port: u16) {
+        // Payload shorter than a block is left unencrypted
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let rtp = [0x80, 0x60, 0, 1, 0, 0, 0, 1, 0, 0, 0, 7, 1, 2, 3];
+        socket.send_to(&rtp, ("127.0.0.1", port)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn realtime_and_buffered_audio_in_one_setup() {
+        let device = ChannelDevice::default();
+        let streams = Arc::clone(&device.streams);
+        let mut cfg = Config::<_, NullDevice<VideoParams, VideoPacket>>::default();
+        cfg.audio.device = device;
+        let receiver = Receiver::new(cfg);
+
+        let shk = [3; 32];
+        let (realtime, buffered) = setup_audio(&receiver, shk).await;
+
+        send_realtime(data_port(&realtime)).await;
+
+        let mut rtp = vec![0x80, 0x60, 0, 1, 0, 0, 0, 1, 0, 0, 0, 8];
+        let mut payload = b"buffered".to_vec();
+        let tag = ChaCha20Poly1305::new(Key::from_slice(&shk))
+            .encrypt_in_place_detached(Nonce::from_slice(&[0; 12]), &rtp[4..12], &mut payload)
+            .unwrap();
+        let len = u16::try_from(2 + rtp.len() + payload.len() + 24).unwrap();
+        let mut frame = len.to_be_bytes().to_vec();
+        frame.append(&mut rtp);
+        frame.append(&mut payload);
+        frame.extend_from_slice(&tag);
+        frame.extend_from_slice(&[0; 8]);
+        let mut conn = TcpStream::connect(("127.0.0.1", data_port(&buffered)))
+            .await
+            .unwrap();
+        conn.write_all(&frame).await.unwrap();
+
+        let mut streams = std::mem::take(&mut *streams.lock().unwrap());
+        assert_eq!(2, streams.len());
+        let ids = streams.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+        assert_eq!(realtime["streamID"].as_unsigned_integer(), Some(ids[0]));
+        assert_eq!(buffered["streamID"].as_unsigned_integer(), Some(ids[1]));
+
+        for ((_, rx), payload) in streams.iter_mut().zip([&b"\x01\x02\x03"[..], b"buffered"]) {
+            let pkt = timeout(Duration::from_secs(5), rx.recv()).await.unwrap();
+            assert_eq!(payload, pkt.unwrap().payload());
+        }
+
+        receiver.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn stream_descriptor_after_setup() {
+        let receiver = Receiver::new(NullConfig::default());
+        let (realtime, buffered) = setup_audio(&receiver, [3; 32]).await;
+        let service = receiver.service();
+
+        let id = realtime["streamID"].as_unsigned_integer().unwrap();
+        let descriptor = service.stream(id).unwrap();
+        assert_eq!(StreamKind::AudioRealtime, descriptor.kind);
+        assert_eq!(data_port(&realtime), descriptor.local_data_port);
+        assert_eq!(
+            realtime["controlPort"].as_unsigned_integer(),
+            descriptor.local_control_port.map(u64::from)
+        );
+        // No sender info was set up
+        assert_eq!(None, descriptor.timing);
+        let StreamParams::Audio(params) = descriptor.params else {
+            panic!("must be audio");
+        };
+        assert_eq!(44100, params.format.sample_rate);
+        assert_eq!(2, params.format.channels);
+        assert_eq!(88200, params.latency.unwrap().max_samples);
+
+        let id = buffered["streamID"].as_unsigned_integer().unwrap();
+        let descriptor = service.stream(id).unwrap();
+        assert_eq!(StreamKind::AudioBuffered, descriptor.kind);
+        assert_eq!(data_port(&buffered), descriptor.local_data_port);
+        assert_eq!(None, descriptor.local_control_port);
+
+        let teardown = plist_request("TEARDOWN", &Dictionary::new());
+        assert_eq!(StatusCode::OK, send(&receiver, teardown).await.status());
+        assert!(service.stream(id).is_none());
+        receiver.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn unsupported_audio_format_refused() {
+        let mut cfg = NullConfig::default();
+        cfg.audio.supported_formats.sample_rates = vec![48000];
+        let receiver = Receiver::new(cfg);
+
+        // ALAC/44100/16/2
+        let buffered = Dictionary::from_iter([
+            ("type", Value::from(103)),
+            ("ct", 2.into()),
+            ("audioFormat", 0x40000.into()),
+            ("spf", 352.into()),
+            ("shk", Value::Data(vec![3; 32])),
+        ]);
+        let setup = Dictionary::from_iter([("streams", Value::Array(vec![buffered.into()]))]);
+
+        let resp = send(&receiver, plist_request("SETUP", &setup)).await;
+        assert_eq!(StatusCode::UNSUPPORTED_MEDIA_TYPE, resp.status());
+        let body = to_bytes(resp.into_body(), 1024).await.unwrap();
+        assert!(body.ends_with(b"isn't supported by the device"));
+        assert!(receiver.service().stats().is_empty());
+    }
+
+    #[tokio::test]
+    async fn record_answered_with_latency() {
+        let device = ChannelDevice::default();
+        let streams = Arc::clone(&device.streams);
+        let mut cfg = Config::<_, NullDevice<VideoParams, VideoPacket>>::default();
+        cfg.audio.device = device;
+        let receiver = Receiver::new(cfg);
+        let record = |rtp_info: Option<&str>| {
+            let mut req = Request::builder().method("RECORD").uri("/1234");
+            if let Some(rtp_info) = rtp_info {
+                req = req.header("RTP-Info", rtp_info);
+            }
+            req.body(Body::empty()).unwrap()
+        };
+
+        let resp = send(&receiver, record(None)).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert!(!resp.headers().contains_key("audio-latency"));
+
+        let (realtime, _) = setup_audio(&receiver, [3; 32]).await;
+        let resp = send(&receiver, record(Some("seq=1;rtptime=1"))).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!("11025", resp.headers()["audio-latency"]);
+
+        send_realtime(data_port(&realtime)).await;
+        let mut streams = std::mem::take(&mut *streams.lock().unwrap());
+        let (_, rx) = &mut streams[0];
+        let pkt = timeout(Duration::from_secs(5), rx.recv()).await.unwrap();
+        assert_eq!(b"\x01\x02\x03", pkt.unwrap().payload());
+
+        receiver.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn latency_renegotiated_mid_session() {
+        let device = ChannelDevice::default();
+        let streams = Arc::clone(&device.streams);
+        let mut cfg = Config::<_, NullDevice<VideoParams, VideoPacket>>::default();
+        cfg.audio.device = device;
+        let receiver = Receiver::new(cfg);
+        let (realtime, _) = setup_audio(&receiver, [3; 32]).await;
+
+        let req = Request::builder()
+            .method("SET_PARAMETER")
+            .uri("/1234")
+            .header(CONTENT_TYPE, "text/parameters")
+            .body(Body::from("latency: 22050\r\n"))
+            .unwrap();
+        assert_eq!(StatusCode::OK, send(&receiver, req).await.status());
+        let record = Request::builder().method("RECORD").uri("/1234");
+        let resp = send(&receiver, record.body(Body::empty()).unwrap()).await;
+        assert_eq!("22050", resp.headers()["audio-latency"]);
+
+        // Stream goes on as it was
+        send_realtime(data_port(&realtime)).await;
+        let mut streams = std::mem::take(&mut *streams.lock().unwrap());
+        let (_, rx) = &mut streams[0];
+        let pkt = timeout(Duration::from_secs(5), rx.recv()).await.unwrap();
+        assert_eq!(b"\x01\x02\x03", pkt.unwrap().payload());
+
+        receiver.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn teardown_of_one_stream_keeps_others() {
+        let device = ChannelDevice::default();
+        let streams = Arc::clone(&device.streams);
+        let mut cfg = Config::<_, NullDevice<VideoParams, VideoPacket>>::default();
+        cfg.audio.device = device;
+        let receiver = Receiver::new(cfg);
+
+        let (realtime, buffered) = setup_audio(&receiver, [3; 32]).await;
+        let teardown = |ty: u32| {
+            let stream = Dictionary::from_iter([
+                ("type", Value::from(ty)),
+                ("streamID", buffered["streamID"].clone()),
+            ]);
+            let streams = Value::Array(vec![stream.into()]);
+            plist_request("TEARDOWN", &Dictionary::from_iter([("streams", streams)]))
+        };
+
+        // ID has to match the type as well
+        let resp = send(&receiver, teardown(96)).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!(2, receiver.service().stats().len());
+
+        let resp = send(&receiver, teardown(103)).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        let stats = receiver.service().stats();
+        assert_eq!(1, stats.len());
+        assert_eq!(
+            realtime["streamID"].as_unsigned_integer(),
+            Some(stats[0].id)
+        );
+        // Answered once the port is released
+        TcpListener::bind(("127.0.0.1", data_port(&buffered)))
+            .await
+            .unwrap();
+
+        send_realtime(data_port(&realtime)).await;
+        let mut streams = std::mem::take(&mut *streams.lock().unwrap());
+        let (_, rx) = &mut streams[0];
+        let pkt = timeout(Duration::from_secs(5), rx.recv()).await.unwrap();
+        assert_eq!(b"\x01\x02\x03", pkt.unwrap().payload());
+        let (_, rx) = &mut streams[1];
+        assert!(rx.recv().await.is_none());
+
+        receiver.shutdown().await;
+    }
+}
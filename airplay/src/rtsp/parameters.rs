@@ -0,0 +1,163 @@
+//! `text/parameters` bodies of `GET_PARAMETER`/`SET_PARAMETER`, lines of `name: value`.
+
+use std::fmt;
+
+use crate::playback::session::PlaybackProgress;
+
+pub const MIME: &str = "text/parameters";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Parameters {
+    /// Volume in dB
+    pub volume: Option<f32>,
+    /// Sample rate isn't sent along, so it's left zero
+    pub progress: Option<PlaybackProgress>,
+    pub name: Option<String>,
+    /// Of realtime audio in its samples, renegotiated mid-session
+    pub latency: Option<u32>,
+}
+
+/// Name of parameter requested by `GET_PARAMETER`, one per line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Query {
+    Volume,
+}
+
+impl Query {
+    /// Blank lines are skipped, the first unknown name is returned as error.
+    pub fn parse_all(body: &str) -> Result<Vec<Self>, &str> {
+        body.lines()
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| match name {
+                "volume" => Ok(Self::Volume),
+                name => Err(name),
+            })
+            .collect()
+    }
+}
+
+impl Parameters {
+    /// Unknown and malformed lines are skipped.
+    pub fn parse(body: &str) -> Self {
+        let mut params = Self::default();
+
+        for line in body.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+
+            match key.trim() {
+                "volume" => match value.parse() {
+                    Ok(volume) => params.volume = Some(volume),
+                    Err(err) => tracing::warn!(%err, %value, "malformed volume"),
+                },
+                "progress" => {
+                    params.progress = parse_progress(value);
+                    if params.progress.is_none() {
+                        tracing::warn!(%value, "malformed progress");
+                    }
+                }
+                "name" => params.name = Some(value.to_string()),
+                "latency" => match value.parse() {
+                    Ok(latency) => params.latency = Some(latency),
+                    Err(err) => tracing::warn!(%err, %value, "malformed latency"),
+                },
+                key => tracing::debug!(%key, "unknown parameter"),
+            }
+        }
+
+        params
+    }
+}
+
+fn parse_progress(value: &str) -> Option<PlaybackProgress> {
+    let mut parts = value.splitn(3, '/').map(str::parse);
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(start)), Some(Ok(current)), Some(Ok(end))) => Some(PlaybackProgress {
+            start,
+            current,
+            end,
+            sample_rate: 0,
+        }),
+        _ => None,
+    }
+}
+
+impl fmt::Display for Parameters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(volume) = self.volume {
+            write!(f, "volume: {volume:.6}\r\n")?;
+        }
+        if let Some(PlaybackProgress {
+            start,
+            current,
+            end,
+            ..
+        }) = self.progress
+        {
+            write!(f, "progress: {start}/{current}/{end}\r\n")?;
+        }
+        if let Some(name) = &self.name {
+            write!(f, "name: {name}\r\n")?;
+        }
+        if let Some(latency) = self.latency {
+            write!(f, "latency: {latency}\r\n")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Parameters, PlaybackProgress, Query};
+
+    #[test]
+    fn parse_parameters() {
+        let params = Parameters::parse(
+            "volume: -11.123456\r\nprogress: 1/2/3\r\nname: Phone\r\nlatency: 11025\r\n",
+        );
+
+        assert_eq!(Some(-11.123_456), params.volume);
+        assert_eq!(
+            Some(PlaybackProgress {
+                start: 1,
+                current: 2,
+                end: 3,
+                sample_rate: 0,
+            }),
+            params.progress
+        );
+        assert_eq!(Some("Phone"), params.name.as_deref());
+        assert_eq!(Some(11025), params.latency);
+    }
+
+    #[test]
+    fn parse_malformed_parameters() {
+        assert_eq!(
+            Parameters::default(),
+            Parameters::parse("volume: loud\r\nprogress: 1/2\r\nlatency: -1\r\ngarbage\r\n")
+        );
+    }
+
+    #[test]
+    fn write_parameters() {
+        let params = Parameters {
+            volume: Some(-144.0),
+            ..Default::default()
+        };
+
+        assert_eq!("volume: -144.000000\r\n", params.to_string());
+        assert_eq!(params, Parameters::parse(&params.to_string()));
+    }
+
+    #[test]
+    fn parse_queries() {
+        assert_eq!(Ok(vec![Query::Volume]), Query::parse_all("volume\r\n"));
+        assert_eq!(Ok(vec![Query::Volume]), Query::parse_all("\r\n volume"));
+        assert_eq!(Err("progress"), Query::parse_all("volume\r\nprogress\r\n"));
+        assert_eq!(Ok(vec![]), Query::parse_all(""));
+    }
+}
@@ -0,0 +1,168 @@
+//! Slots of senders playing at once, taken by connections announcing or setting up a session.
+//!
+//! A slot is freed once its session is torn down or its connection is closed. Sessions quiet
+//! for longer than the feedback timeout are taken for abandoned and give their slot up to the
+//! next sender asking for one.
+//!
+//! State of a session is kept by the receiver, so there's at most one slot. With
+//! [`SessionPolicy::Takeover`] a new sender takes the receiver over instead.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{extract::FromRequestParts, response::IntoResponse};
+use http::{StatusCode, request::Parts};
+use hyper::ext::ReasonPhrase;
+
+use crate::config::SessionPolicy;
+
+/// RTSP connection a request came over, the default one is of requests served in-process.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct ConnectionId(u64);
+
+/// Address of the sender a request came from, unknown for requests served in-process.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerAddr(pub Option<SocketAddr>);
+
+#[derive(Debug)]
+pub struct SessionRegistry {
+    policy: SessionPolicy,
+    timeout: Duration,
+    /// Last request of each session
+    active: Mutex<HashMap<ConnectionId, Instant>>,
+}
+
+/// Answer of senders denied a slot, which they treat as the receiver being busy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Busy;
+
+impl ConnectionId {
+    /// Id of a newly accepted connection, never the default one.
+    pub fn next() -> Self {
+        static LAST: AtomicU64 = AtomicU64::new(0);
+        Self(LAST.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ConnectionId {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts.extensions.get().copied().unwrap_or_default())
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for PeerAddr {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts.extensions.get().copied().unwrap_or_default())
+    }
+}
+
+impl SessionRegistry {
+    pub fn new(policy: SessionPolicy, timeout: Duration) -> Self {
+        Self {
+            policy,
+            timeout,
+            active: Mutex::default(),
+        }
+    }
+
+    /// Takes a slot for the session of `conn`, unless it already has one.
+    pub fn acquire(&self, conn: ConnectionId) -> Result<(), Busy> {
+        let mut active = self.active.lock().unwrap();
+        let now = Instant::now();
+        if let Some(last_seen) = active.get_mut(&conn) {
+            *last_seen = now;
+            return Ok(());
+        }
+
+        if self.policy == SessionPolicy::Exclusive && !active.is_empty() {
+            active.retain(|_, last_seen| now.duration_since(*last_seen) <= self.timeout);
+            if !active.is_empty() {
+                return Err(Busy);
+            }
+            tracing::info!("abandoned session gave its slot up");
+        }
+        active.insert(conn, now);
+        Ok(())
+    }
+
+    /// Keeps the session of `conn` from being taken for abandoned, if it has a slot.
+    pub fn touch(&self, conn: ConnectionId) {
+        if let Some(last_seen) = self.active.lock().unwrap().get_mut(&conn) {
+            *last_seen = Instant::now();
+        }
+    }
+
+    pub fn release(&self, conn: ConnectionId) {
+        if self.active.lock().unwrap().remove(&conn).is_some() {
+            tracing::debug!(?conn, "session slot freed");
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.active.lock().unwrap().len()
+    }
+}
+
+impl IntoResponse for Busy {
+    fn into_response(self) -> axum::response::Response {
+        let status = StatusCode::from_u16(453).expect("status is valid");
+        let mut resp = status.into_response();
+        resp.extensions_mut()
+            .insert(ReasonPhrase::from_static(b"Not Enough Bandwidth"));
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Busy, ConnectionId, SessionRegistry};
+    use crate::config::SessionPolicy;
+
+    #[test]
+    fn slots_limited() {
+        let sessions = SessionRegistry::new(SessionPolicy::Exclusive, Duration::from_secs(30));
+        let (first, second) = (ConnectionId::next(), ConnectionId::next());
+
+        assert_eq!(Ok(()), sessions.acquire(first));
+        assert_eq!(Ok(()), sessions.acquire(first));
+        assert_eq!(Err(Busy), sessions.acquire(second));
+
+        sessions.release(first);
+        assert_eq!(Ok(()), sessions.acquire(second));
+        assert_eq!(1, sessions.len());
+    }
+
+    #[test]
+    fn abandoned_session_gives_slot_up() {
+        let sessions = SessionRegistry::new(SessionPolicy::Exclusive, Duration::ZERO);
+        let (first, second) = (ConnectionId::next(), ConnectionId::next());
+
+        assert_eq!(Ok(()), sessions.acquire(first));
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(Ok(()), sessions.acquire(second));
+        assert_eq!(1, sessions.len());
+    }
+
+    #[test]
+    fn takeover_unlimited() {
+        let sessions = SessionRegistry::new(SessionPolicy::Takeover, Duration::from_secs(30));
+        for _ in 0..4 {
+            assert_eq!(Ok(()), sessions.acquire(ConnectionId::next()));
+        }
+        assert_eq!(4, sessions.len());
+    }
+}
@@ -1,29 +1,72 @@
 use std::{
     ops::Deref,
-    sync::{Arc, Mutex, Weak, atomic::AtomicU64},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU32, AtomicU64},
+    },
+    time::{Duration, Instant},
 };
 
 use bytes::Bytes;
 use derivative::Derivative;
-use tokio::sync::Mutex as AsyncMutex;
-use weak_table::WeakValueHashMap;
+use tokio::sync::{Mutex as AsyncMutex, broadcast};
 
 use crate::{
-    config::Config,
-    crypto::{AesIv128, AesKey128, pairing::legacy::State as LegacyPairing},
-    streaming::{EventChannel, SharedData},
+    config::{Config, DecryptPriority},
+    crypto::{AesKey128, pairing::legacy::State as LegacyPairing, streaming::AudioRealtimeCipher},
+    playback::{
+        event::EventMessage,
+        session::{Quirks, SenderDevice},
+    },
+    remote::RemoteId,
+    rtsp::session::{ConnectionId, SessionRegistry},
+    streaming::{
+        EventChannel, NtpTimingClient, Offload, Priority, PriorityPool, PtpClock, PtpTimingClient,
+        StreamRegistry, TimingMode, TimingMonitor,
+    },
+    util::task::TaskSet,
 };
 
+const EVENTS_CAPACITY: usize = 64;
+
 pub struct State<ADev, VDev> {
     pub last_stream_id: AtomicU64,
     pub pairing: Mutex<LegacyPairing>,
     pub fp_last_msg: Mutex<Bytes>,
+    /// Key of video, hashed with the pairing secret
     pub ekey: Mutex<AesKey128>,
-    pub eiv: Mutex<AesIv128>,
+    pub realtime_cipher: Mutex<AudioRealtimeCipher>,
+    /// Sender info passed no key, realtime audio and video are sent in the clear
+    pub unencrypted: AtomicBool,
     pub event_channel: AsyncMutex<Option<EventChannel>>,
-    pub audio_realtime_channels: Mutex<WeakValueHashMap<u64, Weak<SharedData>>>,
-    pub audio_buffered_channels: Mutex<WeakValueHashMap<u64, Weak<SharedData>>>,
-    pub video_channels: Mutex<WeakValueHashMap<u64, Weak<SharedData>>>,
+    /// Session was set up for remote control only, streams wait for sender info
+    pub remote_control_only: AtomicBool,
+    pub events: broadcast::Sender<EventMessage>,
+    pub timing_client: Mutex<Option<NtpTimingClient>>,
+    /// Negotiated in the last sender info, forgotten on session teardown
+    pub timing_mode: Mutex<Option<TimingMode>>,
+    pub ptp_client: AsyncMutex<Option<PtpTimingClient>>,
+    pub ptp_clock: PtpClock,
+    /// Sync of the NTP or PTP clock, whichever times the session
+    pub timing: TimingMonitor,
+    pub streams: Arc<StreamRegistry>,
+    /// Threads of [`DecryptPriority::AudioFirst`], streams decrypt [`DecryptPriority::PerStream`]
+    /// without them
+    pub decrypt_pool: Option<Arc<PriorityPool>>,
+    pub tasks: Arc<TaskSet>,
+    pub liveness: Arc<Liveness>,
+    /// DACP service of the sender, from headers of its last request carrying them
+    pub remote: Arc<Mutex<Option<RemoteId>>>,
+    pub sessions: Arc<SessionRegistry>,
+    /// Sender of the session as it described itself during setup
+    pub sender: Arc<Mutex<Option<SenderDevice>>>,
+    /// Connection the sender set the session up over, it ends along with it
+    pub session_conn: Mutex<Option<ConnectionId>>,
+    pub quirks: Mutex<Quirks>,
+    /// Of the last audio stream set up, progress of the track is in its samples
+    pub audio_sample_rate: AtomicU32,
+    /// Of the last realtime audio stream set up, in its samples, zero if there's none
+    pub audio_latency: AtomicU32,
 
     pub cfg: Config<ADev, VDev>,
 }
@@ -42,6 +85,7 @@ impl<A, V> Deref for SharedState<A, V> {
 
 impl<A, V> SharedState<A, V> {
     pub fn with_config(cfg: Config<A, V>) -> Self {
+        let timing = TimingMonitor::default();
         Self(Arc::new(State {
             last_stream_id: AtomicU64::default(),
             pairing: Mutex::new(LegacyPairing::from_signing_privkey(
@@ -49,13 +93,82 @@ impl<A, V> SharedState<A, V> {
             )),
             fp_last_msg: Mutex::default(),
             ekey: Mutex::default(),
-            eiv: Mutex::default(),
+            realtime_cipher: Mutex::new(AudioRealtimeCipher::unencrypted()),
+            unencrypted: AtomicBool::default(),
             event_channel: AsyncMutex::default(),
-            audio_realtime_channels: Mutex::default(),
-            audio_buffered_channels: Mutex::default(),
-            video_channels: Mutex::default(),
+            remote_control_only: AtomicBool::default(),
+            events: broadcast::Sender::new(EVENTS_CAPACITY),
+            timing_client: Mutex::default(),
+            timing_mode: Mutex::default(),
+            ptp_client: AsyncMutex::default(),
+            ptp_clock: PtpClock::new(timing.clone()),
+            timing,
+            streams: Arc::default(),
+            decrypt_pool: match cfg.decrypt_priority {
+                DecryptPriority::PerStream => None,
+                DecryptPriority::AudioFirst { threads } => match PriorityPool::new(threads) {
+                    Ok(pool) => Some(Arc::new(pool)),
+                    Err(err) => {
+                        tracing::error!(%err, "decryption pool not spawned, streams decrypt apart");
+                        None
+                    }
+                },
+            },
+            tasks: Arc::default(),
+            liveness: Arc::new(Liveness::new(cfg.feedback_timeout)),
+            remote: Arc::default(),
+            sessions: Arc::new(SessionRegistry::new(
+                cfg.session_policy,
+                cfg.feedback_timeout,
+            )),
+            audio_sample_rate: AtomicU32::default(),
+            audio_latency: AtomicU32::default(),
+            sender: Arc::default(),
+            session_conn: Mutex::default(),
+            quirks: Mutex::default(),
 
             cfg,
         }))
     }
+
+    /// Decryption of streams of `priority`, as the config lays it out.
+    pub fn offload(&self, priority: Priority) -> Offload {
+        match &self.decrypt_pool {
+            Some(pool) => Offload::pooled(self.cfg.decrypt_workers, pool, priority),
+            None => Offload::dedicated(self.cfg.decrypt_workers),
+        }
+    }
+}
+
+/// Time of the last `/feedback`, senders post it periodically while the session is alive.
+#[derive(Debug)]
+pub struct Liveness {
+    last_seen: Mutex<Option<Instant>>,
+    timeout: Duration,
+}
+
+impl Liveness {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            last_seen: Mutex::default(),
+            timeout,
+        }
+    }
+
+    pub fn on_feedback(&self) {
+        *self.last_seen.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// Forgets the sender, e.g. on session teardown.
+    pub fn reset(&self) {
+        *self.last_seen.lock().unwrap() = None;
+    }
+
+    /// Senders that never posted feedback aren't tracked, so they're never stale.
+    pub fn is_stale(&self) -> bool {
+        self.last_seen
+            .lock()
+            .unwrap()
+            .is_some_and(|last_seen| last_seen.elapsed() > self.timeout)
+    }
 }
@@ -0,0 +1,712 @@
+//! Sender talking to a receiver over loopback as iOS senders do, so the whole path from the
+//! RTSP handshake to decoded samples is exercised without a device at hand.
+//!
+//! The session is paired with legacy pair-verify, then set up with sender info carrying the
+//! key of realtime audio and video wrapped to the challenge key, as RAOP senders skipping
+//! `FairPlay` do. Keys the sender makes up are derived from the pairing secret, so each session
+//! has its own. Timing and retransmit requests of the receiver go to a socket nobody answers,
+//! streams aren't scheduled by the sender's clock.
+
+use std::{fmt::Write as _, io, net::SocketAddr};
+
+use aes::cipher::{
+    BlockEncryptMut as _, KeyIvInit as _, StreamCipher as _, block_padding::NoPadding,
+};
+use chacha20poly1305::{AeadInPlace as _, ChaCha20Poly1305, Key, KeyInit as _, Nonce};
+use ed25519_dalek::{Signature, Signer as _, SigningKey, VerifyingKey};
+use plist::{Dictionary, Value};
+use sha2::{Digest as _, Sha512};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufReadExt as _, AsyncReadExt as _, AsyncWriteExt as _, BufReader},
+    net::{TcpStream, UdpSocket},
+};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use super::dto::StreamId;
+use crate::{
+    config::{RsaError, RsaPrivateKey},
+    crypto::{
+        AesIv128, AesKey128, hash_aes_key,
+        pairing::legacy::{self, SIGNATURE_LENGTH, SharedSecret, X25519_KEY_LEN},
+        streaming::{BufferedSuite, StreamCipher as _, VideoCipher},
+    },
+    playback::{audio::AudioFormat, video::VideoPacket},
+};
+
+type AesCbc128Enc = cbc::Encryptor<aes::Aes128>;
+
+const USER_AGENT: &str = "AirPlay/670.6.2";
+const PLIST_MIME: &str = "application/x-apple-binary-plist";
+/// Source of every stream, the receiver doesn't tell them apart by it
+const SSRC: u32 = 0x1234_5678;
+
+#[derive(Debug, Error)]
+pub enum SenderError {
+    #[error("io: {0}")]
+    Io(#[from] io::Error),
+    #[error("{method} answered with {status}")]
+    Status { method: &'static str, status: u16 },
+    #[error("malformed response: {0}")]
+    Malformed(&'static str),
+    #[error("pair-verify signature of the receiver doesn't match")]
+    Verification,
+    #[error("plist: {0}")]
+    Plist(#[from] plist::Error),
+    #[error("rsa: {0}")]
+    Rsa(#[from] RsaError),
+}
+
+/// Session of a single sender over its RTSP connection.
+pub struct TestSender {
+    conn: BufReader<TcpStream>,
+    uri: String,
+    cseq: u32,
+    shared_secret: SharedSecret,
+    /// Of realtime audio and video, hashed with the shared secret. `None` sends them in the
+    /// clear, which receivers take with
+    /// [`Pairing::allow_unencrypted`](crate::config::Pairing::allow_unencrypted) only
+    key: Option<AesKey128>,
+    eiv: AesIv128,
+    /// Timing and control port of the sender, never answered
+    idle: UdpSocket,
+}
+
+/// Stream of realtime audio, sent over UDP as RTP.
+pub struct RealtimeAudio {
+    pub id: u64,
+    socket: UdpSocket,
+    key: Option<(AesKey128, AesIv128)>,
+    samples_per_frame: u32,
+    seq: u16,
+    timestamp: u32,
+}
+
+/// Stream of buffered audio, sent over TCP.
+pub struct BufferedAudio {
+    pub id: u64,
+    conn: TcpStream,
+    cipher: ChaCha20Poly1305,
+    samples_per_frame: u32,
+    seq: u16,
+    timestamp: u32,
+    nonce: u64,
+}
+
+/// Mirroring stream, sent over TCP.
+pub struct Video {
+    pub id: u64,
+    conn: TcpStream,
+    cipher: VideoCipher,
+    keystream_pos: u64,
+}
+
+impl TestSender {
+    /// Ed25519 key the sender is known by, any would do
+    const SIGNING_KEY: [u8; 32] = [1; 32];
+
+    /// Connects and pairs with the receiver at `addr`.
+    ///
+    /// # Errors
+    ///
+    /// If the receiver can't be reached or pairing fails.
+    pub async fn connect(addr: SocketAddr) -> Result<Self, SenderError> {
+        let conn = TcpStream::connect(addr).await?;
+        let idle = UdpSocket::bind((conn.local_addr()?.ip(), 0)).await?;
+        let mut sender = Self {
+            conn: BufReader::new(conn),
+            uri: format!("rtsp://{addr}/1"),
+            cseq: 0,
+            shared_secret: SharedSecret::default(),
+            key: None,
+            eiv: AesIv128::default(),
+            idle,
+        };
+        sender.pair_verify().await?;
+        Ok(sender)
+    }
+
+    async fn pair_verify(&mut self) -> Result<(), SenderError> {
+        let verify_their = self.request("POST", "/pair-setup", &[], &[]).await?;
+        let verify_their = <[u8; 32]>::try_from(&verify_their[..])
+            .ok()
+            .and_then(|key| VerifyingKey::from_bytes(&key).ok())
+            .ok_or(SenderError::Malformed("pair-setup key"))?;
+
+        let signing_our = SigningKey::from_bytes(&Self::SIGNING_KEY);
+        let ephemeral = EphemeralSecret::random();
+        let pubkey_our = PublicKey::from(&ephemeral);
+        let mut body = vec![1, 0, 0, 0];
+        body.extend_from_slice(pubkey_our.as_bytes());
+        body.extend_from_slice(signing_our.verifying_key().as_bytes());
+        let resp = self.request("POST", "/pair-verify", &[], &body).await?;
+        if resp.len() != X25519_KEY_LEN + SIGNATURE_LENGTH {
+            return Err(SenderError::Malformed("pair-verify"));
+        }
+        let (pubkey_their, signature) = resp.split_at(X25519_KEY_LEN);
+        let pubkey_their = PublicKey::from(<[u8; X25519_KEY_LEN]>::try_from(pubkey_their).unwrap());
+        let shared_secret = ephemeral.diffie_hellman(&pubkey_their).to_bytes();
+
+        // Receiver signed its key followed by ours, ours is signed the other way round
+        let mut cipher = legacy::cipher(&shared_secret);
+        let mut signature = <[u8; SIGNATURE_LENGTH]>::try_from(signature).unwrap();
+        cipher.apply_keystream(&mut signature);
+        let message = [&pubkey_their.as_bytes()[..], pubkey_our.as_bytes()].concat();
+        verify_their
+            .verify_strict(&message, &Signature::from_bytes(&signature))
+            .map_err(|_| SenderError::Verification)?;
+
+        let message = [&pubkey_our.as_bytes()[..], pubkey_their.as_bytes()].concat();
+        let mut signature = signing_our.sign(&message).to_bytes();
+        cipher.apply_keystream(&mut signature);
+        let mut body = vec![0, 0, 0, 0];
+        body.extend_from_slice(&signature);
+        self.request("POST", "/pair-verify", &[], &body).await?;
+
+        self.shared_secret = shared_secret;
+        Ok(())
+    }
+
+    /// Sets the session up with sender info of `name`. Key of realtime audio and video is
+    /// wrapped to `challenge_key` of the receiver, `None` negotiates no encryption, which the
+    /// receiver refuses unless
+    /// [`Pairing::allow_unencrypted`](crate::config::Pairing::allow_unencrypted) is set.
+    ///
+    /// # Errors
+    ///
+    /// If the key can't be wrapped or the receiver refuses the setup.
+    pub async fn setup_session(
+        &mut self,
+        name: &str,
+        challenge_key: Option<&RsaPrivateKey>,
+    ) -> Result<(), SenderError> {
+        let mut info = Dictionary::from_iter([
+            ("name", Value::from(name)),
+            ("model", "iPhone14,2".into()),
+            ("deviceID", "AA:BB:CC:DD:EE:FF".into()),
+            ("macAddress", "AA:BB:CC:DD:EE:F0".into()),
+            ("timingProtocol", "NTP".into()),
+            ("timingPort", self.idle.local_addr()?.port().into()),
+        ]);
+        if let Some(challenge_key) = challenge_key {
+            let key: AesKey128 = self.derive("ekey");
+            let eiv: AesIv128 = self.derive("eiv");
+            let ekey = challenge_key.encrypt_oaep(&key)?;
+            info.insert("ekey".into(), Value::Data(ekey));
+            info.insert("eiv".into(), Value::Data(eiv.to_vec()));
+            self.key = Some(hash_aes_key(key, self.shared_secret));
+            self.eiv = eiv;
+        }
+
+        self.setup(&info).await.map(drop)
+    }
+
+    /// Sets up realtime audio of `format`, packets hold `samples_per_frame` frames each.
+    ///
+    /// # Errors
+    ///
+    /// If the receiver refuses the stream or its data port can't be reached.
+    pub async fn setup_realtime_audio(
+        &mut self,
+        format: AudioFormat,
+        samples_per_frame: u32,
+    ) -> Result<RealtimeAudio, SenderError> {
+        let stream = Dictionary::from_iter([
+            ("type", Value::from(StreamId::AUDIO_REALTIME)),
+            ("ct", format.codec.content_type().into()),
+            ("audioFormat", format.to_bits().into()),
+            ("spf", samples_per_frame.into()),
+            ("sr", format.sample_rate.into()),
+            ("latencyMin", (format.sample_rate / 4).into()),
+            ("latencyMax", (format.sample_rate * 2).into()),
+            ("controlPort", self.idle.local_addr()?.port().into()),
+        ]);
+        let (id, data_port) = self.setup_stream(stream).await?;
+
+        let conn = self.conn.get_ref();
+        let socket = UdpSocket::bind((conn.local_addr()?.ip(), 0)).await?;
+        socket.connect((conn.peer_addr()?.ip(), data_port)).await?;
+        Ok(RealtimeAudio {
+            id,
+            socket,
+            key: self.key.map(|key| (key, self.eiv)),
+            samples_per_frame,
+            seq: 0,
+            timestamp: 0,
+        })
+    }
+
+    /// Sets up buffered audio of `format`, packets hold `samples_per_frame` frames each.
+    ///
+    /// # Errors
+    ///
+    /// If the receiver refuses the stream or its data port can't be reached.
+    pub async fn setup_buffered_audio(
+        &mut self,
+        format: AudioFormat,
+        samples_per_frame: u32,
+    ) -> Result<BufferedAudio, SenderError> {
+        let shk: [u8; 32] = self.derive("shk");
+        let stream = Dictionary::from_iter([
+            ("type", Value::from(StreamId::AUDIO_BUFFERED)),
+            ("ct", format.codec.content_type().into()),
+            ("audioFormat", format.to_bits().into()),
+            ("spf", samples_per_frame.into()),
+            ("shk", Value::Data(shk.to_vec())),
+        ]);
+        let (id, data_port) = self.setup_stream(stream).await?;
+
+        let conn = TcpStream::connect((self.conn.get_ref().peer_addr()?.ip(), data_port)).await?;
+        Ok(BufferedAudio {
+            id,
+            conn,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&shk)),
+            samples_per_frame,
+            seq: 0,
+            timestamp: 0,
+            nonce: 0,
+        })
+    }
+
+    /// Sets up mirroring keyed with `stream_connection_id`.
+    ///
+    /// # Errors
+    ///
+    /// If the receiver refuses the stream or its data port can't be reached.
+    pub async fn setup_video(&mut self, stream_connection_id: i64) -> Result<Video, SenderError> {
+        let stream = Dictionary::from_iter([
+            ("type", Value::from(StreamId::VIDEO)),
+            ("streamConnectionID", stream_connection_id.into()),
+            ("latencyMs", 100.into()),
+        ]);
+        let (id, data_port) = self.setup_stream(stream).await?;
+
+        let conn = TcpStream::connect((self.conn.get_ref().peer_addr()?.ip(), data_port)).await?;
+        // Keyed as the receiver does
+        #[allow(clippy::cast_sign_loss)]
+        let cipher = self.key.map_or_else(VideoCipher::unencrypted, |key| {
+            VideoCipher::new(key, stream_connection_id as u64)
+        });
+        Ok(Video {
+            id,
+            conn,
+            cipher,
+            keystream_pos: 0,
+        })
+    }
+
+    /// Starts playback, `realtime` audio at its next packet.
+    ///
+    /// # Errors
+    ///
+    /// If the receiver refuses it.
+    pub async fn record(&mut self, realtime: Option<&RealtimeAudio>) -> Result<(), SenderError> {
+        let rtp_info =
+            realtime.map(|stream| format!("seq={};rtptime={}", stream.seq, stream.timestamp));
+        let headers = rtp_info
+            .as_deref()
+            .map(|info| ("RTP-Info", info))
+            .into_iter()
+            .collect::<Vec<_>>();
+        let uri = self.uri.clone();
+        self.request("RECORD", &uri, &headers, &[]).await.map(drop)
+    }
+
+    /// Keeps the session alive, senders post it every couple of seconds.
+    ///
+    /// # Errors
+    ///
+    /// If the receiver refuses it.
+    pub async fn feedback(&mut self) -> Result<(), SenderError> {
+        self.request("POST", "/feedback", &[], &[]).await.map(drop)
+    }
+
+    /// Tears the whole session down.
+    ///
+    /// # Errors
+    ///
+    /// If the receiver refuses it.
+    pub async fn teardown(&mut self) -> Result<(), SenderError> {
+        let mut body = Vec::new();
+        plist::to_writer_binary(&mut body, &Dictionary::new())?;
+        let uri = self.uri.clone();
+        self.request("TEARDOWN", &uri, &[("Content-Type", PLIST_MIME)], &body)
+            .await
+            .map(drop)
+    }
+
+    /// Key material of `label`, made up by the sender yet unique to the session.
+    fn derive<const N: usize>(&self, label: &str) -> [u8; N] {
+        let digest = Sha512::new()
+            .chain_update(label)
+            .chain_update(self.shared_secret)
+            .finalize();
+        digest[..N]
+            .try_into()
+            .expect("keys are shorter than SHA-512")
+    }
+
+    async fn setup(&mut self, body: &Dictionary) -> Result<Dictionary, SenderError> {
+        let mut buf = Vec::new();
+        plist::to_writer_binary(&mut buf, body)?;
+        let uri = self.uri.clone();
+        let resp = self
+            .request("SETUP", &uri, &[("Content-Type", PLIST_MIME)], &buf)
+            .await?;
+        Ok(plist::from_bytes(&resp)?)
+    }
+
+    /// Sets up a single stream, returns its ID and data port.
+    async fn setup_stream(&mut self, stream: Dictionary) -> Result<(u64, u16), SenderError> {
+        let setup = Dictionary::from_iter([("streams", Value::Array(vec![stream.into()]))]);
+        let resp = self.setup(&setup).await?;
+        let stream = resp
+            .get("streams")
+            .and_then(Value::as_array)
+            .and_then(|streams| streams.first())
+            .and_then(Value::as_dictionary)
+            .ok_or(SenderError::Malformed("setup streams"))?;
+        let id = stream
+            .get("streamID")
+            .and_then(Value::as_unsigned_integer)
+            .ok_or(SenderError::Malformed("stream id"))?;
+        let data_port = stream
+            .get("dataPort")
+            .and_then(Value::as_unsigned_integer)
+            .and_then(|port| u16::try_from(port).ok())
+            .ok_or(SenderError::Malformed("data port"))?;
+        Ok((id, data_port))
+    }
+
+    /// Sends the request and reads its response, returns the body of a successful one.
+    async fn request(
+        &mut self,
+        method: &'static str,
+        uri: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<Vec<u8>, SenderError> {
+        self.cseq += 1;
+        let mut head = format!(
+            "{method} {uri} RTSP/1.0\r\nCSeq: {}\r\nUser-Agent: {USER_AGENT}\r\nContent-Length: {}\r\n",
+            self.cseq,
+            body.len()
+        );
+        for (name, value) in headers {
+            let _ = write!(head, "{name}: {value}\r\n");
+        }
+        head.push_str("\r\n");
+        let conn = self.conn.get_mut();
+        conn.write_all(head.as_bytes()).await?;
+        conn.write_all(body).await?;
+
+        let mut line = String::new();
+        self.conn.read_line(&mut line).await?;
+        let status = line
+            .strip_prefix("RTSP/1.0 ")
+            .and_then(|rest| rest.get(..3))
+            .and_then(|code| code.parse::<u16>().ok())
+            .ok_or(SenderError::Malformed("status line"))?;
+        let mut content_len = 0;
+        loop {
+            line.clear();
+            if self.conn.read_line(&mut line).await? == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            let Some((name, value)) = line.trim_end().split_once(':') else {
+                break;
+            };
+            if name.eq_ignore_ascii_case("content-length") {
+                content_len = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| SenderError::Malformed("content length"))?;
+            }
+        }
+        let mut resp = vec![0; content_len];
+        self.conn.read_exact(&mut resp).await?;
+
+        if status != 200 {
+            return Err(SenderError::Status { method, status });
+        }
+        Ok(resp)
+    }
+}
+
+impl RealtimeAudio {
+    /// Sends `payload` of a single packet, encoded in the format of the stream. Its whole
+    /// blocks are encrypted and the rest is left in the clear, as senders do.
+    ///
+    /// # Errors
+    ///
+    /// If the packet can't be sent.
+    pub async fn send(&mut self, payload: &[u8]) -> io::Result<()> {
+        let mut rtp = rtp_header(self.seq, self.timestamp).to_vec();
+        let start = rtp.len();
+        rtp.extend_from_slice(payload);
+        if let Some((key, eiv)) = &self.key {
+            // Cipher restarts on each packet
+            let encrypted_len = payload.len() - payload.len() % 16;
+            let _ = AesCbc128Enc::new(key.into(), eiv.into()).encrypt_padded_mut::<NoPadding>(
+                &mut rtp[start..start + encrypted_len],
+                encrypted_len,
+            );
+        }
+        self.socket.send(&rtp).await?;
+
+        self.seq = self.seq.wrapping_add(1);
+        self.timestamp = self.timestamp.wrapping_add(self.samples_per_frame);
+        Ok(())
+    }
+}
+
+impl BufferedAudio {
+    /// Sends `payload` of a single packet, encoded in the format of the stream and sealed with
+    /// the key sent in setup.
+    ///
+    /// # Errors
+    ///
+    /// If the packet is too long for its length prefix or can't be sent.
+    pub async fn send(&mut self, payload: &[u8]) -> io::Result<()> {
+        let header = rtp_header(self.seq, self.timestamp);
+        // Last bytes of nonce are sent, the first ones are zeros
+        let sent_nonce = self.nonce.to_le_bytes();
+        let mut nonce = [0; 12];
+        nonce[12 - BufferedSuite::SENT_NONCE_LEN..].copy_from_slice(&sent_nonce);
+        let mut sealed = payload.to_vec();
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(Nonce::from_slice(&nonce), &header[4..], &mut sealed)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "payload can't be sealed"))?;
+
+        let len = 2 + header.len() + sealed.len() + tag.len() + sent_nonce.len();
+        let len = u16::try_from(len)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "packet too long"))?;
+        let mut frame = len.to_be_bytes().to_vec();
+        frame.extend_from_slice(&header);
+        frame.extend_from_slice(&sealed);
+        frame.extend_from_slice(&tag);
+        frame.extend_from_slice(&sent_nonce);
+        self.conn.write_all(&frame).await?;
+
+        self.seq = self.seq.wrapping_add(1);
+        self.timestamp = self.timestamp.wrapping_add(self.samples_per_frame);
+        self.nonce += 1;
+        Ok(())
+    }
+}
+
+impl Video {
+    /// Payload packet of mirroring, its kind in the header
+    const PAYLOAD: u16 = 0;
+    /// Option senders set in payload packets
+    const PAYLOAD_OPTION: u16 = 0x1e;
+
+    /// Sends a whole frame of length prefixed `nal_units` shown at `timestamp`, encrypted
+    /// continuing the keystream of the frames before.
+    ///
+    /// # Errors
+    ///
+    /// If the frame can't be sent.
+    pub async fn send_frame(&mut self, timestamp: u64, nal_units: &[u8]) -> io::Result<()> {
+        let mut payload = nal_units.to_vec();
+        // Keystream is applied alike both ways
+        self.cipher.decrypt(self.keystream_pos, &mut payload);
+        self.keystream_pos += payload.len() as u64;
+
+        let len = u32::try_from(payload.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too long"))?;
+        let mut packet = Vec::with_capacity(VideoPacket::HEADER_LEN + payload.len());
+        packet.extend_from_slice(&len.to_le_bytes());
+        packet.extend_from_slice(&Self::PAYLOAD.to_le_bytes());
+        packet.extend_from_slice(&Self::PAYLOAD_OPTION.to_le_bytes());
+        packet.extend_from_slice(&timestamp.to_le_bytes());
+        packet.resize(VideoPacket::HEADER_LEN, 0);
+        packet.extend_from_slice(&payload);
+        self.conn.write_all(&packet).await
+    }
+}
+
+/// Fixed RTP header of audio packets, the payload type is of realtime audio for both streams.
+fn rtp_header(seq: u16, timestamp: u32) -> [u8; 12] {
+    let mut header = [0; 12];
+    header[0] = 0x80;
+    #[allow(clippy::cast_possible_truncation)]
+    {
+        header[1] = StreamId::AUDIO_REALTIME as u8;
+    }
+    header[2..4].copy_from_slice(&seq.to_be_bytes());
+    header[4..8].copy_from_slice(&timestamp.to_be_bytes());
+    header[8..].copy_from_slice(&SSRC.to_be_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        sync::{Arc, Mutex, Weak},
+        time::Duration,
+    };
+
+    use tokio::{net::TcpListener, time::timeout};
+
+    use super::TestSender;
+    use crate::{
+        config::{Config, RsaPrivateKey},
+        playback::{
+            ChannelHandle, Device,
+            audio::{AudioDevice, AudioFormat, AudioParams, DecodeError, PcmPacket, PcmStream},
+            channel::{Backpressure, ChannelReceiver, ChannelStream, channel},
+            video::{VideoDevice, VideoPacket, VideoParams},
+        },
+        rtsp::Receiver,
+    };
+
+    type Receivers<T> = Arc<Mutex<Vec<ChannelReceiver<T>>>>;
+
+    /// Decodes every audio stream created, hands their samples to the test
+    #[derive(Default)]
+    struct PcmDevice(Receivers<PcmPacket>);
+
+    impl Device for PcmDevice {
+        type Params = AudioParams;
+        type Stream = PcmStream<ChannelStream<PcmPacket>>;
+        type Error = DecodeError;
+
+        fn create(
+            &self,
+            _: u64,
+            params: AudioParams,
+            _: Weak<dyn ChannelHandle>,
+        ) -> impl Future<Output = Result<Self::Stream, Self::Error>> + Send {
+            let (stream, rx) = channel(16, Backpressure::Block);
+            self.0.lock().unwrap().push(rx);
+            let stream = PcmStream::new(&params, stream);
+            async { stream }
+        }
+    }
+
+    impl AudioDevice for PcmDevice {
+        fn get_volume(&self) -> f32 {
+            0.0
+        }
+
+        fn set_volume(&self, _: f32) {}
+    }
+
+    /// Hands packets of every video stream created to the test
+    #[derive(Default)]
+    struct VideoChannelDevice(Receivers<VideoPacket>);
+
+    impl Device for VideoChannelDevice {
+        type Params = VideoParams;
+        type Stream = ChannelStream<VideoPacket>;
+        type Error = std::convert::Infallible;
+
+        fn create(
+            &self,
+            _: u64,
+            _: VideoParams,
+            _: Weak<dyn ChannelHandle>,
+        ) -> impl Future<Output = Result<Self::Stream, Self::Error>> + Send {
+            let (stream, rx) = channel(16, Backpressure::Block);
+            self.0.lock().unwrap().push(rx);
+            async { Ok(stream) }
+        }
+    }
+
+    impl VideoDevice for VideoChannelDevice {}
+
+    async fn recv<T: Send + 'static>(rx: &mut ChannelReceiver<T>) -> T {
+        timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    /// Big endian payload of stereo 16 bits frames, both channels counting up from `start`,
+    /// along with samples it's decoded to.
+    fn pcm(start: i16, frames: i16) -> (Vec<u8>, Vec<i32>) {
+        let samples = (start..start + frames)
+            .flat_map(|sample| [sample, -sample])
+            .collect::<Vec<_>>();
+        let payload = samples.iter().flat_map(|s| s.to_be_bytes()).collect();
+        let decoded = samples.iter().map(|&s| i32::from(s) << 16).collect();
+        (payload, decoded)
+    }
+
+    #[tokio::test]
+    async fn sent_streams_decoded_end_to_end() {
+        let audio = PcmDevice::default();
+        let audio_streams = Arc::clone(&audio.0);
+        let video = VideoChannelDevice::default();
+        let video_streams = Arc::clone(&video.0);
+        let mut cfg = Config::<PcmDevice, VideoChannelDevice> {
+            advertise: false,
+            ..Default::default()
+        };
+        cfg.pairing.challenge_key = Some(RsaPrivateKey::airport_express());
+        cfg.audio.device = audio;
+        cfg.video.device = video;
+        let receiver = Receiver::new(cfg);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = receiver.shutdown_handle();
+        let served = tokio::spawn(receiver.serve_on(listener));
+
+        let mut sender = TestSender::connect(addr).await.unwrap();
+        let challenge_key = RsaPrivateKey::airport_express();
+        sender
+            .setup_session("Loopback", Some(&challenge_key))
+            .await
+            .unwrap();
+        // PCM/44100/16/2
+        let format = AudioFormat::from_bits(0x800).unwrap();
+        let mut realtime = sender.setup_realtime_audio(format, 352).await.unwrap();
+        let mut buffered = sender.setup_buffered_audio(format, 352).await.unwrap();
+        let mut mirroring = sender.setup_video(-42).await.unwrap();
+        assert_ne!(realtime.id, buffered.id);
+        sender.record(Some(&realtime)).await.unwrap();
+
+        let streams = std::mem::take(&mut *audio_streams.lock().unwrap());
+        let Ok([mut realtime_rx, mut buffered_rx]) = <[_; 2]>::try_from(streams) else {
+            panic!("must be two audio streams");
+        };
+        // Short one ends in a partial block, which is sent in the clear
+        for (timestamp, (start, frames)) in (0..).step_by(352).zip([(0, 352), (352, 352), (704, 5)])
+        {
+            let (payload, samples) = pcm(start, frames);
+            realtime.send(&payload).await.unwrap();
+            buffered.send(&payload).await.unwrap();
+            for rx in [&mut realtime_rx, &mut buffered_rx] {
+                let pkt = recv(rx).await;
+                assert_eq!(timestamp, pkt.timestamp);
+                assert_eq!(
+                    (2, 44100, 16),
+                    (pkt.pcm.channels, pkt.pcm.sample_rate, pkt.pcm.bits)
+                );
+                assert_eq!(samples, pkt.pcm.samples);
+            }
+        }
+
+        // Frames continue the keystream of the ones before
+        let mut video_rx = video_streams.lock().unwrap().pop().unwrap();
+        let frames: [&[u8]; 2] = [&[0, 0, 0, 2, 0x65, 0x88], &[0, 0, 0, 3, 0x41, 0x9a, 0x01]];
+        for (timestamp, frame) in (1..).zip(frames) {
+            mirroring.send_frame(timestamp, frame).await.unwrap();
+            let pkt = recv(&mut video_rx).await;
+            assert_eq!(timestamp, pkt.timestamp);
+            assert_eq!(frame, &pkt.payload[..]);
+        }
+
+        sender.feedback().await.unwrap();
+        sender.teardown().await.unwrap();
+        handle.shutdown();
+        timeout(Duration::from_secs(5), served)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+}
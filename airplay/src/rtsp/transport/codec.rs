@@ -0,0 +1,362 @@
+//! RTSP requests read as HTTP/1.1 ones and HTTP responses written as RTSP ones, as both share
+//! the framing besides the version.
+//!
+//! Bodies are passed on as they arrive rather than buffered whole, so the router's body limit
+//! refuses long ones before they're in memory. Requests are framed by their `Content-Length`,
+//! so the ones pipelined after it are read as they follow.
+//!
+//! Packets of streams [interleaved](super::interleaved) between requests are passed apart.
+
+use std::io;
+
+use httparse::{EMPTY_HEADER, Header, Request, Response, Status};
+use hyper::Uri;
+use tokio_util::bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::interleaved::{FrameSender, Interleaved};
+
+const MAX_HEADERS: usize = 32;
+
+const RTSP_VERSION: &[u8] = b"RTSP/1.0";
+const RTSP_VERSION_CRLF: &[u8] = b"RTSP/1.0\r\n";
+const HTTP_VERSION_CRLF: &[u8] = b"HTTP/1.1\r\n";
+const CRLF: &[u8] = b"\r\n";
+
+#[derive(Default)]
+pub struct Rtsp2Http {
+    /// Body of the last request or response yet to be passed on
+    body_left: usize,
+    /// Takes interleaved frames, they're dropped without it
+    interleaved: Option<FrameSender>,
+}
+
+impl Rtsp2Http {
+    /// Passes frames interleaved between requests to `frames`.
+    pub fn interleaved(frames: FrameSender) -> Self {
+        Self {
+            body_left: 0,
+            interleaved: Some(frames),
+        }
+    }
+
+    /// Takes interleaved frames off the front of `src`, `false` if the last one is partial.
+    fn split_interleaved(&self, src: &mut BytesMut) -> bool {
+        while src.first() == Some(&Interleaved::MAGIC) {
+            let Some(&[_, channel, len_hi, len_lo]) = src.first_chunk() else {
+                return false;
+            };
+            let len = Interleaved::HEADER_LEN + usize::from(u16::from_be_bytes([len_hi, len_lo]));
+            if src.len() < len {
+                src.reserve(len - src.len());
+                return false;
+            }
+
+            let mut data = src.split_to(len);
+            data.advance(Interleaved::HEADER_LEN);
+            let frame = Interleaved {
+                channel,
+                data: data.freeze(),
+            };
+            if !self
+                .interleaved
+                .as_ref()
+                .is_some_and(|frames| frames.send(frame))
+            {
+                tracing::trace!(%channel, "interleaved frame dropped");
+            }
+        }
+        true
+    }
+}
+
+fn content_len(headers: &[Header<'_>]) -> Option<usize> {
+    headers.iter().find_map(|header| {
+        if header.name.eq_ignore_ascii_case("content-length") {
+            std::str::from_utf8(header.value).ok()?.parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+impl Decoder for Rtsp2Http {
+    type Item = Bytes;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+
+        if self.body_left > 0 {
+            let len = self.body_left.min(src.len());
+            self.body_left -= len;
+            return Ok(Some(src.split_to(len).freeze()));
+        }
+
+        // Frames come between requests only, never within their bodies
+        if !self.split_interleaved(src) || src.is_empty() {
+            return Ok(None);
+        }
+
+        let mut need_more = false;
+        loop {
+            if let Some(pos) = src
+                .windows(RTSP_VERSION_CRLF.len())
+                .position(|bytes| bytes == RTSP_VERSION_CRLF)
+            {
+                // Replacing version with HTTP and trying to parse again
+                src[pos..pos + RTSP_VERSION_CRLF.len()].copy_from_slice(HTTP_VERSION_CRLF);
+                tracing::trace!("replaced version at {pos} position");
+            } else if let Some(pos) = src
+                .windows(HTTP_VERSION_CRLF.len())
+                .position(|bytes| bytes == HTTP_VERSION_CRLF)
+            {
+                // Replace back rtsp, for another call of decode
+                src[pos..pos + HTTP_VERSION_CRLF.len()].copy_from_slice(RTSP_VERSION_CRLF);
+                tracing::trace!("replaced back version at {pos} position");
+            }
+
+            if need_more {
+                return Ok(None);
+            }
+
+            let mut headers = [EMPTY_HEADER; MAX_HEADERS];
+            let mut request = Request::new(&mut headers);
+            match request.parse(src) {
+                Ok(Status::Complete(len)) => {
+                    let buffered = src.len() - len;
+                    // What follows a request without body is the next request
+                    let content_len = content_len(request.headers).unwrap_or_default();
+                    let body_len = content_len.min(buffered);
+                    self.body_left = content_len - body_len;
+
+                    let path = request.path.unwrap();
+
+                    // Should be enough to fulfill HTTP request and new header
+                    let mut output = BytesMut::with_capacity(len + body_len);
+
+                    // Method
+                    let method = request.method.unwrap();
+                    output.put_slice(method.as_bytes());
+                    output.put_u8(b' ');
+
+                    // URI path, `*` of OPTIONS is kept as is
+                    match path.parse::<Uri>() {
+                        Ok(rtsp_uri) => {
+                            output.put_slice(rtsp_uri.path().as_bytes());
+                        }
+                        _ => {
+                            output.put_slice(path.as_bytes());
+                        }
+                    }
+                    output.put_u8(b' ');
+
+                    // Version & proto (it's always HTTP/1.1)
+                    output.put_slice(HTTP_VERSION_CRLF);
+
+                    // Headers
+                    for header in request.headers {
+                        output.put_slice(header.name.as_bytes());
+                        output.put_slice(b": ");
+                        output.put_slice(header.value);
+                        output.put_slice(CRLF);
+                    }
+                    output.put_slice(CRLF);
+
+                    // Body read so far, the rest is passed on as it arrives
+                    output.put_slice(&src[len..len + body_len]);
+
+                    tracing::trace!(
+                        "built new request, size {}, body left {}",
+                        output.len(),
+                        self.body_left
+                    );
+
+                    // Leave what follows, so the next frame can be pulled
+                    src.advance(len + body_len);
+
+                    return Ok(Some(output.freeze()));
+                }
+                Ok(Status::Partial) => {
+                    need_more = true;
+                }
+                Err(err) => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+                }
+            }
+        }
+    }
+}
+
+impl<T: AsRef<[u8]>> Encoder<T> for Rtsp2Http {
+    type Error = io::Error;
+
+    /// Responses to pipelined requests may be written at once, each one is rewritten.
+    fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let mut item = item.as_ref();
+        // Must be enough
+        dst.reserve(item.len());
+
+        while !item.is_empty() {
+            if self.body_left > 0 {
+                let len = self.body_left.min(item.len());
+                self.body_left -= len;
+                dst.put_slice(&item[..len]);
+                item = &item[len..];
+                continue;
+            }
+
+            let mut headers = [EMPTY_HEADER; MAX_HEADERS];
+            let mut response = Response::new(&mut headers);
+            let len = match response.parse(item) {
+                Ok(Status::Complete(len)) => len,
+                Ok(Status::Partial) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "partial response",
+                    ));
+                }
+                Err(err) => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+                }
+            };
+            // Without length everything written along is the body
+            self.body_left = content_len(response.headers).unwrap_or(item.len() - len);
+
+            // Version and proto
+            dst.put_slice(RTSP_VERSION);
+
+            // Status code
+            dst.put_slice(format!(" {}", response.code.expect("code is mandatory")).as_bytes());
+
+            // Reason word
+            if let Some(reason) = response.reason {
+                dst.put_slice(format!(" {reason}").as_bytes());
+            }
+            dst.put_slice(CRLF);
+
+            // Headers
+            for header in response.headers {
+                if header != &EMPTY_HEADER {
+                    dst.put_slice(header.name.as_bytes());
+                    dst.put_slice(b": ");
+                    dst.put_slice(header.value);
+                    dst.put_slice(CRLF);
+                }
+            }
+            dst.put_slice(CRLF);
+
+            item = &item[len..];
+        }
+
+        tracing::trace!("built new response, size is {}", dst.len());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+    use tokio_util::{
+        bytes::BytesMut,
+        codec::{Decoder, Encoder},
+    };
+
+    use super::{super::interleaved::queue, Rtsp2Http};
+
+    #[test]
+    fn body_passed_as_it_arrives() {
+        let mut codec = Rtsp2Http::default();
+        let mut src = BytesMut::from(
+            &b"SETUP rtsp://10.0.0.2/1234 RTSP/1.0\r\nCSeq: 3\r\nContent-Length: 6\r\n\r\nab"[..],
+        );
+
+        let head = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(
+            &b"SETUP /1234 HTTP/1.1\r\nCSeq: 3\r\nContent-Length: 6\r\n\r\nab"[..],
+            &head[..]
+        );
+        assert!(src.is_empty());
+
+        // Rest of the body, then the next request
+        src.extend_from_slice(b"cdefOPTIONS * RTSP/1.0\r\nCSeq: 4\r\n\r\n");
+        assert_eq!(&b"cdef"[..], &codec.decode(&mut src).unwrap().unwrap()[..]);
+        let next = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(&b"OPTIONS * HTTP/1.1\r\nCSeq: 4\r\n\r\n"[..], &next[..]);
+        assert_eq!(None, codec.decode(&mut src).unwrap());
+    }
+
+    #[test]
+    fn interleaved_frames_passed_apart() {
+        let (tx, mut rx) = queue();
+        let mut codec = Rtsp2Http::interleaved(tx);
+        let mut src = BytesMut::from(&b"$\x02\x00\x03abc$\x03\x00\x04de"[..]);
+
+        // Second frame is partial
+        assert_eq!(None, codec.decode(&mut src).unwrap());
+        let frame = rx.recv().now_or_never().flatten().unwrap();
+        assert_eq!((2, &b"abc"[..]), (frame.channel, &frame.data[..]));
+        assert_eq!((1, false), frame.target());
+        assert!(rx.recv().now_or_never().is_none());
+
+        src.extend_from_slice(b"fgOPTIONS * RTSP/1.0\r\nCSeq: 1\r\n\r\n");
+        let req = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(&b"OPTIONS * HTTP/1.1\r\nCSeq: 1\r\n\r\n"[..], &req[..]);
+        let frame = rx.recv().now_or_never().flatten().unwrap();
+        assert_eq!((3, &b"defg"[..]), (frame.channel, &frame.data[..]));
+        assert_eq!((1, true), frame.target());
+
+        // Bodies starting with the magic are still bodies
+        src.extend_from_slice(b"SETUP /1 RTSP/1.0\r\nContent-Length: 6\r\n\r\n");
+        codec.decode(&mut src).unwrap().unwrap();
+        src.extend_from_slice(b"$\x00\x00\x01a");
+        assert_eq!(
+            &b"$\x00\x00\x01a"[..],
+            &codec.decode(&mut src).unwrap().unwrap()[..]
+        );
+        assert!(rx.recv().now_or_never().is_none());
+    }
+
+    #[test]
+    fn pipelined_requests_split() {
+        let mut codec = Rtsp2Http::default();
+        let mut src = BytesMut::from(
+            &b"OPTIONS * RTSP/1.0\r\nCSeq: 1\r\n\r\nOPTIONS * RTSP/1.0\r\nCSeq: 2\r\n\r\n"[..],
+        );
+
+        for cseq in ["1", "2"] {
+            let req = codec.decode(&mut src).unwrap().unwrap();
+            let expected = format!("OPTIONS * HTTP/1.1\r\nCSeq: {cseq}\r\n\r\n");
+            assert_eq!(expected.as_bytes(), &req[..]);
+        }
+        assert_eq!(None, codec.decode(&mut src).unwrap());
+    }
+
+    #[test]
+    fn responses_written_at_once() {
+        let mut codec = Rtsp2Http::default();
+        let mut dst = BytesMut::new();
+        codec
+            .encode(
+                b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nab\
+                  HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n",
+                &mut dst,
+            )
+            .unwrap();
+        assert_eq!(
+            &b"RTSP/1.0 200 OK\r\nContent-Length: 2\r\n\r\nab\
+               RTSP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n"[..],
+            &dst[..]
+        );
+
+        // Body written apart from the head
+        codec
+            .encode(b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\n", &mut dst)
+            .unwrap();
+        codec.encode(b"abc", &mut dst).unwrap();
+        assert!(dst.ends_with(b"Content-Length: 3\r\n\r\nabc"));
+    }
+}
@@ -0,0 +1,345 @@
+//! Packets of streams interleaved with requests on the RTSP connection, for senders that can't
+//! reach ports of streams, e.g. through a firewall:
+//! ```text
+//! | '$' | channel(8) | len(16) | data(len) |
+//! ```
+//! As with RTSP interleaving, channel `2 * id` carries packets to the data port of the stream of
+//! `id`, the one after it to its control port. They're relayed to those ports on the address
+//! the sender connected to, so processors read them as if the sender sent them there.
+//!
+//! The connection isn't read on while the relay is behind, as frames of buffered audio and
+//! video can't be dropped without breaking the stream apart.
+//!
+//! Only the way from the sender is relayed. Packets streams send, i.e. retransmit requests
+//! and timing ones, still go over UDP to the ports the sender advertised, so senders that
+//! can't be reached there play realtime audio without retransmits and timing replies.
+
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    task::{Context, Poll, ready},
+};
+
+use bytes::Bytes;
+use futures::task::AtomicWaker;
+use pin_project_lite::pin_project;
+use tokio::{
+    io::{AsyncRead, AsyncWriteExt, ReadBuf},
+    net::{TcpStream, UdpSocket},
+    sync::mpsc,
+};
+
+use crate::streaming::{StreamKind, StreamRegistry};
+
+/// Frames waiting for the relay, the connection isn't read on past it
+pub const QUEUE_LEN: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interleaved {
+    pub channel: u8,
+    pub data: Bytes,
+}
+
+/// Count of frames the relay hasn't taken yet, waking the reader once it drops below
+/// [`QUEUE_LEN`].
+#[derive(Default)]
+struct Backlog {
+    queued: AtomicUsize,
+    waker: AtomicWaker,
+}
+
+/// Passes frames split off the connection to the relay, see [`queue`].
+pub struct FrameSender {
+    frames: mpsc::UnboundedSender<Interleaved>,
+    backlog: Arc<Backlog>,
+}
+
+pub struct FrameReceiver {
+    frames: mpsc::UnboundedReceiver<Interleaved>,
+    backlog: Arc<Backlog>,
+}
+
+pin_project! {
+    /// Reader of the connection, pending while the relay is behind.
+    pub struct Throttled<R> {
+        #[pin]
+        reader: R,
+        backlog: Arc<Backlog>,
+    }
+}
+
+/// Passes interleaved frames of a single connection on to processors of their streams.
+pub struct Relay {
+    local_ip: IpAddr,
+    streams: Arc<StreamRegistry>,
+    /// Sends packets of realtime audio, bound on the first one
+    udp: Option<UdpSocket>,
+    /// Connections to listeners of buffered audio and video, by channel
+    tcp: HashMap<u8, TcpStream>,
+}
+
+impl Interleaved {
+    pub const MAGIC: u8 = b'$';
+    pub const HEADER_LEN: usize = 4;
+
+    /// Stream the frame is of and whether it's for its control port
+    pub fn target(&self) -> (u64, bool) {
+        (u64::from(self.channel / 2), self.channel % 2 == 1)
+    }
+}
+
+/// Queue of frames between the connection and the relay. It isn't bounded by itself, the
+/// connection read through [`FrameSender::throttle`] is held instead.
+pub fn queue() -> (FrameSender, FrameReceiver) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let backlog = Arc::new(Backlog::default());
+    (
+        FrameSender {
+            frames: tx,
+            backlog: Arc::clone(&backlog),
+        },
+        FrameReceiver {
+            frames: rx,
+            backlog,
+        },
+    )
+}
+
+impl Backlog {
+    fn poll_room(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.queued.load(Ordering::Acquire) < QUEUE_LEN {
+            return Poll::Ready(());
+        }
+        self.waker.register(cx.waker());
+        // Frame may have been taken before the waker was registered
+        if self.queued.load(Ordering::Acquire) < QUEUE_LEN {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl FrameSender {
+    /// Returns `false` if the relay is gone.
+    pub fn send(&self, frame: Interleaved) -> bool {
+        self.backlog.queued.fetch_add(1, Ordering::AcqRel);
+        let sent = self.frames.send(frame).is_ok();
+        if !sent {
+            self.backlog.queued.fetch_sub(1, Ordering::AcqRel);
+        }
+        sent
+    }
+
+    /// Holds reading `reader` while [`QUEUE_LEN`] frames are waiting for the relay.
+    pub fn throttle<R>(&self, reader: R) -> Throttled<R> {
+        Throttled {
+            reader,
+            backlog: Arc::clone(&self.backlog),
+        }
+    }
+}
+
+impl FrameReceiver {
+    pub async fn recv(&mut self) -> Option<Interleaved> {
+        let frame = self.frames.recv().await?;
+        self.backlog.queued.fetch_sub(1, Ordering::AcqRel);
+        self.backlog.waker.wake();
+        Some(frame)
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for Throttled<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        ready!(this.backlog.poll_room(cx));
+        this.reader.poll_read(cx, buf)
+    }
+}
+
+impl Relay {
+    pub fn new(local_ip: IpAddr, streams: Arc<StreamRegistry>) -> Self {
+        Self {
+            local_ip,
+            streams,
+            udp: None,
+            tcp: HashMap::new(),
+        }
+    }
+
+    /// Relays frames until the connection is closed, frames no stream takes are dropped.
+    pub async fn run(mut self, mut frames: FrameReceiver) {
+        while let Some(frame) = frames.recv().await {
+            let channel = frame.channel;
+            if let Err(err) = self.forward(frame).await {
+                tracing::debug!(%err, %channel, "interleaved frame dropped");
+                self.tcp.remove(&channel);
+            }
+        }
+    }
+
+    async fn forward(&mut self, frame: Interleaved) -> io::Result<()> {
+        let (id, control) = frame.target();
+        let Some((stream, descriptor)) = self
+            .streams
+            .get(id)
+            .and_then(|stream| Some((stream.clone(), *stream.descriptor.get()?)))
+        else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no such stream"));
+        };
+        let port = if control {
+            descriptor.local_control_port.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "stream has no control port")
+            })?
+        } else {
+            descriptor.local_data_port
+        };
+        let addr = SocketAddr::new(self.local_ip, port);
+
+        match descriptor.kind {
+            StreamKind::AudioRealtime => {
+                let udp = match &mut self.udp {
+                    Some(udp) => udp,
+                    udp @ None => udp.insert(UdpSocket::bind((self.local_ip, 0)).await?),
+                };
+                // So the stream doesn't take the relay for the sender
+                let _ = stream.relayed_from.set(udp.local_addr()?);
+                udp.send_to(&frame.data, addr).await?;
+            }
+            // Listeners take a single connection, kept for the following frames
+            StreamKind::AudioBuffered | StreamKind::Video => {
+                let conn = match self.tcp.entry(frame.channel) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        tracing::debug!(channel = %frame.channel, %id, "interleaved stream relayed");
+                        entry.insert(TcpStream::connect(addr).await?)
+                    }
+                };
+                conn.write_all(&frame.data).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        sync::Arc,
+    };
+
+    use bytes::Bytes;
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, UdpSocket},
+        time::{Duration, timeout},
+    };
+
+    use super::{Interleaved, QUEUE_LEN, Relay, queue};
+    use crate::{
+        playback::{
+            audio::{AudioFormat, AudioParams},
+            stats::{StreamDescriptor, StreamParams},
+        },
+        streaming::{SharedData, StreamKind, StreamRegistry},
+    };
+
+    fn register(
+        streams: &StreamRegistry,
+        id: u64,
+        kind: StreamKind,
+        local_data_port: u16,
+    ) -> Arc<SharedData> {
+        let data = Arc::new(SharedData::new(kind));
+        let params = AudioParams {
+            samples_per_frame: 352,
+            format: AudioFormat::from_bits(0x40000).unwrap(),
+            latency: None,
+        };
+        data.descriptor
+            .set(StreamDescriptor {
+                id,
+                kind,
+                params: StreamParams::Audio(params),
+                local_data_port,
+                local_control_port: None,
+                timing: None,
+            })
+            .unwrap();
+        streams.insert(id, Arc::clone(&data));
+        data
+    }
+
+    #[tokio::test]
+    async fn frames_relayed_to_stream_ports() {
+        const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        let realtime = UdpSocket::bind((LOCALHOST, 0)).await.unwrap();
+        let buffered = TcpListener::bind((LOCALHOST, 0)).await.unwrap();
+        let streams = Arc::new(StreamRegistry::default());
+        let _realtime = register(
+            &streams,
+            0,
+            StreamKind::AudioRealtime,
+            realtime.local_addr().unwrap().port(),
+        );
+        let _buffered = register(
+            &streams,
+            1,
+            StreamKind::AudioBuffered,
+            buffered.local_addr().unwrap().port(),
+        );
+
+        let (tx, rx) = queue();
+        let relay = tokio::spawn(Relay::new(LOCALHOST, Arc::clone(&streams)).run(rx));
+        for (channel, data) in [
+            (0, &b"realtime"[..]),
+            // No control port, nor stream of the channel
+            (1, b"control"),
+            (4, b"unknown"),
+            (2, b"buff"),
+            (2, b"ered"),
+        ] {
+            let data = Bytes::from_static(data);
+            assert!(tx.send(Interleaved { channel, data }));
+        }
+        drop(tx);
+        relay.await.unwrap();
+
+        let mut buf = [0; 16];
+        let len = realtime.recv(&mut buf).await.unwrap();
+        assert_eq!(b"realtime", &buf[..len]);
+        let (mut conn, _) = buffered.accept().await.unwrap();
+        let mut relayed = Vec::new();
+        conn.read_to_end(&mut relayed).await.unwrap();
+        assert_eq!(b"buffered", &relayed[..]);
+    }
+
+    #[tokio::test]
+    async fn reading_held_while_relay_behind() {
+        let (tx, mut rx) = queue();
+        let mut conn = tx.throttle(&b"next"[..]);
+        for _ in 0..QUEUE_LEN {
+            let data = Bytes::from_static(b"frame");
+            assert!(tx.send(Interleaved { channel: 0, data }));
+        }
+
+        let mut buf = [0; 4];
+        let held = timeout(Duration::from_millis(50), conn.read_exact(&mut buf)).await;
+        assert!(held.is_err());
+        rx.recv().await.unwrap();
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"next", &buf);
+    }
+}
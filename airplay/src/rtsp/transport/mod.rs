@@ -0,0 +1,144 @@
+//! Serving RTSP connections with the HTTP router.
+
+use std::{
+    future::{Future, poll_fn},
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use hyper::{server::conn::http1, service::service_fn};
+use hyper_util::rt::TokioIo;
+use pin_project_lite::pin_project;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf, split},
+    net::TcpListener,
+};
+use tokio_util::{
+    codec::{FramedRead, FramedWrite},
+    io::{SinkWriter, StreamReader},
+};
+use tower::Service;
+
+use super::{
+    RouterService,
+    session::{ConnectionId, PeerAddr},
+};
+use crate::util::task::TaskSet;
+
+mod codec;
+mod interleaved;
+
+pin_project! {
+    /// Joins read and write halves back into one transport.
+    struct ReadWrite<R, W> {
+        #[pin]
+        reader: R,
+        #[pin]
+        writer: W,
+    }
+}
+
+/// Accepts connections until `stop` resolves, they're served on `tasks` so shut down with them.
+pub async fn serve(
+    listener: TcpListener,
+    mut make_service: RouterService,
+    tasks: &TaskSet,
+    stop: impl Future<Output = ()>,
+) {
+    tokio::pin!(stop);
+    loop {
+        let (stream, remote_addr) = tokio::select! {
+            () = &mut stop => return,
+            res = listener.accept() => match res {
+                Ok(res) => res,
+                Err(err) => {
+                    tracing::warn!(%err, "rtsp connection not accepted");
+                    continue;
+                }
+            },
+        };
+        // Handlers bind sockets of the session on the interface the sender connected to
+        let local_addr = match stream.local_addr() {
+            Ok(addr) => addr,
+            Err(err) => {
+                tracing::warn!(%err, %remote_addr, "rtsp connection dropped");
+                continue;
+            }
+        };
+        tracing::info!(%remote_addr, "rtsp connection accepted");
+
+        let Ok(()) = poll_fn(|cx| Service::<SocketAddr>::poll_ready(&mut make_service, cx)).await;
+        let Ok(router) = make_service.call(local_addr).await;
+        let conn_id = ConnectionId::next();
+        let service = service_fn(move |mut req: hyper::Request<_>| {
+            req.extensions_mut().insert(conn_id);
+            req.extensions_mut().insert(PeerAddr(Some(remote_addr)));
+            router.clone().call(req)
+        });
+
+        let (frames_tx, frames) = interleaved::queue();
+        let relay = interleaved::Relay::new(local_addr.ip(), Arc::clone(&make_service.streams));
+        let (rx, tx) = split(stream);
+        let io = TokioIo::new(ReadWrite {
+            reader: StreamReader::new(FramedRead::new(
+                frames_tx.throttle(rx),
+                codec::Rtsp2Http::interleaved(frames_tx),
+            )),
+            writer: SinkWriter::new(FramedWrite::new(tx, codec::Rtsp2Http::default())),
+        });
+
+        let cancelled = tasks.cancelled();
+        let sessions = Arc::clone(&make_service.sessions);
+        let on_disconnect = Arc::clone(&make_service.on_disconnect);
+        tasks.spawn(async move {
+            let conn = http1::Builder::new()
+                .preserve_header_case(true)
+                .title_case_headers(true)
+                .serve_connection(io, service);
+            // Frames end along with the connection reading them, the relay passes on what's
+            // left and stops
+            let served = async { tokio::join!(conn, relay.run(frames)).0 };
+            tokio::select! {
+                () = cancelled => {}
+                res = served => match res {
+                    Ok(()) => tracing::info!(%remote_addr, "rtsp connection closed"),
+                    Err(err) => tracing::warn!(%err, %remote_addr, "rtsp connection failed"),
+                },
+            }
+            // Senders don't come back to a session over another connection
+            sessions.release(conn_id);
+            on_disconnect(conn_id).await;
+        });
+    }
+}
+
+impl<R: AsyncRead, W> AsyncRead for ReadWrite<R, W> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.project().reader.poll_read(cx, buf)
+    }
+}
+
+impl<R, W: AsyncWrite> AsyncWrite for ReadWrite<R, W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.project().writer.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().writer.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().writer.poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,98 @@
+//! Local time as the playout and timing see it.
+//!
+//! Both read the wall clock, as the sender's timestamps are mapped onto it, and sleep until a
+//! moment of it. Tests swap the clock for a virtual one, so they step through time exactly
+//! rather than wait for it.
+
+use std::{future::Future, time::Duration};
+
+use super::timing::NtpTimestamp;
+
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> NtpTimestamp;
+
+    /// Resolves once [`Self::now`] reached `deadline`, right away if it's already passed.
+    fn sleep_until(&self, deadline: NtpTimestamp) -> impl Future<Output = ()> + Send;
+}
+
+/// System time, slept on with tokio timers.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> NtpTimestamp {
+        NtpTimestamp::now()
+    }
+
+    fn sleep_until(&self, deadline: NtpTimestamp) -> impl Future<Output = ()> + Send {
+        let wait = u64::try_from(deadline.diff_ns(self.now()))
+            .map_or(Duration::ZERO, Duration::from_nanos);
+        tokio::time::sleep(wait)
+    }
+}
+
+/// Virtual time moving only when advanced, waking sleepers whose deadline came.
+#[cfg(test)]
+#[derive(Clone)]
+pub struct MockClock(std::sync::Arc<tokio::sync::watch::Sender<NtpTimestamp>>);
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new(start: NtpTimestamp) -> Self {
+        Self(std::sync::Arc::new(tokio::sync::watch::Sender::new(start)))
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.0.send_modify(|now| *now = now.after(by));
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> NtpTimestamp {
+        *self.0.borrow()
+    }
+
+    fn sleep_until(&self, deadline: NtpTimestamp) -> impl Future<Output = ()> + Send {
+        let mut now = self.0.subscribe();
+        async move {
+            // Sender lives as long as the clock, which outlives its sleepers
+            let _ = now.wait_for(|now| *now >= deadline).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::FutureExt as _;
+
+    use super::{Clock, MockClock, TokioClock};
+    use crate::streaming::timing::NtpTimestamp;
+
+    #[test]
+    fn mock_sleepers_woken_when_advanced() {
+        let start = NtpTimestamp::from_nanos(1_000_000_000);
+        let clock = MockClock::new(start);
+        let mut sleep = Box::pin(clock.sleep_until(start.after(Duration::from_millis(10))));
+
+        assert!((&mut sleep).now_or_never().is_none());
+        clock.advance(Duration::from_millis(9));
+        assert!((&mut sleep).now_or_never().is_none());
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(Some(()), sleep.now_or_never());
+        assert_eq!(start.after(Duration::from_millis(10)), clock.now());
+
+        // Passed deadlines don't wait
+        assert_eq!(Some(()), clock.sleep_until(start).now_or_never());
+    }
+
+    #[tokio::test]
+    async fn tokio_clock_sleeps_until_deadline() {
+        let clock = TokioClock;
+        let deadline = clock.now().after(Duration::from_millis(20));
+        clock.sleep_until(deadline).await;
+        assert!(clock.now() >= deadline);
+    }
+}
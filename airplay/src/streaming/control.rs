@@ -0,0 +1,506 @@
+//! Packets sent by the sender to the control socket of realtime audio stream.
+//!
+//! Every packet starts with the RTP-like 4 bytes header:
+//! ```text
+//! | V(2) | P(1) | X(1) | CC(4) | M(1) | PT(7) | seq(16) |
+//! ```
+//!
+//! Besides those, the sender may send standard RTCP, where the whole second byte is the payload
+//! type and the last two are the length of the packet. Several of them can be sent in one
+//! datagram, e.g. sender report followed by SDES.
+//!
+//! Retransmit requests go to the control port the sender advertised until its first control
+//! packet arrives, then to the address that packet came from. Senders behind NAT have their
+//! packets rewritten, so the advertised port isn't the one reaching them. Only packets from
+//! the address the sender connected from count, and not the ones relayed from the RTSP
+//! connection, which come from a local socket.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicU16, Ordering},
+    },
+};
+
+use thiserror::Error;
+use tokio::net::UdpSocket;
+
+use super::SharedData;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControlHeader {
+    pub version: u8,
+    pub marker: bool,
+    pub payload_type: u8,
+    pub seq: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlPacket<'a> {
+    /// Maps RTP time of the sender to its NTP time. First packet is sent with extension bit set.
+    TimeSync {
+        /// RTP timestamp of the currently played frame, i.e. `rtp_timestamp - latency`
+        rtp_timestamp: u32,
+        /// NTP time of the sender when `rtp_timestamp` should be played
+        ntp_timestamp: u64,
+        /// RTP timestamp of the next frame sent
+        next_rtp_timestamp: u32,
+    },
+    /// Resent audio packet with the original RTP header
+    RetransmitReply {
+        rtp: &'a [u8],
+    },
+    /// Compound RTCP packet
+    Rtcp(RtcpPackets<'a>),
+    Unknown(u8),
+}
+
+/// RTCP packets of one datagram, iterated in the order they were sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtcpPackets<'a>(&'a [u8]);
+
+pub struct RtcpIter<'a> {
+    buf: &'a [u8],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcpPacket {
+    SenderReport(SenderReport),
+    Other(u8),
+}
+
+/// Maps RTP time of the sender to its NTP time at which the sample was taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SenderReport {
+    pub ssrc: u32,
+    pub ntp_timestamp: u64,
+    pub rtp_timestamp: u32,
+    /// Packets sent since the stream has started
+    pub packet_count: u32,
+    /// Payload bytes sent since the stream has started
+    pub octet_count: u32,
+}
+
+#[derive(Debug, Error)]
+pub enum ControlError {
+    #[error("insufficient data: {0} bytes")]
+    InsufficientData(usize),
+}
+
+/// Control socket shared by receiving and sending sides of realtime audio stream.
+pub struct ControlChannel {
+    socket: UdpSocket,
+    /// Address the sender connected from, `None` if it's not known
+    peer_ip: Option<IpAddr>,
+    remote_port: u16,
+    /// Source of the first control packet of the sender, takes over the advertised port
+    learned_addr: OnceLock<SocketAddr>,
+    retransmit_window: u16,
+    seq: AtomicU16,
+    /// Of the stream, telling the socket its packets are relayed from
+    shared_data: Arc<SharedData>,
+}
+
+impl ControlHeader {
+    pub const LEN: usize = 4;
+
+    pub fn parse(buf: &[u8]) -> Result<Self, ControlError> {
+        let Some(header) = buf.first_chunk::<{ Self::LEN }>() else {
+            return Err(ControlError::InsufficientData(buf.len()));
+        };
+
+        Ok(Self {
+            version: header[0] >> 6,
+            marker: header[1] & 0x80 != 0,
+            payload_type: header[1] & 0x7f,
+            seq: u16::from_be_bytes([header[2], header[3]]),
+        })
+    }
+}
+
+impl<'a> ControlPacket<'a> {
+    pub const TIME_SYNC: u8 = 0x54;
+    pub const RETRANSMIT_REPLY: u8 = 0x56;
+    /// Sender report, receiver report, SDES, BYE and APP
+    pub const RTCP: std::ops::RangeInclusive<u8> = 200..=204;
+
+    const TIME_SYNC_LEN: usize = 20;
+
+    pub fn parse(buf: &'a [u8]) -> Result<(ControlHeader, Self), ControlError> {
+        let header = ControlHeader::parse(buf)?;
+        if Self::RTCP.contains(&buf[1]) {
+            return Ok((header, Self::Rtcp(RtcpPackets(buf))));
+        }
+
+        let packet = match header.payload_type {
+            Self::TIME_SYNC => {
+                let Some(body) = buf.get(ControlHeader::LEN..Self::TIME_SYNC_LEN) else {
+                    return Err(ControlError::InsufficientData(buf.len()));
+                };
+
+                Self::TimeSync {
+                    rtp_timestamp: u32::from_be_bytes(body[..4].try_into().unwrap()),
+                    ntp_timestamp: u64::from_be_bytes(body[4..12].try_into().unwrap()),
+                    next_rtp_timestamp: u32::from_be_bytes(body[12..].try_into().unwrap()),
+                }
+            }
+            Self::RETRANSMIT_REPLY => Self::RetransmitReply {
+                rtp: &buf[ControlHeader::LEN..],
+            },
+            other => Self::Unknown(other),
+        };
+
+        Ok((header, packet))
+    }
+}
+
+impl<'a> IntoIterator for RtcpPackets<'a> {
+    type Item = Result<RtcpPacket, ControlError>;
+    type IntoIter = RtcpIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        RtcpIter { buf: self.0 }
+    }
+}
+
+impl Iterator for RtcpIter<'_> {
+    type Item = Result<RtcpPacket, ControlError>;
+
+    /// Stops on the first truncated packet, as the ones after it can't be found.
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        let res = RtcpPacket::parse(self.buf);
+        self.buf = match res {
+            Ok((_, len)) => &self.buf[len..],
+            Err(_) => &[],
+        };
+        Some(res.map(|(packet, _)| packet))
+    }
+}
+
+impl RtcpPacket {
+    pub const SENDER_REPORT: u8 = 200;
+
+    const SENDER_REPORT_LEN: usize = 28;
+
+    /// Returns the packet and its length.
+    fn parse(buf: &[u8]) -> Result<(Self, usize), ControlError> {
+        let header = ControlHeader::parse(buf)?;
+        // Length is in 32-bit words minus one, the header included
+        let len = (usize::from(header.seq) + 1) * 4;
+        let Some(body) = buf.get(..len) else {
+            return Err(ControlError::InsufficientData(buf.len()));
+        };
+
+        let packet = match buf[1] {
+            Self::SENDER_REPORT => {
+                let Some(body) = body.get(ControlHeader::LEN..Self::SENDER_REPORT_LEN) else {
+                    return Err(ControlError::InsufficientData(body.len()));
+                };
+                let word = |at: usize| u32::from_be_bytes(body[at..at + 4].try_into().unwrap());
+
+                Self::SenderReport(SenderReport {
+                    ssrc: word(0),
+                    ntp_timestamp: u64::from_be_bytes(body[4..12].try_into().unwrap()),
+                    rtp_timestamp: word(12),
+                    packet_count: word(16),
+                    octet_count: word(20),
+                })
+            }
+            other => Self::Other(other),
+        };
+
+        Ok((packet, len))
+    }
+}
+
+impl ControlChannel {
+    pub const RETRANSMIT_REQUEST: u8 = 0x55;
+
+    const RETRANSMIT_REQUEST_LEN: usize = 8;
+
+    pub fn new(
+        socket: UdpSocket,
+        peer_ip: Option<IpAddr>,
+        remote_port: u16,
+        retransmit_window: u16,
+        shared_data: Arc<SharedData>,
+    ) -> Self {
+        Self {
+            socket,
+            peer_ip: peer_ip.map(|ip| ip.to_canonical()),
+            remote_port,
+            learned_addr: OnceLock::new(),
+            retransmit_window,
+            seq: AtomicU16::new(1),
+            shared_data,
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let (len, addr) = self.socket.recv_from(buf).await?;
+        let from_sender = self.peer_ip == Some(addr.ip().to_canonical())
+            && self.shared_data.relayed_from.get() != Some(&addr);
+        if from_sender && self.learned_addr.set(addr).is_ok() && addr.port() != self.remote_port {
+            tracing::debug!(%addr, advertised = %self.remote_port, "control rebound by NAT");
+        }
+        Ok(len)
+    }
+
+    /// Where control packets are sent, the sender's address with the advertised port until
+    /// learned. `remote_ip` stands for the former if it's not known.
+    pub fn remote_addr(&self, remote_ip: IpAddr) -> SocketAddr {
+        self.learned_addr.get().copied().unwrap_or(SocketAddr::new(
+            self.peer_ip.unwrap_or(remote_ip),
+            self.remote_port,
+        ))
+    }
+
+    /// Asks the sender to resend `count` packets starting from `start_seq`.
+    ///
+    /// Only the latest packets fitting into retransmit window are requested, because the older
+    /// ones most likely won't be in time for playback anyway.
+    pub async fn request_retransmit(
+        &self,
+        remote_ip: IpAddr,
+        start_seq: u16,
+        count: u16,
+    ) -> io::Result<()> {
+        let (start_seq, count) = if count > self.retransmit_window {
+            tracing::debug!(%start_seq, %count, "gap exceeds retransmit window");
+            (
+                start_seq.wrapping_add(count - self.retransmit_window),
+                self.retransmit_window,
+            )
+        } else {
+            (start_seq, count)
+        };
+
+        if count == 0 {
+            return Ok(());
+        }
+
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        let pkt = Self::retransmit_request(seq, start_seq, count);
+        self.socket
+            .send_to(&pkt, self.remote_addr(remote_ip))
+            .await?;
+        tracing::trace!(%start_seq, %count, "retransmit requested");
+
+        Ok(())
+    }
+
+    fn retransmit_request(
+        seq: u16,
+        start_seq: u16,
+        count: u16,
+    ) -> [u8; Self::RETRANSMIT_REQUEST_LEN] {
+        let mut pkt = [0u8; Self::RETRANSMIT_REQUEST_LEN];
+        pkt[0] = 0x80;
+        pkt[1] = 0x80 | Self::RETRANSMIT_REQUEST;
+        pkt[2..4].copy_from_slice(&seq.to_be_bytes());
+        pkt[4..6].copy_from_slice(&start_seq.to_be_bytes());
+        pkt[6..8].copy_from_slice(&count.to_be_bytes());
+        pkt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        sync::Arc,
+    };
+
+    use tokio::net::UdpSocket;
+
+    use super::{
+        ControlChannel, ControlError, ControlHeader, ControlPacket, RtcpPacket, SenderReport,
+    };
+    use crate::streaming::{SharedData, StreamKind};
+
+    #[test]
+    fn parse_time_sync() {
+        const PACKET: &[u8] = &[
+            0x90, 0xd4, 0x00, 0x07, 0x00, 0x00, 0x10, 0x00, 0xe0, 0x00, 0x00, 0x01, 0x80, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x1b, 0x10,
+        ];
+
+        let (header, packet) = ControlPacket::parse(PACKET).unwrap();
+        assert_eq!(
+            ControlHeader {
+                version: 2,
+                marker: true,
+                payload_type: ControlPacket::TIME_SYNC,
+                seq: 7,
+            },
+            header
+        );
+        assert_eq!(
+            ControlPacket::TimeSync {
+                rtp_timestamp: 0x1000,
+                ntp_timestamp: 0xe000_0001_8000_0000,
+                next_rtp_timestamp: 0x1b10,
+            },
+            packet
+        );
+    }
+
+    #[test]
+    fn parse_retransmit_reply() {
+        const PACKET: &[u8] = &[0x80, 0xd6, 0x00, 0x01, 0x80, 0x60, 0x00, 0x05];
+
+        let (_, packet) = ControlPacket::parse(PACKET).unwrap();
+        assert_eq!(
+            ControlPacket::RetransmitReply {
+                rtp: &[0x80, 0x60, 0x00, 0x05]
+            },
+            packet
+        );
+    }
+
+    #[test]
+    fn parse_short_packets() {
+        assert!(matches!(
+            ControlPacket::parse(&[0x80, 0xd4, 0x00]),
+            Err(ControlError::InsufficientData(3))
+        ));
+        assert!(matches!(
+            ControlPacket::parse(&[0x80, 0xd4, 0x00, 0x07, 0x00]),
+            Err(ControlError::InsufficientData(5))
+        ));
+        assert!(matches!(
+            ControlPacket::parse(&[0x80, 0xff, 0x00, 0x01]),
+            Ok((_, ControlPacket::Unknown(0x7f)))
+        ));
+    }
+
+    #[test]
+    fn parse_compound_rtcp() {
+        const PACKET: &[u8] = &[
+            // Sender report without report blocks
+            0x80, 0xc8, 0x00, 0x06, 0x12, 0x34, 0x56, 0x78, 0xe0, 0x00, 0x00, 0x01, 0x80, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x06, 0xe0,
+            // SDES with one CNAME chunk
+            0x81, 0xca, 0x00, 0x02, 0x12, 0x34, 0x56, 0x78, 0x01, 0x01, 0x61, 0x00,
+            // Truncated BYE
+            0x81, 0xcb, 0x00, 0x01, 0x12, 0x34,
+        ];
+
+        let (_, ControlPacket::Rtcp(packets)) = ControlPacket::parse(PACKET).unwrap() else {
+            panic!("must be rtcp");
+        };
+        let mut packets = packets.into_iter();
+        assert_eq!(
+            RtcpPacket::SenderReport(SenderReport {
+                ssrc: 0x1234_5678,
+                ntp_timestamp: 0xe000_0001_8000_0000,
+                rtp_timestamp: 0x1000,
+                packet_count: 5,
+                octet_count: 0x6e0,
+            }),
+            packets.next().unwrap().unwrap()
+        );
+        assert_eq!(RtcpPacket::Other(202), packets.next().unwrap().unwrap());
+        assert!(matches!(
+            packets.next(),
+            Some(Err(ControlError::InsufficientData(6)))
+        ));
+        assert!(packets.next().is_none());
+
+        // Sender report shorter than its fixed part
+        let (_, ControlPacket::Rtcp(packets)) = ControlPacket::parse(&PACKET[..4]).unwrap() else {
+            panic!("must be rtcp");
+        };
+        assert!(matches!(
+            packets.into_iter().next(),
+            Some(Err(ControlError::InsufficientData(4)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn retransmits_follow_first_control_packet() {
+        let advertised = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let rebound = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = socket.local_addr().unwrap();
+        let advertised_addr = advertised.local_addr().unwrap();
+        let shared_data = Arc::new(SharedData::new(StreamKind::AudioRealtime));
+        shared_data
+            .relayed_from
+            .set(relay.local_addr().unwrap())
+            .unwrap();
+        let control = ControlChannel::new(
+            socket,
+            Some(advertised_addr.ip()),
+            advertised_addr.port(),
+            16,
+            shared_data,
+        );
+
+        let mut buf = [0; 16];
+        control
+            .request_retransmit(advertised_addr.ip(), 1, 1)
+            .await
+            .unwrap();
+        assert_eq!(8, advertised.recv(&mut buf).await.unwrap());
+
+        // Relay isn't the sender, and only the first source of the sender is taken
+        for sender in [&relay, &rebound, &other] {
+            sender
+                .send_to(&[0x80, 0xd4, 0, 1], local_addr)
+                .await
+                .unwrap();
+            assert_eq!(4, control.recv(&mut buf).await.unwrap());
+        }
+        let rebound_addr = rebound.local_addr().unwrap();
+        assert_eq!(rebound_addr, control.remote_addr(advertised_addr.ip()));
+        control
+            .request_retransmit(advertised_addr.ip(), 2, 1)
+            .await
+            .unwrap();
+        let (len, from) = rebound.recv_from(&mut buf).await.unwrap();
+        assert_eq!((8, local_addr), (len, from));
+        assert_eq!([0x00, 0x02, 0x00, 0x01], buf[4..8]);
+    }
+
+    #[tokio::test]
+    async fn control_from_other_hosts_not_learned() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = socket.local_addr().unwrap();
+        let peer_ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let shared_data = Arc::new(SharedData::new(StreamKind::AudioRealtime));
+        let control = ControlChannel::new(socket, Some(peer_ip), 6001, 16, shared_data);
+
+        let stray = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        stray
+            .send_to(&[0x80, 0xd4, 0, 1], local_addr)
+            .await
+            .unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(4, control.recv(&mut buf).await.unwrap());
+        // Data packets relayed from the local socket don't stand for the sender either
+        assert_eq!(
+            SocketAddr::new(peer_ip, 6001),
+            control.remote_addr(local_addr.ip())
+        );
+    }
+
+    #[test]
+    fn build_retransmit_request() {
+        assert_eq!(
+            [0x80, 0xd5, 0x00, 0x01, 0xff, 0xfe, 0x00, 0x03],
+            ControlChannel::retransmit_request(1, 0xfffe, 3)
+        );
+    }
+}
@@ -0,0 +1,462 @@
+//! Queue between audio processors and their stream, so a stream stalling for a moment doesn't
+//! stall reading packets off the network.
+//!
+//! Packets are passed to [`Stream::on_data`] on a thread of the queue. Once it's full, new
+//! packets are handled as [`Backpressure`] says and the dropped ones are counted in stats.
+//! Flushes and the end of the stream are never dropped and keep their order with packets.
+//! Raw packets skip the queue, they're passed right away as they're only borrowed.
+//!
+//! Processors wait on a full queue with [`Backpressure::Block`] through
+//! [`tokio::task::block_in_place`], so other tasks of a multi-threaded runtime keep running
+//! meanwhile. A current-thread runtime has no other worker to move them to and stalls until
+//! there's room.
+
+use std::{
+    collections::VecDeque,
+    error::Error,
+    io,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+};
+
+use tokio::{
+    runtime::{Handle, RuntimeFlavor},
+    task,
+};
+
+use super::{SharedData, StreamError};
+use crate::playback::{
+    Stream,
+    audio::{AudioPacket, AudioStream},
+    channel::Backpressure,
+};
+
+/// Wraps the stream of an audio channel, to be passed to its processor instead.
+pub struct QueuedAudioStream {
+    queue: Arc<Queue>,
+    capacity: usize,
+    backpressure: Backpressure,
+    shared_data: Arc<SharedData>,
+    /// Passes raw packets to the stream shared with the thread, `None` once finished
+    raw: Option<RawSink>,
+}
+
+type RawSink = Box<dyn Fn(&[u8]) + Send + Sync>;
+
+struct Queue {
+    state: Mutex<State>,
+    /// Signalled on push and on close
+    ready: Condvar,
+    /// Signalled once a packet is taken
+    room: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    items: VecDeque<Item>,
+    /// Packets among items, the others don't count towards capacity
+    packets: usize,
+    /// Wrapper is gone, the thread exits once the items are passed
+    closed: bool,
+    /// Thread is gone, e.g. the stream panicked, so packets aren't waited with
+    stopped: bool,
+}
+
+enum Item {
+    Packet(AudioPacket),
+    Flush(Option<u32>, u32),
+    Finished(Result<(), StreamError>),
+}
+
+impl QueuedAudioStream {
+    /// Holds up to `capacity` packets, at least one, and passes them to `stream` on a thread
+    /// of its own.
+    pub fn spawn(
+        stream: impl AudioStream,
+        capacity: usize,
+        backpressure: Backpressure,
+        shared_data: Arc<SharedData>,
+    ) -> io::Result<Self> {
+        let queue = Arc::new(Queue {
+            state: Mutex::default(),
+            ready: Condvar::new(),
+            room: Condvar::new(),
+        });
+
+        let stream = Arc::new(stream);
+        let raw_stream = Arc::clone(&stream);
+        let thread_queue = Arc::clone(&queue);
+        thread::Builder::new()
+            .name("rairplay-audio".to_string())
+            .spawn(move || thread_queue.deliver(stream))?;
+
+        Ok(Self {
+            queue,
+            capacity: capacity.max(1),
+            backpressure,
+            shared_data,
+            raw: Some(Box::new(move |packet| raw_stream.on_raw(packet))),
+        })
+    }
+
+    /// Passes the end of the stream once the queued packets are, see [`Stream::on_ok`] and
+    /// [`Stream::on_err`].
+    pub fn finish(mut self, res: Result<(), StreamError>) {
+        // The thread takes the stream back once it's the only one holding it
+        self.raw = None;
+        self.queue.push(Item::Finished(res));
+    }
+}
+
+impl Queue {
+    fn push(&self, item: Item) {
+        let mut state = self.state.lock().unwrap();
+        if matches!(item, Item::Packet(_)) {
+            state.packets += 1;
+        }
+        state.items.push_back(item);
+        self.ready.notify_one();
+    }
+
+    /// Waits for a packet to be taken, off the runtime worker if there's another one.
+    fn wait_for_room<'a>(&'a self, state: MutexGuard<'a, State>) -> MutexGuard<'a, State> {
+        let multi_thread = Handle::try_current()
+            .is_ok_and(|runtime| runtime.runtime_flavor() == RuntimeFlavor::MultiThread);
+        if multi_thread {
+            task::block_in_place(|| self.room.wait(state).unwrap())
+        } else {
+            self.room.wait(state).unwrap()
+        }
+    }
+
+    /// Passes items to `stream` until the end of it or until the wrapper is gone.
+    fn deliver(&self, stream: Arc<impl AudioStream>) {
+        struct Stopped<'a>(&'a Queue);
+        impl Drop for Stopped<'_> {
+            fn drop(&mut self) {
+                self.0.state.lock().unwrap().stopped = true;
+                self.0.room.notify_all();
+            }
+        }
+
+        let _stopped = Stopped(self);
+        loop {
+            let mut state = self.state.lock().unwrap();
+            let item = loop {
+                if let Some(item) = state.items.pop_front() {
+                    break item;
+                }
+                if state.closed {
+                    tracing::debug!("audio stream dropped unfinished");
+                    return;
+                }
+                state = self.ready.wait(state).unwrap();
+            };
+            if matches!(item, Item::Packet(_)) {
+                state.packets -= 1;
+                self.room.notify_one();
+            }
+            drop(state);
+
+            match item {
+                Item::Packet(pkt) => stream.on_data(pkt),
+                Item::Flush(from_seq, until_seq) => stream.on_flush(from_seq, until_seq),
+                Item::Finished(res) => {
+                    let Ok(stream) = Arc::try_unwrap(stream) else {
+                        unreachable!("stream still shared once finished");
+                    };
+                    return match res {
+                        Ok(()) => stream.on_ok(),
+                        Err(err) => stream.on_err(err.into()),
+                    };
+                }
+            }
+        }
+    }
+}
+
+impl Stream for QueuedAudioStream {
+    type Content = AudioPacket;
+
+    fn on_data(&self, pkt: Self::Content) {
+        let mut state = self.queue.state.lock().unwrap();
+        while state.packets >= self.capacity && !state.stopped {
+            match self.backpressure {
+                Backpressure::DropOldest => {
+                    let oldest = state
+                        .items
+                        .iter()
+                        .position(|item| matches!(item, Item::Packet(_)));
+                    if let Some(pos) = oldest {
+                        state.items.remove(pos);
+                        state.packets -= 1;
+                    }
+                    tracing::trace!("audio stream behind, oldest packet dropped");
+                    self.shared_data.stats.on_sink_drop();
+                }
+                Backpressure::DropNewest => {
+                    tracing::trace!("audio stream behind, new packet dropped");
+                    self.shared_data.stats.on_sink_drop();
+                    return;
+                }
+                Backpressure::Block => state = self.queue.wait_for_room(state),
+            }
+        }
+        state.packets += 1;
+        state.items.push_back(Item::Packet(pkt));
+        self.queue.ready.notify_one();
+    }
+
+    fn on_raw(&self, packet: &[u8]) {
+        if let Some(raw) = &self.raw {
+            raw(packet);
+        }
+    }
+
+    fn on_ok(self) {
+        self.finish(Ok(()));
+    }
+
+    fn on_err(self, err: Box<dyn Error>) {
+        // Processors fail with `StreamError` only
+        let err = err.downcast().map_or_else(
+            |err| StreamError::Io(io::Error::other(err.to_string())),
+            |err| *err,
+        );
+        self.finish(Err(err));
+    }
+}
+
+impl AudioStream for QueuedAudioStream {
+    fn on_flush(&self, from_seq: Option<u32>, until_seq: u32) {
+        self.queue.push(Item::Flush(from_seq, until_seq));
+    }
+}
+
+impl Drop for QueuedAudioStream {
+    fn drop(&mut self) {
+        self.queue.state.lock().unwrap().closed = true;
+        self.queue.ready.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        error::Error,
+        sync::{
+            Arc,
+            atomic::{AtomicBool, Ordering},
+            mpsc::{self, Receiver, Sender},
+        },
+        thread,
+        time::Duration,
+    };
+
+    use bytes::BytesMut;
+
+    use super::QueuedAudioStream;
+    use crate::{
+        playback::{
+            Stream,
+            audio::{AudioPacket, AudioStream},
+            channel::Backpressure,
+        },
+        streaming::{SharedData, StreamError, StreamKind},
+    };
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    enum Event {
+        Packet(u8),
+        Raw(u8),
+        Flush(u32),
+        Ok,
+        Err(String),
+    }
+
+    /// Reports what it's passed, packets stall while `stalled` is set
+    struct Recorder {
+        events: Sender<Event>,
+        stalled: Arc<AtomicBool>,
+    }
+
+    impl Stream for Recorder {
+        type Content = AudioPacket;
+
+        fn on_data(&self, pkt: Self::Content) {
+            while self.stalled.load(Ordering::Acquire) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            self.events.send(Event::Packet(pkt.rtp[0])).unwrap();
+        }
+
+        fn on_raw(&self, packet: &[u8]) {
+            self.events.send(Event::Raw(packet[0])).unwrap();
+        }
+
+        fn on_ok(self) {
+            self.events.send(Event::Ok).unwrap();
+        }
+
+        fn on_err(self, err: Box<dyn Error>) {
+            self.events.send(Event::Err(err.to_string())).unwrap();
+        }
+    }
+
+    impl AudioStream for Recorder {
+        fn on_flush(&self, _: Option<u32>, until_seq: u32) {
+            self.events.send(Event::Flush(until_seq)).unwrap();
+        }
+    }
+
+    fn queued(
+        capacity: usize,
+        backpressure: Backpressure,
+    ) -> (QueuedAudioStream, Receiver<Event>, Arc<AtomicBool>) {
+        let (events, rx) = mpsc::channel();
+        let stalled = Arc::default();
+        let recorder = Recorder {
+            events,
+            stalled: Arc::clone(&stalled),
+        };
+        let shared_data = Arc::new(SharedData::new(StreamKind::AudioRealtime));
+        let stream = QueuedAudioStream::spawn(recorder, capacity, backpressure, shared_data);
+        (stream.unwrap(), rx, stalled)
+    }
+
+    fn packet(id: u8) -> AudioPacket {
+        AudioPacket {
+            rtp: BytesMut::from(&[id][..]),
+            playout_time: None,
+        }
+    }
+
+    /// Sends packet 0 and waits for the recorder to stall on it
+    fn stall(stream: &QueuedAudioStream, stalled: &AtomicBool) {
+        stalled.store(true, Ordering::Release);
+        stream.on_data(packet(0));
+        while stream.queue.state.lock().unwrap().packets != 0 {
+            thread::yield_now();
+        }
+    }
+
+    fn received(rx: &Receiver<Event>, count: usize) -> Vec<Event> {
+        (0..count).map(|_| rx.recv_timeout(WAIT).unwrap()).collect()
+    }
+
+    #[test]
+    fn oldest_dropped_while_stalled() {
+        let (stream, rx, stalled) = queued(2, Backpressure::DropOldest);
+        stall(&stream, &stalled);
+        (1..=5).for_each(|id| stream.on_data(packet(id)));
+        stalled.store(false, Ordering::Release);
+
+        let expected = [0, 4, 5].map(Event::Packet);
+        assert_eq!(expected.to_vec(), received(&rx, 3));
+        assert_eq!(3, stream.shared_data.stats.snapshot().sink_drops);
+    }
+
+    #[test]
+    fn newest_dropped_while_stalled() {
+        let (stream, rx, stalled) = queued(2, Backpressure::DropNewest);
+        stall(&stream, &stalled);
+        (1..=5).for_each(|id| stream.on_data(packet(id)));
+        stalled.store(false, Ordering::Release);
+
+        let expected = [0, 1, 2].map(Event::Packet);
+        assert_eq!(expected.to_vec(), received(&rx, 3));
+        assert_eq!(3, stream.shared_data.stats.snapshot().sink_drops);
+    }
+
+    #[test]
+    fn blocked_until_room() {
+        let (stream, rx, stalled) = queued(1, Backpressure::Block);
+        stall(&stream, &stalled);
+        stream.on_data(packet(1));
+
+        let unstall = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            stalled.store(false, Ordering::Release);
+        });
+        stream.on_data(packet(2));
+        unstall.join().unwrap();
+
+        let expected = [0, 1, 2].map(Event::Packet);
+        assert_eq!(expected.to_vec(), received(&rx, 3));
+        assert_eq!(0, stream.shared_data.stats.snapshot().sink_drops);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn blocked_off_runtime_worker() {
+        let (stream, rx, stalled) = queued(1, Backpressure::Block);
+        stall(&stream, &stalled);
+        stream.on_data(packet(1));
+
+        let stream = Arc::new(stream);
+        let blocked = tokio::spawn({
+            let stream = Arc::clone(&stream);
+            async move { stream.on_data(packet(2)) }
+        });
+        // Needs the only worker while the task above waits for room
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            stalled.store(false, Ordering::Release);
+        })
+        .await
+        .unwrap();
+        blocked.await.unwrap();
+
+        let expected = [0, 1, 2].map(Event::Packet);
+        assert_eq!(expected.to_vec(), received(&rx, 3));
+    }
+
+    #[test]
+    fn raw_passed_while_stalled() {
+        let (stream, rx, stalled) = queued(1, Backpressure::DropOldest);
+        stall(&stream, &stalled);
+        stream.on_raw(&[1]);
+        assert_eq!(vec![Event::Raw(1)], received(&rx, 1));
+
+        stalled.store(false, Ordering::Release);
+        stream.on_ok();
+        assert_eq!(vec![Event::Packet(0), Event::Ok], received(&rx, 2));
+    }
+
+    #[test]
+    fn flush_and_end_kept_in_order() {
+        let (stream, rx, stalled) = queued(1, Backpressure::DropOldest);
+        stall(&stream, &stalled);
+        stream.on_flush(None, 7);
+        stream.on_data(packet(1));
+        stream.on_data(packet(2));
+        stream.on_err(Box::new(StreamError::Closed));
+        stalled.store(false, Ordering::Release);
+
+        assert_eq!(
+            vec![
+                Event::Packet(0),
+                Event::Flush(7),
+                Event::Packet(2),
+                Event::Err(StreamError::Closed.to_string())
+            ],
+            received(&rx, 4)
+        );
+        assert!(rx.recv_timeout(WAIT).is_err());
+    }
+
+    #[test]
+    fn dropped_unfinished() {
+        let (stream, rx, _) = queued(4, Backpressure::Block);
+        stream.on_data(packet(0));
+        drop(stream);
+
+        assert_eq!(vec![Event::Packet(0)], received(&rx, 1));
+        // Sender is gone along with the recorder
+        assert!(matches!(
+            rx.recv_timeout(WAIT),
+            Err(mpsc::RecvTimeoutError::Disconnected)
+        ));
+    }
+}
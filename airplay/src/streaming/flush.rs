@@ -0,0 +1,139 @@
+//! Flushing of audio packets requested by the sender, e.g. on seek.
+//!
+//! Requests are picked by processors, which drop the flushed packets still buffered or being
+//! decrypted, so none of them is delivered after the stream has been told about the flush.
+
+use std::sync::Mutex;
+
+use tokio::sync::Notify;
+
+/// Sequence numbers flushed by the sender, `until_seq` is exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlushRange {
+    /// Everything before `until_seq` if `None`
+    pub from_seq: Option<u32>,
+    pub until_seq: u32,
+}
+
+/// The latest flush requested for a stream.
+#[derive(Default)]
+pub struct FlushSignal {
+    pending: Mutex<Option<FlushRange>>,
+    notify: Notify,
+}
+
+/// Drops packets of the last flushed range until the stream has passed it.
+#[derive(Debug)]
+pub struct FlushFilter {
+    /// Width of sequence numbers, which wrap around
+    bits: u32,
+    range: Option<FlushRange>,
+}
+
+impl FlushSignal {
+    /// Replaces the flush not picked yet.
+    pub fn request(&self, range: FlushRange) {
+        *self.pending.lock().unwrap() = Some(range);
+        self.notify.notify_one();
+    }
+
+    pub async fn next(&self) -> FlushRange {
+        loop {
+            if let Some(range) = self.pending.lock().unwrap().take() {
+                return range;
+            }
+            self.notify.notified().await;
+        }
+    }
+}
+
+impl FlushFilter {
+    pub fn new(bits: u32) -> Self {
+        Self { bits, range: None }
+    }
+
+    pub fn set(&mut self, range: FlushRange) {
+        self.range = Some(range);
+    }
+
+    /// Returns whether packet is flushed.
+    pub fn drops(&mut self, seq: u32) -> bool {
+        let Some(range) = self.range else {
+            return false;
+        };
+
+        // Distances ahead of half of the sequence space are considered behind
+        let ahead = |from: u32, to: u32| {
+            let distance = to.wrapping_sub(from) & ((1 << self.bits) - 1);
+            distance < 1 << (self.bits - 1)
+        };
+        let before_until = !ahead(range.until_seq, seq);
+        let after_from = range.from_seq.is_none_or(|from_seq| ahead(from_seq, seq));
+
+        if before_until && after_from {
+            true
+        } else {
+            // Later packets can't be flushed by the same range anymore
+            if !before_until {
+                self.range = None;
+            }
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::{FlushFilter, FlushRange, FlushSignal};
+
+    #[test]
+    fn drops_before_until_across_wrap_around() {
+        let mut filter = FlushFilter::new(16);
+        filter.set(FlushRange {
+            from_seq: None,
+            until_seq: 2,
+        });
+
+        assert!(filter.drops(65530));
+        assert!(filter.drops(1));
+        assert!(!filter.drops(2));
+        // Range is passed
+        assert!(!filter.drops(1));
+    }
+
+    #[test]
+    fn keeps_before_from() {
+        let mut filter = FlushFilter::new(24);
+        filter.set(FlushRange {
+            from_seq: Some(0xff_fff0),
+            until_seq: 0x10,
+        });
+
+        assert!(!filter.drops(0xff_ffe0));
+        assert!(filter.drops(0xff_fff0));
+        assert!(filter.drops(0x0f));
+        assert!(!filter.drops(0x10));
+    }
+
+    #[tokio::test]
+    async fn signal_keeps_latest() {
+        let signal = FlushSignal::default();
+        let range = |until_seq| FlushRange {
+            from_seq: None,
+            until_seq,
+        };
+
+        signal.request(range(1));
+        signal.request(range(2));
+        assert_eq!(range(2), signal.next().await);
+        assert!(
+            timeout(Duration::from_millis(10), signal.next())
+                .await
+                .is_err()
+        );
+    }
+}
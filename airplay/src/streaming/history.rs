@@ -0,0 +1,110 @@
+//! Last packets of a stream as read off the network, for a look at what preceded its failure.
+//!
+//! Packets are only kept with the `diagnostics` feature and
+//! [`Config::packet_history`](crate::config::Config) above zero, otherwise recording one does
+//! nothing.
+
+#[cfg(feature = "diagnostics")]
+use std::{collections::VecDeque, fmt::Write as _, sync::Mutex};
+
+#[cfg(feature = "diagnostics")]
+use bytes::Bytes;
+
+use super::StreamError;
+
+/// Rolling window of raw packets, kept per stream.
+#[derive(Debug, Default)]
+pub struct PacketHistory {
+    #[cfg(feature = "diagnostics")]
+    inner: Option<Mutex<Window>>,
+}
+
+#[cfg(feature = "diagnostics")]
+#[derive(Debug)]
+struct Window {
+    capacity: usize,
+    packets: VecDeque<Bytes>,
+}
+
+impl PacketHistory {
+    /// Leading bytes of each packet logged when the stream fails
+    #[cfg(feature = "diagnostics")]
+    const LOGGED_LEN: usize = 64;
+
+    /// Zero `capacity` keeps none.
+    #[cfg(feature = "diagnostics")]
+    pub fn new(capacity: usize) -> Self {
+        let window = Window {
+            capacity,
+            packets: VecDeque::with_capacity(capacity),
+        };
+        Self {
+            inner: (capacity != 0).then(|| Mutex::new(window)),
+        }
+    }
+
+    /// Keeps a copy of the packet just read, in place of the oldest one once full.
+    #[inline]
+    #[cfg_attr(not(feature = "diagnostics"), allow(clippy::unused_self))]
+    pub fn record(&self, packet: &[u8]) {
+        #[cfg(feature = "diagnostics")]
+        if let Some(inner) = &self.inner {
+            let mut window = inner.lock().unwrap();
+            if window.packets.len() == window.capacity {
+                window.packets.pop_front();
+            }
+            window.packets.push_back(Bytes::copy_from_slice(packet));
+        }
+
+        let _ = packet;
+    }
+
+    /// Oldest first.
+    #[cfg(feature = "diagnostics")]
+    pub fn packets(&self) -> Vec<Bytes> {
+        self.inner.as_ref().map_or_else(Vec::new, |inner| {
+            inner.lock().unwrap().packets.iter().cloned().collect()
+        })
+    }
+
+    /// Logs the kept packets if the stream failed, a closed one has nothing to look into.
+    #[cfg_attr(not(feature = "diagnostics"), allow(clippy::unused_self))]
+    pub fn on_finish(&self, res: &Result<(), StreamError>) {
+        #[cfg(feature = "diagnostics")]
+        if let (Err(err), Some(inner)) = (res, &self.inner) {
+            let window = inner.lock().unwrap();
+            tracing::warn!(%err, count = %window.packets.len(), "packets read before stream failed");
+            for (index, packet) in window.packets.iter().enumerate() {
+                let head = &packet[..packet.len().min(Self::LOGGED_LEN)];
+                let hex = head.iter().fold(String::new(), |mut hex, byte| {
+                    let _ = write!(hex, "{byte:02x}");
+                    hex
+                });
+                tracing::warn!(%index, len = %packet.len(), %hex, "packet before failure");
+            }
+        }
+
+        let _ = res;
+    }
+}
+
+#[cfg(all(test, feature = "diagnostics"))]
+mod tests {
+    use super::PacketHistory;
+
+    #[test]
+    fn oldest_packets_dropped() {
+        let history = PacketHistory::new(2);
+        for packet in [&b"first"[..], b"second", b"third"] {
+            history.record(packet);
+        }
+        assert_eq!(vec![&b"second"[..], b"third"], history.packets());
+    }
+
+    #[test]
+    fn none_kept_without_capacity() {
+        let history = PacketHistory::new(0);
+        history.record(b"packet");
+        assert!(history.packets().is_empty());
+    }
+}
@@ -1,14 +1,68 @@
-use std::{io, net::SocketAddr, sync::Arc};
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex, OnceLock, Weak,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
 
-use tokio::net::{TcpListener, ToSocketAddrs, UdpSocket};
+use clock::Clock;
+use control::{ControlPacket, RtcpPacket, RtcpPackets};
+use flush::FlushSignal;
+use playout::LatencySignal;
+use record::RecordSignal;
+use thiserror::Error;
+use tokio::net::{TcpListener, UdpSocket};
+use weak_table::WeakValueHashMap;
 
 use crate::{
-    crypto::streaming::{AudioBufferedCipher, AudioRealtimeCipher, VideoCipher},
-    playback::{ChannelHandle, audio::AudioStream, video::VideoStream},
-    util::sync::WakerFlag,
+    config::BufferConfig,
+    crypto::streaming::{AeadCipher, StreamCipher},
+    playback::{
+        ChannelHandle,
+        audio::AudioPacket,
+        event::EventSink,
+        stats::{StreamDescriptor, StreamStats, StreamStatus},
+        video::VideoStream,
+    },
+    util::{
+        sync::{WakerFlag, until_idle},
+        task::TaskSet,
+    },
 };
 
+mod clock;
+mod control;
+mod delivery;
+mod flush;
+mod history;
+pub(crate) mod offload;
+mod playout;
 mod processing;
+mod ptp;
+mod record;
+mod reorder;
+mod shaper;
+mod timing;
+
+pub use clock::TokioClock;
+pub use control::ControlChannel;
+pub use delivery::QueuedAudioStream;
+pub use flush::FlushRange;
+pub use history::PacketHistory;
+pub use offload::{Offload, Priority, PriorityPool};
+pub use playout::PlayoutScheduler;
+pub use processing::ProcessorCtx;
+pub use ptp::{PtpClock, PtpLock, PtpTimingClient};
+pub use record::RecordStart;
+pub use reorder::ReorderBuffer;
+pub use shaper::IngestShaper;
+pub use timing::{
+    NtpTimestamp, NtpTimingClient, SenderClock, SenderTimeline, TimingMode, TimingMonitor,
+    TimingState, TimingStatus,
+};
 
 pub struct EventChannel {
     local_addr: SocketAddr,
@@ -29,22 +83,226 @@ pub struct VideoChannel {
     pub local_addr: SocketAddr,
 }
 
-#[derive(Default)]
+/// What every channel is created with: the state shared with the stream's handle, how its
+/// packets are buffered and decrypted, and the stream they're passed to.
+pub struct ChannelCtx<'a, C, S> {
+    pub shared_data: Arc<SharedData>,
+    pub buffers: BufferConfig,
+    pub offload: Offload,
+    pub idle_timeout: Duration,
+    pub cipher: C,
+    pub stream: S,
+    pub tasks: &'a TaskSet,
+}
+
+/// How realtime audio is received and played, on top of [`ChannelCtx`].
+pub struct AudioRealtimeParams<K> {
+    /// Retransmits and time sync of the stream
+    pub control: ControlChannel,
+    pub payload_type: u32,
+    pub reorder: ReorderBuffer<AudioPacket>,
+    /// Maps time sync packets to local time, `None` without NTP timing
+    pub clock: Option<SenderClock>,
+    /// Playout and latency changes are timed with it
+    pub local_clock: K,
+    pub timing: TimingMonitor,
+    pub playout: Option<PlayoutScheduler<AudioPacket>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamKind {
+    AudioRealtime,
+    AudioBuffered,
+    Video,
+}
+
+/// Reason a stream processor has stopped, passed to [`Stream::on_err`](crate::playback::Stream::on_err)
+/// unless the stream was just closed.
+#[derive(Debug, Error)]
+pub enum StreamError {
+    #[error("stream i/o failed: {0}")]
+    Io(io::Error),
+    /// Sender broke the framing, so the rest of the stream can't be read
+    #[error("malformed {0}")]
+    Malformed(&'static str),
+    /// Packets failing to decrypt one after another mean the key is wrong rather than packets
+    /// damaged, see [`Audio::max_decrypt_failures`](crate::config::Audio::max_decrypt_failures)
+    #[error("{0} packets in a row failed to decrypt")]
+    DecryptFailed(u32),
+    #[error("frame at {timestamp} grew to {len} bytes while reassembled")]
+    Reassembly { timestamp: u64, len: usize },
+    /// Connection ended by the sender, even in the middle of a packet
+    #[error("stream closed by sender")]
+    Closed,
+}
+
 pub struct SharedData {
+    pub kind: StreamKind,
     pub waker_flag: WakerFlag,
+    pub stats: StreamStats,
+    pub flush: FlushSignal,
+    pub record: RecordSignal,
+    pub latency: LatencySignal,
+    pub shaper: IngestShaper,
+    pub history: PacketHistory,
+    /// Count of `/feedback` requests of the sender since the stream was set up
+    pub keepalives: AtomicU64,
+    /// Set once the processor has stopped and its sockets are released
+    pub finished: WakerFlag,
+    /// Set once the stream is set up, before it's registered
+    pub descriptor: OnceLock<StreamDescriptor>,
+    /// Local socket interleaved packets of the stream are relayed from, set on the first one
+    pub relayed_from: OnceLock<SocketAddr>,
+}
+
+/// Running streams keyed by ID handed out in setup response.
+///
+/// Holds weak handles only, so finished streams are gone on their own.
+#[derive(Default)]
+pub struct StreamRegistry {
+    streams: Mutex<WeakValueHashMap<u64, Weak<SharedData>>>,
+}
+
+impl SharedData {
+    pub fn new(kind: StreamKind) -> Self {
+        Self {
+            kind,
+            waker_flag: WakerFlag::default(),
+            stats: StreamStats::default(),
+            flush: FlushSignal::default(),
+            record: RecordSignal::default(),
+            latency: LatencySignal::default(),
+            shaper: IngestShaper::default(),
+            history: PacketHistory::default(),
+            keepalives: AtomicU64::default(),
+            finished: WakerFlag::default(),
+            descriptor: OnceLock::new(),
+            relayed_from: OnceLock::new(),
+        }
+    }
+
+    /// Changes on packets and on keepalives, the latter keep paused streams from idling.
+    pub fn activity(&self) -> u64 {
+        self.stats.packets() + self.keepalives.load(Ordering::Relaxed)
+    }
+}
+
+impl StreamRegistry {
+    pub fn insert(&self, id: u64, shared_data: Arc<SharedData>) {
+        self.streams.lock().unwrap().insert(id, shared_data);
+    }
+
+    /// Returns whether the stream was running.
+    pub fn close(&self, id: u64) -> bool {
+        let stream = self.streams.lock().unwrap().remove(&id);
+        stream.inspect(|stream| stream.close()).is_some()
+    }
+
+    /// Closes streams of `kind`, just the one of `id` if given, and returns the closed ones.
+    pub fn close_matching(&self, kind: StreamKind, id: Option<u64>) -> Vec<Arc<SharedData>> {
+        let mut closed = Vec::new();
+        self.streams.lock().unwrap().retain(|&stream_id, stream| {
+            if stream.kind == kind && id.is_none_or(|id| id == stream_id) {
+                stream.close();
+                closed.push(stream);
+                false
+            } else {
+                true
+            }
+        });
+        closed
+    }
+
+    pub fn flush_kind(&self, kind: StreamKind, range: FlushRange) {
+        self.streams
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|stream| stream.kind == kind)
+            .for_each(|stream| stream.flush.request(range));
+    }
+
+    pub fn record_kind(&self, kind: StreamKind, start: RecordStart) {
+        self.streams
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|stream| stream.kind == kind)
+            .for_each(|stream| stream.record.request(start));
+    }
+
+    pub fn set_latency_kind(&self, kind: StreamKind, latency: Duration) {
+        self.streams
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|stream| stream.kind == kind)
+            .for_each(|stream| stream.latency.request(latency));
+    }
+
+    pub fn keepalive(&self) {
+        self.streams
+            .lock()
+            .unwrap()
+            .values()
+            .for_each(|stream| _ = stream.keepalives.fetch_add(1, Ordering::Relaxed));
+    }
+
+    pub fn stats(&self) -> Vec<StreamStatus> {
+        self.streams
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, stream)| StreamStatus {
+                id,
+                kind: stream.kind,
+                stats: stream.stats.snapshot(),
+            })
+            .collect()
+    }
+
+    /// Raw packets last read by the stream of `id`, oldest first.
+    #[cfg(feature = "diagnostics")]
+    pub fn recent_packets(&self, id: u64) -> Option<Vec<bytes::Bytes>> {
+        let stream = self.streams.lock().unwrap().get(&id)?;
+        Some(stream.history.packets())
+    }
+
+    /// What was negotiated for the stream of `id`, `None` once it's finished.
+    pub fn get(&self, id: u64) -> Option<Arc<SharedData>> {
+        self.streams.lock().unwrap().get(&id)
+    }
+
+    pub fn descriptor(&self, id: u64) -> Option<StreamDescriptor> {
+        let stream = self.streams.lock().unwrap().get(&id)?;
+        stream.descriptor.get().copied()
+    }
+
+    pub fn close_all(&self) {
+        self.streams
+            .lock()
+            .unwrap()
+            .drain()
+            .for_each(|(_, stream)| stream.close());
+    }
 }
 
 impl EventChannel {
-    pub async fn create(bind_addr: impl ToSocketAddrs) -> io::Result<Self> {
-        let listener = TcpListener::bind(bind_addr).await?;
+    pub fn create(
+        listener: TcpListener,
+        buffers: BufferConfig,
+        sink: impl EventSink,
+        tasks: &TaskSet,
+    ) -> io::Result<Self> {
         let local_addr = listener.local_addr()?;
         let waker_flag = Arc::new(WakerFlag::default());
 
-        let wf = Arc::clone(&waker_flag);
-        tokio::spawn(async move {
+        let (wf, cancelled) = (Arc::clone(&waker_flag), tasks.cancelled());
+        tasks.spawn(async move {
             tokio::select! {
                 () = &*wf => {}
-                () = processing::event_processor(listener) => {}
+                () = cancelled => {}
+                () = processing::event_processor(listener, buffers, &sink) => {}
             };
             tracing::info!("event listener done");
         });
@@ -61,41 +319,89 @@ impl EventChannel {
 }
 
 impl AudioRealtimeChannel {
-    pub async fn create(
-        data_bind_addr: impl ToSocketAddrs,
-        control_bind_addr: impl ToSocketAddrs,
-        audio_buf_size: u32,
-        shared_data: Arc<SharedData>,
-        cipher: AudioRealtimeCipher,
-        stream: impl AudioStream,
+    pub fn create(
+        data_socket: UdpSocket,
+        params: AudioRealtimeParams<impl Clock>,
+        ctx: ChannelCtx<'_, impl StreamCipher, QueuedAudioStream>,
     ) -> io::Result<Self> {
-        let data_socket = UdpSocket::bind(data_bind_addr).await?;
-        let control_socket = UdpSocket::bind(control_bind_addr).await?;
+        let AudioRealtimeParams {
+            control: control_channel,
+            payload_type,
+            reorder,
+            clock,
+            local_clock,
+            timing,
+            playout,
+        } = params;
+        let ChannelCtx {
+            shared_data,
+            buffers,
+            offload,
+            idle_timeout,
+            cipher,
+            stream,
+            tasks,
+        } = ctx;
 
         let local_data_addr = data_socket.local_addr()?;
-        let local_control_addr = control_socket.local_addr()?;
+        let local_control_addr = control_channel.local_addr()?;
 
-        tokio::spawn(async move {
+        let cancelled = tasks.cancelled();
+        tasks.spawn(async move {
+            let control_packets = AtomicU64::new(0);
+            // Anchored by time sync packets and sender reports, which come on the same task
+            let playout = playout.map(Mutex::new);
             let task = async {
-                let data = processing::audio_realtime_processor(
-                    data_socket,
-                    audio_buf_size,
+                let ctx = ProcessorCtx {
+                    transport: data_socket,
+                    buffers,
+                    offload,
+                    shared: &shared_data,
                     cipher,
-                    &stream,
+                    stream: &stream,
+                };
+                let data = processing::audio_realtime_processor(
+                    ctx,
+                    &control_channel,
+                    payload_type,
+                    reorder,
+                    playout.as_ref(),
+                    Some(&timing),
+                    &local_clock,
+                );
+                let control =
+                    processing::control_processor(&control_channel, buffers, |_, packet| {
+                        control_packets.fetch_add(1, Ordering::Relaxed);
+                        on_control(packet, clock.as_ref(), playout.as_ref());
+                    });
+                let renegotiate = processing::latency_processor(
+                    &shared_data.latency,
+                    playout.as_ref(),
+                    &local_clock,
                 );
-                let control = processing::control_processor(control_socket);
 
-                let (first, second) = tokio::join!(data, control);
-                first.or(second)
+                tokio::select! {
+                    (first, second) = async { tokio::join!(data, control) } => first.or(second),
+                    never = renegotiate => match never {},
+                }
             };
 
-            tokio::select! {
-                () = &shared_data.waker_flag => {},
-                res = task => match remap_io_error_if_needed(res) {
-                    Ok(()) => stream.on_ok(),
-                    Err(err) => stream.on_err(err.into()),
+            // Closed stream is finished as well as the one ended by the sender
+            let res = tokio::select! {
+                () = &shared_data.waker_flag => Ok(()),
+                () = cancelled => Ok(()),
+                () = until_idle(idle_timeout, || {
+                    control_packets.load(Ordering::Relaxed)
+                        + shared_data.keepalives.load(Ordering::Relaxed)
+                }) => {
+                    Err(timed_out(shared_data.kind))
                 }
-            }
+                res = task => ok_if_closed(res),
+            };
+            drop(control_channel);
+            shared_data.history.on_finish(&res);
+            shared_data.finished.set_and_wake();
+            stream.finish(res);
         });
 
         Ok(Self {
@@ -106,76 +412,128 @@ impl AudioRealtimeChannel {
 }
 
 impl AudioBufferedChannel {
-    pub async fn create(
-        bind_addr: impl ToSocketAddrs,
-        audio_buf_size: u32,
-        shared_data: Arc<SharedData>,
-        cipher: AudioBufferedCipher,
-        stream: impl AudioStream,
+    pub fn create(
+        listener: TcpListener,
+        max_decrypt_failures: u32,
+        timing: TimingMonitor,
+        ctx: ChannelCtx<'_, impl AeadCipher, QueuedAudioStream>,
     ) -> io::Result<Self> {
-        let listener = TcpListener::bind(bind_addr).await?;
+        let ChannelCtx {
+            shared_data,
+            buffers,
+            offload,
+            idle_timeout,
+            cipher,
+            stream,
+            tasks,
+        } = ctx;
         let local_addr = listener.local_addr()?;
 
-        tokio::spawn(async move {
+        let cancelled = tasks.cancelled();
+        tasks.spawn(async move {
             let task = async {
                 match listener.accept().await {
                     Ok((tcp_stream, _)) => {
-                        processing::audio_buffered_processor(
-                            audio_buf_size,
-                            tcp_stream,
+                        let ctx = ProcessorCtx {
+                            transport: tcp_stream,
+                            buffers,
+                            offload,
+                            shared: &shared_data,
                             cipher,
-                            &stream,
+                            stream: &stream,
+                        };
+                        processing::audio_buffered_processor(
+                            ctx,
+                            max_decrypt_failures,
+                            Some(&timing),
                         )
                         .await
                     }
-                    Err(err) => Err(err),
+                    Err(err) => Err(err.into()),
                 }
             };
 
-            tokio::select! {
-                () = &shared_data.waker_flag => {},
-                res = task => match remap_io_error_if_needed(res) {
-                    Ok(()) => stream.on_ok(),
-                    Err(err) => stream.on_err(err.into()),
+            // Closed stream is finished as well as the one ended by the sender
+            let res = tokio::select! {
+                () = &shared_data.waker_flag => Ok(()),
+                () = cancelled => Ok(()),
+                () = until_idle(idle_timeout, || shared_data.activity()) => {
+                    Err(timed_out(shared_data.kind))
                 }
-            }
+                res = task => ok_if_closed(res),
+            };
+            // Listener and connection went along with the task
+            shared_data.history.on_finish(&res);
+            shared_data.finished.set_and_wake();
+            stream.finish(res);
         });
 
         Ok(Self {
             local_addr,
-            audio_buf_size,
+            audio_buf_size: buffers.audio,
         })
     }
 }
 
 impl VideoChannel {
-    pub async fn create(
-        bind_addr: impl ToSocketAddrs,
-        video_buf_size: u32,
-        shared_data: Arc<SharedData>,
-        cipher: VideoCipher,
-        stream: impl VideoStream,
+    pub fn create(
+        listener: TcpListener,
+        decrypted_other_kinds: Vec<u16>,
+        reassemble_frames: bool,
+        timeline: Option<SenderTimeline>,
+        ctx: ChannelCtx<'_, impl StreamCipher, impl VideoStream>,
     ) -> io::Result<Self> {
-        let listener = TcpListener::bind(bind_addr).await?;
+        let ChannelCtx {
+            shared_data,
+            buffers,
+            offload,
+            idle_timeout,
+            cipher,
+            stream,
+            tasks,
+        } = ctx;
         let local_addr = listener.local_addr()?;
 
-        tokio::spawn(async move {
+        let cancelled = tasks.cancelled();
+        tasks.spawn(async move {
             let task = async {
                 match listener.accept().await {
                     Ok((tcp_stream, _)) => {
-                        processing::video_processor(video_buf_size, tcp_stream, cipher, &stream)
-                            .await
+                        let ctx = ProcessorCtx {
+                            transport: tcp_stream,
+                            buffers,
+                            offload,
+                            shared: &shared_data,
+                            cipher,
+                            stream: &stream,
+                        };
+                        processing::video_processor(
+                            ctx,
+                            &decrypted_other_kinds,
+                            reassemble_frames,
+                            timeline.as_ref(),
+                        )
+                        .await
                     }
-                    Err(err) => Err(err),
+                    Err(err) => Err(err.into()),
                 }
             };
 
-            tokio::select! {
-                () = &shared_data.waker_flag => {},
-                res = task => match remap_io_error_if_needed(res) {
-                    Ok(()) => stream.on_ok(),
-                    Err(err) => stream.on_err(err.into()),
+            // Closed stream is finished as well as the one ended by the sender
+            let res = tokio::select! {
+                () = &shared_data.waker_flag => Ok(()),
+                () = cancelled => Ok(()),
+                () = until_idle(idle_timeout, || shared_data.activity()) => {
+                    Err(timed_out(shared_data.kind))
                 }
+                res = task => ok_if_closed(res),
+            };
+            // Listener and connection went along with the task
+            shared_data.history.on_finish(&res);
+            shared_data.finished.set_and_wake();
+            match res {
+                Ok(()) => stream.on_ok(),
+                Err(err) => stream.on_err(err.into()),
             }
         });
 
@@ -195,19 +553,142 @@ impl ChannelHandle for SharedData {
     }
 }
 
-fn remap_io_error_if_needed(res: io::Result<()>) -> io::Result<()> {
-    match res {
-        Ok(()) => Ok(()),
-        Err(err)
-            if matches!(
-                err.kind(),
-                io::ErrorKind::UnexpectedEof
-                    | io::ErrorKind::ConnectionAborted
-                    | io::ErrorKind::ConnectionReset
-            ) =>
-        {
-            Ok(())
+impl From<io::Error> for StreamError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset => Self::Closed,
+            _ => Self::Io(err),
+        }
+    }
+}
+
+fn on_control(
+    packet: ControlPacket<'_>,
+    clock: Option<&SenderClock>,
+    playout: Option<&Mutex<PlayoutScheduler<AudioPacket>>>,
+) {
+    match packet {
+        ControlPacket::TimeSync {
+            rtp_timestamp,
+            ntp_timestamp,
+            next_rtp_timestamp,
+        } => {
+            // Local time `rtp_timestamp` is to be played at
+            let local_time =
+                clock.map(|clock| clock.estimate().to_local(NtpTimestamp(ntp_timestamp)));
+            if let (Some(playout), Some(local_time)) = (playout, local_time) {
+                playout.lock().unwrap().anchor(rtp_timestamp, local_time);
+            }
+            tracing::trace!(
+                %rtp_timestamp,
+                %ntp_timestamp,
+                local_time = ?local_time.map(|time| time.0),
+                %next_rtp_timestamp,
+                "time sync"
+            );
         }
-        Err(err) => Err(err),
+        ControlPacket::RetransmitReply { rtp } => {
+            tracing::trace!(len = %rtp.len(), "retransmitted packet");
+        }
+        ControlPacket::Rtcp(packets) => on_rtcp(packets, clock, playout),
+        ControlPacket::Unknown(payload_type) => {
+            tracing::debug!(%payload_type, "unknown control packet");
+        }
+    }
+}
+
+fn on_rtcp(
+    packets: RtcpPackets<'_>,
+    clock: Option<&SenderClock>,
+    playout: Option<&Mutex<PlayoutScheduler<AudioPacket>>>,
+) {
+    for packet in packets {
+        match packet {
+            Ok(RtcpPacket::SenderReport(report)) => {
+                // Local time `rtp_timestamp` was sampled at
+                let sampled_at = clock.map(|clock| {
+                    clock
+                        .estimate()
+                        .to_local(NtpTimestamp(report.ntp_timestamp))
+                });
+                if let (Some(playout), Some(sampled_at)) = (playout, sampled_at) {
+                    playout
+                        .lock()
+                        .unwrap()
+                        .anchor_sampled(report.rtp_timestamp, sampled_at);
+                }
+                tracing::trace!(?report, "sender report");
+            }
+            Ok(RtcpPacket::Other(payload_type)) => {
+                tracing::trace!(%payload_type, "rtcp packet");
+            }
+            Err(err) => tracing::debug!(%err, "malformed rtcp packet"),
+        }
+    }
+}
+
+fn timed_out(kind: StreamKind) -> StreamError {
+    tracing::info!(?kind, "stream timed out");
+    StreamError::Io(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "no packets from sender",
+    ))
+}
+
+/// Stream ended by the sender is finished rather than failed.
+fn ok_if_closed(res: Result<(), StreamError>) -> Result<(), StreamError> {
+    match res {
+        Err(StreamError::Closed) => Ok(()),
+        res => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures::FutureExt;
+
+    use super::{SharedData, StreamKind, StreamRegistry};
+
+    fn closed(stream: &SharedData) -> bool {
+        (&stream.waker_flag).now_or_never().is_some()
+    }
+
+    #[test]
+    fn registry_closes_by_id_and_kind() {
+        let registry = StreamRegistry::default();
+        let audio = Arc::new(SharedData::new(StreamKind::AudioBuffered));
+        let video = Arc::new(SharedData::new(StreamKind::Video));
+        let other_video = Arc::new(SharedData::new(StreamKind::Video));
+        registry.insert(1, Arc::clone(&audio));
+        registry.insert(2, Arc::clone(&video));
+        registry.insert(3, Arc::clone(&other_video));
+
+        video.stats.on_packet(100);
+        let mut stats = registry.stats();
+        stats.sort_by_key(|status| status.id);
+        assert_eq!(
+            vec![1, 2, 3],
+            stats.iter().map(|s| s.id).collect::<Vec<_>>()
+        );
+        assert_eq!(StreamKind::Video, stats[1].kind);
+        assert_eq!((1, 100), (stats[1].stats.packets, stats[1].stats.bytes));
+
+        assert!(registry.close(2));
+        assert!(!registry.close(2));
+        assert!(closed(&video) && !closed(&audio) && !closed(&other_video));
+
+        // ID of another kind is left running
+        let audio_as_video = registry.close_matching(StreamKind::Video, Some(1));
+        assert!(audio_as_video.is_empty() && !closed(&audio));
+        let closed_video = registry.close_matching(StreamKind::Video, None);
+        assert_eq!(1, closed_video.len());
+        assert!(closed(&other_video) && !closed(&audio));
+
+        registry.close_all();
+        assert!(closed(&audio));
     }
 }
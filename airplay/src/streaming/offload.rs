@@ -0,0 +1,437 @@
+//! Decryption of packets off the network read loop.
+//!
+//! Packets are decrypted on long lived threads and delivered to the stream in the same order
+//! they have been read, so a slow packet only delays the ones read after it. Spawning a task
+//! on the blocking pool of tokio per packet cost more than decrypting it, see
+//! `benches/offload.rs`, threads only wake up for packets queued while they sleep.
+//!
+//! Each stream has its own threads by default, so video of a mirroring session can hold audio
+//! back on a busy CPU. [`PriorityPool`] of the receiver is shared by its streams and takes jobs
+//! of audio ahead of the ones of video instead.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    future::Future,
+    io,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+};
+
+use tokio::sync::{mpsc, oneshot};
+
+use super::StreamError;
+
+/// Jobs of higher priority are taken first, the order of equal ones is kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Video,
+    Audio,
+}
+
+/// Where packets of a stream are decrypted.
+#[derive(Debug, Clone)]
+pub struct Offload {
+    /// Packets decrypted concurrently, zero decrypts them on the read loop
+    workers: usize,
+    /// Threads of the stream alone without it
+    pool: Option<(Arc<PriorityPool>, Priority)>,
+}
+
+/// Threads decrypting jobs by their priority, stopped once dropped and the queued jobs are
+/// done.
+pub struct PriorityPool {
+    shared: Arc<PoolShared>,
+}
+
+type PoolJob = Box<dyn FnOnce() + Send>;
+
+struct PoolShared {
+    queues: Mutex<Queues>,
+    queued: Condvar,
+}
+
+#[derive(Default)]
+struct Queues {
+    /// Lowest priority first
+    by_priority: [VecDeque<PoolJob>; 2],
+    /// Threads waiting for a job, busy ones take the next one without being woken up
+    idle: usize,
+    stopped: bool,
+}
+
+/// Sending half of the queue, owned by the read loop.
+pub struct DecryptQueue<T> {
+    workers: usize,
+    pool: Option<(Arc<PriorityPool>, Priority)>,
+    tx: mpsc::Sender<Job<T>>,
+}
+
+/// Receiving half of the queue, yields packets in order they have been pushed.
+pub struct DecryptedPackets<T> {
+    rx: mpsc::Receiver<Job<T>>,
+}
+
+enum Job<T> {
+    Inline(T),
+    Pooled(oneshot::Receiver<T>),
+}
+
+impl Offload {
+    /// Threads of the stream alone, spawned along with its queue.
+    #[must_use]
+    pub const fn dedicated(workers: usize) -> Self {
+        Self {
+            workers,
+            pool: None,
+        }
+    }
+
+    #[must_use]
+    pub fn pooled(workers: usize, pool: &Arc<PriorityPool>, priority: Priority) -> Self {
+        Self {
+            workers,
+            pool: Some((Arc::clone(pool), priority)),
+        }
+    }
+}
+
+impl PriorityPool {
+    /// Spawns at least one thread.
+    ///
+    /// # Errors
+    ///
+    /// If any thread isn't spawned, the ones that are stop right away.
+    pub fn new(threads: usize) -> io::Result<Self> {
+        Self::spawning(threads, spawn_thread)
+    }
+
+    fn spawning(
+        threads: usize,
+        mut spawn: impl FnMut(String, PoolJob) -> io::Result<()>,
+    ) -> io::Result<Self> {
+        let pool = Self {
+            shared: Arc::new(PoolShared {
+                queues: Mutex::default(),
+                queued: Condvar::new(),
+            }),
+        };
+        for i in 0..threads.max(1) {
+            let shared = Arc::clone(&pool.shared);
+            spawn(
+                format!("rairplay-decrypt-{i}"),
+                Box::new(move || shared.work()),
+            )?;
+        }
+        Ok(pool)
+    }
+
+    fn spawn<T: Send + 'static>(
+        &self,
+        priority: Priority,
+        job: impl FnOnce() -> T + Send + 'static,
+    ) -> oneshot::Receiver<T> {
+        let (tx, rx) = oneshot::channel();
+        let job = Box::new(move || {
+            // Receiver gone along with its stream
+            let _ = tx.send(job());
+        });
+        let mut queues = self.shared.queues.lock().unwrap();
+        queues.by_priority[priority as usize].push_back(job);
+        let idle = queues.idle > 0;
+        drop(queues);
+        if idle {
+            self.shared.queued.notify_one();
+        }
+        rx
+    }
+}
+
+impl PoolShared {
+    fn work(&self) {
+        loop {
+            let job = {
+                let mut queues = self.queues.lock().unwrap();
+                loop {
+                    let by_priority = &mut queues.by_priority;
+                    if let Some(job) = by_priority.iter_mut().rev().find_map(VecDeque::pop_front) {
+                        break job;
+                    }
+                    if queues.stopped {
+                        return;
+                    }
+                    queues.idle += 1;
+                    queues = self.queued.wait(queues).unwrap();
+                    queues.idle -= 1;
+                }
+            };
+            // Result is dropped along with the job, which its queue reports as failed
+            if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                tracing::error!("decryption job panicked");
+            }
+        }
+    }
+}
+
+impl fmt::Debug for PriorityPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PriorityPool").finish_non_exhaustive()
+    }
+}
+
+impl Drop for PriorityPool {
+    fn drop(&mut self) {
+        self.shared.queues.lock().unwrap().stopped = true;
+        self.shared.queued.notify_all();
+    }
+}
+
+fn spawn_thread(name: String, work: PoolJob) -> io::Result<()> {
+    thread::Builder::new().name(name).spawn(work).map(drop)
+}
+
+impl<T: Send + 'static> DecryptQueue<T> {
+    /// Up to [`Offload::workers`] packets are decrypted concurrently, on the read loop if the
+    /// stream's threads can't be spawned.
+    pub fn new(offload: Offload) -> (Self, DecryptedPackets<T>) {
+        Self::spawning(offload, spawn_thread)
+    }
+
+    fn spawning(
+        offload: Offload,
+        spawn: impl FnMut(String, PoolJob) -> io::Result<()>,
+    ) -> (Self, DecryptedPackets<T>) {
+        let Offload { workers, pool } = offload;
+        let pool = match pool {
+            None if workers > 0 => match PriorityPool::spawning(workers, spawn) {
+                Ok(pool) => Some((Arc::new(pool), Priority::Audio)),
+                Err(err) => {
+                    tracing::error!(%err, "decryption threads not spawned, decrypting inline");
+                    None
+                }
+            },
+            pool => pool,
+        };
+        let (tx, rx) = mpsc::channel(workers.max(1));
+        (Self { workers, pool, tx }, DecryptedPackets { rx })
+    }
+
+    /// Waits only if all workers are busy.
+    pub async fn push(&self, decrypt: impl FnOnce() -> T + Send + 'static) {
+        let job = match &self.pool {
+            Some((pool, priority)) if self.workers > 0 => {
+                Job::Pooled(pool.spawn(*priority, decrypt))
+            }
+            _ => Job::Inline(decrypt()),
+        };
+
+        // Receiver lives as long as the processor does
+        let _ = self.tx.send(job).await;
+    }
+}
+
+impl<T> DecryptedPackets<T> {
+    /// Returns `None` once the queue is dropped and all pushed packets are delivered.
+    pub async fn next(&mut self) -> Option<T> {
+        loop {
+            match self.rx.recv().await? {
+                Job::Inline(pkt) => return Some(pkt),
+                Job::Pooled(rx) => {
+                    if let Ok(pkt) = rx.await {
+                        return Some(pkt);
+                    }
+                    tracing::error!("decryption job failed");
+                }
+            }
+        }
+    }
+}
+
+/// Runs `read` loop feeding the queue, while passing decrypted packets to `deliver`.
+///
+/// Packets pushed before `read` has failed are still delivered, failed delivery stops reading.
+///
+/// # Errors
+///
+/// The first error of `deliver`, else the one of `read`.
+pub async fn ordered<T, R>(
+    offload: Offload,
+    read: impl FnOnce(DecryptQueue<T>) -> R,
+    mut deliver: impl FnMut(T) -> Result<(), StreamError>,
+) -> Result<(), StreamError>
+where
+    T: Send + 'static,
+    R: Future<Output = Result<(), StreamError>>,
+{
+    let (queue, mut packets) = DecryptQueue::new(offload);
+
+    let delivery = async {
+        while let Some(pkt) = packets.next().await {
+            deliver(pkt)?;
+        }
+        Ok::<_, StreamError>(())
+    };
+    let read = read(queue);
+    tokio::pin!(read, delivery);
+
+    tokio::select! {
+        res = &mut delivery => {
+            res?;
+            read.await
+        }
+        res = &mut read => {
+            delivery.await?;
+            res
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex, mpsc},
+        time::Duration,
+    };
+
+    use super::{DecryptQueue, Offload, Priority, PriorityPool, ordered};
+    use crate::streaming::StreamError;
+
+    async fn run(offload: Offload) -> Vec<u32> {
+        let mut delivered = Vec::new();
+        let res = ordered(
+            offload,
+            |queue| async move {
+                for i in 0..16u32 {
+                    queue
+                        .push(move || {
+                            // Earlier packets finish later
+                            std::thread::sleep(Duration::from_millis(u64::from(16 - i)));
+                            i
+                        })
+                        .await;
+                }
+                Err(StreamError::Closed)
+            },
+            |pkt| {
+                delivered.push(pkt);
+                Ok(())
+            },
+        )
+        .await;
+
+        assert!(res.is_err());
+        delivered
+    }
+
+    #[tokio::test]
+    async fn keeps_order() {
+        let expected = (0..16).collect::<Vec<_>>();
+
+        assert_eq!(expected, run(Offload::dedicated(0)).await);
+        assert_eq!(expected, run(Offload::dedicated(1)).await);
+        assert_eq!(expected, run(Offload::dedicated(4)).await);
+        let pool = Arc::new(PriorityPool::new(4).unwrap());
+        assert_eq!(
+            expected,
+            run(Offload::pooled(4, &pool, Priority::Video)).await
+        );
+    }
+
+    #[tokio::test]
+    async fn audio_jobs_taken_ahead_of_video() {
+        let pool = PriorityPool::new(1).unwrap();
+        let done = Arc::new(Mutex::new(Vec::new()));
+        let job = |name: &'static str| {
+            let done = Arc::clone(&done);
+            move || done.lock().unwrap().push(name)
+        };
+
+        // Single thread is held until the rest is queued
+        let (release, held) = mpsc::channel::<()>();
+        let first = pool.spawn(Priority::Video, move || held.recv().unwrap());
+        let mut pending = ["video 1", "video 2", "video 3"]
+            .map(|name| pool.spawn(Priority::Video, job(name)))
+            .into_iter()
+            .collect::<Vec<_>>();
+        pending.push(pool.spawn(Priority::Audio, job("audio 1")));
+        pending.push(pool.spawn(Priority::Audio, job("audio 2")));
+        release.send(()).unwrap();
+
+        first.await.unwrap();
+        for rx in pending {
+            rx.await.unwrap();
+        }
+        assert_eq!(
+            vec!["audio 1", "audio 2", "video 1", "video 2", "video 3"],
+            *done.lock().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn audio_latency_bounded_under_video_load() {
+        const JOB: Duration = Duration::from_millis(2);
+
+        let pool = Arc::new(PriorityPool::new(2).unwrap());
+        let busy = || std::thread::sleep(JOB);
+        // Backlog of video far longer than the audio packet waits
+        let video = (0..200)
+            .map(|_| pool.spawn(Priority::Video, busy))
+            .collect::<Vec<_>>();
+
+        let start = std::time::Instant::now();
+        pool.spawn(Priority::Audio, busy).await.unwrap();
+        // Running jobs are finished first, the queued ones are jumped
+        assert!(start.elapsed() < JOB * 20, "{:?}", start.elapsed());
+        drop(video);
+    }
+
+    #[tokio::test]
+    async fn queued_jobs_done_once_dropped() {
+        let pool = PriorityPool::new(1).unwrap();
+        let (release, held) = mpsc::channel::<()>();
+        let first = pool.spawn(Priority::Video, move || held.recv().unwrap());
+        let queued = (0..4u32)
+            .map(|i| pool.spawn(Priority::Video, move || i))
+            .collect::<Vec<_>>();
+        drop(pool);
+        release.send(()).unwrap();
+
+        first.await.unwrap();
+        for (i, rx) in (0..).zip(queued) {
+            assert_eq!(i, rx.await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn decrypted_inline_without_threads() {
+        let no_threads = |_, _| Err(io::Error::other("out of threads"));
+        assert!(PriorityPool::spawning(2, no_threads).is_err());
+
+        let (queue, mut packets) = DecryptQueue::spawning(Offload::dedicated(2), no_threads);
+        assert!(queue.pool.is_none());
+        queue.push(|| 7).await;
+        assert_eq!(Some(7), packets.next().await);
+    }
+
+    #[tokio::test]
+    async fn failed_delivery_stops_reading() {
+        let res = ordered(
+            Offload::dedicated(2),
+            |queue| async move {
+                for i in 0u32.. {
+                    queue.push(move || i).await;
+                }
+                Ok(())
+            },
+            |pkt| match pkt {
+                3 => Err(StreamError::DecryptFailed(1)),
+                _ => Ok(()),
+            },
+        )
+        .await;
+
+        assert!(matches!(res, Err(StreamError::DecryptFailed(1))));
+    }
+}
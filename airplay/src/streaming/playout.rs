@@ -0,0 +1,285 @@
+//! Releasing realtime audio packets at the local time they're to be played at.
+//!
+//! RTP timestamps are mapped onto the local clock through the anchor taken from time sync
+//! packets, which already carries the latency the sender keeps. Until the first one arrives,
+//! the first packet is anchored at its arrival plus the negotiated latency. Packets whose
+//! playout time has already passed when they arrive are dropped.
+//!
+//! Latency the sender renegotiates mid-session is applied on top of every anchor. Playout times
+//! move towards it gradually, so the audio is neither cut nor played twice over while they do.
+
+use std::{collections::VecDeque, sync::Mutex, time::Duration};
+
+use tokio::sync::Notify;
+
+use super::timing::NtpTimestamp;
+
+const NANOS_PER_SEC: i128 = 1_000_000_000;
+/// Playout times move by at most this fraction of the time passed
+const MAX_SLEW_DIVISOR: i128 = 20;
+
+pub struct PlayoutScheduler<T> {
+    sample_rate: u32,
+    /// Negotiated in setup, anchors not taken from time sync are offset by it
+    latency: Duration,
+    /// Added to playout times, towards the renegotiated latency less the one of setup
+    shift_ns: i128,
+    target_shift_ns: i128,
+    /// Last time the shift moved
+    shifted_at: Option<NtpTimestamp>,
+    /// RTP timestamp and the local time it's played at
+    anchor: Option<(u32, NtpTimestamp)>,
+    /// Sorted by playout time, equal ones keep the order they were pushed in
+    queue: VecDeque<(NtpTimestamp, T)>,
+}
+
+/// The latest latency renegotiated for a stream.
+#[derive(Default)]
+pub struct LatencySignal {
+    pending: Mutex<Option<Duration>>,
+    notify: Notify,
+}
+
+impl LatencySignal {
+    /// Replaces the latency not picked yet.
+    pub fn request(&self, latency: Duration) {
+        *self.pending.lock().unwrap() = Some(latency);
+        self.notify.notify_one();
+    }
+
+    pub async fn next(&self) -> Duration {
+        loop {
+            if let Some(latency) = self.pending.lock().unwrap().take() {
+                return latency;
+            }
+            self.notify.notified().await;
+        }
+    }
+}
+
+impl<T> PlayoutScheduler<T> {
+    pub fn new(sample_rate: u32, latency: Duration) -> Self {
+        Self {
+            sample_rate: sample_rate.max(1),
+            latency,
+            shift_ns: 0,
+            target_shift_ns: 0,
+            shifted_at: None,
+            anchor: None,
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// Takes the mapping of a time sync packet, already held packets keep their playout time.
+    pub fn anchor(&mut self, rtp_timestamp: u32, local: NtpTimestamp) {
+        self.anchor = Some((rtp_timestamp, local));
+    }
+
+    /// Takes the mapping of an RTCP sender report, which is the time `rtp_timestamp` was sampled
+    /// at rather than played at.
+    pub fn anchor_sampled(&mut self, rtp_timestamp: u32, sampled_at: NtpTimestamp) {
+        let latency = i128::try_from(self.latency.as_nanos()).unwrap_or(i128::MAX);
+        self.anchor(
+            rtp_timestamp,
+            NtpTimestamp::from_nanos(sampled_at.as_nanos() + latency),
+        );
+    }
+
+    /// Anchors `rtp_timestamp` as if sampled at `now`, unless anchored already.
+    pub fn start_at(&mut self, rtp_timestamp: u32, now: NtpTimestamp) {
+        if self.anchor.is_none() {
+            self.anchor_sampled(rtp_timestamp, now);
+        }
+    }
+
+    /// Moves playout times of packets pushed from `now` on towards `latency`, held packets
+    /// keep theirs.
+    pub fn set_latency(&mut self, latency: Duration, now: NtpTimestamp) {
+        self.slew(now);
+        let nanos = |latency: Duration| i128::try_from(latency.as_nanos()).unwrap_or(i128::MAX);
+        self.target_shift_ns = nanos(latency) - nanos(self.latency);
+    }
+
+    /// Latency playout times are at or moving towards.
+    pub fn latency(&self) -> Duration {
+        let nanos = i128::try_from(self.latency.as_nanos()).unwrap_or(i128::MAX);
+        u64::try_from(nanos + self.target_shift_ns).map_or(Duration::ZERO, Duration::from_nanos)
+    }
+
+    /// Local time packet of `rtp_timestamp` is played at, `None` until anchored.
+    pub fn playout_time(&self, rtp_timestamp: u32) -> Option<NtpTimestamp> {
+        let (anchor_rtp, anchor_local) = self.anchor?;
+        // Half of the timestamp space behind the anchor is considered the past
+        #[allow(clippy::cast_possible_wrap)]
+        let samples = rtp_timestamp.wrapping_sub(anchor_rtp) as i32;
+        let offset = i128::from(samples) * NANOS_PER_SEC / i128::from(self.sample_rate);
+        Some(NtpTimestamp::from_nanos(
+            anchor_local.as_nanos() + offset + self.shift_ns,
+        ))
+    }
+
+    /// Returns `false` if packet is late, so it's dropped.
+    pub fn push(&mut self, rtp_timestamp: u32, pkt: T, now: NtpTimestamp) -> bool {
+        self.slew(now);
+        self.start_at(rtp_timestamp, now);
+        let Some(at) = self.playout_time(rtp_timestamp).filter(|at| *at >= now) else {
+            return false;
+        };
+
+        let pos = self.queue.partition_point(|(held_at, _)| *held_at <= at);
+        self.queue.insert(pos, (at, pkt));
+        true
+    }
+
+    /// Playout time of the earliest held packet.
+    pub fn next_deadline(&self) -> Option<NtpTimestamp> {
+        self.queue.front().map(|(at, _)| *at)
+    }
+
+    /// Releases packets whose playout time has come, along with the time.
+    pub fn release_due(&mut self, now: NtpTimestamp, mut release: impl FnMut(NtpTimestamp, T)) {
+        while self.next_deadline().is_some_and(|at| at <= now) {
+            if let Some((at, pkt)) = self.queue.pop_front() {
+                release(at, pkt);
+            }
+        }
+    }
+
+    /// Drops held packets not passing `keep`, e.g. flushed ones.
+    pub fn retain(&mut self, mut keep: impl FnMut(&T) -> bool) {
+        self.queue.retain(|(_, pkt)| keep(pkt));
+    }
+
+    /// Releases every held packet right away, e.g. once the stream has ended.
+    pub fn drain(&mut self, release: impl FnMut(T)) {
+        self.queue.drain(..).map(|(_, pkt)| pkt).for_each(release);
+    }
+
+    /// Moves the shift towards the target as much as the time passed since it last did allows.
+    fn slew(&mut self, now: NtpTimestamp) {
+        if let Some(shifted_at) = self.shifted_at {
+            let max_step = now.diff_ns(shifted_at).max(0) / MAX_SLEW_DIVISOR;
+            self.shift_ns += (self.target_shift_ns - self.shift_ns).clamp(-max_step, max_step);
+        }
+        self.shifted_at = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::PlayoutScheduler;
+    use crate::streaming::timing::NtpTimestamp;
+
+    fn at_ms(ms: i128) -> NtpTimestamp {
+        NtpTimestamp::from_nanos(ms * 1_000_000)
+    }
+
+    fn release(sched: &mut PlayoutScheduler<u32>, now_ms: i128) -> Vec<u32> {
+        let mut released = Vec::new();
+        sched.release_due(at_ms(now_ms), |_, pkt| released.push(pkt));
+        released
+    }
+
+    #[test]
+    fn first_packet_anchored_with_latency() {
+        let mut sched = PlayoutScheduler::new(44100, Duration::from_millis(250));
+
+        assert!(sched.push(1000, 1000, at_ms(10_000)));
+        // 4410 samples are 100 ms
+        assert!(sched.push(5410, 5410, at_ms(10_000)));
+        assert_eq!(Some(at_ms(10_250)), sched.next_deadline());
+
+        assert!(release(&mut sched, 10_249).is_empty());
+        assert_eq!(vec![1000], release(&mut sched, 10_250));
+        assert!(release(&mut sched, 10_349).is_empty());
+        assert_eq!(vec![5410], release(&mut sched, 10_350));
+        assert_eq!(None, sched.next_deadline());
+    }
+
+    #[test]
+    fn late_packets_dropped() {
+        let mut sched = PlayoutScheduler::new(44100, Duration::from_millis(250));
+        sched.anchor(u32::MAX - 4409, at_ms(10_000));
+
+        // Played 100 ms before now, timestamp wrapped around since the anchor
+        assert_eq!(Some(at_ms(10_100)), sched.playout_time(0));
+        assert!(!sched.push(0, 0, at_ms(10_200)));
+        assert!(sched.push(4410, 4410, at_ms(10_200)));
+        assert_eq!(vec![4410], release(&mut sched, 10_200));
+    }
+
+    #[test]
+    fn sender_report_anchor_adds_latency() {
+        let mut sched = PlayoutScheduler::<u32>::new(1000, Duration::from_millis(100));
+        sched.anchor_sampled(500, at_ms(1_000));
+        assert_eq!(Some(at_ms(1_100)), sched.playout_time(500));
+        assert_eq!(Some(at_ms(1_150)), sched.playout_time(550));
+    }
+
+    #[test]
+    fn record_start_anchored_unless_synced() {
+        let mut sched = PlayoutScheduler::<u32>::new(1000, Duration::from_millis(100));
+        sched.start_at(500, at_ms(1_000));
+        sched.start_at(0, at_ms(2_000));
+        assert_eq!(Some(at_ms(1_100)), sched.playout_time(500));
+
+        sched.anchor(0, at_ms(5_000));
+        sched.start_at(500, at_ms(6_000));
+        assert_eq!(Some(at_ms(5_500)), sched.playout_time(500));
+    }
+
+    #[test]
+    fn released_by_playout_time() {
+        let mut sched = PlayoutScheduler::new(1000, Duration::ZERO);
+        sched.anchor(0, at_ms(0));
+
+        for rtp in [30, 10, 20, 10] {
+            assert!(sched.push(rtp, rtp, at_ms(0)));
+        }
+        // New anchor moves packets pushed afterwards only
+        sched.anchor(0, at_ms(5));
+        assert!(sched.push(10, 11, at_ms(0)));
+
+        assert_eq!(vec![10, 10, 11, 20, 30], release(&mut sched, 30));
+    }
+
+    #[test]
+    fn renegotiated_latency_slewed() {
+        let mut sched = PlayoutScheduler::new(1000, Duration::from_millis(100));
+        sched.anchor(0, at_ms(1_000));
+        assert!(sched.push(0, 0, at_ms(0)));
+
+        sched.set_latency(Duration::from_millis(150), at_ms(0));
+        assert_eq!(Duration::from_millis(150), sched.latency());
+        // Moved by a twentieth of the time passed, so packets a second apart are played 1.05 s
+        // apart rather than with a gap
+        assert!(sched.push(1000, 1000, at_ms(1_000)));
+        assert_eq!(Some(at_ms(2_050)), sched.playout_time(1000));
+        assert!(sched.push(2000, 2000, at_ms(2_000)));
+        assert_eq!(Some(at_ms(3_050)), sched.playout_time(2000));
+        // Lowered back, later packets are played as much closer
+        sched.set_latency(Duration::from_millis(100), at_ms(2_000));
+        assert!(sched.push(3000, 3000, at_ms(3_000)));
+        assert_eq!(Some(at_ms(4_000)), sched.playout_time(3000));
+
+        assert_eq!(vec![0], release(&mut sched, 1_000));
+        assert_eq!(vec![1000, 2000, 3000], release(&mut sched, 4_000));
+    }
+
+    #[test]
+    fn flushed_packets_discarded() {
+        let mut sched = PlayoutScheduler::new(1000, Duration::ZERO);
+        sched.anchor(0, at_ms(0));
+        for rtp in 1..=4 {
+            sched.push(rtp, rtp, at_ms(0));
+        }
+
+        sched.retain(|&pkt| pkt % 2 == 0);
+        let mut drained = Vec::new();
+        sched.drain(|pkt| drained.push(pkt));
+        assert_eq!(vec![2, 4], drained);
+        assert_eq!(None, sched.next_deadline());
+    }
+}
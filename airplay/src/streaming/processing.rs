@@ -1,176 +1,1689 @@
-use std::io;
+use std::{
+    convert::Infallible,
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
+use bytes::BytesMut;
 use tokio::{
-    io::AsyncReadExt,
-    net::{TcpListener, TcpStream, UdpSocket},
+    io::{AsyncRead, AsyncReadExt},
+    net::{TcpListener, UdpSocket},
+    sync::Notify,
+    time,
 };
 use tracing::Instrument;
 
+use super::{
+    SharedData, StreamError,
+    clock::Clock,
+    control::{ControlChannel, ControlHeader, ControlPacket},
+    flush::FlushFilter,
+    offload::{self, Offload},
+    playout::{LatencySignal, PlayoutScheduler},
+    reorder::ReorderBuffer,
+    timing::{NtpTimestamp, SenderTimeline, TimingMonitor},
+};
 use crate::{
-    crypto::streaming::{AudioBufferedCipher, AudioRealtimeCipher, VideoCipher},
+    config::BufferConfig,
+    crypto::streaming::{AeadCipher, AudioBufferedCipher, StreamCipher},
     playback::{
         audio::{AudioPacket, AudioStream},
-        video::{PacketKind, VideoPacket, VideoStream},
+        event::{EventMessage, EventSink},
+        video::{self, FrameDimensions, PacketKind, VideoCodec, VideoPacket, VideoStream},
     },
-    util::memory,
+    util::{memory, redact::Redacted},
 };
 
-#[tracing::instrument]
-pub async fn event_processor(listener: TcpListener) {
-    const BUF_SIZE: usize = 16 * 1024;
+/// Target of processor spans and events, filtered apart from the rest of the crate, e.g.
+/// `RUST_LOG=info,rairplay::streaming=off`. Spans repeat it, as attributes take literals only
+const TRACING_TARGET: &str = "rairplay::streaming";
 
-    let mut buf = [0; BUF_SIZE];
+/// Realtime packets are held this long past their playout time while the sync is lost, so
+/// the ones played once it's back are still close to the others
+const MAX_UNSYNCED_HOLD: Duration = Duration::from_secs(2);
+
+#[tracing::instrument(target = "rairplay::streaming", skip(sink))]
+pub async fn event_processor(listener: TcpListener, buffers: BufferConfig, sink: &impl EventSink) {
     while let Ok((mut stream, remote_addr)) = listener.accept().await {
-        while let Ok(len @ 1..) = stream.read(&mut buf).await {
-            tracing::trace!(%len, %remote_addr, "event data");
+        loop {
+            match read_event(&mut stream, buffers.event_message).await {
+                Ok(Some(msg)) => {
+                    tracing::trace!(target: TRACING_TARGET, ?msg, %remote_addr, "event");
+                    sink.on_event(msg);
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    if !matches!(err, StreamError::Closed) {
+                        tracing::warn!(
+                            target: TRACING_TARGET,
+                            %err,
+                            %remote_addr,
+                            "event connection closed"
+                        );
+                    }
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Reads a single message prefixed with its length, `None` is returned for undecodable ones.
+async fn read_event(
+    stream: &mut (impl AsyncRead + Unpin),
+    max_msg_len: usize,
+) -> Result<Option<EventMessage>, StreamError> {
+    let len = stream.read_u32().await? as usize;
+    if len > max_msg_len {
+        tracing::debug!(target: TRACING_TARGET, %len, "event message too long");
+        return Err(StreamError::Malformed("event message length"));
+    }
+
+    let mut buf = vec![0; len];
+    stream.read_exact(&mut buf).await?;
+
+    match EventMessage::parse(&buf) {
+        Ok(msg) => Ok(Some(msg)),
+        Err(err) => {
+            tracing::warn!(target: TRACING_TARGET, %err, %len, "malformed event message");
+            Ok(None)
+        }
+    }
+}
+
+/// Buffered packets failing to decrypt one after another, up to
+/// [`Audio::max_decrypt_failures`](crate::config::Audio::max_decrypt_failures).
+struct DecryptFailures {
+    max: u32,
+    in_row: u32,
+}
+
+impl DecryptFailures {
+    fn new(max: u32) -> Self {
+        Self { max, in_row: 0 }
+    }
+
+    fn on_decrypted(&mut self) {
+        self.in_row = 0;
+    }
+
+    /// Fails once there are `max` in a row, the key is taken for wrong then. Zero `max` never
+    /// gives up.
+    fn on_failed(&mut self) -> Result<(), StreamError> {
+        self.in_row += 1;
+        if self.in_row == self.max {
+            return Err(StreamError::DecryptFailed(self.in_row));
         }
+        Ok(())
     }
 }
 
-#[tracing::instrument(skip(cipher, stream))]
+/// Where a processor reads packets from and passes them to, along with the state it shares with
+/// the stream's handle.
+pub struct ProcessorCtx<'a, T, C, S> {
+    pub transport: T,
+    pub buffers: BufferConfig,
+    pub offload: Offload,
+    pub shared: &'a SharedData,
+    pub cipher: C,
+    pub stream: &'a S,
+}
+
+#[tracing::instrument(target = "rairplay::streaming", skip(ctx, timing))]
 pub async fn audio_buffered_processor(
-    audio_buf_size: u32,
-    mut tcp_stream: TcpStream,
-    cipher: AudioBufferedCipher,
-    stream: &impl AudioStream,
-) -> io::Result<()> {
-    const TRAILER_LEN: usize = 24;
+    ctx: ProcessorCtx<'_, impl AsyncRead + Unpin, impl AeadCipher, impl AudioStream>,
+    max_decrypt_failures: u32,
+    timing: Option<&TimingMonitor>,
+) -> Result<(), StreamError> {
+    let ProcessorCtx {
+        mut transport,
+        buffers,
+        offload,
+        shared,
+        cipher,
+        stream,
+    } = ctx;
+    let SharedData {
+        stats,
+        flush: flushes,
+        shaper,
+        history,
+        ..
+    } = shared;
+    let config = cipher.config();
+    let trailer_len = config.trailer_len();
+    let cipher = Arc::new(cipher);
+    let mut audio_buf = memory::BytesHunk::new(buffers.audio as usize);
 
-    let mut audio_buf = memory::BytesHunk::new(audio_buf_size as usize);
+    let read = |queue: offload::DecryptQueue<_>| async move {
+        loop {
+            // Played at the wrong rate otherwise, the sender's buffer holds the stream meanwhile
+            if let Some(timing) = timing.filter(|timing| timing.is_lost()) {
+                tracing::debug!(target: TRACING_TARGET, "buffered audio held until synced");
+                timing.not_lost().await;
+            }
+            async {
+                let prefix = transport.read_u16().await?;
+                // Prefix counts its own 2 bytes, rtp pkt length is w/o encryption data
+                let Some(pkt_len) = usize::from(prefix)
+                    .checked_sub(2 + trailer_len)
+                    .filter(|&len| len >= config.header_len)
+                else {
+                    tracing::debug!(target: TRACING_TARGET, %prefix, "buffered packet too short");
+                    return Err(StreamError::Malformed("buffered packet length"));
+                };
+                // Read along with the trailer, so the packet is passed raw in one piece
+                let mut rtp = audio_buf.allocate_buf(pkt_len + trailer_len);
+                transport.read_exact(&mut rtp).await?;
+                history.record(&rtp);
 
-    loop {
-        async {
-            let pkt_len = tcp_stream.read_u16().await?;
-            // 2 is pkt_len field size itself
-            let pkt_len: usize = pkt_len.saturating_sub(2).into();
-
-            if pkt_len < AudioPacket::HEADER_LEN + AudioPacket::TRAILER_LEN {
-                return Err(io::Error::other("malformed buffered stream"));
-            }
-
-            // rtp pkt length w/o encryption data
-            let pkt_len = pkt_len - TRAILER_LEN;
-            let mut rtp = audio_buf.allocate_buf(pkt_len);
-            tcp_stream.read_exact(&mut rtp).await?;
-
-            let mut tag = [0u8; AudioBufferedCipher::TAG_LEN];
-            let mut nonce = [0u8; AudioBufferedCipher::NONCE_LEN];
-            let aad = (rtp.as_ref()[4..][..AudioBufferedCipher::AAD_LEN])
-                .try_into()
-                .unwrap();
-
-            tcp_stream.read_exact(&mut tag).await?;
-            tcp_stream.read_exact(&mut nonce[4..]).await?;
-            tracing::trace!(%pkt_len, "packet read");
-
-            // TODO : offload to thread pool
-            if cipher
-                .open_in_place(nonce, aad, tag, &mut rtp[AudioPacket::HEADER_LEN..])
-                .is_err()
-            {
-                tracing::warn!(?nonce, ?aad, ?tag, "packet decryption failed");
-            } else {
-                tracing::trace!("packet decrypted");
+                // Payload is encrypted after CSRC list and extension, if there are any
+                let Some(header_len) = AudioPacket::header_len(&rtp[..pkt_len]) else {
+                    return Err(StreamError::Malformed("buffered rtp header"));
+                };
+                // In the fixed part of the header, which every packet read has
+                let aad_range = config.aad_offset..config.aad_offset + config.aad_len;
+                if rtp[..pkt_len].get(aad_range.clone()).is_none() {
+                    return Err(StreamError::Malformed("buffered aad"));
+                }
+
+                stats.on_packet(pkt_len + trailer_len);
+                tracing::trace!(target: TRACING_TARGET, %pkt_len, "packet read");
+                if !shaper.admit(pkt_len + trailer_len).await {
+                    tracing::trace!(target: TRACING_TARGET, "packet lost by shaper");
+                    return Ok(());
+                }
+                stream.on_raw(&rtp);
 
-                stream.on_data(AudioPacket { rtp });
+                let cipher = Arc::clone(&cipher);
+                queue
+                    .push(move || open_buffered(&*cipher, rtp, pkt_len, header_len, aad_range))
+                    .await;
+
+                Ok(())
             }
+            .instrument(tracing::trace_span!(target: TRACING_TARGET, "buffered packet"))
+            .await?;
+        }
+    };
 
-            Ok(())
+    // Shared by delivery and flushing, which both run on this task, so never contended
+    let filter = Mutex::new(FlushFilter::new(24));
+    let mut failures = DecryptFailures::new(max_decrypt_failures);
+    let deliver = |pkt: Option<AudioPacket>| {
+        match pkt {
+            Some(pkt) if filter.lock().unwrap().drops(pkt.buffered_seq()) => {
+                failures.on_decrypted();
+                tracing::trace!(
+                    target: TRACING_TARGET,
+                    seq = %pkt.buffered_seq(),
+                    "flushed packet dropped"
+                );
+            }
+            Some(pkt) => {
+                failures.on_decrypted();
+                stream.on_data(pkt);
+            }
+            None => {
+                stats.on_decrypt_failure();
+                failures.on_failed()?;
+            }
+        }
+        Ok(())
+    };
+    let flush = async {
+        loop {
+            let range = flushes.next().await;
+            tracing::debug!(target: TRACING_TARGET, ?range, "flush");
+            filter.lock().unwrap().set(range);
+            stream.on_flush(range.from_seq, range.until_seq);
         }
-        .instrument(tracing::trace_span!("buffered packet"))
-        .await?;
+    };
+
+    tokio::select! {
+        res = offload::ordered(offload, read, deliver) => res,
+        () = flush => unreachable!(),
     }
 }
 
-#[tracing::instrument(skip(cipher, stream))]
+/// Decrypts the payload of a buffered packet, which follows the `header_len` bytes of its header,
+/// with the tag and nonce from the trailer past `pkt_len`.
+fn open_buffered(
+    cipher: &impl AeadCipher,
+    mut rtp: BytesMut,
+    pkt_len: usize,
+    header_len: usize,
+    aad_range: Range<usize>,
+) -> Option<AudioPacket> {
+    let config = cipher.config();
+    let trailer_len = config.trailer_len();
+    let mut trailer = [0u8; AudioBufferedCipher::MAX_TRAILER_LEN];
+    trailer[..trailer_len].copy_from_slice(&rtp[pkt_len..]);
+    rtp.truncate(pkt_len);
+    // Sent part of nonce is the last one
+    let mut nonce = [0u8; AudioBufferedCipher::MAX_NONCE_LEN];
+    nonce[config.nonce_len - config.sent_nonce_len..config.nonce_len]
+        .copy_from_slice(&trailer[config.tag_len..trailer_len]);
+    let nonce = &nonce[..config.nonce_len];
+    let tag = &trailer[..config.tag_len];
+
+    let (header, payload) = rtp.split_at_mut(header_len);
+    let aad = &header[aad_range];
+    if cipher.open_in_place(nonce, aad, tag, payload).is_err() {
+        tracing::warn!(
+            target: TRACING_TARGET,
+            suite = ?config.suite,
+            nonce = ?Redacted(nonce),
+            aad = ?Redacted(aad),
+            tag = ?Redacted(tag),
+            "packet decryption failed"
+        );
+        return None;
+    }
+    tracing::trace!(target: TRACING_TARGET, "packet decrypted");
+    Some(AudioPacket {
+        rtp,
+        playout_time: None,
+    })
+}
+
+#[allow(clippy::too_many_lines)]
+#[tracing::instrument(
+    target = "rairplay::streaming",
+    skip(ctx, control, reorder, playout, timing, clock)
+)]
 pub async fn audio_realtime_processor(
-    socket: UdpSocket,
-    audio_buf_size: u32,
-    cipher: AudioRealtimeCipher,
-    stream: &impl AudioStream,
-) -> io::Result<()> {
-    const PKT_BUF_SIZE: usize = 16 * 1024;
-
-    let mut pkt_buf = [0u8; PKT_BUF_SIZE];
-    let mut audio_buf = memory::BytesHunk::new(audio_buf_size as usize);
-    loop {
-        async {
-            let pkt_len = socket.recv(&mut pkt_buf).await?;
+    ctx: ProcessorCtx<'_, UdpSocket, impl StreamCipher, impl AudioStream>,
+    control: &ControlChannel,
+    payload_type: u32,
+    reorder: ReorderBuffer<AudioPacket>,
+    playout: Option<&Mutex<PlayoutScheduler<AudioPacket>>>,
+    timing: Option<&TimingMonitor>,
+    clock: &impl Clock,
+) -> Result<(), StreamError> {
+    let ProcessorCtx {
+        transport: socket,
+        buffers,
+        offload,
+        shared,
+        cipher,
+        stream,
+    } = ctx;
+    let SharedData {
+        stats,
+        flush: flushes,
+        record: records,
+        shaper,
+        history,
+        ..
+    } = shared;
+    let cipher = Arc::new(cipher);
+    // A byte more than the largest packet, so longer ones are told from the ones fitting
+    let mut pkt_buf = vec![0u8; buffers.audio_packet + 1];
+    let mut audio_buf = memory::BytesHunk::new(buffers.audio as usize);
+    let mut last_seq: Option<u16> = None;
 
-            if pkt_len < AudioPacket::HEADER_LEN {
-                tracing::warn!(%pkt_len, "malformed packet");
-            } else {
-                let mut rtp = audio_buf.allocate_buf(pkt_len);
-                rtp.copy_from_slice(&pkt_buf[..pkt_len]);
-                tracing::trace!(%pkt_len, "packet read");
+    let read = |queue: offload::DecryptQueue<_>| async move {
+        loop {
+            async {
+                let (pkt_len, remote_addr) = socket.recv_from(&mut pkt_buf).await?;
+                stats.on_packet(pkt_len);
+                history.record(&pkt_buf[..pkt_len]);
+
+                if !shaper.admit(pkt_len).await {
+                    tracing::trace!(target: TRACING_TARGET, "packet lost by shaper");
+                } else if pkt_len < AudioPacket::HEADER_LEN {
+                    tracing::warn!(target: TRACING_TARGET, %pkt_len, "malformed packet");
+                } else if pkt_len > buffers.audio_packet {
+                    tracing::warn!(
+                        target: TRACING_TARGET,
+                        max = %buffers.audio_packet,
+                        "truncated packet"
+                    );
+                    stats.on_truncated_drop();
+                } else if u32::from(pkt_buf[1] & 0x7f) != payload_type {
+                    // Stray packets mustn't reach the decoder nor move the sequence
+                    tracing::warn!(target: TRACING_TARGET,
+                        got = pkt_buf[1] & 0x7f,
+                        expected = %payload_type,
+                        %remote_addr,
+                        "unexpected payload type"
+                    );
+                    stats.on_payload_type_mismatch();
+                } else {
+                    let seq = u16::from_be_bytes([pkt_buf[2], pkt_buf[3]]);
+                    match last_seq.map(|last| seq.wrapping_sub(last)) {
+                        // Late or duplicated packet, the newest one is still the last
+                        Some(0 | 0x8000..) => {}
+                        Some(1) | None => last_seq = Some(seq),
+                        Some(diff) => {
+                            let start_seq = seq.wrapping_sub(diff - 1);
+                            tracing::debug!(
+                                target: TRACING_TARGET,
+                                %start_seq,
+                                gap = diff - 1,
+                                "sequence gap"
+                            );
+                            stats.on_seq_gap(diff - 1);
+                            if let Err(err) = control
+                                .request_retransmit(remote_addr.ip(), start_seq, diff - 1)
+                                .await
+                            {
+                                tracing::warn!(
+                                    target: TRACING_TARGET,
+                                    %err,
+                                    "retransmit request failed"
+                                );
+                            }
+                            last_seq = Some(seq);
+                        }
+                    }
 
-                // TODO : offload data
-                cipher.decrypt(&mut rtp[AudioPacket::HEADER_LEN..]);
-                tracing::trace!("packet decrypted");
+                    stream.on_raw(&pkt_buf[..pkt_len]);
+                    let mut rtp = audio_buf.allocate_buf(pkt_len);
+                    rtp.copy_from_slice(&pkt_buf[..pkt_len]);
+                    tracing::trace!(target: TRACING_TARGET, %pkt_len, %seq, "packet read");
 
-                stream.on_data(AudioPacket { rtp });
+                    let cipher = Arc::clone(&cipher);
+                    queue
+                        .push(move || {
+                            cipher.decrypt(0, &mut rtp[AudioPacket::HEADER_LEN..]);
+                            tracing::trace!(target: TRACING_TARGET, "packet decrypted");
+                            AudioPacket {
+                                rtp,
+                                playout_time: None,
+                            }
+                        })
+                        .await;
+                }
+
+                Ok::<_, StreamError>(())
+            }
+            .instrument(tracing::trace_span!(target: TRACING_TARGET, "realtime packet"))
+            .await?;
+        }
+    };
+
+    // Expired packets are looked for twice per deadline
+    let expiry_period = (reorder.deadline() / 2).max(Duration::from_millis(1));
+    // Shared by delivery, expiry and flushing, which all run on this task, so never contended
+    let reorder = Mutex::new(reorder);
+    let filter = Mutex::new(FlushFilter::new(16));
+    let scheduled = Notify::new();
+    // Ordered packets are played right away or once their playout time comes
+    let emit = |pkt: AudioPacket| {
+        let Some(playout) = playout else {
+            return stream.on_data(pkt);
+        };
+        let header = pkt.header();
+        if playout
+            .lock()
+            .unwrap()
+            .push(header.timestamp, pkt, clock.now())
+        {
+            scheduled.notify_one();
+        } else {
+            tracing::debug!(
+                target: TRACING_TARGET,
+                seq = %header.seq,
+                timestamp = %header.timestamp,
+                "packet past playout time dropped"
+            );
+            stats.on_late_drop();
+        }
+    };
+    let deliver = |pkt: AudioPacket| {
+        let seq = pkt.header().seq;
+        if filter.lock().unwrap().drops(seq.into()) {
+            tracing::trace!(target: TRACING_TARGET, %seq, "flushed packet dropped");
+            return Ok(());
+        }
+        let mut reorder = reorder.lock().unwrap();
+        if !reorder.push(seq, pkt, Instant::now(), emit) {
+            tracing::debug!(target: TRACING_TARGET, %seq, "late packet dropped");
+        }
+        Ok(())
+    };
+    let expire = async {
+        let mut interval = time::interval(expiry_period);
+        loop {
+            interval.tick().await;
+            let mut reorder = reorder.lock().unwrap();
+            reorder.release_expired(Instant::now(), emit);
+        }
+    };
+    let play = async {
+        let Some(playout) = playout else {
+            return std::future::pending().await;
+        };
+        let mut sync = timing.map(TimingMonitor::subscribe);
+        loop {
+            // Played at the wrong rate otherwise, so packets are held until synced again
+            let lost = timing.is_some_and(TimingMonitor::is_lost);
+            let next = playout.lock().unwrap().next_deadline();
+            let next = next.map(|at| {
+                if lost {
+                    at.after(MAX_UNSYNCED_HOLD)
+                } else {
+                    at
+                }
+            });
+            let timing_changed = async {
+                match &mut sync {
+                    // Monitor is borrowed, so it's never closed
+                    Some(changes) => drop(changes.changed().await),
+                    None => std::future::pending().await,
+                }
+            };
+            if let Some(at) = next {
+                // Packet pushed meanwhile may be due earlier
+                tokio::select! {
+                    () = clock.sleep_until(at) => {}
+                    () = scheduled.notified() => {}
+                    () = timing_changed => {}
+                }
+            } else {
+                tokio::select! {
+                    () = scheduled.notified() => {}
+                    () = timing_changed => {}
+                }
             }
 
-            io::Result::Ok(())
+            let mut playout = playout.lock().unwrap();
+            let now = clock.now();
+            if timing.is_some_and(TimingMonitor::is_lost) {
+                let hold = i128::try_from(MAX_UNSYNCED_HOLD.as_nanos()).unwrap_or(i128::MAX);
+                let held_since = NtpTimestamp::from_nanos((now.as_nanos() - hold).max(0));
+                playout.release_due(held_since, |_, pkt| {
+                    tracing::trace!(
+                        target: TRACING_TARGET,
+                        seq = %pkt.header().seq,
+                        "packet held too long while unsynced dropped"
+                    );
+                    stats.on_unsynced_drop();
+                });
+            } else {
+                playout.release_due(now, |at, mut pkt| {
+                    pkt.playout_time = Some(at.into());
+                    stream.on_data(pkt);
+                });
+            }
+        }
+    };
+    let flush = async {
+        loop {
+            let range = flushes.next().await;
+            tracing::debug!(target: TRACING_TARGET, ?range, "flush");
+            let mut filter = filter.lock().unwrap();
+            filter.set(range);
+            if let Some(playout) = playout {
+                playout
+                    .lock()
+                    .unwrap()
+                    .retain(|pkt| !filter.drops(pkt.header().seq.into()));
+            }
+            // Held packets past the range would be taken as flushed if delivered first
+            stream.on_flush(range.from_seq, range.until_seq);
+            reorder.lock().unwrap().drain(|pkt| {
+                if !filter.drops(pkt.header().seq.into()) {
+                    emit(pkt);
+                }
+            });
         }
-        .instrument(tracing::trace_span!("realtime packet"))
-        .await?;
+    };
+    let record = async {
+        loop {
+            let start = records.next().await;
+            tracing::debug!(target: TRACING_TARGET, ?start, "record");
+            reorder.lock().unwrap().start_at(start.seq, emit);
+            if let (Some(playout), Some(rtptime)) = (playout, start.rtptime) {
+                playout.lock().unwrap().start_at(rtptime, clock.now());
+            }
+        }
+    };
+
+    let res = tokio::select! {
+        res = offload::ordered(offload, read, deliver) => res,
+        () = expire => unreachable!(),
+        () = play => unreachable!(),
+        () = flush => unreachable!(),
+        () = record => unreachable!(),
+    };
+    if let Some(playout) = playout {
+        playout.lock().unwrap().drain(|pkt| stream.on_data(pkt));
     }
+    reorder.lock().unwrap().drain(|pkt| stream.on_data(pkt));
+    res
 }
 
-#[tracing::instrument]
-pub async fn control_processor(socket: UdpSocket) -> io::Result<()> {
-    const BUF_SIZE: usize = 16 * 1024;
+#[tracing::instrument(target = "rairplay::streaming", skip(control, handler))]
+pub async fn control_processor(
+    control: &ControlChannel,
+    buffers: BufferConfig,
+    mut handler: impl FnMut(ControlHeader, ControlPacket<'_>),
+) -> Result<(), StreamError> {
+    let mut buf = vec![0u8; buffers.control_packet];
+    loop {
+        let pkt_len = control.recv(&mut buf).await?;
+
+        match ControlPacket::parse(&buf[..pkt_len]) {
+            Ok((header, packet)) => {
+                tracing::trace!(target: TRACING_TARGET, ?header, %pkt_len, "control packet read");
+                handler(header, packet);
+            }
+            Err(err) => tracing::warn!(target: TRACING_TARGET, %err, "malformed control packet"),
+        }
+    }
+}
 
-    let mut buf = [0u8; BUF_SIZE];
+/// Applies latency the sender renegotiates to `playout`, packets pushed afterwards move towards
+/// it while the stream goes on. Runs until dropped.
+pub async fn latency_processor<T>(
+    latencies: &LatencySignal,
+    playout: Option<&Mutex<PlayoutScheduler<T>>>,
+    clock: &impl Clock,
+) -> Infallible {
     loop {
-        let _pkt_len = socket.recv(&mut buf).await?;
+        let latency = latencies.next().await;
+        let Some(playout) = playout else {
+            tracing::debug!(
+                target: TRACING_TARGET,
+                ?latency,
+                "latency renegotiated, nothing scheduled"
+            );
+            continue;
+        };
+        let mut playout = playout.lock().unwrap();
+        tracing::info!(
+            target: TRACING_TARGET,
+            old = ?playout.latency(),
+            new = ?latency,
+            "latency renegotiated"
+        );
+        playout.set_latency(latency, clock.now());
     }
 }
 
-#[tracing::instrument(skip(cipher, stream))]
+#[allow(clippy::too_many_lines)]
+#[tracing::instrument(target = "rairplay::streaming", skip(ctx, timeline))]
 pub async fn video_processor(
-    video_buf_size: u32,
-    mut tcp_stream: TcpStream,
-    mut cipher: VideoCipher,
-    stream: &impl VideoStream,
-) -> io::Result<()> {
-    const UNKNOWN_BYTES: usize = 112;
-
-    let mut video_buf = memory::BytesHunk::new(video_buf_size as usize);
-    loop {
-        async {
-            let payload_len = tcp_stream.read_u32_le().await?;
-            let kind = match tcp_stream.read_u16_le().await? {
-                1 => PacketKind::AvcC,
-                0 | 4096 => PacketKind::Payload,
-                other => PacketKind::Other(other),
+    ctx: ProcessorCtx<'_, impl AsyncRead + Unpin, impl StreamCipher, impl VideoStream>,
+    decrypted_other_kinds: &[u16],
+    reassemble_frames: bool,
+    timeline: Option<&SenderTimeline>,
+) -> Result<(), StreamError> {
+    let ProcessorCtx {
+        mut transport,
+        buffers,
+        offload,
+        shared,
+        cipher,
+        stream,
+    } = ctx;
+    let SharedData {
+        stats,
+        shaper,
+        history,
+        ..
+    } = shared;
+    let cipher = Arc::new(cipher);
+    let mut video_buf = memory::BytesHunk::new(buffers.video as usize);
+    let mut keystream_pos = 0u64;
+    let mut assembler = reassemble_frames.then(|| video::FrameAssembler::new(stats));
+
+    let read = |queue: offload::DecryptQueue<_>| async move {
+        // Told by codec records, frames before the first one aren't played anyway
+        let mut codec = VideoCodec::H264;
+        loop {
+            async {
+                let payload_len = transport.read_u32_le().await?;
+                if payload_len as usize > video::MAX_FRAME_LEN {
+                    tracing::debug!(target: TRACING_TARGET, %payload_len, "video packet too long");
+                    return Err(StreamError::Malformed("video packet length"));
+                }
+                // Read along with the header, so the packet is passed raw in one piece
+                let mut raw =
+                    video_buf.allocate_buf(VideoPacket::HEADER_LEN + payload_len as usize);
+                raw[..4].copy_from_slice(&payload_len.to_le_bytes());
+                transport.read_exact(&mut raw[4..]).await?;
+                history.record(&raw);
+
+                let kind = match u16::from_le_bytes([raw[4], raw[5]]) {
+                    1 => {
+                        codec = video::record_codec(&raw[VideoPacket::HEADER_LEN..]);
+                        match codec {
+                            VideoCodec::H264 => PacketKind::AvcC,
+                            VideoCodec::H265 => PacketKind::HvcC,
+                        }
+                    }
+                    0 | 4096 => PacketKind::Payload,
+                    other => PacketKind::Other(other),
+                };
+                let option = u16::from_le_bytes([raw[6], raw[7]]);
+                let timestamp = u64::from_le_bytes(raw[8..16].try_into().unwrap());
+                let block: &[u8; FrameDimensions::BLOCK_LEN] =
+                    raw[16..VideoPacket::HEADER_LEN].try_into().unwrap();
+                let dimensions = matches!(kind, PacketKind::AvcC | PacketKind::HvcC)
+                    .then(|| FrameDimensions::parse(block));
+                stats.on_packet(payload_len as usize);
+                tracing::trace!(
+                    target: TRACING_TARGET,
+                    ?kind,
+                    %timestamp,
+                    %option,
+                    %payload_len,
+                    "packet read"
+                );
+
+                let decrypt = match kind {
+                    PacketKind::Payload => true,
+                    PacketKind::Other(code) if decrypted_other_kinds.contains(&code) => {
+                        // Misconfigured code mustn't garble plaintext frames
+                        let plain =
+                            video::is_plain_nal_units(codec, &raw[VideoPacket::HEADER_LEN..]);
+                        if plain {
+                            tracing::debug!(
+                                target: TRACING_TARGET,
+                                %code,
+                                "plaintext packet of decrypted kind"
+                            );
+                        }
+                        !plain
+                    }
+                    PacketKind::AvcC | PacketKind::HvcC | PacketKind::Other(_) => false,
+                };
+                // Frames are in plaintext once decrypted, unknown kinds may never be
+                let frame = match kind {
+                    PacketKind::Payload => true,
+                    PacketKind::Other(code) => decrypted_other_kinds.contains(&code),
+                    PacketKind::AvcC | PacketKind::HvcC => false,
+                };
+
+                // Nonce of the frame, taken from lengths in headers rather than from the
+                // cipher so workers decrypt frames in any order
+                let pos = keystream_pos;
+                if decrypt {
+                    keystream_pos += u64::from(payload_len);
+                }
+                if !shaper.admit(payload_len as usize).await {
+                    tracing::trace!(target: TRACING_TARGET, "packet lost by shaper");
+                    return Ok(());
+                }
+                stream.on_raw(&raw);
+
+                let payload = raw.split_off(VideoPacket::HEADER_LEN);
+                let pts = timeline.and_then(|timeline| timeline.to_local(NtpTimestamp(timestamp)));
+                let mut pkt = VideoPacket {
+                    kind,
+                    option,
+                    timestamp,
+                    pts,
+                    dts: pts,
+                    dimensions,
+                    reserved: raw.split_off(16).freeze(),
+                    is_keyframe: false,
+                    payload,
+                };
+
+                if decrypt {
+                    let cipher = Arc::clone(&cipher);
+                    queue
+                        .push(move || {
+                            cipher.decrypt(pos, &mut pkt.payload);
+                            tracing::trace!(target: TRACING_TARGET, "packet decrypted");
+                            pkt.is_keyframe = video::contains_keyframe(codec, &pkt.payload);
+                            pkt
+                        })
+                        .await;
+                } else {
+                    pkt.is_keyframe = frame && video::contains_keyframe(codec, &pkt.payload);
+                    queue.push(move || pkt).await;
+                }
+
+                Ok::<_, StreamError>(())
+            }
+            .instrument(tracing::trace_span!(target: TRACING_TARGET, "video packet"))
+            .await?;
+        }
+    };
+
+    offload::ordered(offload, read, |pkt| {
+        // Delivered along with packets so the format change keeps its place in the stream
+        match pkt.kind {
+            PacketKind::AvcC => match video::parse_avcc(&pkt.payload) {
+                Ok(config) => stream.on_format(config),
+                Err(err) => tracing::warn!(target: TRACING_TARGET, %err, "malformed avcc packet"),
+            },
+            PacketKind::HvcC => match video::parse_hvcc(&pkt.payload) {
+                Ok(config) => stream.on_hevc_format(config),
+                Err(err) => tracing::warn!(target: TRACING_TARGET, %err, "malformed hvcc packet"),
+            },
+            PacketKind::Payload | PacketKind::Other(_) => {}
+        }
+
+        match (&mut assembler, pkt.kind) {
+            (Some(assembler), PacketKind::Payload) => {
+                let frame = assembler.push(pkt.timestamp, pkt.pts, pkt.payload, pkt.is_keyframe)?;
+                if let Some(frame) = frame {
+                    stream.on_frame(frame);
+                }
+            }
+            _ => stream.on_data(pkt),
+        }
+        Ok(())
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        error::Error,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use chacha20poly1305::{AeadInPlace, ChaCha20Poly1305, Key, KeyInit, Nonce};
+    use plist::{Dictionary, Value};
+    use tokio::{io::AsyncWriteExt, net::UdpSocket};
+
+    use super::{
+        MAX_UNSYNCED_HOLD, ProcessorCtx, audio_buffered_processor, audio_realtime_processor,
+        latency_processor, read_event, video_processor,
+    };
+    use crate::{
+        config::BufferConfig,
+        crypto::streaming::{
+            AudioBufferedCipher, AudioRealtimeCipher, NullCipher, StreamCipher, VideoCipher,
+        },
+        playback::{
+            Stream,
+            audio::{AudioPacket, AudioStream},
+            channel::{Backpressure, channel},
+            event::EventMessage,
+            video::{PacketKind, VideoPacket, VideoStream},
+        },
+        streaming::{
+            SharedData, StreamError, StreamKind,
+            clock::{Clock, MockClock, TokioClock},
+            control::ControlChannel,
+            flush::FlushRange,
+            offload::Offload,
+            playout::{LatencySignal, PlayoutScheduler},
+            reorder::ReorderBuffer,
+            timing::{NtpTimestamp, TimingMonitor, TimingState},
+        },
+    };
+
+    /// Hunks small enough to run out, which validation wouldn't let through
+    const MAX_DECRYPT_FAILURES: u32 = 64;
+
+    fn buffers(hunk: u32) -> BufferConfig {
+        BufferConfig {
+            audio: hunk,
+            video: hunk,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn event_spanning_reads() {
+        let mut dict = Dictionary::new();
+        dict.insert("type".into(), "playbackState".into());
+        dict.insert(
+            "params".into(),
+            Value::Dictionary(
+                [("playing".to_string(), Value::Boolean(true))]
+                    .into_iter()
+                    .collect(),
+            ),
+        );
+        let mut msg = Vec::new();
+        plist::to_writer_binary(&mut msg, &Value::Dictionary(dict)).unwrap();
+
+        let mut frame = u32::try_from(msg.len()).unwrap().to_be_bytes().to_vec();
+        frame.extend_from_slice(&msg);
+        frame.extend_from_slice(&[0, 0, 0, 3, b'b', b'a', b'd']);
+
+        let (mut tx, mut rx) = tokio::io::duplex(64);
+        let writer = tokio::spawn(async move {
+            for chunk in frame.chunks(5) {
+                tx.write_all(chunk).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+
+        assert_eq!(
+            Some(EventMessage::PlaybackState { playing: true }),
+            read_event(&mut rx, 1024).await.unwrap()
+        );
+        assert_eq!(None, read_event(&mut rx, 1024).await.unwrap());
+        assert!(read_event(&mut rx, 1024).await.is_err());
+        writer.await.unwrap();
+    }
+
+    /// Realtime processor of payload type 0x60 reading what the returned socket sends, with
+    /// retransmits asked of that socket too, as the sender is unknown
+    async fn realtime_fixture<'a>(
+        stream: &'a impl AudioStream,
+        buffers: BufferConfig,
+        reorder: ReorderBuffer<AudioPacket>,
+        playout: Option<&'a Mutex<PlayoutScheduler<AudioPacket>>>,
+        timing: Option<&'a TimingMonitor>,
+        clock: &'a impl Clock,
+    ) -> (
+        UdpSocket,
+        impl Future<Output = Result<(), StreamError>> + 'a,
+        Arc<SharedData>,
+    ) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sender.connect(socket.local_addr().unwrap()).await.unwrap();
+        let shared = Arc::new(SharedData::new(StreamKind::AudioRealtime));
+        let control = ControlChannel::new(
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            None,
+            sender.local_addr().unwrap().port(),
+            0,
+            Arc::clone(&shared),
+        );
+
+        let processor = {
+            let shared = Arc::clone(&shared);
+            async move {
+                let ctx = ProcessorCtx {
+                    transport: socket,
+                    buffers,
+                    offload: Offload::dedicated(1),
+                    shared: &shared,
+                    cipher: AudioRealtimeCipher::new([0; 16], [0; 16]),
+                    stream,
+                };
+                audio_realtime_processor(ctx, &control, 0x60, reorder, playout, timing, clock).await
+            }
+        };
+        (sender, processor, shared)
+    }
+
+    #[tokio::test]
+    async fn drop_unexpected_payload_type() {
+        let (stream, mut rx) = channel(4, Backpressure::DropOldest);
+        let (sender, processor, shared) = realtime_fixture(
+            &stream,
+            buffers(1024),
+            ReorderBuffer::new(0, Duration::from_millis(50)),
+            None,
+            None,
+            &TokioClock,
+        )
+        .await;
+
+        let mut pkt = [0u8; 16];
+        pkt[1] = 0x56;
+        sender.send(&pkt).await.unwrap();
+        pkt[1] = 0x80 | 0x60;
+        sender.send(&pkt).await.unwrap();
+
+        tokio::select! {
+            res = processor => panic!("processor finished: {res:?}"),
+            pkt = rx.recv() => assert_eq!(0x60, pkt.unwrap().header().payload_type),
+        }
+        assert_eq!(1, shared.stats.snapshot().payload_type_mismatches);
+    }
+
+    #[tokio::test]
+    async fn drop_truncated_packet() {
+        let (stream, mut rx) = channel(4, Backpressure::DropOldest);
+        let (sender, processor, shared) = realtime_fixture(
+            &stream,
+            BufferConfig {
+                audio_packet: 1500,
+                ..buffers(1024 * 1024)
+            },
+            ReorderBuffer::new(0, Duration::from_millis(50)),
+            None,
+            None,
+            &TokioClock,
+        )
+        .await;
+
+        let mut pkt = vec![0u8; 1501];
+        pkt[1] = 0x60;
+        sender.send(&pkt).await.unwrap();
+        pkt[3] = 1;
+        sender.send(&pkt[..1500]).await.unwrap();
+
+        tokio::select! {
+            res = processor => panic!("processor finished: {res:?}"),
+            pkt = rx.recv() => assert_eq!(1500, pkt.unwrap().rtp.len()),
+        }
+        assert_eq!(1, shared.stats.snapshot().truncated_drops);
+    }
+
+    #[tokio::test]
+    async fn packets_played_at_playout_time() {
+        // Timestamp zero is played 50 ms from now, at 1000 samples per second
+        let start = Instant::now();
+        let mut playout = PlayoutScheduler::new(1000, Duration::ZERO);
+        playout.anchor(
+            0,
+            NtpTimestamp::from_nanos(NtpTimestamp::now().as_nanos() + 50_000_000),
+        );
+        let playout = Mutex::new(playout);
+
+        let (stream, mut rx) = channel(4, Backpressure::DropOldest);
+        let (sender, processor, shared) = realtime_fixture(
+            &stream,
+            buffers(1024),
+            ReorderBuffer::new(0, Duration::from_millis(50)),
+            Some(&playout),
+            None,
+            &TokioClock,
+        )
+        .await;
+
+        let mut pkt = [0u8; 16];
+        pkt[1] = 0x60;
+        for (seq, timestamp) in [(1u16, 0u32), (2, u32::MAX - 999)] {
+            pkt[2..4].copy_from_slice(&seq.to_be_bytes());
+            pkt[4..8].copy_from_slice(&timestamp.to_be_bytes());
+            sender.send(&pkt).await.unwrap();
+        }
+
+        tokio::select! {
+            res = processor => panic!("processor finished: {res:?}"),
+            pkt = rx.recv() => assert_eq!(1, pkt.unwrap().header().seq),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(45));
+        // Second packet was due a second before the first one
+        assert_eq!(1, shared.stats.snapshot().late_drops);
+    }
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum Delivered {
+        Packet(u16),
+        Flush(u32),
+    }
+
+    #[derive(Default)]
+    struct FlushRecorder(Mutex<Vec<Delivered>>);
+
+    impl Stream for FlushRecorder {
+        type Content = AudioPacket;
+
+        fn on_data(&self, pkt: Self::Content) {
+            let seq = pkt.header().seq;
+            self.0.lock().unwrap().push(Delivered::Packet(seq));
+        }
+
+        fn on_ok(self) {}
+
+        fn on_err(self, _: Box<dyn Error>) {}
+    }
+
+    impl AudioStream for FlushRecorder {
+        fn on_flush(&self, _: Option<u32>, until_seq: u32) {
+            self.0.lock().unwrap().push(Delivered::Flush(until_seq));
+        }
+    }
+
+    #[tokio::test]
+    async fn flush_told_before_held_packets() {
+        let stream = FlushRecorder::default();
+        let (sender, processor, shared) = realtime_fixture(
+            &stream,
+            buffers(1024),
+            ReorderBuffer::new(4, Duration::from_secs(10)),
+            None,
+            None,
+            &TokioClock,
+        )
+        .await;
+
+        // Third packet is held for the missing second one
+        let mut pkt = [0u8; 16];
+        pkt[1] = 0x60;
+        for seq in [1u16, 3] {
+            pkt[2..4].copy_from_slice(&seq.to_be_bytes());
+            sender.send(&pkt).await.unwrap();
+        }
+        let flush = async {
+            while stream.0.lock().unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            shared.flush.request(FlushRange {
+                from_seq: None,
+                until_seq: 2,
+            });
+            while stream.0.lock().unwrap().len() < 3 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+
+        tokio::select! {
+            res = processor => panic!("processor finished: {res:?}"),
+            () = flush => {}
+        }
+        assert_eq!(
+            vec![
+                Delivered::Packet(1),
+                Delivered::Flush(2),
+                Delivered::Packet(3)
+            ],
+            *stream.0.lock().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn packets_released_at_clock_ticks() {
+        // Timestamp zero is played 50 ms from the start, at 1000 samples per second
+        let clock = MockClock::new(NtpTimestamp::from_nanos(1_000_000_000));
+        let mut playout = PlayoutScheduler::new(1000, Duration::ZERO);
+        playout.anchor(0, clock.now().after(Duration::from_millis(50)));
+        let playout = Mutex::new(playout);
+
+        let (stream, mut rx) = channel(4, Backpressure::DropOldest);
+        let (sender, processor, shared) = realtime_fixture(
+            &stream,
+            buffers(1024),
+            ReorderBuffer::new(0, Duration::from_millis(50)),
+            Some(&playout),
+            None,
+            &clock,
+        )
+        .await;
+
+        let check = async {
+            for (seq, timestamp) in [(1u16, 0u32), (2, 10)] {
+                let mut pkt = [0u8; 16];
+                pkt[1] = 0x60;
+                pkt[2..4].copy_from_slice(&seq.to_be_bytes());
+                pkt[4..8].copy_from_slice(&timestamp.to_be_bytes());
+                sender.send(&pkt).await.unwrap();
+            }
+            while shared.stats.snapshot().packets < 2
+                || playout.lock().unwrap().next_deadline().is_none()
+            {
+                tokio::task::yield_now().await;
+            }
+            // Processor gets to run between every step
+            let mut released_after = async |by_ms| {
+                clock.advance(Duration::from_millis(by_ms));
+                for _ in 0..16 {
+                    tokio::task::yield_now().await;
+                }
+                let mut released = Vec::new();
+                while let Some(pkt) = rx.try_recv() {
+                    released.push(pkt.header().seq);
+                }
+                released
             };
-            let unknown_field = tcp_stream.read_u16_le().await?;
-            let timestamp = tcp_stream.read_u64_le().await?;
-            tcp_stream.read_exact(&mut [0; UNKNOWN_BYTES]).await?;
-
-            let mut pkt = VideoPacket {
-                kind,
-                timestamp,
-                payload: video_buf.allocate_buf(payload_len as usize),
+
+            assert!(released_after(49).await.is_empty());
+            assert_eq!(vec![1], released_after(1).await);
+            assert!(released_after(9).await.is_empty());
+            assert_eq!(vec![2], released_after(1).await);
+        };
+
+        tokio::select! {
+            res = processor => panic!("processor finished: {res:?}"),
+            () = check => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn renegotiated_latency_moves_playout() {
+        let clock = MockClock::new(NtpTimestamp::from_nanos(1_000_000_000));
+        let start = clock.now();
+        let mut playout = PlayoutScheduler::<u32>::new(1000, Duration::from_millis(50));
+        playout.anchor(0, start.after(Duration::from_millis(50)));
+        let playout = Mutex::new(playout);
+        let latencies = LatencySignal::default();
+
+        let check = async {
+            latencies.request(Duration::from_millis(150));
+            while playout.lock().unwrap().latency() != Duration::from_millis(150) {
+                tokio::task::yield_now().await;
+            }
+            // Moves by a twentieth of the time passed at most, packet sampled now is played
+            // after the latency it's at
+            let playout_after = |by_ms: u32| {
+                clock.advance(Duration::from_millis(by_ms.into()));
+                let rtp_timestamp = u32::try_from(clock.now().diff_ns(start) / 1_000_000).unwrap();
+                let mut playout = playout.lock().unwrap();
+                assert!(playout.push(rtp_timestamp, 0, clock.now()));
+                let at = playout.playout_time(rtp_timestamp).unwrap();
+                at.diff_ns(clock.now()) / 1_000_000
             };
-            tcp_stream.read_exact(&mut pkt.payload).await?;
-            tracing::trace!(?kind, %timestamp, unknown=%unknown_field, %payload_len, "packet read");
+            assert_eq!(50 + 50, playout_after(1000));
+            assert_eq!(50 + 100, playout_after(2000));
+        };
+
+        tokio::select! {
+            never = latency_processor(&latencies, Some(&playout), &clock) => match never {},
+            () = check => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn playout_held_while_unsynced() {
+        let clock = MockClock::new(NtpTimestamp::from_nanos(1_000_000_000));
+        let mut playout = PlayoutScheduler::new(1000, Duration::ZERO);
+        playout.anchor(0, clock.now().after(Duration::from_millis(50)));
+        let playout = Mutex::new(playout);
+        let timing = TimingMonitor::default();
+        timing.set(TimingState::Lost);
+
+        let (stream, mut rx) = channel(4, Backpressure::DropOldest);
+        let (sender, processor, shared) = realtime_fixture(
+            &stream,
+            buffers(1024),
+            ReorderBuffer::new(0, Duration::from_millis(50)),
+            Some(&playout),
+            Some(&timing),
+            &clock,
+        )
+        .await;
+
+        let check = async {
+            // Due 50 ms and a second later
+            for (seq, timestamp) in [(1u16, 0u32), (2, 1000)] {
+                let mut pkt = [0u8; 16];
+                pkt[1] = 0x60;
+                pkt[2..4].copy_from_slice(&seq.to_be_bytes());
+                pkt[4..8].copy_from_slice(&timestamp.to_be_bytes());
+                sender.send(&pkt).await.unwrap();
+            }
+            while shared.stats.snapshot().packets < 2
+                || playout.lock().unwrap().next_deadline().is_none()
+            {
+                tokio::task::yield_now().await;
+            }
+            let mut released_after = async |by: Duration| {
+                clock.advance(by);
+                for _ in 0..16 {
+                    tokio::task::yield_now().await;
+                }
+                let mut released = Vec::new();
+                while let Some(pkt) = rx.try_recv() {
+                    released.push(pkt.header().seq);
+                }
+                released
+            };
+
+            assert!(released_after(Duration::from_millis(50)).await.is_empty());
+            // First one was held for too long, the second one still waits
+            assert!(released_after(MAX_UNSYNCED_HOLD).await.is_empty());
+            assert_eq!(1, shared.stats.snapshot().unsynced_drops);
+
+            timing.set(TimingState::Locked {
+                offset_ns: 0,
+                jitter: Duration::ZERO,
+            });
+            assert_eq!(vec![2], released_after(Duration::ZERO).await);
+        };
+
+        tokio::select! {
+            res = processor => panic!("processor finished: {res:?}"),
+            () = check => {}
+        }
+        assert_eq!(1, shared.stats.snapshot().unsynced_drops);
+    }
+
+    /// Length prefixed RTP packet sealed with ChaCha20-Poly1305, then tag and nonce
+    fn buffered_frame(key: &[u8; 32], seq: u8, csrcs: &[u32], payload: &[u8]) -> Vec<u8> {
+        let csrc_count = u8::try_from(csrcs.len()).unwrap();
+        let mut rtp = vec![
+            0x80 | csrc_count,
+            0x60,
+            0,
+            seq,
+            0,
+            0,
+            0,
+            1,
+            0xde,
+            0xad,
+            0xbe,
+            0xef,
+        ];
+        rtp.extend(csrcs.iter().flat_map(|csrc| csrc.to_be_bytes()));
+        let sent_nonce = [0, 0, 0, 0, 0, 0, 0, seq];
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&sent_nonce);
+
+        let mut sealed = payload.to_vec();
+        let tag = ChaCha20Poly1305::new(Key::from_slice(key))
+            .encrypt_in_place_detached(Nonce::from_slice(&nonce), &rtp[4..12], &mut sealed)
+            .unwrap();
+
+        let len = 2 + rtp.len() + sealed.len() + tag.len() + sent_nonce.len();
+        let mut frame = u16::try_from(len).unwrap().to_be_bytes().to_vec();
+        frame.extend_from_slice(&rtp);
+        frame.extend_from_slice(&sealed);
+        frame.extend_from_slice(&tag);
+        frame.extend_from_slice(&sent_nonce);
+        frame
+    }
+
+    #[tokio::test]
+    async fn buffered_golden_packet() {
+        let key = [7; 32];
+        let mut input = buffered_frame(&key, 1, &[], b"golden payload");
+        let mut forged = buffered_frame(&key, 2, &[], b"forged payload");
+        // Flip a bit of the tag
+        let tag_pos = forged.len() - 24;
+        forged[tag_pos] ^= 1;
+        input.extend_from_slice(&forged);
+
+        let shared = SharedData::new(StreamKind::AudioBuffered);
+        let (stream, mut rx) = channel::<AudioPacket>(4, Backpressure::Block);
+        let res = audio_buffered_processor(
+            ProcessorCtx {
+                transport: &input[..],
+                buffers: buffers(1024),
+                offload: Offload::dedicated(1),
+                shared: &shared,
+                cipher: AudioBufferedCipher::from_shared_key(&key).unwrap(),
+                stream: &stream,
+            },
+            MAX_DECRYPT_FAILURES,
+            None,
+        )
+        .await;
+
+        // Input is over
+        assert!(res.is_err());
+        let pkt = rx.try_recv().unwrap();
+        assert_eq!(1, pkt.buffered_seq() & 0xffff);
+        assert_eq!(b"golden payload", pkt.payload());
+        assert!(rx.try_recv().is_none());
+        assert_eq!(1, shared.stats.snapshot().decrypt_failures);
+    }
+
+    #[tokio::test]
+    async fn buffered_packet_with_csrc() {
+        let key = [7; 32];
+        let input = buffered_frame(&key, 1, &[0x1234_5678], b"after csrc");
+
+        let shared = SharedData::new(StreamKind::AudioBuffered);
+        let (stream, mut rx) = channel::<AudioPacket>(4, Backpressure::Block);
+        let res = audio_buffered_processor(
+            ProcessorCtx {
+                transport: &input[..],
+                buffers: buffers(1024),
+                offload: Offload::dedicated(0),
+                shared: &shared,
+                cipher: AudioBufferedCipher::from_shared_key(&key).unwrap(),
+                stream: &stream,
+            },
+            MAX_DECRYPT_FAILURES,
+            None,
+        )
+        .await;
+
+        assert!(res.is_err());
+        let pkt = rx.try_recv().unwrap();
+        assert_eq!(1, pkt.header().csrc_count);
+        assert_eq!(0x1234_5678_u32.to_be_bytes(), pkt.rtp[12..16]);
+        assert_eq!(b"after csrc", pkt.payload());
+        assert_eq!(0, shared.stats.snapshot().decrypt_failures);
+    }
+
+    #[tokio::test]
+    async fn buffered_null_cipher() {
+        let rtp = [0x80, 0x60, 0, 1, 0, 0, 0, 1, 0xde, 0xad, 0xbe, 0xef];
+        let payload = b"plain payload";
+        // Trailer of tag and nonce is left as is
+        let len = 2 + rtp.len() + payload.len() + NullCipher::default().config.trailer_len();
+        let mut input = u16::try_from(len).unwrap().to_be_bytes().to_vec();
+        input.extend_from_slice(&rtp);
+        input.extend_from_slice(payload);
+        input.resize(len, 0xff);
+
+        let shared = SharedData::new(StreamKind::AudioBuffered);
+        let (stream, mut rx) = channel::<AudioPacket>(4, Backpressure::Block);
+        let res = audio_buffered_processor(
+            ProcessorCtx {
+                transport: &input[..],
+                buffers: buffers(1024),
+                offload: Offload::dedicated(0),
+                shared: &shared,
+                cipher: NullCipher::default(),
+                stream: &stream,
+            },
+            MAX_DECRYPT_FAILURES,
+            None,
+        )
+        .await;
+
+        assert!(res.is_err());
+        assert_eq!(payload, rx.try_recv().unwrap().payload());
+        assert_eq!(0, shared.stats.snapshot().decrypt_failures);
+    }
+
+    #[tokio::test]
+    async fn buffered_unencrypted() {
+        let mut input = Vec::new();
+        for (seq, payload) in [(1u8, &b"first in the clear"[..]), (2, b"second")] {
+            let rtp = [0x80, 0x60, 0, seq, 0, 0, 0, 1, 0xde, 0xad, 0xbe, 0xef];
+            // No trailer follows
+            let len = 2 + rtp.len() + payload.len();
+            input.extend_from_slice(&u16::try_from(len).unwrap().to_be_bytes());
+            input.extend_from_slice(&rtp);
+            input.extend_from_slice(payload);
+        }
+
+        let shared = SharedData::new(StreamKind::AudioBuffered);
+        let (stream, mut rx) = channel::<AudioPacket>(4, Backpressure::Block);
+        let res = audio_buffered_processor(
+            ProcessorCtx {
+                transport: &input[..],
+                buffers: buffers(1024),
+                offload: Offload::dedicated(1),
+                shared: &shared,
+                cipher: AudioBufferedCipher::from_shared_key(&[]).unwrap(),
+                stream: &stream,
+            },
+            MAX_DECRYPT_FAILURES,
+            None,
+        )
+        .await;
+
+        assert!(res.is_err());
+        let first = rx.try_recv().unwrap();
+        assert_eq!(1, first.buffered_seq() & 0xffff);
+        assert_eq!(b"first in the clear", first.payload());
+        assert_eq!(b"second", rx.try_recv().unwrap().payload());
+        assert_eq!(0, shared.stats.snapshot().decrypt_failures);
+    }
 
-            // Only payload need to be decrypted
-            // TODO: Other(_) too?
-            if matches!(kind, PacketKind::Payload) {
-                // TODO : Offload to thread
-                cipher.decrypt(&mut pkt.payload);
-                tracing::trace!("packet decrypted");
+    #[tokio::test]
+    async fn buffered_held_while_unsynced() {
+        let rtp = [0x80, 0x60, 0, 1, 0, 0, 0, 1, 0xde, 0xad, 0xbe, 0xef];
+        let mut input = u16::try_from(2 + rtp.len() + 4)
+            .unwrap()
+            .to_be_bytes()
+            .to_vec();
+        input.extend_from_slice(&rtp);
+        input.extend_from_slice(b"held");
+
+        let timing = TimingMonitor::default();
+        timing.set(TimingState::Lost);
+        let shared = SharedData::new(StreamKind::AudioBuffered);
+        let (stream, mut rx) = channel::<AudioPacket>(4, Backpressure::Block);
+        let processor = audio_buffered_processor(
+            ProcessorCtx {
+                transport: &input[..],
+                buffers: buffers(1024),
+                offload: Offload::dedicated(0),
+                shared: &shared,
+                cipher: AudioBufferedCipher::from_shared_key(&[]).unwrap(),
+                stream: &stream,
+            },
+            MAX_DECRYPT_FAILURES,
+            Some(&timing),
+        );
+
+        let check = async {
+            for _ in 0..16 {
+                tokio::task::yield_now().await;
             }
+            assert_eq!(0, shared.stats.snapshot().packets);
+            timing.set(TimingState::Locked {
+                offset_ns: 0,
+                jitter: Duration::ZERO,
+            });
+            rx.recv().await.unwrap()
+        };
+
+        // Input ends right after the packet
+        let (res, pkt) = tokio::join!(processor, check);
+        assert!(matches!(res, Err(StreamError::Closed)));
+        assert_eq!(b"held", pkt.payload());
+    }
+
+    #[tokio::test]
+    async fn buffered_decrypt_failures_end_stream() {
+        let process = |input: Vec<u8>, max_decrypt_failures| async move {
+            let shared = SharedData::new(StreamKind::AudioBuffered);
+            let (stream, _rx) = channel::<AudioPacket>(128, Backpressure::DropOldest);
+            let res = audio_buffered_processor(
+                ProcessorCtx {
+                    transport: &input[..],
+                    buffers: buffers(1024),
+                    offload: Offload::dedicated(1),
+                    shared: &shared,
+                    cipher: AudioBufferedCipher::from_shared_key(&[8; 32]).unwrap(),
+                    stream: &stream,
+                },
+                max_decrypt_failures,
+                None,
+            )
+            .await;
+            (res, shared.stats.snapshot().decrypt_failures)
+        };
+        let frames = |key, seqs: std::ops::Range<u8>| {
+            seqs.flat_map(|seq| buffered_frame(key, seq, &[], b"payload"))
+                .collect::<Vec<_>>()
+        };
+
+        let wrong_key = frames(&[7; 32], 0..4);
+        assert!(matches!(
+            process(wrong_key.clone(), 3).await,
+            (Err(StreamError::DecryptFailed(3)), 3)
+        ));
+        // Zero threshold drops them until the input is over
+        assert!(matches!(
+            process(wrong_key, 0).await,
+            (Err(StreamError::Closed), 4)
+        ));
+        // Packet decrypted in between starts counting anew
+        let mut interrupted = frames(&[7; 32], 0..2);
+        interrupted.extend(frames(&[8; 32], 2..3));
+        interrupted.extend(frames(&[7; 32], 3..5));
+        assert!(matches!(
+            process(interrupted, 3).await,
+            (Err(StreamError::Closed), 4)
+        ));
+    }
 
-            stream.on_data(pkt);
+    #[tokio::test]
+    async fn buffered_stream_errors() {
+        let process = |input: Vec<u8>, max_decrypt_failures| async move {
+            let shared = SharedData::new(StreamKind::AudioBuffered);
+            let (stream, _rx) = channel::<AudioPacket>(128, Backpressure::DropOldest);
+            audio_buffered_processor(
+                ProcessorCtx {
+                    transport: &input[..],
+                    buffers: buffers(1024),
+                    offload: Offload::dedicated(1),
+                    shared: &shared,
+                    cipher: AudioBufferedCipher::from_shared_key(&[8; 32]).unwrap(),
+                    stream: &stream,
+                },
+                max_decrypt_failures,
+                None,
+            )
+            .await
+        };
 
-            io::Result::Ok(())
+        assert!(matches!(
+            process(vec![0, 3, 0], MAX_DECRYPT_FAILURES).await,
+            Err(StreamError::Malformed(_))
+        ));
+        // Prefix counts itself, the 12 bytes header, 16 bytes tag and 8 bytes nonce
+        let prefixed = |prefix: u16| {
+            let mut input = prefix.to_be_bytes().to_vec();
+            input.resize(usize::from(prefix).max(2), 0);
+            input
+        };
+        for prefix in [0, 1, 2, 12, 2 + 12 + 24 - 1] {
+            assert!(
+                matches!(
+                    process(prefixed(prefix), 0).await,
+                    Err(StreamError::Malformed(_))
+                ),
+                "{prefix}"
+            );
         }
-        .instrument(tracing::trace_span!("video packet"))
-        .await?;
+        // Read whole and dropped as undecryptable
+        for prefix in [2 + 12 + 24, u16::MAX] {
+            assert!(
+                matches!(process(prefixed(prefix), 0).await, Err(StreamError::Closed)),
+                "{prefix}"
+            );
+        }
+        assert!(matches!(
+            process(vec![0], MAX_DECRYPT_FAILURES).await,
+            Err(StreamError::Closed)
+        ));
+    }
+
+    fn video_frame(kind: u16, timestamp: u64, payload: &[u8]) -> Vec<u8> {
+        let mut frame = u32::try_from(payload.len()).unwrap().to_le_bytes().to_vec();
+        frame.extend_from_slice(&kind.to_le_bytes());
+        frame.extend_from_slice(&0x1e_u16.to_le_bytes());
+        frame.extend_from_slice(&timestamp.to_le_bytes());
+        frame.extend_from_slice(&[0; 112]);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[tokio::test]
+    async fn video_golden_packets() {
+        let plain: [&[u8]; 2] = [&[0, 0, 0, 2, 0x65, 0x88], &[0, 0, 0, 3, 0x41, 0x9a, 0x01]];
+        let cipher = VideoCipher::new([1; 16], 1000);
+
+        // Payloads continue the keystream of the previous ones
+        let mut input = Vec::new();
+        let mut pos = 0;
+        for (timestamp, payload) in (1..).zip(plain) {
+            let mut sealed = payload.to_vec();
+            cipher.decrypt(pos, &mut sealed);
+            pos += sealed.len() as u64;
+            input.extend(video_frame(0, timestamp, &sealed));
+        }
+
+        let shared = SharedData::new(StreamKind::Video);
+        let (stream, mut rx) = channel::<VideoPacket>(4, Backpressure::Block);
+        let res = video_processor(
+            ProcessorCtx {
+                transport: &input[..],
+                buffers: buffers(1024),
+                offload: Offload::dedicated(1),
+                shared: &shared,
+                cipher: VideoCipher::new([1; 16], 1000),
+                stream: &stream,
+            },
+            &[],
+            false,
+            None,
+        )
+        .await;
+
+        assert!(res.is_err());
+        for ((timestamp, payload), is_keyframe) in (1..).zip(plain).zip([true, false]) {
+            let pkt = rx.try_recv().unwrap();
+            assert!(matches!(pkt.kind, PacketKind::Payload));
+            assert_eq!(timestamp, pkt.timestamp);
+            assert_eq!(is_keyframe, pkt.is_keyframe);
+            assert_eq!(payload, &pkt.payload[..]);
+        }
+        assert_eq!(2, shared.stats.snapshot().packets);
+    }
+
+    #[tokio::test]
+    async fn video_frames_decrypted_in_any_order() {
+        let plain = (0..16u8)
+            .map(|i| vec![i; 7 + usize::from(i)])
+            .collect::<Vec<_>>();
+        let cipher = VideoCipher::new([2; 16], 7);
+
+        let mut input = Vec::new();
+        let mut pos = 0;
+        for (timestamp, payload) in (1..).zip(&plain) {
+            let mut sealed = payload.clone();
+            cipher.decrypt(pos, &mut sealed);
+            pos += sealed.len() as u64;
+            input.extend(video_frame(0, timestamp, &sealed));
+        }
+
+        let shared = SharedData::new(StreamKind::Video);
+        let (stream, mut rx) = channel::<VideoPacket>(plain.len(), Backpressure::Block);
+        let res = video_processor(
+            ProcessorCtx {
+                transport: &input[..],
+                buffers: buffers(4096),
+                offload: Offload::dedicated(4),
+                shared: &shared,
+                cipher: VideoCipher::new([2; 16], 7),
+                stream: &stream,
+            },
+            &[],
+            false,
+            None,
+        )
+        .await;
+
+        assert!(res.is_err());
+        for (timestamp, payload) in (1..).zip(&plain) {
+            let pkt = rx.try_recv().unwrap();
+            assert_eq!(timestamp, pkt.timestamp);
+            assert_eq!(payload, &pkt.payload[..]);
+        }
+    }
+
+    /// Keeps packets passed raw, decrypted ones are dropped
+    #[derive(Default)]
+    struct RawRecorder(Mutex<Vec<Vec<u8>>>);
+
+    impl Stream for RawRecorder {
+        type Content = VideoPacket;
+
+        fn on_data(&self, _: Self::Content) {}
+
+        fn on_raw(&self, packet: &[u8]) {
+            self.0.lock().unwrap().push(packet.to_vec());
+        }
+
+        fn on_ok(self) {}
+
+        fn on_err(self, _: Box<dyn Error>) {}
+    }
+
+    impl VideoStream for RawRecorder {}
+
+    #[tokio::test]
+    async fn video_raw_packets_sealed() {
+        let cipher = VideoCipher::new([3; 16], 9);
+        let mut sealed = vec![0, 0, 0, 2, 0x65, 0x88];
+        cipher.decrypt(0, &mut sealed);
+        let frames = [
+            video_frame(1, 1, &[1, 0x64, 0, 0x1f]),
+            video_frame(0, 2, &sealed),
+        ];
+
+        let shared = SharedData::new(StreamKind::Video);
+        let stream = RawRecorder::default();
+        let res = video_processor(
+            ProcessorCtx {
+                transport: &frames.concat()[..],
+                buffers: buffers(1024),
+                offload: Offload::dedicated(1),
+                shared: &shared,
+                cipher: VideoCipher::new([3; 16], 9),
+                stream: &stream,
+            },
+            &[],
+            false,
+            None,
+        )
+        .await;
+
+        assert!(res.is_err());
+        assert_eq!(frames.to_vec(), *stream.0.lock().unwrap());
     }
 }
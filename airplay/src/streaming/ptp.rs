@@ -0,0 +1,844 @@
+//! PTP (IEEE 1588-2008) ordinary clock in slave-only mode, timing senders of buffered audio.
+//!
+//! Master is picked among the clocks sending Announce messages by comparing their datasets,
+//! just the ones of the sender's timing peers once they're known, then the offset is measured
+//! with two exchanges:
+//! ```text
+//! master  t1 ---- Sync (+ Follow_Up) ----> t2  slave
+//! master  t4 <-------- Delay_Req --------- t3  slave
+//!            --------- Delay_Resp(t4) ---->
+//! ```
+//! Every change of the master, or a jump of its clock, drops the estimate and starts locking
+//! from scratch, so offsets of different clocks are never mixed.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    net::UdpSocket,
+    sync::watch,
+    time::{MissedTickBehavior, interval},
+};
+
+use super::timing::{ClockEstimate, ClockEstimator, NtpTimestamp, TimingMonitor, TimingState};
+use crate::{
+    config::MacAddr6,
+    util::{net, sync::WakerFlag, task::TaskSet},
+};
+
+const EVENT_PORT: u16 = 319;
+const GENERAL_PORT: u16 = 320;
+const MULTICAST_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 1, 129);
+const MULTICAST_V6: Ipv6Addr = Ipv6Addr::new(0xff0e, 0, 0, 0, 0, 0, 0, 0x181);
+
+const SYNC: u8 = 0x0;
+const DELAY_REQ: u8 = 0x1;
+const FOLLOW_UP: u8 = 0x8;
+const DELAY_RESP: u8 = 0x9;
+const ANNOUNCE: u8 = 0xb;
+
+const HEADER_LEN: usize = 34;
+const DELAY_REQ_LEN: usize = HEADER_LEN + 10;
+const TWO_STEP_FLAG: u16 = 0x0200;
+
+/// Masters not announcing themselves for this long are forgotten
+const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(6);
+
+/// Exchanges measured before the offset is trusted
+const LOCK_SAMPLES: usize = 4;
+
+/// Offset changing by more than this on top of the round trip means the master's clock jumped
+const STEP_THRESHOLD_NS: i128 = 10_000_000;
+
+/// Clock identity with the port it sends from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PortIdentity {
+    pub clock_id: u64,
+    pub port: u16,
+}
+
+/// Whether [`PtpClock::master_offset`] can be relied on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PtpLock {
+    /// No master is known
+    Unlocked,
+    /// Master is picked, but not enough exchanges have been measured yet
+    Locking,
+    Locked,
+}
+
+/// Shared view of the master's clock, kept up to date by [`PtpTimingClient`].
+#[derive(Clone)]
+pub struct PtpClock(Arc<ClockState>);
+
+struct ClockState {
+    estimator: Mutex<ClockEstimator>,
+    lock: watch::Sender<PtpLock>,
+    timing: TimingMonitor,
+}
+
+/// Slave clock listening on the PTP ports for as long as it's alive.
+pub struct PtpTimingClient {
+    waker_flag: Arc<WakerFlag>,
+    peers: Peers,
+}
+
+/// Addresses masters are picked among, any if empty
+type Peers = Arc<Mutex<Vec<IpAddr>>>;
+
+/// Dataset of an announced master, lower is better in field order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct MasterQuality {
+    priority1: u8,
+    class: u8,
+    accuracy: u8,
+    variance: u16,
+    priority2: u8,
+    identity: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Body {
+    Sync {
+        origin: i128,
+    },
+    FollowUp {
+        precise_origin: i128,
+    },
+    DelayResp {
+        receive: i128,
+        requesting: PortIdentity,
+    },
+    Announce(MasterQuality),
+    Other(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Message {
+    source: PortIdentity,
+    seq: u16,
+    two_step: bool,
+    correction_ns: i128,
+    body: Body,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Foreign {
+    quality: MasterQuality,
+    /// Scope of link-local address is kept, so requests leave through the same interface
+    addr: SocketAddr,
+    seen: Instant,
+}
+
+/// Sans-io state of the slave, fed with received messages.
+struct Slave {
+    identity: PortIdentity,
+    clock: PtpClock,
+    foreign: HashMap<PortIdentity, Foreign>,
+    peers: Peers,
+    master: Option<PortIdentity>,
+    /// Two step Sync waiting for its `Follow_Up`: sequence, local receive time and correction
+    pending_sync: Option<(u16, NtpTimestamp, i128)>,
+    /// Master send and local receive time of the last Sync
+    sync: Option<(NtpTimestamp, NtpTimestamp)>,
+    /// `Delay_Req` waiting for its response: sequence and local send time
+    pending_delay: Option<(u16, NtpTimestamp)>,
+    delay_seq: u16,
+    samples: usize,
+}
+
+impl PtpClock {
+    /// Clock reporting its sync to `timing` as well.
+    pub(crate) fn new(timing: TimingMonitor) -> Self {
+        Self(Arc::new(ClockState {
+            estimator: Mutex::new(ClockEstimator::new()),
+            lock: watch::Sender::new(PtpLock::Unlocked),
+            timing,
+        }))
+    }
+
+    #[must_use]
+    pub fn lock_state(&self) -> PtpLock {
+        *self.0.lock.borrow()
+    }
+
+    /// Waits until the offset can be relied on, e.g. to delay the playback.
+    pub async fn locked(&self) {
+        let mut lock = self.0.lock.subscribe();
+        // Sender is owned by self, so it can't be closed
+        let _ = lock.wait_for(|lock| *lock == PtpLock::Locked).await;
+    }
+
+    /// Master time minus the local one in nanoseconds, `None` unless locked.
+    #[must_use]
+    pub fn master_offset(&self) -> Option<i64> {
+        (self.lock_state() == PtpLock::Locked)
+            .then(|| self.estimate().offset_at(NtpTimestamp::now()))
+    }
+
+    /// Estimate of the master's clock, meaningless unless locked.
+    pub(crate) fn estimate(&self) -> ClockEstimate {
+        self.0.estimator.lock().unwrap().estimate()
+    }
+
+    fn reset(&self, lock: PtpLock) {
+        *self.0.estimator.lock().unwrap() = ClockEstimator::new();
+        let prev = self.0.lock.send_replace(lock);
+        // Relocking after the master changed or stepped loses the sync as well
+        if prev == PtpLock::Locked {
+            self.0.timing.lose();
+        }
+        if lock == PtpLock::Locking {
+            self.0.timing.set(TimingState::Syncing);
+        }
+    }
+}
+
+impl Default for PtpClock {
+    fn default() -> Self {
+        Self::new(TimingMonitor::default())
+    }
+}
+
+impl PtpTimingClient {
+    /// Binds the PTP ports on the family of `local_addr`, which requires privileges on most
+    /// systems, and feeds `clock` until dropped.
+    ///
+    /// Multicast group is joined on the interface of `local_addr`, so on hosts with several
+    /// ones messages of the interface the sender connected on are heard.
+    pub async fn create(
+        local_addr: SocketAddr,
+        mac_addr: MacAddr6,
+        clock: PtpClock,
+        tasks: &TaskSet,
+    ) -> io::Result<Self> {
+        let (event, general) = match local_addr.ip() {
+            IpAddr::V4(interface) => {
+                let bind = |port| UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port));
+                let (event, general) = (bind(EVENT_PORT).await?, bind(GENERAL_PORT).await?);
+                event.join_multicast_v4(MULTICAST_V4, interface)?;
+                general.join_multicast_v4(MULTICAST_V4, interface)?;
+                (event, general)
+            }
+            IpAddr::V6(_) => {
+                let interface = net::scope_id(local_addr);
+                let bind = |port| UdpSocket::bind((Ipv6Addr::UNSPECIFIED, port));
+                let (event, general) = (bind(EVENT_PORT).await?, bind(GENERAL_PORT).await?);
+                event.join_multicast_v6(&MULTICAST_V6, interface)?;
+                general.join_multicast_v6(&MULTICAST_V6, interface)?;
+                (event, general)
+            }
+        };
+
+        // EUI-64 of the MAC address
+        let mac = mac_addr.into_array();
+        let clock_id =
+            u64::from_be_bytes([mac[0], mac[1], mac[2], 0xff, 0xfe, mac[3], mac[4], mac[5]]);
+        let identity = PortIdentity { clock_id, port: 1 };
+        let mut slave = Slave::new(identity, clock);
+        let peers = Arc::clone(&slave.peers);
+        let waker_flag = Arc::new(WakerFlag::default());
+
+        let (wf, cancelled) = (Arc::clone(&waker_flag), tasks.cancelled());
+        tasks.spawn(async move {
+            tokio::select! {
+                () = &*wf => {}
+                () = cancelled => {}
+                res = run(&event, &general, &mut slave) => {
+                    if let Err(err) = res {
+                        tracing::error!(%err, "ptp client failed");
+                    }
+                }
+            };
+            slave.clock.reset(PtpLock::Unlocked);
+            slave.clock.0.timing.set(TimingState::Unsynced);
+            tracing::info!("ptp client done");
+        });
+
+        Ok(Self { waker_flag, peers })
+    }
+
+    /// Picks masters among clocks of `peers` only, i.e. the ones of the sender's group rather
+    /// than of others playing on the network. Any clock is followed once it's empty again.
+    ///
+    /// Master is picked again on the next Announce.
+    pub fn follow_peers(&self, peers: impl IntoIterator<Item = IpAddr>) {
+        let peers = peers.into_iter().map(|ip| ip.to_canonical()).collect();
+        *self.peers.lock().unwrap() = peers;
+    }
+}
+
+impl Drop for PtpTimingClient {
+    fn drop(&mut self) {
+        self.waker_flag.set_and_wake();
+    }
+}
+
+impl Slave {
+    fn new(identity: PortIdentity, clock: PtpClock) -> Self {
+        clock.reset(PtpLock::Unlocked);
+        Self {
+            identity,
+            clock,
+            foreign: HashMap::new(),
+            peers: Peers::default(),
+            master: None,
+            pending_sync: None,
+            sync: None,
+            pending_delay: None,
+            delay_seq: 0,
+            samples: 0,
+        }
+    }
+
+    /// Returns address of the master to send `Delay_Req` to once a Sync is complete.
+    fn on_message(
+        &mut self,
+        msg: Message,
+        from: SocketAddr,
+        received: NtpTimestamp,
+        now: Instant,
+    ) -> Option<SocketAddr> {
+        if msg.source.clock_id == self.identity.clock_id {
+            return None;
+        }
+
+        if let Body::Announce(quality) = msg.body {
+            let foreign = Foreign {
+                quality,
+                addr: from,
+                seen: now,
+            };
+            self.foreign.insert(msg.source, foreign);
+            self.expire(now);
+            return None;
+        }
+
+        if self.master != Some(msg.source) {
+            return None;
+        }
+
+        match msg.body {
+            Body::Sync { .. } if msg.two_step => {
+                self.pending_sync = Some((msg.seq, received, msg.correction_ns));
+                None
+            }
+            Body::Sync { origin } => self.on_sync(origin + msg.correction_ns, received),
+            Body::FollowUp { precise_origin } => match self.pending_sync.take() {
+                Some((seq, received, correction_ns)) if seq == msg.seq => {
+                    self.on_sync(precise_origin + correction_ns + msg.correction_ns, received)
+                }
+                pending => {
+                    self.pending_sync = pending;
+                    None
+                }
+            },
+            Body::DelayResp {
+                receive,
+                requesting,
+            } if requesting == self.identity => {
+                match self.pending_delay {
+                    Some((seq, sent)) if seq == msg.seq => {
+                        self.pending_delay = None;
+                        self.on_delay_resp(sent, receive - msg.correction_ns);
+                    }
+                    _ => tracing::debug!(seq=%msg.seq, "unexpected delay response"),
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Forgets masters which stopped announcing and picks the best remaining one.
+    fn expire(&mut self, now: Instant) {
+        self.foreign
+            .retain(|_, foreign| now.saturating_duration_since(foreign.seen) < ANNOUNCE_TIMEOUT);
+
+        let peers = self.peers.lock().unwrap();
+        let best = self
+            .foreign
+            .iter()
+            .filter(|(_, foreign)| {
+                peers.is_empty() || peers.contains(&foreign.addr.ip().to_canonical())
+            })
+            .min_by_key(|(_, foreign)| foreign.quality)
+            .map(|(id, _)| *id);
+        drop(peers);
+        if best != self.master {
+            tracing::info!(from=?self.master, to=?best, "ptp master changed");
+            self.master = best;
+            self.relock();
+        }
+    }
+
+    /// Builds `Delay_Req` sent at local time `sent`.
+    fn delay_req(&mut self, sent: NtpTimestamp) -> [u8; DELAY_REQ_LEN] {
+        let seq = self.delay_seq;
+        self.delay_seq = seq.wrapping_add(1);
+        self.pending_delay = Some((seq, sent));
+        encode_delay_req(self.identity, seq)
+    }
+
+    fn on_sync(&mut self, origin: i128, received: NtpTimestamp) -> Option<SocketAddr> {
+        self.sync = Some((NtpTimestamp::from_nanos(origin), received));
+        // Previous request is given up if its response is still missing
+        let mut addr = self.foreign.get(&self.master?)?.addr;
+        addr.set_port(EVENT_PORT);
+        Some(addr)
+    }
+
+    fn on_delay_resp(&mut self, t3: NtpTimestamp, t4: i128) {
+        let Some((t1, t2)) = self.sync else {
+            return;
+        };
+        let t4 = NtpTimestamp::from_nanos(t4);
+
+        let offset = i128::midpoint(t4.diff_ns(t3), t1.diff_ns(t2));
+        let delay = t2.diff_ns(t3) - t1.diff_ns(t4);
+        if self.samples > 0 {
+            let expected = i128::from(self.clock.estimate().offset_at(t2));
+            if (offset - expected).abs() > STEP_THRESHOLD_NS + delay.abs() {
+                tracing::info!(%offset, %expected, "ptp master clock stepped");
+                self.relock();
+            }
+        }
+
+        // Exchanges are mapped onto the one of NTP: the request is Delay_Req, the reply is Sync
+        self.clock
+            .0
+            .estimator
+            .lock()
+            .unwrap()
+            .on_reply(t3, t4, t1, t2);
+        self.samples += 1;
+        if self.samples == LOCK_SAMPLES {
+            tracing::info!(offset=?self.clock.estimate().offset_ns, "ptp clock locked");
+            self.clock.0.lock.send_replace(PtpLock::Locked);
+        }
+        if self.samples >= LOCK_SAMPLES {
+            self.clock.0.timing.set(self.clock.estimate().locked());
+        }
+    }
+
+    /// Drops everything measured against the previous master or its previous time.
+    fn relock(&mut self) {
+        self.pending_sync = None;
+        self.sync = None;
+        self.pending_delay = None;
+        self.samples = 0;
+        let lock = if self.master.is_some() {
+            PtpLock::Locking
+        } else {
+            PtpLock::Unlocked
+        };
+        self.clock.reset(lock);
+    }
+}
+
+async fn run(event: &UdpSocket, general: &UdpSocket, slave: &mut Slave) -> io::Result<()> {
+    let mut ticker = interval(ANNOUNCE_TIMEOUT / 2);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut event_buf = [0u8; 128];
+    let mut general_buf = [0u8; 128];
+
+    loop {
+        let (len, addr, buf) = tokio::select! {
+            _ = ticker.tick() => {
+                slave.expire(Instant::now());
+                continue;
+            }
+            res = event.recv_from(&mut event_buf) => {
+                let (len, addr) = res?;
+                (len, addr, &event_buf)
+            }
+            res = general.recv_from(&mut general_buf) => {
+                let (len, addr) = res?;
+                (len, addr, &general_buf)
+            }
+        };
+        let received = NtpTimestamp::now();
+
+        let Some(msg) = decode(&buf[..len]) else {
+            tracing::debug!(%len, %addr, "malformed ptp message");
+            continue;
+        };
+        if let Some(master) = slave.on_message(msg, addr, received, Instant::now()) {
+            let req = slave.delay_req(NtpTimestamp::now());
+            event.send_to(&req, master).await?;
+        }
+    }
+}
+
+fn decode(buf: &[u8]) -> Option<Message> {
+    let header = buf.first_chunk::<HEADER_LEN>()?;
+    // Only the version 2 is understood
+    if header[1] & 0x0f != 2 {
+        return None;
+    }
+
+    let u16_at = |pos: usize| u16::from_be_bytes([buf[pos], buf[pos + 1]]);
+    let u64_at = |pos: usize| u64::from_be_bytes(buf[pos..pos + 8].try_into().unwrap());
+    // 48 bits of seconds and 32 bits of nanoseconds
+    let timestamp_at = |pos: usize| -> Option<i128> {
+        let raw = buf.get(pos..pos + 10)?;
+        let secs = raw[..6]
+            .iter()
+            .fold(0i128, |secs, b| secs << 8 | i128::from(*b));
+        let nanos = i128::from(u32::from_be_bytes(raw[6..].try_into().unwrap()));
+        Some(secs * 1_000_000_000 + nanos)
+    };
+    let port_identity_at = |pos: usize| PortIdentity {
+        clock_id: u64_at(pos),
+        port: u16_at(pos + 8),
+    };
+
+    #[allow(clippy::cast_possible_wrap)]
+    let correction = u64_at(8) as i64;
+    let ty = header[0] & 0x0f;
+    let body = match ty {
+        SYNC => Body::Sync {
+            origin: timestamp_at(34)?,
+        },
+        FOLLOW_UP => Body::FollowUp {
+            precise_origin: timestamp_at(34)?,
+        },
+        DELAY_RESP if buf.len() >= 54 => Body::DelayResp {
+            receive: timestamp_at(34)?,
+            requesting: port_identity_at(44),
+        },
+        ANNOUNCE if buf.len() >= 64 => Body::Announce(MasterQuality {
+            priority1: buf[47],
+            class: buf[48],
+            accuracy: buf[49],
+            variance: u16_at(50),
+            priority2: buf[52],
+            identity: u64_at(53),
+        }),
+        DELAY_RESP | ANNOUNCE => return None,
+        ty => Body::Other(ty),
+    };
+
+    Some(Message {
+        source: port_identity_at(20),
+        seq: u16_at(30),
+        two_step: u16_at(6) & TWO_STEP_FLAG != 0,
+        // Fixed point with 16 fractional bits
+        correction_ns: i128::from(correction >> 16),
+        body,
+    })
+}
+
+fn encode_delay_req(source: PortIdentity, seq: u16) -> [u8; DELAY_REQ_LEN] {
+    let mut packet = [0; DELAY_REQ_LEN];
+    packet[0] = DELAY_REQ;
+    packet[1] = 2;
+    #[allow(clippy::cast_possible_truncation)]
+    packet[2..4].copy_from_slice(&(DELAY_REQ_LEN as u16).to_be_bytes());
+    packet[20..28].copy_from_slice(&source.clock_id.to_be_bytes());
+    packet[28..30].copy_from_slice(&source.port.to_be_bytes());
+    packet[30..32].copy_from_slice(&seq.to_be_bytes());
+    // Control field of Delay_Req and no message interval
+    packet[32] = 1;
+    packet[33] = 0x7f;
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6},
+        time::{Duration, Instant},
+    };
+
+    use super::{
+        ANNOUNCE, ANNOUNCE_TIMEOUT, Body, DELAY_RESP, EVENT_PORT, GENERAL_PORT, LOCK_SAMPLES,
+        Message, PortIdentity, PtpClock, PtpLock, SYNC, Slave, TWO_STEP_FLAG, decode,
+        encode_delay_req,
+    };
+    use crate::streaming::timing::{NtpTimestamp, TimingMonitor, TimingState};
+
+    const LOCAL: PortIdentity = PortIdentity {
+        clock_id: 0x0102_03ff_fe04_0506,
+        port: 1,
+    };
+    const MASTER_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+    const MASTER: SocketAddr = SocketAddr::new(MASTER_IP, GENERAL_PORT);
+
+    fn port(clock_id: u64) -> PortIdentity {
+        PortIdentity { clock_id, port: 1 }
+    }
+
+    fn ms(ms: i128) -> i128 {
+        1_000_000_000_000 + ms * 1_000_000
+    }
+
+    fn local(ms: i128) -> NtpTimestamp {
+        NtpTimestamp::from_nanos(3_000_000_000_000_000_000 + ms * 1_000_000)
+    }
+
+    fn header(ty: u8, source: PortIdentity, seq: u16, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        buf[0] = ty;
+        buf[1] = 2;
+        buf[20..28].copy_from_slice(&source.clock_id.to_be_bytes());
+        buf[28..30].copy_from_slice(&source.port.to_be_bytes());
+        buf[30..32].copy_from_slice(&seq.to_be_bytes());
+        buf
+    }
+
+    fn put_timestamp(buf: &mut [u8], nanos: i128) {
+        let secs = u64::try_from(nanos / 1_000_000_000).unwrap();
+        let nanos = u32::try_from(nanos % 1_000_000_000).unwrap();
+        buf[34..40].copy_from_slice(&secs.to_be_bytes()[2..]);
+        buf[40..44].copy_from_slice(&nanos.to_be_bytes());
+    }
+
+    fn announce(source: PortIdentity, priority1: u8) -> Message {
+        let mut buf = header(ANNOUNCE, source, 0, 64);
+        buf[47] = priority1;
+        buf[52] = 128;
+        buf[53..61].copy_from_slice(&source.clock_id.to_be_bytes());
+        decode(&buf).unwrap()
+    }
+
+    fn message(source: PortIdentity, seq: u16, body: Body) -> Message {
+        Message {
+            source,
+            seq,
+            two_step: false,
+            correction_ns: 0,
+            body,
+        }
+    }
+
+    /// Runs an exchange with the master `ahead_ms` ahead and 1ms one-way delay.
+    fn exchange(slave: &mut Slave, master: PortIdentity, at_ms: i128, ahead_ms: i128) {
+        let now = Instant::now();
+        let seq = u16::try_from(at_ms).unwrap();
+        let sync = message(
+            master,
+            seq,
+            Body::Sync {
+                origin: ms(at_ms + ahead_ms),
+            },
+        );
+        let to = slave.on_message(sync, MASTER, local(at_ms + 1), now);
+        assert_eq!(Some(SocketAddr::new(MASTER_IP, EVENT_PORT)), to);
+
+        let req = slave.delay_req(local(at_ms + 2));
+        let resp = message(
+            master,
+            u16::from_be_bytes([req[30], req[31]]),
+            Body::DelayResp {
+                receive: ms(at_ms + ahead_ms + 3),
+                requesting: LOCAL,
+            },
+        );
+        assert_eq!(None, slave.on_message(resp, MASTER, local(at_ms + 4), now));
+    }
+
+    fn lock_on(slave: &mut Slave, master: PortIdentity, ahead_ms: i128) {
+        for i in 0..LOCK_SAMPLES {
+            assert_ne!(PtpLock::Locked, slave.clock.lock_state());
+            exchange(slave, master, i128::try_from(i).unwrap() * 100, ahead_ms);
+        }
+        assert_eq!(PtpLock::Locked, slave.clock.lock_state());
+    }
+
+    fn expected_offset(ahead_ms: i128) -> i64 {
+        i64::try_from(ms(ahead_ms) - local(0).as_nanos()).unwrap()
+    }
+
+    #[test]
+    fn message_decoding() {
+        let mut buf = header(SYNC, port(7), 42, 44);
+        buf[6..8].copy_from_slice(&TWO_STEP_FLAG.to_be_bytes());
+        buf[8..16].copy_from_slice(&(5i64 << 16).to_be_bytes());
+        put_timestamp(&mut buf, ms(1500));
+
+        let msg = decode(&buf).unwrap();
+        assert_eq!(port(7), msg.source);
+        assert_eq!(42, msg.seq);
+        assert!(msg.two_step);
+        assert_eq!(5, msg.correction_ns);
+        assert_eq!(Body::Sync { origin: ms(1500) }, msg.body);
+
+        let mut buf = header(DELAY_RESP, port(7), 3, 54);
+        put_timestamp(&mut buf, ms(1));
+        buf[44..52].copy_from_slice(&LOCAL.clock_id.to_be_bytes());
+        buf[52..54].copy_from_slice(&LOCAL.port.to_be_bytes());
+        let resp = Body::DelayResp {
+            receive: ms(1),
+            requesting: LOCAL,
+        };
+        assert_eq!(resp, decode(&buf).unwrap().body);
+
+        // Truncated body and other versions are rejected
+        assert_eq!(None, decode(&buf[..50]));
+        buf[1] = 1;
+        assert_eq!(None, decode(&buf));
+    }
+
+    #[test]
+    fn delay_req_encoding() {
+        let req = encode_delay_req(LOCAL, 9);
+        let msg = decode(&req).unwrap();
+        assert_eq!(LOCAL, msg.source);
+        assert_eq!(9, msg.seq);
+        assert_eq!(Body::Other(1), msg.body);
+        assert_eq!(44, u16::from_be_bytes([req[2], req[3]]));
+    }
+
+    #[test]
+    fn locks_on_best_master() {
+        let clock = PtpClock::default();
+        let mut slave = Slave::new(LOCAL, clock.clone());
+        let now = Instant::now();
+        assert_eq!(PtpLock::Unlocked, clock.lock_state());
+
+        slave.on_message(announce(port(2), 250), MASTER, local(0), now);
+        slave.on_message(announce(port(1), 128), MASTER, local(0), now);
+        assert_eq!(Some(port(1)), slave.master);
+        assert_eq!(PtpLock::Locking, clock.lock_state());
+        assert_eq!(None, clock.master_offset());
+
+        // Sync of the other clock is ignored
+        let sync = message(port(2), 1, Body::Sync { origin: ms(0) });
+        assert_eq!(None, slave.on_message(sync, MASTER, local(0), now));
+
+        lock_on(&mut slave, port(1), 500);
+        assert_eq!(expected_offset(500), clock.estimate().offset_ns);
+        assert!(clock.master_offset().is_some());
+    }
+
+    #[test]
+    fn master_picked_among_peers() {
+        let mut slave = Slave::new(LOCAL, PtpClock::default());
+        let now = Instant::now();
+        let other = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 3).into(), GENERAL_PORT);
+        *slave.peers.lock().unwrap() = vec![MASTER_IP];
+
+        slave.on_message(announce(port(2), 250), MASTER, local(0), now);
+        slave.on_message(announce(port(1), 128), other, local(0), now);
+        assert_eq!(Some(port(2)), slave.master);
+
+        // Better one is followed once any clock is
+        slave.peers.lock().unwrap().clear();
+        slave.expire(now);
+        assert_eq!(Some(port(1)), slave.master);
+    }
+
+    #[test]
+    fn link_local_master_keeps_scope() {
+        let mut slave = Slave::new(LOCAL, PtpClock::default());
+        let now = Instant::now();
+        let ip = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 2);
+        let from = SocketAddr::V6(SocketAddrV6::new(ip, GENERAL_PORT, 0, 4));
+        slave.on_message(announce(port(1), 128), from, local(0), now);
+
+        let sync = message(port(1), 1, Body::Sync { origin: ms(0) });
+        assert_eq!(
+            Some(SocketAddr::V6(SocketAddrV6::new(ip, EVENT_PORT, 0, 4))),
+            slave.on_message(sync, from, local(1), now)
+        );
+    }
+
+    #[test]
+    fn two_step_sync() {
+        let mut slave = Slave::new(LOCAL, PtpClock::default());
+        let now = Instant::now();
+        slave.on_message(announce(port(1), 128), MASTER, local(0), now);
+
+        let sync = Message {
+            two_step: true,
+            correction_ns: 1_000_000,
+            ..message(port(1), 5, Body::Sync { origin: ms(0) })
+        };
+        assert_eq!(None, slave.on_message(sync, MASTER, local(1), now));
+
+        // Follow_Up of another Sync doesn't complete it
+        let follow_up = |seq| {
+            message(
+                port(1),
+                seq,
+                Body::FollowUp {
+                    precise_origin: ms(-1),
+                },
+            )
+        };
+        assert_eq!(None, slave.on_message(follow_up(4), MASTER, local(2), now));
+        assert!(
+            slave
+                .on_message(follow_up(5), MASTER, local(2), now)
+                .is_some()
+        );
+        assert_eq!(
+            Some((NtpTimestamp::from_nanos(ms(0)), local(1))),
+            slave.sync
+        );
+    }
+
+    #[test]
+    fn relocks_on_master_change() {
+        let timing = TimingMonitor::default();
+        let clock = PtpClock::new(timing.clone());
+        let mut slave = Slave::new(LOCAL, clock.clone());
+        let now = Instant::now();
+
+        slave.on_message(announce(port(1), 128), MASTER, local(0), now);
+        assert_eq!(TimingState::Syncing, timing.status().state);
+        lock_on(&mut slave, port(1), 500);
+
+        // Better master shows up with an unrelated timescale
+        slave.on_message(announce(port(3), 1), MASTER, local(0), now);
+        assert_eq!(Some(port(3)), slave.master);
+        assert_eq!(PtpLock::Locking, clock.lock_state());
+        assert_eq!(None, clock.master_offset());
+        assert_eq!(TimingState::Syncing, timing.status().state);
+
+        lock_on(&mut slave, port(3), -7000);
+        assert_eq!(expected_offset(-7000), clock.estimate().offset_ns);
+        assert!(matches!(
+            timing.status().state,
+            TimingState::Locked { offset_ns, .. } if offset_ns == expected_offset(-7000)
+        ));
+
+        // Master stops announcing, the previous one is gone too
+        slave.expire(now + ANNOUNCE_TIMEOUT);
+        assert_eq!(None, slave.master);
+        assert_eq!(PtpLock::Unlocked, clock.lock_state());
+        assert_eq!(TimingState::Lost, timing.status().state);
+    }
+
+    #[test]
+    fn relocks_on_clock_step() {
+        let clock = PtpClock::default();
+        let mut slave = Slave::new(LOCAL, clock.clone());
+        slave.on_message(announce(port(1), 128), MASTER, local(0), Instant::now());
+        lock_on(&mut slave, port(1), 500);
+
+        // Same master jumps by a second
+        exchange(&mut slave, port(1), 1000, 1500);
+        assert_eq!(PtpLock::Locking, clock.lock_state());
+        assert_eq!(expected_offset(1500), clock.estimate().offset_ns);
+    }
+
+    #[tokio::test]
+    async fn locked_waits() {
+        let clock = PtpClock::default();
+        let mut slave = Slave::new(LOCAL, clock.clone());
+        slave.on_message(announce(port(1), 128), MASTER, local(0), Instant::now());
+
+        let waiting = tokio::time::timeout(Duration::from_millis(10), clock.locked());
+        assert!(waiting.await.is_err());
+        lock_on(&mut slave, port(1), 0);
+        clock.locked().await;
+    }
+}
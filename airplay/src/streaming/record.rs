@@ -0,0 +1,39 @@
+//! Start of realtime audio announced by the sender in RECORD.
+//!
+//! The sequence number and RTP timestamp of the first packet are the baseline of reordering and
+//! of the playout, senders omitting them have their first packet taken for it instead.
+
+use std::sync::Mutex;
+
+use tokio::sync::Notify;
+
+/// `RTP-Info` of RECORD.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordStart {
+    pub seq: u16,
+    pub rtptime: Option<u32>,
+}
+
+/// The latest start announced for a stream.
+#[derive(Default)]
+pub struct RecordSignal {
+    pending: Mutex<Option<RecordStart>>,
+    notify: Notify,
+}
+
+impl RecordSignal {
+    /// Replaces the start not picked yet.
+    pub fn request(&self, start: RecordStart) {
+        *self.pending.lock().unwrap() = Some(start);
+        self.notify.notify_one();
+    }
+
+    pub async fn next(&self) -> RecordStart {
+        loop {
+            if let Some(start) = self.pending.lock().unwrap().take() {
+                return start;
+            }
+            self.notify.notified().await;
+        }
+    }
+}
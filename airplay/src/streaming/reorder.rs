@@ -0,0 +1,210 @@
+//! Restoring order of packets by their 16 bits RTP sequence number.
+//!
+//! Packets ahead of the next expected one are held until the missing ones arrive, the buffer
+//! overflows or the oldest held packet waits longer than the deadline. Missing packets are given
+//! up then, and the ones arriving after their slot has been released are dropped.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+pub struct ReorderBuffer<T> {
+    depth: usize,
+    deadline: Duration,
+    /// Sequence number of the packet at the front of `slots`
+    next_seq: Option<u16>,
+    slots: VecDeque<Option<(Instant, T)>>,
+}
+
+impl<T> ReorderBuffer<T> {
+    /// Depth of zero or one passes packets through, dropping only late ones.
+    pub fn new(depth: u16, deadline: Duration) -> Self {
+        let depth = usize::from(depth.max(1));
+        Self {
+            depth,
+            deadline,
+            next_seq: None,
+            slots: VecDeque::with_capacity(depth),
+        }
+    }
+
+    pub fn deadline(&self) -> Duration {
+        self.deadline
+    }
+
+    /// Returns `false` if packet is late or duplicated, so it's dropped.
+    pub fn push(&mut self, seq: u16, pkt: T, now: Instant, mut release: impl FnMut(T)) -> bool {
+        let next_seq = *self.next_seq.get_or_insert(seq);
+        let mut offset = usize::from(seq.wrapping_sub(next_seq));
+        // Half of the sequence space behind is considered the past
+        if offset >= 0x8000 {
+            return false;
+        }
+
+        while offset >= self.depth {
+            self.pop_front(&mut release);
+            offset -= 1;
+        }
+
+        if self.slots.len() <= offset {
+            self.slots.resize_with(offset + 1, || None);
+        }
+        if self.slots[offset].is_some() {
+            return false;
+        }
+        self.slots[offset] = Some((now, pkt));
+
+        self.release_ready(&mut release);
+        true
+    }
+
+    /// Expects `seq` next, unless packets past it have already been released. Held packets before
+    /// it are released.
+    pub fn start_at(&mut self, seq: u16, mut release: impl FnMut(T)) {
+        let Some(next_seq) = self.next_seq else {
+            self.next_seq = Some(seq);
+            return;
+        };
+        let offset = usize::from(seq.wrapping_sub(next_seq));
+        if offset >= 0x8000 {
+            return;
+        }
+
+        if offset >= self.slots.len() {
+            self.drain(&mut release);
+            self.next_seq = Some(seq);
+        } else {
+            (0..offset).for_each(|_| self.pop_front(&mut release));
+            self.release_ready(&mut release);
+        }
+    }
+
+    /// Gives up missing packets in front of the ones held longer than the deadline.
+    pub fn release_expired(&mut self, now: Instant, mut release: impl FnMut(T)) {
+        while let Some(held_since) = self.slots.iter().flatten().map(|(since, _)| *since).next() {
+            if now.saturating_duration_since(held_since) < self.deadline {
+                break;
+            }
+            while matches!(self.slots.front(), Some(None)) {
+                self.pop_front(&mut release);
+            }
+            self.release_ready(&mut release);
+        }
+    }
+
+    /// Releases every held packet regardless of gaps, e.g. once the stream has ended.
+    pub fn drain(&mut self, mut release: impl FnMut(T)) {
+        while !self.slots.is_empty() {
+            self.pop_front(&mut release);
+        }
+    }
+
+    fn release_ready(&mut self, release: &mut impl FnMut(T)) {
+        while matches!(self.slots.front(), Some(Some(_))) {
+            self.pop_front(release);
+        }
+    }
+
+    /// Advances by one slot, empty slot means packet is given up.
+    fn pop_front(&mut self, release: &mut impl FnMut(T)) {
+        if let Some((_, pkt)) = self.slots.pop_front().flatten() {
+            release(pkt);
+        }
+        if let Some(seq) = &mut self.next_seq {
+            *seq = seq.wrapping_add(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::ReorderBuffer;
+
+    fn push_all(buf: &mut ReorderBuffer<u16>, seqs: &[u16], now: Instant) -> Vec<u16> {
+        let mut released = Vec::new();
+        for &seq in seqs {
+            buf.push(seq, seq, now, |pkt| released.push(pkt));
+        }
+        released
+    }
+
+    #[test]
+    fn restores_order_across_wrap_around() {
+        let mut buf = ReorderBuffer::new(4, Duration::from_secs(1));
+        let now = Instant::now();
+
+        assert_eq!(
+            vec![65534, 65535, 0, 1, 2],
+            push_all(&mut buf, &[65534, 0, 65535, 2, 1], now)
+        );
+    }
+
+    #[test]
+    fn drops_late_and_duplicated() {
+        let mut buf = ReorderBuffer::new(2, Duration::from_secs(1));
+        let now = Instant::now();
+
+        // 1 is given up once 3 doesn't fit, then arrives late
+        assert_eq!(vec![0, 2, 3], push_all(&mut buf, &[0, 2, 3], now));
+        assert!(!buf.push(1, 1, now, |_| panic!("late packet released")));
+
+        // 5 is held waiting for 4
+        assert!(push_all(&mut buf, &[5], now).is_empty());
+        assert!(!buf.push(5, 5, now, |_| panic!("duplicate released")));
+        assert!(!buf.push(65535, 65535, now, |_| panic!("late packet released")));
+    }
+
+    #[test]
+    fn starts_at_record_seq() {
+        let mut buf = ReorderBuffer::new(4, Duration::from_secs(1));
+        let now = Instant::now();
+
+        // First packet doesn't move the start, so it waits for the ones before it
+        buf.start_at(10, |_| panic!("nothing held"));
+        assert!(push_all(&mut buf, &[11], now).is_empty());
+        assert_eq!(vec![10, 11], push_all(&mut buf, &[10], now));
+
+        // Later start skips missing packets, an earlier one is ignored
+        assert!(push_all(&mut buf, &[14], now).is_empty());
+        let mut released = Vec::new();
+        buf.start_at(14, |pkt| released.push(pkt));
+        buf.start_at(5, |pkt| released.push(pkt));
+        assert_eq!(vec![14], released);
+        assert_eq!(vec![15], push_all(&mut buf, &[15], now));
+
+        buf.start_at(1000, |_| panic!("nothing held"));
+        assert!(!buf.push(999, 999, now, |_| panic!("late packet released")));
+        assert_eq!(vec![1000], push_all(&mut buf, &[1000], now));
+    }
+
+    #[test]
+    fn releases_after_deadline() {
+        let deadline = Duration::from_millis(20);
+        let mut buf = ReorderBuffer::new(8, deadline);
+        let now = Instant::now();
+
+        assert_eq!(vec![10], push_all(&mut buf, &[10, 12, 13], now));
+
+        let mut released = Vec::new();
+        buf.release_expired(now + deadline / 2, |pkt| released.push(pkt));
+        assert!(released.is_empty());
+        buf.release_expired(now + deadline, |pkt| released.push(pkt));
+        assert_eq!(vec![12, 13], released);
+
+        assert_eq!(vec![14], push_all(&mut buf, &[11, 14], now));
+    }
+
+    #[test]
+    fn drains_held() {
+        let mut buf = ReorderBuffer::new(8, Duration::from_secs(1));
+        let now = Instant::now();
+        push_all(&mut buf, &[1, 3, 5], now);
+
+        let mut released = Vec::new();
+        buf.drain(|pkt| released.push(pkt));
+        assert_eq!(vec![3, 5], released);
+    }
+}
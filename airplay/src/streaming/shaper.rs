@@ -0,0 +1,134 @@
+//! Impairment of packets read off the network, for testing how streams cope with a poor one.
+//!
+//! Shapers only do anything with the `testing` feature and
+//! [`Config::ingest_shaping`](crate::config::Config) set, otherwise every packet is admitted
+//! right away.
+
+#[cfg(feature = "testing")]
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "testing")]
+use crate::config::IngestShaping;
+
+/// Decides the fate of each packet a processor reads, kept per stream.
+#[derive(Debug, Default)]
+pub struct IngestShaper {
+    #[cfg(feature = "testing")]
+    inner: Option<Mutex<Shaping>>,
+}
+
+#[cfg(feature = "testing")]
+#[derive(Debug)]
+struct Shaping {
+    cfg: IngestShaping,
+    /// State of xorshift64*, seeded so impaired runs repeat
+    rng: u64,
+    /// Packets read at the max rate aren't read before it
+    next_read: Instant,
+}
+
+impl IngestShaper {
+    #[cfg(feature = "testing")]
+    pub fn new(cfg: IngestShaping) -> Self {
+        let shaping = Shaping {
+            cfg,
+            // Zero state would stay zero
+            rng: cfg.seed | 1,
+            next_read: Instant::now(),
+        };
+        Self {
+            inner: Some(Mutex::new(shaping)),
+        }
+    }
+
+    /// Holds a packet of `len` bytes just read as long as it's delayed, false if it's lost.
+    #[allow(clippy::unused_async)]
+    pub async fn admit(&self, len: usize) -> bool {
+        #[cfg(feature = "testing")]
+        if let Some(inner) = &self.inner {
+            let (admitted, until) = inner.lock().unwrap().next(len, Instant::now());
+            tokio::time::sleep_until(until.into()).await;
+            return admitted;
+        }
+
+        let _ = len;
+        true
+    }
+}
+
+#[cfg(feature = "testing")]
+impl Shaping {
+    /// Whether the packet is admitted and when it's let through.
+    #[allow(clippy::cast_precision_loss)]
+    fn next(&mut self, len: usize, now: Instant) -> (bool, Instant) {
+        let lost = self.uniform() < self.cfg.loss;
+        let jitter = self.cfg.jitter.mul_f64(self.uniform());
+
+        let mut until = now.max(self.next_read);
+        if self.cfg.max_rate != 0 {
+            self.next_read = until + Duration::from_secs_f64(len as f64 / self.cfg.max_rate as f64);
+        }
+        until += jitter;
+        (!lost, until)
+    }
+
+    /// Uniformly distributed in `0.0..1.0`.
+    #[allow(clippy::cast_precision_loss)]
+    fn uniform(&mut self) -> f64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let next = self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        // Top 53 bits fill the mantissa exactly
+        (next >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::IngestShaper;
+    use crate::config::IngestShaping;
+
+    #[test]
+    fn fraction_lost() {
+        let shaper = IngestShaper::new(IngestShaping {
+            loss: 0.25,
+            seed: 7,
+            ..Default::default()
+        });
+        let mut shaping = shaper.inner.unwrap().into_inner().unwrap();
+        let now = Instant::now();
+        let admitted = (0..4000).filter(|_| shaping.next(100, now).0).count();
+        assert!((2800..3200).contains(&admitted), "{admitted}");
+    }
+
+    #[test]
+    fn rate_limited() {
+        let shaper = IngestShaper::new(IngestShaping {
+            max_rate: 1000,
+            ..Default::default()
+        });
+        let mut shaping = shaper.inner.unwrap().into_inner().unwrap();
+        let now = Instant::now();
+
+        // Each packet waits out the ones read before it
+        for (len, expected) in [(100, 0), (200, 100), (100, 300)] {
+            let (admitted, until) = shaping.next(len, now);
+            assert!(admitted);
+            assert_eq!(Duration::from_millis(expected), until - now);
+        }
+    }
+
+    #[tokio::test]
+    async fn disabled_admits_everything() {
+        let shaper = IngestShaper::default();
+        for _ in 0..16 {
+            assert!(shaper.admit(1500).await);
+        }
+    }
+}
@@ -0,0 +1,785 @@
+//! NTP-like timing of realtime audio, used to map the sender's clock onto the local one.
+//!
+//! Receiver sends requests to the sender's timing port and answers the ones the sender sends
+//! to ours, both have the same layout:
+//! ```text
+//! | 0x80 | PT(8) | seq(16) | 0(32) | reference(64) | receive(64) | transmit(64) |
+//! ```
+//! Reply carries transmit time of the request as the reference one. Requests go to the
+//! address the sender connected from, or to the one of its first request if it's unknown.
+//!
+//! Sync is reported through [`TimingMonitor`], shared with the PTP clock, as only one of them
+//! times a session.
+
+use std::{
+    collections::VecDeque,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{net::UdpSocket, sync::watch};
+
+use super::{
+    clock::Clock,
+    ptp::{PtpClock, PtpLock},
+};
+use crate::util::{sync::WakerFlag, task::TaskSet};
+
+const REQUEST: u8 = 0xd2;
+const REPLY: u8 = 0xd3;
+const PACKET_LEN: usize = 32;
+
+const REQUEST_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Samples the offset is picked from, the one with the smallest round trip wins
+const SAMPLES: usize = 8;
+
+/// Drift of either clock that's assumed when no reply came, in parts per million
+const MAX_DRIFT_PPM: f64 = 500.0;
+
+/// Replies measured before the offset is trusted
+const LOCK_SAMPLES: usize = 2;
+
+/// Time without any reply that loses the sync, a few requests missed in a row
+const LOST_AFTER: Duration = Duration::from_secs(10);
+
+const NANOS_PER_SEC: i128 = 1_000_000_000;
+
+/// Seconds since 1900 in the upper half and fraction of a second in the lower one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct NtpTimestamp(pub u64);
+
+/// Estimate of the sender's clock relative to the local one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockEstimate {
+    /// Remote time minus local one at `updated_at`, in nanoseconds
+    pub offset_ns: i64,
+    /// How much faster the remote clock runs, in parts per million
+    pub skew_ppm: f64,
+    /// Half width of the interval the true offset lies in
+    pub uncertainty: Duration,
+    /// Local time of the last accepted reply, `None` until the first one
+    pub updated_at: Option<NtpTimestamp>,
+}
+
+/// Sync of the sender's clock, NTP one of realtime audio or PTP one of buffered audio.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimingState {
+    /// No timing client is running, or the PTP one hasn't heard of a master
+    Unsynced,
+    /// Client is running, but hasn't measured enough to trust the offset yet
+    Syncing,
+    Locked {
+        /// Sender's time minus the local one, in nanoseconds
+        offset_ns: i64,
+        /// Half width of the interval the true offset lies in
+        jitter: Duration,
+    },
+    /// Was locked until the sender stopped answering or its clock went away
+    Lost,
+}
+
+/// Clock the session is timed by, negotiated in sender info.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimingMode {
+    /// Sender's clock polled by the receiver, of realtime audio
+    Ntp,
+    /// Clock of the PTP master, of buffered audio
+    Ptp,
+}
+
+/// [`TimingState`] along with the last time it was locked.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimingStatus {
+    pub state: TimingState,
+    /// Local time of the last exchange the offset was updated with
+    pub last_sync: Option<SystemTime>,
+}
+
+/// Publishes [`TimingStatus`] of the session, watched by the app and the realtime playout.
+#[derive(Clone)]
+pub struct TimingMonitor(Arc<watch::Sender<TimingStatus>>);
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    local: NtpTimestamp,
+    offset_ns: i64,
+    delay_ns: i64,
+}
+
+/// Keeps the offset estimate steady: missed replies only widen its uncertainty and every new
+/// sample is filtered by the round trip time.
+#[derive(Debug)]
+pub(super) struct ClockEstimator {
+    samples: VecDeque<Sample>,
+    estimate: ClockEstimate,
+}
+
+/// Client of the sender's timing port, also answering the sender's own requests.
+pub struct NtpTimingClient {
+    local_addr: SocketAddr,
+    clock: SenderClock,
+    waker_flag: Arc<WakerFlag>,
+}
+
+/// Shared view of the estimate kept up to date by [`NtpTimingClient`].
+#[derive(Clone)]
+pub struct SenderClock(Arc<Mutex<ClockEstimator>>);
+
+/// Clock the sender's timestamps are in, either of [`TimingMode`].
+#[derive(Clone)]
+pub enum SenderTimeline {
+    Ntp(SenderClock),
+    Ptp(PtpClock),
+}
+
+impl NtpTimestamp {
+    /// Seconds between 1900 and 1970
+    const UNIX_OFFSET: u64 = 2_208_988_800;
+
+    pub fn now() -> Self {
+        SystemTime::now().into()
+    }
+
+    pub(super) fn as_nanos(self) -> i128 {
+        let secs = i128::from(self.0 >> 32);
+        let frac = i128::from(self.0 & 0xffff_ffff);
+        secs * NANOS_PER_SEC + ((frac * NANOS_PER_SEC + (1 << 31)) >> 32)
+    }
+
+    pub(super) fn from_nanos(nanos: i128) -> Self {
+        let secs = nanos.div_euclid(NANOS_PER_SEC);
+        let frac = ((nanos.rem_euclid(NANOS_PER_SEC) << 32) + NANOS_PER_SEC / 2) / NANOS_PER_SEC;
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        Self(((secs as u64) << 32) | frac as u64)
+    }
+
+    /// Signed difference `self - other` in nanoseconds
+    pub(super) fn diff_ns(self, other: Self) -> i128 {
+        self.as_nanos() - other.as_nanos()
+    }
+
+    pub(super) fn after(self, duration: Duration) -> Self {
+        let nanos = i128::try_from(duration.as_nanos()).unwrap_or(i128::MAX);
+        Self::from_nanos(self.as_nanos().saturating_add(nanos))
+    }
+}
+
+impl From<SystemTime> for NtpTimestamp {
+    fn from(time: SystemTime) -> Self {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Self::from_nanos(
+            i128::from(since_epoch.as_secs() + Self::UNIX_OFFSET) * NANOS_PER_SEC
+                + i128::from(since_epoch.subsec_nanos()),
+        )
+    }
+}
+
+impl From<NtpTimestamp> for SystemTime {
+    fn from(time: NtpTimestamp) -> Self {
+        let nanos = time.as_nanos() - i128::from(NtpTimestamp::UNIX_OFFSET) * NANOS_PER_SEC;
+        // Times before 1970 aren't sent by anyone, they're clamped to it
+        let nanos = u64::try_from(nanos).unwrap_or_default();
+        UNIX_EPOCH + Duration::from_nanos(nanos)
+    }
+}
+
+impl ClockEstimate {
+    /// Offset extrapolated with the skew to local time `at`.
+    pub fn offset_at(self, at: NtpTimestamp) -> i64 {
+        let Some(updated_at) = self.updated_at else {
+            return self.offset_ns;
+        };
+
+        #[allow(clippy::cast_precision_loss, clippy::cast_possible_truncation)]
+        let drift = (at.diff_ns(updated_at) as f64 * self.skew_ppm / 1e6) as i64;
+        self.offset_ns.saturating_add(drift)
+    }
+
+    pub(super) fn locked(self) -> TimingState {
+        TimingState::Locked {
+            offset_ns: self.offset_ns,
+            jitter: self.uncertainty,
+        }
+    }
+
+    /// Maps the sender's time, e.g. of time sync packets, onto the local clock.
+    pub fn to_local(self, remote: NtpTimestamp) -> NtpTimestamp {
+        // Skew is tiny, so extrapolating from the remote time instead of the local one is fine
+        let offset = self.offset_at(remote);
+        NtpTimestamp::from_nanos(remote.as_nanos() - i128::from(offset))
+    }
+}
+
+impl TimingMonitor {
+    pub fn status(&self) -> TimingStatus {
+        *self.0.borrow()
+    }
+
+    /// Receiver notified of every change, including the offset of a locked clock.
+    pub fn subscribe(&self) -> watch::Receiver<TimingStatus> {
+        self.0.subscribe()
+    }
+
+    pub(crate) fn set(&self, state: TimingState) {
+        self.0.send_if_modified(|status| {
+            let locked = matches!(state, TimingState::Locked { .. });
+            if locked {
+                status.last_sync = Some(SystemTime::now());
+            } else if status.state == state {
+                return false;
+            }
+            status.state = state;
+            true
+        });
+    }
+
+    /// Loses the sync if it's locked, other states are left as is.
+    pub(super) fn lose(&self) {
+        if matches!(self.status().state, TimingState::Locked { .. }) {
+            tracing::warn!("timing sync lost");
+            self.set(TimingState::Lost);
+        }
+    }
+
+    pub(crate) fn is_lost(&self) -> bool {
+        self.status().state == TimingState::Lost
+    }
+
+    /// Resolves once the sync isn't lost, right away unless it is.
+    pub(crate) async fn not_lost(&self) {
+        let mut status = self.0.subscribe();
+        // Sender is owned by self, so it can't be closed
+        let _ = status
+            .wait_for(|status| status.state != TimingState::Lost)
+            .await;
+    }
+}
+
+impl Default for TimingMonitor {
+    fn default() -> Self {
+        Self(Arc::new(watch::Sender::new(TimingStatus {
+            state: TimingState::Unsynced,
+            last_sync: None,
+        })))
+    }
+}
+
+impl Default for ClockEstimate {
+    fn default() -> Self {
+        Self {
+            offset_ns: 0,
+            skew_ppm: 0.0,
+            uncertainty: Duration::MAX,
+            updated_at: None,
+        }
+    }
+}
+
+impl ClockEstimator {
+    pub(super) fn new() -> Self {
+        Self {
+            samples: VecDeque::with_capacity(SAMPLES),
+            estimate: ClockEstimate::default(),
+        }
+    }
+
+    /// Takes times of a single exchange: request sent (`t1`), received by the sender (`t2`),
+    /// reply sent by the sender (`t3`) and received (`t4`).
+    pub(super) fn on_reply(
+        &mut self,
+        t1: NtpTimestamp,
+        t2: NtpTimestamp,
+        t3: NtpTimestamp,
+        t4: NtpTimestamp,
+    ) {
+        let delay = t4.diff_ns(t1) - t3.diff_ns(t2);
+        if delay < 0 {
+            tracing::debug!(%delay, "timing reply with negative round trip");
+            return;
+        }
+
+        let offset = i128::midpoint(t2.diff_ns(t1), t3.diff_ns(t4));
+        let (Ok(offset_ns), Ok(delay_ns)) = (i64::try_from(offset), i64::try_from(delay)) else {
+            tracing::debug!(%offset, %delay, "timing reply out of range");
+            return;
+        };
+
+        if self.samples.len() == SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(Sample {
+            local: t4,
+            offset_ns,
+            delay_ns,
+        });
+
+        let best = *self
+            .samples
+            .iter()
+            .min_by_key(|sample| sample.delay_ns)
+            .unwrap();
+
+        let prev = self.estimate;
+        if let Some(updated_at) = prev.updated_at {
+            let elapsed = best.local.diff_ns(updated_at);
+            if elapsed > NANOS_PER_SEC {
+                #[allow(clippy::cast_precision_loss)]
+                let skew = (best.offset_ns - prev.offset_ns) as f64 / elapsed as f64 * 1e6;
+                self.estimate.skew_ppm =
+                    (prev.skew_ppm * 3.0 + skew.clamp(-MAX_DRIFT_PPM, MAX_DRIFT_PPM)) / 4.0;
+            }
+        }
+
+        #[allow(clippy::cast_sign_loss)]
+        let half_delay = Duration::from_nanos(best.delay_ns as u64 / 2);
+        self.estimate.offset_ns = best.offset_ns;
+        self.estimate.uncertainty = half_delay;
+        self.estimate.updated_at = Some(best.local);
+    }
+
+    pub(super) fn estimate(&self) -> ClockEstimate {
+        self.estimate
+    }
+
+    /// Of the replies measured so far, PTP tells locking apart on its own.
+    fn timing_state(&self) -> TimingState {
+        if self.samples.len() < LOCK_SAMPLES {
+            TimingState::Syncing
+        } else {
+            self.estimate.locked()
+        }
+    }
+
+    /// Widens the uncertainty by what the clocks could have drifted apart since the last
+    /// request, the offset itself is left as is.
+    fn on_missed(&mut self, elapsed: Duration) {
+        let drift = elapsed.mul_f64(MAX_DRIFT_PPM / 1e6);
+        self.estimate.uncertainty = self.estimate.uncertainty.saturating_add(drift);
+    }
+}
+
+impl NtpTimingClient {
+    /// Reports sync to `timing` until dropped, the one taking over the session resets it.
+    ///
+    /// Requests go to `remote_port` of `remote`, the sender that connected, right away. Without
+    /// it, they wait for the first request of the sender to learn its address.
+    pub fn create(
+        socket: UdpSocket,
+        remote: Option<SocketAddr>,
+        remote_port: u16,
+        timing: TimingMonitor,
+        local_clock: impl Clock,
+        tasks: &TaskSet,
+    ) -> io::Result<Self> {
+        let local_addr = socket.local_addr()?;
+        let clock = SenderClock(Arc::new(Mutex::new(ClockEstimator::new())));
+        let waker_flag = Arc::new(WakerFlag::default());
+        timing.set(TimingState::Syncing);
+
+        let (est, wf) = (Arc::clone(&clock.0), Arc::clone(&waker_flag));
+        let cancelled = tasks.cancelled();
+        tasks.spawn(async move {
+            tokio::select! {
+                () = &*wf => {}
+                () = cancelled => {}
+                res = run(&socket, remote, remote_port, &est, &timing, &local_clock) => {
+                    if let Err(err) = res {
+                        tracing::error!(%err, "timing client failed");
+                    }
+                }
+            };
+            tracing::info!("timing client done");
+        });
+
+        Ok(Self {
+            local_addr,
+            clock,
+            waker_flag,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn clock(&self) -> SenderClock {
+        self.clock.clone()
+    }
+}
+
+impl SenderClock {
+    pub fn estimate(&self) -> ClockEstimate {
+        self.0.lock().unwrap().estimate()
+    }
+}
+
+impl SenderTimeline {
+    /// Local time of the sender's `remote` one, `None` until the clock is synced.
+    pub fn to_local(&self, remote: NtpTimestamp) -> Option<SystemTime> {
+        let estimate = match self {
+            Self::Ntp(clock) => Some(clock.estimate()).filter(|est| est.updated_at.is_some()),
+            Self::Ptp(clock) => (clock.lock_state() == PtpLock::Locked).then(|| clock.estimate()),
+        }?;
+        Some(estimate.to_local(remote).into())
+    }
+}
+
+impl Drop for NtpTimingClient {
+    fn drop(&mut self) {
+        self.waker_flag.set_and_wake();
+    }
+}
+
+async fn run(
+    socket: &UdpSocket,
+    remote: Option<SocketAddr>,
+    remote_port: u16,
+    estimator: &Mutex<ClockEstimator>,
+    timing: &TimingMonitor,
+    local_clock: &impl Clock,
+) -> io::Result<()> {
+    // Requests are spaced apart by the interval, however late the previous one was
+    let mut next_request = local_clock.now();
+
+    // Sender's address along with the scope of link-local one, requests go to its timing port
+    let mut remote = remote;
+    let mut seq = 0u16;
+    // Transmit time of the request still waiting for reply
+    let mut pending = None;
+    // Local time of the last accepted reply, none while there's no sync to lose
+    let mut last_reply: Option<NtpTimestamp> = None;
+    let mut buf = [0u8; 128];
+
+    loop {
+        let lost_at = last_reply.map(|at| at.after(LOST_AFTER));
+        tokio::select! {
+            () = local_clock.sleep_until(next_request) => {
+                next_request = local_clock.now().after(REQUEST_INTERVAL);
+                let Some(mut remote) = remote else {
+                    continue;
+                };
+                remote.set_port(remote_port);
+                if pending.is_some() {
+                    tracing::debug!("timing reply missed");
+                    estimator.lock().unwrap().on_missed(REQUEST_INTERVAL);
+                }
+
+                let now = local_clock.now();
+                let packet = encode(REQUEST, seq, NtpTimestamp(0), NtpTimestamp(0), now);
+                socket.send_to(&packet, remote).await?;
+                seq = seq.wrapping_add(1);
+                pending = Some(now);
+            }
+            // Also when requests can't be sent, e.g. the sender went away with its address
+            () = local_clock.sleep_until(lost_at.unwrap_or(next_request)), if lost_at.is_some() => {
+                tracing::debug!(?LOST_AFTER, "no timing reply");
+                last_reply = None;
+                timing.lose();
+            }
+            res = socket.recv_from(&mut buf) => {
+                let (len, addr) = res?;
+                let received = local_clock.now();
+                let Some((ty, seq, [reference, receive, transmit])) = decode(&buf[..len]) else {
+                    tracing::debug!(%len, "malformed timing packet");
+                    continue;
+                };
+
+                match ty {
+                    REQUEST => {
+                        remote.get_or_insert(addr);
+                        let reply = encode(REPLY, seq, transmit, received, local_clock.now());
+                        socket.send_to(&reply, addr).await?;
+                    }
+                    // Late replies to already missed requests are dropped
+                    REPLY if pending == Some(reference) => {
+                        pending = None;
+                        last_reply = Some(received);
+                        let mut estimator = estimator.lock().unwrap();
+                        estimator.on_reply(reference, receive, transmit, received);
+                        timing.set(estimator.timing_state());
+                    }
+                    REPLY => tracing::debug!("unexpected timing reply"),
+                    ty => tracing::debug!(%ty, "unknown timing packet"),
+                }
+            }
+        }
+    }
+}
+
+fn encode(
+    ty: u8,
+    seq: u16,
+    reference: NtpTimestamp,
+    receive: NtpTimestamp,
+    transmit: NtpTimestamp,
+) -> [u8; PACKET_LEN] {
+    let mut packet = [0; PACKET_LEN];
+    packet[0] = 0x80;
+    packet[1] = 0x80 | ty;
+    packet[2..4].copy_from_slice(&seq.to_be_bytes());
+    packet[8..16].copy_from_slice(&reference.0.to_be_bytes());
+    packet[16..24].copy_from_slice(&receive.0.to_be_bytes());
+    packet[24..32].copy_from_slice(&transmit.0.to_be_bytes());
+    packet
+}
+
+fn decode(buf: &[u8]) -> Option<(u8, u16, [NtpTimestamp; 3])> {
+    let packet = buf.first_chunk::<PACKET_LEN>()?;
+    let timestamp =
+        |pos: usize| NtpTimestamp(u64::from_be_bytes(packet[pos..pos + 8].try_into().unwrap()));
+
+    Some((
+        packet[1] | 0x80,
+        u16::from_be_bytes([packet[2], packet[3]]),
+        [timestamp(8), timestamp(16), timestamp(24)],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use tokio::net::UdpSocket;
+
+    use super::{
+        ClockEstimate, ClockEstimator, LOCK_SAMPLES, LOST_AFTER, NtpTimestamp, PACKET_LEN, REPLY,
+        REQUEST, REQUEST_INTERVAL, SenderClock, SenderTimeline, TimingMonitor, TimingState, decode,
+        encode, run,
+    };
+    use crate::streaming::clock::MockClock;
+
+    fn ms(ms: i128) -> NtpTimestamp {
+        NtpTimestamp::from_nanos(1_000_000_000_000_000_000 + ms * 1_000_000)
+    }
+
+    #[test]
+    fn timestamp_conversion() {
+        let time = ms(1500);
+        assert_eq!(0x8000_0000, time.0 & 0xffff_ffff);
+        assert_eq!(1_500_000_000, time.diff_ns(ms(0)));
+        assert_eq!(-1_500_000_000, ms(0).diff_ns(time));
+    }
+
+    #[test]
+    fn sender_time_to_local() {
+        let estimator = Arc::new(Mutex::new(ClockEstimator::new()));
+        let timeline = SenderTimeline::Ntp(SenderClock(Arc::clone(&estimator)));
+        let sender_time = NtpTimestamp::from(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        assert_eq!(None, timeline.to_local(sender_time));
+
+        // Sender is 250 ms ahead
+        estimator.lock().unwrap().estimate = ClockEstimate {
+            offset_ns: 250_000_000,
+            updated_at: Some(sender_time),
+            ..ClockEstimate::default()
+        };
+        let local = UNIX_EPOCH + Duration::from_millis(1_699_999_999_750);
+        assert_eq!(Some(local), timeline.to_local(sender_time));
+        assert_eq!(local, SystemTime::from(NtpTimestamp::from(local)));
+    }
+
+    #[test]
+    fn packet_roundtrip() {
+        let packet = encode(REQUEST, 7, ms(1), ms(2), ms(3));
+        assert_eq!(&[0x80, 0xd2, 0, 7], &packet[..4]);
+        assert_eq!(Some((REQUEST, 7, [ms(1), ms(2), ms(3)])), decode(&packet));
+        assert_eq!(None, decode(&packet[..31]));
+    }
+
+    #[test]
+    fn estimate_offset() {
+        let mut estimator = ClockEstimator::new();
+
+        // Remote clock is 100ms ahead, 10ms round trip
+        estimator.on_reply(ms(0), ms(105), ms(105), ms(10));
+        let estimate = estimator.estimate;
+        assert_eq!(100_000_000, estimate.offset_ns);
+        assert_eq!(Duration::from_millis(5), estimate.uncertainty);
+        assert_eq!(ms(500), estimate.to_local(ms(600)));
+
+        // Slower exchange with skewed offset doesn't replace the better one
+        estimator.on_reply(ms(3000), ms(3150), ms(3150), ms(3100));
+        assert_eq!(100_000_000, estimator.estimate.offset_ns);
+        assert_eq!(Duration::from_millis(5), estimator.estimate.uncertainty);
+    }
+
+    #[tokio::test]
+    async fn requests_sent_at_clock_ticks() {
+        let clock = MockClock::new(ms(0));
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sender.connect(socket.local_addr().unwrap()).await.unwrap();
+        let estimator = Mutex::new(ClockEstimator::new());
+        let timing = TimingMonitor::default();
+        let remote_port = sender.local_addr().unwrap().port();
+        let client = run(&socket, None, remote_port, &estimator, &timing, &clock);
+
+        // Next packet of type `ty`, requests of the first tick may come along
+        let recv = async |ty| loop {
+            let mut buf = [0; PACKET_LEN];
+            let len = sender.recv(&mut buf).await.unwrap();
+            match decode(&buf[..len]) {
+                Some((got, _, times)) if got == ty && times[2] != ms(0) => return times,
+                _ => {}
+            }
+        };
+        let check = async {
+            // Sender's request tells where requests go, it's answered at the virtual time
+            let request = encode(REQUEST, 1, NtpTimestamp(0), NtpTimestamp(0), ms(-5));
+            sender.send(&request).await.unwrap();
+            let mut buf = [0; PACKET_LEN];
+            loop {
+                sender.recv(&mut buf).await.unwrap();
+                if let Some((REPLY, seq, times)) = decode(&buf) {
+                    assert_eq!((1, [ms(-5), ms(0), ms(0)]), (seq, times));
+                    break;
+                }
+            }
+
+            let interval_ms = i128::try_from(REQUEST_INTERVAL.as_millis()).unwrap();
+            clock.advance(REQUEST_INTERVAL);
+            assert_eq!(ms(interval_ms), recv(REQUEST).await[2]);
+            // Missed reply doesn't hold the next request back
+            clock.advance(REQUEST_INTERVAL);
+            assert_eq!(ms(2 * interval_ms), recv(REQUEST).await[2]);
+        };
+
+        tokio::select! {
+            res = client => panic!("client finished: {res:?}"),
+            () = check => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_sent_to_known_sender() {
+        let clock = MockClock::new(ms(0));
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let estimator = Mutex::new(ClockEstimator::new());
+        let timing = TimingMonitor::default();
+        // Port of the RTSP connection is replaced with the timing one
+        let mut remote = sender.local_addr().unwrap();
+        let remote_port = remote.port();
+        remote.set_port(1);
+        let client = run(
+            &socket,
+            Some(remote),
+            remote_port,
+            &estimator,
+            &timing,
+            &clock,
+        );
+
+        // First request goes out before the sender sent any
+        let check = async {
+            let mut buf = [0; PACKET_LEN];
+            let len = sender.recv(&mut buf).await.unwrap();
+            assert!(matches!(decode(&buf[..len]), Some((REQUEST, 0, _))));
+        };
+
+        tokio::select! {
+            res = client => panic!("client finished: {res:?}"),
+            () = check => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn lost_without_replies() {
+        let clock = MockClock::new(ms(0));
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sender.connect(socket.local_addr().unwrap()).await.unwrap();
+        let estimator = Mutex::new(ClockEstimator::new());
+        let timing = TimingMonitor::default();
+        let remote = sender.local_addr().unwrap();
+        let client = run(
+            &socket,
+            Some(remote),
+            remote.port(),
+            &estimator,
+            &timing,
+            &clock,
+        );
+
+        let check = async {
+            let mut changes = timing.subscribe();
+            // Replies to the requests of two ticks lock the sync
+            for _ in 0..LOCK_SAMPLES {
+                let mut buf = [0; PACKET_LEN];
+                let len = sender.recv(&mut buf).await.unwrap();
+                let Some((REQUEST, seq, [.., transmit])) = decode(&buf[..len]) else {
+                    panic!("not a request");
+                };
+                let reply = encode(REPLY, seq, transmit, ms(100), ms(100));
+                sender.send(&reply).await.unwrap();
+                let _ = changes.changed().await;
+                clock.advance(REQUEST_INTERVAL);
+            }
+            assert!(matches!(
+                changes.borrow_and_update().state,
+                TimingState::Locked { .. }
+            ));
+
+            // Requests go unanswered from now on
+            clock.advance(LOST_AFTER);
+            let status = changes.wait_for(|status| status.state == TimingState::Lost);
+            assert!(status.await.unwrap().last_sync.is_some());
+        };
+
+        tokio::select! {
+            res = client => panic!("client finished: {res:?}"),
+            () = check => {}
+        }
+    }
+
+    #[test]
+    fn missed_reply_widens_interval() {
+        let mut estimator = ClockEstimator::new();
+        estimator.on_reply(ms(0), ms(105), ms(105), ms(10));
+
+        estimator.on_missed(Duration::from_secs(10));
+        assert_eq!(100_000_000, estimator.estimate.offset_ns);
+        assert_eq!(Duration::from_millis(10), estimator.estimate.uncertainty);
+    }
+
+    #[test]
+    fn timing_lost_once_locked() {
+        let timing = TimingMonitor::default();
+        let mut changes = timing.subscribe();
+
+        // Nothing to lose yet
+        timing.lose();
+        assert!(!changes.has_changed().unwrap());
+        timing.set(TimingState::Syncing);
+        timing.lose();
+        assert_eq!(TimingState::Syncing, changes.borrow_and_update().state);
+        assert_eq!(None, timing.status().last_sync);
+
+        let mut estimator = ClockEstimator::new();
+        estimator.on_reply(ms(0), ms(105), ms(105), ms(10));
+        assert_eq!(TimingState::Syncing, estimator.timing_state());
+        estimator.on_reply(ms(3000), ms(3105), ms(3105), ms(3010));
+        let locked = estimator.timing_state();
+        assert_eq!(
+            TimingState::Locked {
+                offset_ns: 100_000_000,
+                jitter: Duration::from_millis(5)
+            },
+            locked
+        );
+
+        timing.set(locked);
+        assert!(changes.has_changed().unwrap());
+        assert!(timing.status().last_sync.is_some());
+        timing.lose();
+        assert_eq!(TimingState::Lost, changes.borrow_and_update().state);
+        assert!(timing.is_lost());
+        assert!(timing.status().last_sync.is_some());
+    }
+}
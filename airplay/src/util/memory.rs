@@ -1,5 +1,10 @@
 use bytes::BytesMut;
 
+/// Allocator of packet buffers carved one after another from a single hunk.
+///
+/// When the hunk runs out, it's reused from the start if every buffer carved from it is
+/// already dropped, otherwise a new hunk is allocated and the old one is freed along with its
+/// last buffer. So allocation never fails and never overwrites packets still in flight.
 pub struct BytesHunk {
     buf: BytesMut,
     size: usize,
@@ -19,9 +24,60 @@ impl BytesHunk {
         }
 
         if self.buf.len() < requested_len {
-            self.buf = BytesMut::zeroed(self.size.max(requested_len));
+            self.buf.clear();
+            let size = self.size.max(requested_len);
+            if self.buf.try_reclaim(size) {
+                self.buf.resize(size, 0);
+            } else {
+                self.buf = BytesMut::zeroed(size);
+            }
         }
 
         self.buf.split_to(requested_len)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::BytesHunk;
+
+    #[test]
+    fn overflow_keeps_in_flight_buffers() {
+        const SIZE: usize = 64;
+
+        let mut hunk = BytesHunk::new(SIZE);
+        let bufs = (0..10u8)
+            .map(|i| {
+                let mut buf = hunk.allocate_buf(usize::from(i) + 20);
+                buf.fill(i);
+                buf
+            })
+            .collect::<Vec<_>>();
+
+        assert!(bufs.iter().map(bytes::BytesMut::len).sum::<usize>() > SIZE);
+        for (i, buf) in (0..10u8).zip(&bufs) {
+            assert_eq!(usize::from(i) + 20, buf.len());
+            assert!(buf.iter().all(|byte| *byte == i));
+        }
+
+        let large = hunk.allocate_buf(SIZE * 2);
+        assert_eq!(SIZE * 2, large.len());
+    }
+
+    #[test]
+    fn reuse_freed_hunk() {
+        let mut hunk = BytesHunk::new(64);
+        let first = hunk.allocate_buf(40);
+        let start = first.as_ptr();
+        drop(first);
+
+        // The rest is too short, but the hunk is free again
+        let second = hunk.allocate_buf(40);
+        assert_eq!(start, second.as_ptr());
+        assert!(second.iter().all(|byte| *byte == 0));
+
+        // Not free anymore
+        let third = hunk.allocate_buf(40);
+        assert_ne!(start, third.as_ptr());
+    }
+}
@@ -1,2 +1,5 @@
 pub mod memory;
+pub mod net;
+pub mod redact;
 pub mod sync;
+pub mod task;
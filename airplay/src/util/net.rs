@@ -0,0 +1,162 @@
+use std::{
+    future::Future,
+    io,
+    net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6},
+};
+
+use tokio::net::{TcpListener, UdpSocket};
+
+use crate::config::{PortAllocation, SocketConfig};
+
+pub async fn bind_tcp(cfg: &SocketConfig, local_ip: IpAddr) -> io::Result<TcpListener> {
+    bind(cfg, local_ip, TcpListener::bind).await
+}
+
+pub async fn bind_udp(cfg: &SocketConfig, local_ip: IpAddr) -> io::Result<UdpSocket> {
+    bind(cfg, local_ip, UdpSocket::bind).await
+}
+
+/// Unspecified address is replaced with the one of the family senders connected with,
+/// as IPv4 one doesn't accept IPv6 connections.
+fn bind_ip(cfg: &SocketConfig, local_ip: IpAddr) -> IpAddr {
+    match (cfg.bind_ip, local_ip) {
+        (ip, IpAddr::V6(_)) if ip.is_unspecified() => Ipv6Addr::UNSPECIFIED.into(),
+        (ip, IpAddr::V4(_)) if ip.is_unspecified() => Ipv4Addr::UNSPECIFIED.into(),
+        (ip, _) => ip,
+    }
+}
+
+/// Index of the interface `local_addr` was reached on, zero unless it's a scoped IPv6 one.
+pub fn scope_id(local_addr: SocketAddr) -> u32 {
+    match local_addr {
+        SocketAddr::V6(addr) => addr.scope_id(),
+        SocketAddr::V4(_) => 0,
+    }
+}
+
+/// Parses address of a peer sent by the sender, e.g. `fe80::1%4`, into one with zero port.
+///
+/// Link-local IPv6 address without numeric scope, including the one with interface name,
+/// is scoped to the interface the sender connected on, i.e. the one of `local_addr`.
+///
+/// # Errors
+///
+/// If the address without scope isn't a valid IP one.
+pub fn parse_peer_addr(addr: &str, local_addr: SocketAddr) -> Result<SocketAddr, AddrParseError> {
+    let (ip, scope) = match addr.split_once('%') {
+        Some((ip, scope)) => (ip, Some(scope)),
+        None => (addr, None),
+    };
+
+    match ip.parse()? {
+        IpAddr::V6(ip) if ip.is_unicast_link_local() => {
+            let scope_id = scope
+                .and_then(|scope| scope.parse().ok())
+                .unwrap_or_else(|| scope_id(local_addr));
+            Ok(SocketAddrV6::new(ip, 0, 0, scope_id).into())
+        }
+        ip => Ok(SocketAddr::new(ip, 0)),
+    }
+}
+
+async fn bind<S, F>(
+    cfg: &SocketConfig,
+    local_ip: IpAddr,
+    bind: impl Fn(SocketAddr) -> F,
+) -> io::Result<S>
+where
+    F: Future<Output = io::Result<S>>,
+{
+    let ip = bind_ip(cfg, local_ip);
+    let range = match &cfg.ports {
+        PortAllocation::Ephemeral => return bind(SocketAddr::new(ip, 0)).await,
+        PortAllocation::Range(range) => range,
+    };
+
+    for port in range.clone() {
+        match bind(SocketAddr::new(ip, port)).await {
+            Err(err) if err.kind() == io::ErrorKind::AddrInUse => {}
+            res => return res,
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!(
+            "no free port on {ip} in {}..={}",
+            range.start(),
+            range.end()
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    };
+
+    use super::{bind_ip, bind_tcp, bind_udp, parse_peer_addr};
+    use crate::config::{PortAllocation, SocketConfig};
+
+    #[test]
+    fn unspecified_follows_sender_family() {
+        let cfg = SocketConfig::default();
+        let v6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        let v4 = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        assert_eq!(IpAddr::V6(Ipv6Addr::UNSPECIFIED), bind_ip(&cfg, v6));
+        assert_eq!(IpAddr::V4(Ipv4Addr::UNSPECIFIED), bind_ip(&cfg, v4));
+
+        let cfg = SocketConfig {
+            bind_ip: v4,
+            ..Default::default()
+        };
+        assert_eq!(v4, bind_ip(&cfg, v6));
+    }
+
+    #[tokio::test]
+    async fn bind_within_range() {
+        let local = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let free = bind_udp(&SocketConfig::default(), local).await.unwrap();
+        let port = free.local_addr().unwrap().port();
+        drop(free);
+
+        let cfg = SocketConfig {
+            bind_ip: local,
+            ports: PortAllocation::Range(port..=port),
+        };
+        let listener = bind_tcp(&cfg, local).await.unwrap();
+        assert_eq!(port, listener.local_addr().unwrap().port());
+
+        let err = bind_tcp(&cfg, local).await.unwrap_err();
+        assert_eq!(io::ErrorKind::AddrInUse, err.kind());
+        assert!(err.to_string().contains(&format!("{port}..={port}")));
+    }
+
+    #[test]
+    fn peer_addr_scoped_to_connection() {
+        let link_local = "fe80::1".parse::<Ipv6Addr>().unwrap();
+        let local = SocketAddr::V6(SocketAddrV6::new(link_local, 7000, 0, 3));
+        let scoped = |scope_id| SocketAddr::V6(SocketAddrV6::new(link_local, 0, 0, scope_id));
+
+        assert_eq!(Ok(scoped(5)), parse_peer_addr("fe80::1%5", local));
+        assert_eq!(Ok(scoped(3)), parse_peer_addr("fe80::1%en0", local));
+        assert_eq!(Ok(scoped(3)), parse_peer_addr("fe80::1", local));
+        assert_eq!(
+            Ok(SocketAddr::new(Ipv4Addr::new(192, 168, 1, 2).into(), 0)),
+            parse_peer_addr("192.168.1.2", local)
+        );
+        // Global addresses are reached through routing, never a scope
+        assert_eq!(
+            Ok(SocketAddr::new(
+                Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(),
+                0
+            )),
+            parse_peer_addr("2001:db8::1%5", local)
+        );
+        assert!(parse_peer_addr("fe80::zz%5", local).is_err());
+        assert!(parse_peer_addr("", local).is_err());
+    }
+}
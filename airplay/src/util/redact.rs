@@ -0,0 +1,45 @@
+use std::fmt;
+
+/// Bytes next to keys, e.g. nonces and tags, logged only as length and a short digest to
+/// tell them apart. Logged as is with `debug-crypto` feature.
+pub struct Redacted<'a>(pub &'a [u8]);
+
+impl fmt::Debug for Redacted<'_> {
+    #[cfg(feature = "debug-crypto")]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.0, f)
+    }
+
+    #[cfg(not(feature = "debug-crypto"))]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use sha2::{Digest, Sha256};
+
+        let digest = Sha256::digest(self.0);
+        write!(f, "<{} bytes, sha256 ", self.0.len())?;
+        digest[..4]
+            .iter()
+            .try_for_each(|byte| write!(f, "{byte:02x}"))?;
+        write!(f, ">")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Redacted;
+
+    #[test]
+    #[cfg(not(feature = "debug-crypto"))]
+    fn redacted_bytes() {
+        let nonce = [0xab; 12];
+        let logged = format!("{:?}", Redacted(&nonce));
+        assert!(logged.starts_with("<12 bytes, sha256 "));
+        assert!(!logged.contains("ab, ab") && !logged.contains("171"));
+        assert_ne!(logged, format!("{:?}", Redacted(&[0xac; 12])));
+    }
+
+    #[test]
+    #[cfg(feature = "debug-crypto")]
+    fn full_bytes() {
+        assert_eq!("[1, 2]", format!("{:?}", Redacted(&[1, 2])));
+    }
+}
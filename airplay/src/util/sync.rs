@@ -3,8 +3,11 @@ use std::{
     pin::Pin,
     sync::atomic::{AtomicBool, Ordering},
     task::{Context, Poll},
+    time::Duration,
 };
 
+use tokio::time::{Instant, sleep};
+
 use futures::task::AtomicWaker;
 
 pub struct WakerFlag {
@@ -44,3 +47,54 @@ impl Future for &WakerFlag {
         }
     }
 }
+
+/// Resolves once `activity` counter hasn't changed for `timeout`, checked a few times per
+/// timeout so it's overrun by a quarter of it at most.
+pub async fn until_idle(timeout: Duration, activity: impl Fn() -> u64) {
+    let mut last = activity();
+    let mut last_change = Instant::now();
+    loop {
+        sleep(timeout / 4).await;
+
+        let current = activity();
+        if current != last {
+            last = current;
+            last_change = Instant::now();
+        } else if last_change.elapsed() >= timeout {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicU64, Ordering},
+        time::Duration,
+    };
+
+    use tokio::time::{Instant, timeout};
+
+    use super::until_idle;
+
+    #[tokio::test]
+    async fn idle_after_activity_stops() {
+        const TIMEOUT: Duration = Duration::from_millis(40);
+
+        let activity = AtomicU64::new(0);
+        let start = Instant::now();
+        let busy = async {
+            for _ in 0..8 {
+                tokio::time::sleep(TIMEOUT / 4).await;
+                activity.fetch_add(1, Ordering::Relaxed);
+            }
+        };
+        let idle = until_idle(TIMEOUT, || activity.load(Ordering::Relaxed));
+
+        let ((), ()) = timeout(TIMEOUT * 10, async { tokio::join!(busy, idle) })
+            .await
+            .unwrap();
+        // Activity kept it going for 2 timeouts, then it's idle for one more
+        assert!(start.elapsed() >= TIMEOUT * 3);
+    }
+}
@@ -0,0 +1,120 @@
+use std::{future::Future, mem, sync::Mutex, time::Duration};
+
+use tokio::{sync::watch, task::JoinSet, time::timeout};
+
+/// Tasks spawned on behalf of senders, cancelled and awaited together.
+///
+/// Tasks are expected to finish on their own once [`TaskSet::cancelled`] resolves, the ones
+/// still running after a grace period are aborted. Dropping the set aborts every task.
+pub struct TaskSet {
+    tasks: Mutex<JoinSet<()>>,
+    cancel: watch::Sender<bool>,
+}
+
+impl TaskSet {
+    /// Tasks still running after cancellation for this long are aborted
+    const GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+    pub fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
+        let mut tasks = self.tasks.lock().unwrap();
+        // Finished tasks are reaped here, nobody is interested in their results
+        while tasks.try_join_next().is_some() {}
+        tasks.spawn(task);
+    }
+
+    /// Resolves once the set is cancelled or dropped.
+    pub fn cancelled(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut cancel = self.cancel.subscribe();
+        async move {
+            let _ = cancel.wait_for(|cancelled| *cancelled).await;
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.cancel.borrow()
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.send_replace(true);
+    }
+
+    /// Cancels and awaits every task, including the ones spawned meanwhile.
+    pub async fn shutdown(&self) {
+        self.cancel();
+
+        loop {
+            let mut tasks = mem::take(&mut *self.tasks.lock().unwrap());
+            if tasks.is_empty() {
+                return;
+            }
+
+            let join_all = async { while tasks.join_next().await.is_some() {} };
+            if timeout(Self::GRACE_PERIOD, join_all).await.is_err() {
+                tracing::warn!(count = %tasks.len(), "tasks not finished in time, aborting");
+                tasks.abort_all();
+                while tasks.join_next().await.is_some() {}
+            }
+        }
+    }
+
+    /// Count of tasks not reaped yet, some of them may have finished already.
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.tasks.lock().unwrap().len()
+    }
+}
+
+impl Default for TaskSet {
+    fn default() -> Self {
+        Self {
+            tasks: Mutex::default(),
+            cancel: watch::Sender::new(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future::pending, sync::Arc, time::Duration};
+
+    use tokio::time::timeout;
+
+    use super::TaskSet;
+
+    #[tokio::test]
+    async fn shutdown_leaves_nothing_running() {
+        let tasks = TaskSet::default();
+        let alive = Arc::new(());
+
+        // Finishing on cancellation and ignoring it
+        let (cancelled, guard) = (tasks.cancelled(), Arc::clone(&alive));
+        tasks.spawn(async move {
+            cancelled.await;
+            drop(guard);
+        });
+        let guard = Arc::clone(&alive);
+        tasks.spawn(async move {
+            pending::<()>().await;
+            drop(guard);
+        });
+        assert_eq!(3, Arc::strong_count(&alive));
+
+        timeout(Duration::from_secs(5), tasks.shutdown())
+            .await
+            .unwrap();
+        assert!(tasks.is_cancelled());
+        assert_eq!(0, tasks.len());
+        assert_eq!(1, Arc::strong_count(&alive));
+    }
+
+    #[tokio::test]
+    async fn finished_tasks_are_reaped() {
+        let tasks = TaskSet::default();
+        tasks.spawn(async {});
+        tokio::task::yield_now().await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        tasks.spawn(pending());
+        assert_eq!(1, tasks.len());
+    }
+}
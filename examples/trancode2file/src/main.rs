@@ -1,11 +1,8 @@
-use tokio::net::TcpListener;
 use tracing_chrome::ChromeLayerBuilder;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 mod audio;
-mod discovery;
 mod playback;
-mod transport;
 mod video;
 
 #[tokio::main]
@@ -15,9 +12,6 @@ async fn main() {
 
     gstreamer::init().expect("gstreamer initialization");
 
-    let svc_listener = TcpListener::bind("0.0.0.0:5200").await.unwrap();
-    discovery::mdns_broadcast();
-
     let cfg = airplay::config::Config::<_, _> {
         video: airplay::config::Video {
             device: playback::PipeDevice {
@@ -31,8 +25,16 @@ async fn main() {
             },
             ..Default::default()
         },
+        rtsp_port: 5200,
         ..Default::default()
     };
 
-    transport::serve_with_rtsp_remap(svc_listener, airplay::rtsp::RouterService::serve(cfg)).await;
+    let receiver = airplay::rtsp::Receiver::new(cfg);
+    let handle = receiver.shutdown_handle();
+    tokio::spawn(async move {
+        let _ = tokio::signal::ctrl_c().await;
+        tracing::info!("shutting down");
+        handle.shutdown();
+    });
+    receiver.serve().await.expect("receiver served");
 }
@@ -7,8 +7,8 @@ use std::{
 };
 
 use airplay::playback::{
-    audio::{AudioDevice, AudioPacket, AudioParams},
-    video::{VideoDevice, VideoPacket, VideoParams},
+    audio::{AudioDevice, AudioPacket, AudioParams, AudioStream},
+    video::{VideoDevice, VideoPacket, VideoParams, VideoStream},
     ChannelHandle, Device, Stream,
 };
 
@@ -65,6 +65,10 @@ where
 
 impl VideoDevice for PipeDevice<VideoParams, VideoPacket> {}
 
+impl VideoStream for PipeStream<VideoPacket> {}
+
+impl AudioStream for PipeStream<AudioPacket> {}
+
 impl AudioDevice for PipeDevice<AudioParams, AudioPacket> {
     fn get_volume(&self) -> f32 {
         0.0